@@ -0,0 +1,253 @@
+//! A misbehaving peer for local development.
+//!
+//! Serves the data of a single torrent over the peer protocol while injecting
+//! latency, dropped blocks, corrupted blocks and choking so that edge cases in
+//! the choker and piece picker can be exercised without a live swarm.
+//!
+//! cargo run --features test-peer --bin test-peer -- ubuntu.torrent ubuntu.iso --latency 200
+use clap::{Parser, ValueEnum};
+use rand::Rng;
+use std::{
+    io,
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use torrent::{
+    disk::{Durability, PieceWriter},
+    meta_info::MetaInfo,
+    peer::{validation::MAX_BLOCK_LENGTH, Handshake, Message, PeerError, BLOCK_SIZE},
+};
+
+/// A configurable test peer speaking the BitTorrent peer protocol.
+#[derive(Parser)]
+struct Args {
+    /// Path to the torrent file to serve.
+    torrent: PathBuf,
+
+    /// The torrent's (complete) data, or the directory it is in.
+    data: PathBuf,
+
+    /// The port to listen on for incoming connections.
+    #[clap(short, long, default_value_t = 6882)]
+    port: u16,
+
+    /// Delay in milliseconds before answering each request.
+    #[clap(short, long, default_value_t = 0)]
+    latency: u64,
+
+    /// Probability (0.0 - 1.0) that a requested block is silently dropped.
+    #[clap(long, default_value_t = 0.0)]
+    loss: f64,
+
+    /// Probability (0.0 - 1.0) that a block is sent with corrupted bytes.
+    #[clap(long, default_value_t = 0.0)]
+    corrupt: f64,
+
+    /// Silently drop requests for blocks larger than this many bytes, like
+    /// strict clients do past 16 KiB.
+    #[clap(
+        long,
+        default_value_t = BLOCK_SIZE,
+        value_parser = clap::value_parser!(u32).range(1..=MAX_BLOCK_LENGTH as i64),
+    )]
+    max_block_length: u32,
+
+    /// How the peer chokes whoever is connected to it.
+    #[clap(long, value_enum, default_value_t = ChokeMode::Never)]
+    choke: ChokeMode,
+
+    /// Seconds between toggling choke state when `--choke periodic` is used.
+    #[clap(long, default_value_t = 10)]
+    choke_interval: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChokeMode {
+    /// Unchoke as soon as the remote is interested.
+    Never,
+    /// Never unchoke the remote.
+    Always,
+    /// Toggle between choked and unchoked every `--choke-interval` seconds.
+    Periodic,
+}
+
+fn main() {
+    let args = Arc::new(Args::parse());
+
+    let torrent = match MetaInfo::try_from(args.torrent.clone()) {
+        Ok(torrent) => Arc::new(torrent),
+        Err(_) => {
+            eprintln!("unable to parse torrent file");
+            return;
+        }
+    };
+    let data = Arc::new(PieceWriter::new(
+        torrent.info(),
+        data_root(&torrent, &args.data),
+        Durability::Fast,
+    ));
+
+    let listener = TcpListener::bind(("0.0.0.0", args.port)).expect("failed to bind port");
+    println!(
+        "test peer serving {} on port {}",
+        torrent.info().hash(),
+        args.port
+    );
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+
+        let args = Arc::clone(&args);
+        let torrent = Arc::clone(&torrent);
+        let data = Arc::clone(&data);
+        thread::spawn(move || {
+            let addr = stream.peer_addr().ok();
+            if let Err(err) = serve(stream, &args, &torrent, &data) {
+                eprintln!("{:?}: {:?}", addr, err);
+            }
+        });
+    }
+}
+
+/// The directory the torrent's files are under, `data` may also be the
+/// torrent's file or folder itself.
+fn data_root(torrent: &MetaInfo, data: &Path) -> PathBuf {
+    match data.parent() {
+        Some(parent) if data.ends_with(torrent.info().name()) => parent.to_owned(),
+        _ => data.to_owned(),
+    }
+}
+
+fn serve(
+    mut stream: TcpStream,
+    args: &Args,
+    torrent: &MetaInfo,
+    data: &PieceWriter,
+) -> Result<(), PeerError> {
+    let info_hash = *torrent.info().hash().as_bytes();
+
+    let handshake = Handshake::read_from(&mut stream)?;
+    if handshake.info_hash != info_hash {
+        return Ok(());
+    }
+
+    let mut peer_id = *b"-FT0001-000000000000";
+    rand::thread_rng().fill(&mut peer_id[8..]);
+    Handshake::new(info_hash, peer_id).write_to(&mut stream)?;
+
+    let piece_count = torrent.info().pieces().len();
+    let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+    for index in 0..piece_count {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    Message::Bitfield(bitfield).write_to(&mut stream)?;
+
+    // Choking is toggled from a thread of its own, so a message is never
+    // read only in part
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let choked = Arc::new(AtomicBool::new(true));
+    if let ChokeMode::Periodic = args.choke {
+        let interval = Duration::from_secs(args.choke_interval.max(1));
+        let writer = Arc::clone(&writer);
+        let choked = Arc::clone(&choked);
+        thread::spawn(move || toggle_choke(&writer, &choked, interval));
+    }
+
+    let result = receive(&mut stream, args, torrent, data, &writer, &choked);
+    // Ends the choke thread at its next write
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Choke and unchoke the remote every `interval` until the connection is
+/// closed.
+fn toggle_choke(writer: &Mutex<TcpStream>, choked: &AtomicBool, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let mut writer = writer.lock().unwrap();
+        let message = if choked.fetch_xor(true, Ordering::Relaxed) {
+            Message::Unchoke
+        } else {
+            Message::Choke
+        };
+        if message.write_to(&mut *writer).is_err() {
+            return;
+        }
+    }
+}
+
+fn receive(
+    stream: &mut TcpStream,
+    args: &Args,
+    torrent: &MetaInfo,
+    data: &PieceWriter,
+    writer: &Mutex<TcpStream>,
+    choked: &AtomicBool,
+) -> Result<(), PeerError> {
+    let info = torrent.info();
+    loop {
+        let message = match Message::read_from(stream) {
+            Ok(message) => message,
+            Err(PeerError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        match message {
+            Message::Interested
+                if matches!(args.choke, ChokeMode::Never)
+                    && choked.swap(false, Ordering::Relaxed) =>
+            {
+                Message::Unchoke.write_to(&mut *writer.lock().unwrap())?;
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } if !choked.load(Ordering::Relaxed) => {
+                if length > args.max_block_length {
+                    continue;
+                }
+                // Outside the piece, or past the end of the torrent
+                if info.block_spans(index as usize, begin, length).is_none() {
+                    let err =
+                        io::Error::new(io::ErrorKind::InvalidInput, "request outside the piece");
+                    return Err(err.into());
+                }
+
+                thread::sleep(Duration::from_millis(args.latency));
+
+                let mut rng = rand::thread_rng();
+                if rng.gen_bool(args.loss.clamp(0.0, 1.0)) {
+                    continue;
+                }
+
+                let mut block = data.read_block(info, index, begin, length)?;
+
+                if rng.gen_bool(args.corrupt.clamp(0.0, 1.0)) {
+                    let byte = rng.gen_range(0..block.len().max(1));
+                    if let Some(byte) = block.get_mut(byte) {
+                        *byte = !*byte;
+                    }
+                }
+
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+                .write_to(&mut *writer.lock().unwrap())?;
+            }
+            _ => {}
+        }
+    }
+}
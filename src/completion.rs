@@ -0,0 +1,47 @@
+use clap_complete::engine::CompletionCandidate;
+use std::collections::BTreeSet;
+
+use crate::{config::Config, state::StateStore};
+
+// Shell completions are generated at runtime, so they can offer the torrents
+// the daemon knows about. Enable them with e.g.
+//
+// bash: echo 'source <(COMPLETE=bash flud)' >> ~/.bashrc
+// zsh:  echo 'source <(COMPLETE=zsh flud)' >> ~/.zshrc
+// fish: echo 'COMPLETE=fish flud | source' >> ~/.config/fish/config.fish
+//
+// Candidates come from the same state store `flud daemon list` reads, not
+// from the running daemon: there is no connection to it yet, and the store
+// holds every torrent it knows about anyway. Completing never has to wait
+// on (or start) the daemon this way, which should stay the fallback once
+// it can be asked.
+
+/// Info hashes of every torrent, described by their name.
+pub fn torrents() -> Vec<CompletionCandidate> {
+    let Ok(entries) = StateStore::open().and_then(|store| store.list()) else {
+        return Vec::new();
+    };
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let help = entry
+                .name
+                .map(|name| format!("{name} ({})", entry.status.folder_name()));
+            CompletionCandidate::new(entry.info_hash).help(help.map(Into::into))
+        })
+        .collect()
+}
+
+/// Labels with a configured download directory along with labels already in use.
+pub fn labels() -> Vec<CompletionCandidate> {
+    let mut labels: BTreeSet<String> = Config::load()
+        .map(|config| config.downloads.labels.into_keys().collect())
+        .unwrap_or_default();
+
+    if let Ok(entries) = StateStore::open().and_then(|store| store.list()) {
+        labels.extend(entries.into_iter().filter_map(|entry| entry.label));
+    }
+
+    labels.into_iter().map(CompletionCandidate::new).collect()
+}
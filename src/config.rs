@@ -1,18 +1,872 @@
-use dirs::config_dir;
-use std::fs::File;
+use dirs::{config_dir, download_dir, home_dir};
+use serde::{
+    de::{self, Deserializer, Unexpected},
+    Deserialize, Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use torrent::{
+    cache::{WriteCache, WriteCachePolicy, DEFAULT_WRITE_CACHE, DEFAULT_WRITE_CACHE_AGE},
+    discovery::Discovery,
+    disk::{Durability, PieceWriter},
+    hash_pool::HashPool,
+    identity::Identity,
+    interface::{AddressFamily, OutgoingInterface},
+    memory::MemoryBudget,
+    meta_info::Info,
+    peer::{
+        connection::{ConnectionLimits, SocketOptions},
+        mse::EncryptionMode,
+        unchoke::{Unchoker, UploadSlots, DEFAULT_RESERVED_SLOTS},
+    },
+    proxy::{self, HttpProxy, ProxiedTraffic, ProxyError, Socks5Proxy},
+    rate_limit::RateLimits,
+    share_limit::ShareLimits,
+    source::{SourceResolver, DEFAULT_MAX_TORRENT_SIZE},
+    storage::{Allocation, FileStorage},
+    swarm::StallAction,
+    tracker::{filter::TrackerFilter, stats::TrackerQuota},
+    units::{ByteSize, HumanDuration},
+    update::UpdateAction,
+};
 
-// TODO: replace this whole module with a Config struct
-// so that we can have better interface for setting and getting properties
+use crate::{logging::LogFilter, rules::ArchivePolicy, turtle::TurtleWindow};
 
 static CONFIG_FILE_NAME: &str = "config.toml";
 
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
     #[error("io error")]
     IoError(#[from] std::io::Error),
+    #[error("unable to parse config file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("unable to serialize config")]
+    SerializeError(#[from] toml::ser::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputDirError {
+    #[error("no download directory configured and none could be found, pass --output")]
+    NotConfigured,
+    #[error("unable to create download directory {0}: {1}")]
+    Create(PathBuf, std::io::Error),
+    #[error("download directory {0} is not writable: {1}")]
+    NotWritable(PathBuf, std::io::Error),
+}
+
+/// The user's settings, stored as `config.toml` in the flud config directory.
+///
+/// Every field has a default so a partial (or empty) file is still valid.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub memory: MemoryConfig,
+    pub network: NetworkConfig,
+    pub proxy: ProxyConfig,
+    pub http_proxy: HttpProxyConfig,
+    pub privacy: PrivacyConfig,
+    pub trackers: TrackersConfig,
+    pub downloads: DownloadsConfig,
+    pub rate_limits: RateLimitConfig,
+    pub low_power: LowPowerConfig,
+    pub dht: DhtConfig,
+    pub pex: PexConfig,
+    pub lsd: LsdConfig,
+    pub daemon: DaemonConfig,
+    pub storage: StorageConfig,
+    pub share_limits: ShareLimitsConfig,
+    pub hooks: HooksConfig,
+    /// Rules for torrents with a label, by label.
+    pub labels: BTreeMap<String, LabelRules>,
+    /// Rules labelling torrents by their name as they are added, the first
+    /// that matches applies.
+    pub auto_label: Vec<AutoLabelRule>,
 }
 
-pub fn get_or_create() -> Result<File, ConfigError> {
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Approximate upper bound for the memory used by piece buffers, caches,
+    /// DHT tables and peer buffers, e.g. `512MiB`. When exceeded the daemon
+    /// sheds load by reducing request pipelining and shrinking caches.
+    ///
+    /// `0` means unlimited.
+    pub budget: ByteSize,
+}
+
+impl MemoryConfig {
+    pub fn budget_bytes(&self) -> usize {
+        usize::try_from(self.budget.bytes()).unwrap_or(usize::MAX)
+    }
+}
+
+/// Socket options applied to every peer connection.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// The port peers connect to. If it is one of 6881-6889 and taken, the
+    /// next free one of them is used.
+    pub listen_port: u16,
+    /// DSCP value (0-63) used to mark peer traffic, e.g. `8` (CS1) to have
+    /// routers treat it as low priority bulk traffic. Unset leaves the OS default.
+    #[serde(deserialize_with = "dscp")]
+    pub dscp: Option<u8>,
+    /// Disable Nagle's algorithm on peer sockets.
+    pub tcp_nodelay: bool,
+    /// Kernel send buffer size in bytes, `0` leaves the OS default.
+    pub send_buffer_bytes: usize,
+    /// Kernel receive buffer size in bytes, `0` leaves the OS default.
+    pub recv_buffer_bytes: usize,
+    /// Seconds a connection may be idle before TCP keepalive probes are sent,
+    /// `0` disables keepalive.
+    pub keepalive_secs: u64,
+    /// Maximum outgoing peer connection attempts started per second, `0` is unlimited.
+    pub connections_per_second: u32,
+    /// Maximum outgoing peer connection attempts in progress at once, `0` is
+    /// unlimited. Some consumer routers drop connections with too many.
+    pub max_half_open: usize,
+    /// Maximum peers waiting in line for a connection attempt, more are
+    /// tried again later, `0` is unlimited.
+    pub max_pending_connections: usize,
+    /// Protocol encryption (MSE/PE) for peer connections: `disabled`,
+    /// `prefer` (encrypt when the peer supports it) or `require` (only talk
+    /// to peers that encrypt).
+    pub encryption: EncryptionMode,
+    /// Ask the router to forward the listen port (UPnP, or NAT-PMP/PCP when
+    /// the router has no UPnP), so peers outside the LAN can connect to us.
+    pub upnp: bool,
+    /// Send all peer, tracker and DHT traffic from this interface (e.g.
+    /// `tun0`, Linux only) or local address (e.g. `10.8.0.2`), and none at
+    /// all while it is down. Unset uses whatever the routing table picks.
+    pub outgoing_interface: Option<String>,
+    /// Which IP versions peer, tracker and DHT traffic use: `both`,
+    /// `ipv4-only` or `ipv6-only`, for networks where one is broken or not
+    /// allowed.
+    pub address_family: AddressFamily,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: DEFAULT_LISTEN_PORT,
+            dscp: None,
+            tcp_nodelay: true,
+            send_buffer_bytes: 0,
+            recv_buffer_bytes: 0,
+            keepalive_secs: 0,
+            connections_per_second: ConnectionLimits::default().per_second,
+            max_half_open: ConnectionLimits::default().half_open,
+            max_pending_connections: ConnectionLimits::default().pending,
+            encryption: EncryptionMode::default(),
+            upnp: true,
+            outgoing_interface: None,
+            address_family: AddressFamily::default(),
+        }
+    }
+}
+
+/// DSCP is the upper 6 bits of the TOS byte, anything above 63 is a typo
+/// rather than something to quietly cut down.
+fn dscp<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u8>::deserialize(deserializer)? {
+        Some(dscp) if dscp > 63 => Err(de::Error::invalid_value(
+            Unexpected::Unsigned(dscp.into()),
+            &"a DSCP value from 0 to 63",
+        )),
+        dscp => Ok(dscp),
+    }
+}
+
+impl NetworkConfig {
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            per_second: self.connections_per_second,
+            half_open: self.max_half_open,
+            pending: self.max_pending_connections,
+        }
+    }
+
+    pub fn outgoing_interface(&self) -> OutgoingInterface {
+        match &self.outgoing_interface {
+            Some(interface) => interface.parse().unwrap_or_default(),
+            None => OutgoingInterface::Any,
+        }
+    }
+
+    pub fn socket_options(&self) -> SocketOptions {
+        let non_zero = |bytes: usize| (bytes > 0).then_some(bytes);
+        SocketOptions {
+            dscp: self.dscp,
+            nodelay: self.tcp_nodelay,
+            send_buffer_size: non_zero(self.send_buffer_bytes),
+            recv_buffer_size: non_zero(self.recv_buffer_bytes),
+            keepalive: (self.keepalive_secs > 0).then(|| Duration::from_secs(self.keepalive_secs)),
+        }
+    }
+}
+
+/// A SOCKS5 proxy to send traffic through, e.g.
+///
+/// ```toml
+/// [proxy]
+/// host = "127.0.0.1"
+/// port = 1080
+/// username = "user"
+/// password = "secret"
+/// ```
+///
+/// Only TCP goes through it: UDP trackers are skipped while trackers are
+/// proxied, and the DHT talks to nodes directly unless `[dht]` is disabled.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// The proxy's host name or address, unset to not use a proxy.
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connect to peers and web seeds through the proxy.
+    pub peers: bool,
+    /// Announce, scrape and download .torrent files through the proxy.
+    pub trackers: bool,
+    /// Have the proxy resolve host names, so lookups don't leak outside it.
+    pub remote_dns: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: proxy::DEFAULT_PORT,
+            username: None,
+            password: None,
+            peers: true,
+            trackers: true,
+            remote_dns: true,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// The proxy and what goes through it, `None` without a host.
+    pub fn proxy(&self) -> Option<(Socks5Proxy, ProxiedTraffic)> {
+        let mut proxy =
+            Socks5Proxy::new(self.host.clone()?, self.port).with_remote_dns(self.remote_dns);
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            proxy = proxy.with_credentials(username.clone(), password);
+        }
+        let traffic = ProxiedTraffic {
+            peers: self.peers,
+            trackers: self.trackers,
+        };
+        Some((proxy, traffic))
+    }
+}
+
+/// An HTTP(S) proxy for tracker and web seed requests only, e.g.
+///
+/// ```toml
+/// [http_proxy]
+/// url = "http://proxy.example.com:3128"
+/// username = "user"
+/// password = "secret"
+/// ```
+///
+/// The SOCKS5 `[proxy]` takes precedence for the traffic it covers.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpProxyConfig {
+    pub enabled: bool,
+    pub url: Option<String>,
+    /// For proxies requiring basic auth.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for HttpProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            url: None,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl HttpProxyConfig {
+    /// The proxy, `None` when disabled or without a url.
+    pub fn proxy(&self) -> Result<Option<HttpProxy>, ProxyError> {
+        let Some(url) = self.url.as_deref().filter(|_| self.enabled) else {
+            return Ok(None);
+        };
+        let mut proxy: HttpProxy = url.parse()?;
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            proxy = proxy.with_credentials(username.clone(), password);
+        }
+        Ok(Some(proxy))
+    }
+}
+
+/// How much torrents give away about this client.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    /// Leave the client name out of peer ids and extension handshakes and
+    /// other addresses out of announces, and don't use the DHT, PEX or LSD.
+    /// Torrents can override it when added.
+    pub anonymous: bool,
+}
+
+impl PrivacyConfig {
+    /// The identity of a torrent, `anonymous` being its own setting if it
+    /// has one.
+    pub fn identity(&self, anonymous: Option<bool>) -> Identity {
+        if anonymous.unwrap_or(self.anonymous) {
+            Identity::anonymous()
+        } else {
+            Identity::new()
+        }
+    }
+}
+
+pub const DEFAULT_QUOTA_WARNING: f64 = 0.9;
+
+/// Which tracker hosts may be announced to and how much may be transferred
+/// with them.
+///
+/// Rules are host names, `*.example.com` matches every subdomain.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackersConfig {
+    /// When not empty, only announce to trackers on these hosts.
+    pub allow_hosts: Vec<String>,
+    /// Never announce to trackers on these hosts, e.g. dead public trackers.
+    pub deny_hosts: Vec<String>,
+    /// Let a forced re-announce through before the tracker's `min interval`
+    /// is over. Only for trackers you run yourself, public ones ban clients
+    /// that announce too often.
+    pub ignore_min_interval: bool,
+    /// Soft limits on what is uploaded to and downloaded from the peers of
+    /// a tracker host, counted across every torrent from what is announced
+    /// to it, e.g. `"tracker.example.com" = { download = "100GiB" }`.
+    pub quotas: BTreeMap<String, TrackerQuota>,
+    /// The share of a quota at which the `quota` hooks fire first, they
+    /// fire again once it is used up.
+    pub quota_warning: f64,
+}
+
+impl Default for TrackersConfig {
+    fn default() -> Self {
+        Self {
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            ignore_min_interval: false,
+            quotas: BTreeMap::new(),
+            quota_warning: DEFAULT_QUOTA_WARNING,
+        }
+    }
+}
+
+impl TrackersConfig {
+    pub fn filter(&self) -> TrackerFilter {
+        TrackerFilter::new(self.allow_hosts.clone(), self.deny_hosts.clone())
+    }
+}
+
+/// Where downloaded data is written when `--output` is not given.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DownloadsConfig {
+    /// The default download directory, the system download directory
+    /// (e.g. `~/Downloads`) if unset. A leading `~` is the home directory
+    /// and `{label}` is replaced with the torrent's label.
+    pub directory: Option<String>,
+    /// Directory templates for torrents added with a label, taking
+    /// precedence over `directory`, e.g. `linux = "~/isos/{label}"`.
+    pub labels: BTreeMap<String, String>,
+    /// The largest .torrent file to download from a URL or feed, e.g. `10MiB`.
+    pub max_torrent_size: ByteSize,
+    /// What to do with a torrent whose missing pieces no peer has, `keep-trying`
+    /// or `pause`. Either way it shows as stalled.
+    pub on_stall: StallAction,
+    /// What to do once a torrent's `update-url` has a newer version: `ignore`,
+    /// `prompt` or replace it right away with `auto`.
+    pub on_update: UpdateAction,
+}
+
+impl Default for DownloadsConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            labels: BTreeMap::new(),
+            max_torrent_size: ByteSize(DEFAULT_MAX_TORRENT_SIZE),
+            on_stall: StallAction::default(),
+            on_update: UpdateAction::default(),
+        }
+    }
+}
+
+impl DownloadsConfig {
+    /// A resolver for the sources of torrents being added.
+    pub fn source_resolver(&self) -> SourceResolver {
+        SourceResolver::new().with_max_torrent_size(self.max_torrent_size.bytes())
+    }
+
+    /// The download directory for a torrent with `label`.
+    pub fn directory_for(&self, label: Option<&str>) -> Option<PathBuf> {
+        let template = label
+            .and_then(|label| self.labels.get(label))
+            .or(self.directory.as_ref());
+
+        let Some(template) = template else {
+            let dir = download_dir()?;
+            return Some(match label {
+                Some(label) => dir.join(label),
+                None => dir,
+            });
+        };
+
+        expand_home(&template.replace("{label}", label.unwrap_or_default()))
+    }
+}
+
+/// `path` with a leading `~` replaced by the home directory.
+pub fn expand_home(path: &str) -> Option<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => Some(home_dir()?.join(rest)),
+        None if path == "~" => home_dir(),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Global transfer rate limits, per second, e.g. `1.5MiB`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Maximum download rate, `0` is unlimited.
+    pub download: ByteSize,
+    /// Maximum upload rate, `0` is unlimited.
+    pub upload: ByteSize,
+    /// The slower limits of turtle mode and when it is on.
+    pub turtle: TurtleConfig,
+    /// Peers uploaded to at once across every torrent, `auto` scales them
+    /// with the upload limit, `0` is unlimited.
+    pub upload_slots: UploadSlots,
+    /// Peers uploaded to at once per torrent, unless set for the torrent.
+    pub upload_slots_per_torrent: UploadSlots,
+    /// The share of a torrent's upload slots only peers with next to
+    /// nothing may take, so new peers get started, `0` reserves none.
+    pub reserved_upload_slots: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            download: ByteSize::default(),
+            upload: ByteSize::default(),
+            turtle: TurtleConfig::default(),
+            upload_slots: UploadSlots::default(),
+            upload_slots_per_torrent: UploadSlots::default(),
+            reserved_upload_slots: DEFAULT_RESERVED_SLOTS,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn limits(&self) -> RateLimits {
+        limits(self.download, self.upload)
+    }
+
+    /// The session's unchoker, with the number of slots for the upload
+    /// limit of the current speed mode.
+    pub fn unchoker(&self, limits: RateLimits) -> Unchoker {
+        Unchoker::new(self.upload_slots.resolve(limits.upload))
+    }
+
+    /// The unchoker of a torrent, drawing from `session`'s slots, with
+    /// some of its own reserved for new peers.
+    pub fn torrent_unchoker(&self, session: &Unchoker, limits: RateLimits) -> Unchoker {
+        session
+            .for_torrent(self.upload_slots_per_torrent.resolve(limits.upload))
+            .with_reserved(self.reserved_upload_slots)
+    }
+}
+
+fn limits(download: ByteSize, upload: ByteSize) -> RateLimits {
+    let cap = |rate: ByteSize| (rate.bytes() > 0).then_some(rate.bytes());
+    RateLimits {
+        download: cap(download),
+        upload: cap(upload),
+    }
+}
+
+/// Alternative global rate limits, switched to by hand or on a weekly
+/// schedule, see `turtle::TurtleWindow`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TurtleConfig {
+    /// Maximum download rate in turtle mode, `0` is unlimited.
+    pub download: ByteSize,
+    /// Maximum upload rate in turtle mode, `0` is unlimited.
+    pub upload: ByteSize,
+    /// When turtle mode is on, never on its own if empty.
+    pub schedule: Vec<TurtleWindow>,
+}
+
+impl TurtleConfig {
+    pub fn limits(&self) -> RateLimits {
+        limits(self.download, self.upload)
+    }
+}
+
+/// What changes in low-power mode and whether it switches on by itself,
+/// see `power`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LowPowerConfig {
+    /// Switch to low-power mode while running on battery.
+    pub on_battery: bool,
+    /// Maximum download rate in low-power mode, `0` is unlimited.
+    pub download: ByteSize,
+    /// Maximum upload rate in low-power mode, `0` is unlimited.
+    pub upload: ByteSize,
+    /// Peers connected at once per torrent in low-power mode, `0` is
+    /// unlimited.
+    pub max_peers: usize,
+    /// Announce at most this often in low-power mode, e.g. `1h`.
+    pub announce_interval: HumanDuration,
+}
+
+impl Default for LowPowerConfig {
+    fn default() -> Self {
+        Self {
+            on_battery: true,
+            download: ByteSize(1024 * 1024),
+            upload: ByteSize(128 * 1024),
+            max_peers: 10,
+            announce_interval: HumanDuration(Duration::from_secs(3600)),
+        }
+    }
+}
+
+impl LowPowerConfig {
+    pub fn limits(&self) -> RateLimits {
+        limits(self.download, self.upload)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DhtConfig {
+    /// Find peers through the DHT (BEP 5) as well as trackers.
+    pub enabled: bool,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PexConfig {
+    /// Swap peer lists with connected peers (BEP 11).
+    pub enabled: bool,
+}
+
+impl Default for PexConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LsdConfig {
+    /// Find peers on the local network by multicast (BEP 14).
+    pub enabled: bool,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Start the daemon when the user logs in.
+    pub start_at_login: bool,
+    /// Which events are logged, e.g. `info` or `warn,torrent::peer=debug`.
+    /// `flud daemon log-level` changes it until the daemon restarts.
+    pub log_filter: LogFilter,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// How sure flud makes that a piece is on disk before advertising it:
+    /// `fast` (written), `safe` (fsync'd) or `paranoid` (fsync'd and read back).
+    pub durability: Durability,
+    /// How room for a torrent's files is reserved when it is added:
+    /// `sparse` (extended, written as pieces arrive) or `full` (zeroed up front).
+    /// Sparse falls back to full on file systems that can't keep files
+    /// sparse, e.g. FAT.
+    pub allocation: Allocation,
+    /// Memory for blocks waiting to be written in large runs rather than
+    /// one at a time, e.g. `64MiB`. Counts towards `memory.budget`, `0`
+    /// writes every block as it arrives.
+    pub write_cache: ByteSize,
+    /// The longest a block waits in the write cache, e.g. `30s`. Blocks
+    /// are written once their piece verified either way.
+    pub write_cache_max_age: HumanDuration,
+    /// Read and write files in batches through io_uring, in builds with
+    /// the `io-uring` feature on Linux. Falls back to a blocking call per
+    /// block where the kernel doesn't offer it.
+    pub io_uring: bool,
+    /// Threads hashing pieces, for checks as well as pieces completed by
+    /// peers, `0` is one per core.
+    pub hashing_threads: usize,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            allocation: Allocation::default(),
+            write_cache: ByteSize(DEFAULT_WRITE_CACHE as u64),
+            write_cache_max_age: HumanDuration(DEFAULT_WRITE_CACHE_AGE),
+            io_uring: true,
+            hashing_threads: 0,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// The threads every torrent's pieces are hashed on, start it once.
+    pub fn hash_pool(&self) -> HashPool {
+        HashPool::new(self.hashing_threads)
+    }
+
+    /// A writer for the torrent's files under `root` with these settings,
+    /// its write cache accounted to `memory` and its pieces hashed on
+    /// `hashes`.
+    pub fn writer(
+        &self,
+        info: &Info,
+        root: PathBuf,
+        memory: &MemoryBudget,
+        hashes: &HashPool,
+    ) -> PieceWriter {
+        let files = FileStorage::new(info, root).with_allocation(self.allocation);
+        let policy = WriteCachePolicy {
+            budget: usize::try_from(self.write_cache.bytes()).unwrap_or(usize::MAX),
+            max_age: self.write_cache_max_age.as_duration(),
+        };
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.io_uring {
+            let files = torrent::storage::uring::UringStorage::new(files);
+            return PieceWriter::from_storage(
+                WriteCache::with_memory(files, policy, memory.clone()),
+                self.durability,
+            )
+            .with_hash_pool(hashes.clone());
+        }
+        PieceWriter::from_storage(
+            WriteCache::with_memory(files, policy, memory.clone()),
+            self.durability,
+        )
+        .with_hash_pool(hashes.clone())
+    }
+}
+
+/// When seeding torrents stop, and what happens to them then, e.g.
+///
+/// ```toml
+/// [share_limits]
+/// ratio = 2.0
+/// action = "pause"
+///
+/// [share_limits.labels.linux]
+/// seed_time_mins = 1440
+/// action = "remove-with-data"
+/// ```
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ShareLimitsConfig {
+    #[serde(flatten)]
+    pub global: ShareLimits,
+    /// Limits for torrents with a label, taking precedence over the global ones.
+    pub labels: BTreeMap<String, ShareLimits>,
+}
+
+impl ShareLimitsConfig {
+    /// The limits of a torrent with its own `limits` and `label`.
+    pub fn resolve(&self, limits: ShareLimits, label: Option<&str>) -> ShareLimits {
+        let label = label
+            .and_then(|label| self.labels.get(label))
+            .copied()
+            .unwrap_or_default();
+        limits.or(label).or(self.global)
+    }
+}
+
+/// What happens to torrents with a label as they change state, e.g.
+///
+/// ```toml
+/// [labels.linux]
+/// move_to = "~/isos"
+/// on_complete = ["/usr/local/bin/verify-iso"]
+/// ratio = 3.0
+/// archive = "on-stop"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LabelRules {
+    /// Where the data is moved once complete. A leading `~` is the home
+    /// directory.
+    pub move_to: Option<String>,
+    /// A command run once complete, after the data was moved, like a
+    /// `completed` hook.
+    pub on_complete: Vec<String>,
+    /// Upload ratio to seed to, taking precedence over `[share_limits]`.
+    pub ratio: Option<f64>,
+    /// When the torrent is archived: `never`, `on-complete` or `on-stop`.
+    pub archive: ArchivePolicy,
+}
+
+/// Labels torrents whose name matches `pattern` as they are added, however
+/// they are added, e.g.
+///
+/// ```toml
+/// [[auto_label]]
+/// pattern = ".*S[0-9]+E[0-9]+.*"
+/// label = "tv"
+/// save_path = "~/tv"
+/// ```
+///
+/// A label or output directory given when adding a torrent wins over the
+/// rule's.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AutoLabelRule {
+    /// A regular expression matched anywhere in the torrent's name, `(?i)`
+    /// in front ignores case.
+    pub pattern: String,
+    pub label: Option<String>,
+    /// Where the data is saved, the label's download directory if unset. A
+    /// leading `~` is the home directory.
+    pub save_path: Option<String>,
+}
+
+pub const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_MAX_CONCURRENT_HOOKS: usize = 2;
+
+/// Commands run when something happens to a torrent, e.g.
+///
+/// ```toml
+/// [hooks]
+/// max_concurrent = 2
+///
+/// [[hooks.commands]]
+/// on = "completed"
+/// command = ["/usr/bin/notify-send", "flud", "a download finished"]
+/// timeout_secs = 10
+/// env = ["DISPLAY", "DBUS_SESSION_BUS_ADDRESS"]
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// How many hooks may run at once, the rest wait their turn.
+    pub max_concurrent: usize,
+    pub commands: Vec<HookConfig>,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT_HOOKS,
+            commands: Vec::new(),
+        }
+    }
+}
+
+/// One command run on a torrent event.
+///
+/// The command is run directly, not through a shell. It learns about the
+/// torrent from `FLUD_EVENT`, `FLUD_INFO_HASH`, `FLUD_NAME`, `FLUD_LABEL`
+/// and `FLUD_OUTPUT`, any other variable has to be allowed in `env`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HookConfig {
+    pub on: HookEvent,
+    /// The program followed by its arguments.
+    pub command: Vec<String>,
+    /// Kill the command if it runs longer than this.
+    #[serde(default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Variables passed on from the daemon's environment, e.g. `PATH`.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Where the command runs, the torrent's download directory if unset.
+    /// A leading `~` is the home directory.
+    pub working_dir: Option<String>,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    DEFAULT_HOOK_TIMEOUT_SECS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    Added,
+    Completed,
+    Removed,
+    /// A stalled torrent was paused, see `downloads.on_stall`.
+    Stalled,
+    /// A tracker host's quota is nearly or entirely used up, see
+    /// `trackers.quotas`.
+    Quota,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::Added => "added",
+            HookEvent::Completed => "completed",
+            HookEvent::Removed => "removed",
+            HookEvent::Stalled => "stalled",
+            HookEvent::Quota => "quota",
+        }
+    }
+}
+
+/// Create `dir` if needed and make sure we can write to it, so a bad
+/// download directory is reported when the torrent is added rather than
+/// when the first piece is written.
+pub fn prepare_output_dir(dir: &Path) -> Result<(), OutputDirError> {
+    std::fs::create_dir_all(dir).map_err(|err| OutputDirError::Create(dir.to_owned(), err))?;
+
+    let probe = dir.join(".flud-write-test");
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|err| OutputDirError::NotWritable(dir.to_owned(), err))
+}
+
+/// The flud config directory, e.g. `~/.config/flud`, created if it doesn't exist.
+pub fn dir() -> Result<PathBuf, ConfigError> {
     let mut config_path = config_dir().ok_or(std::io::Error::new(
         std::io::ErrorKind::NotFound,
         "Config directory not found",
@@ -20,17 +874,105 @@ pub fn get_or_create() -> Result<File, ConfigError> {
 
     let app_name = env!("CARGO_PKG_NAME");
 
-    // Add app name and config file name to the path
     config_path.push(app_name);
     std::fs::create_dir_all(&config_path)?; // Create the app config directory if it doesn't exist
+    Ok(config_path)
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf, ConfigError> {
+        Ok(dir()?.join(CONFIG_FILE_NAME))
+    }
+
+    /// Whether the config file has been written, if not this is the first run.
+    pub fn exists() -> bool {
+        Self::path().is_ok_and(|path| path.exists())
+    }
+
+    /// Load the config file, or the defaults if it does not exist yet.
+    ///
+    /// The file is only written by the setup wizard or `save`, so running a
+    /// command before opening the TUI doesn't skip the wizard.
+    pub fn load() -> Result<Self, ConfigError> {
+        let config_path = Self::path()?;
+        if !config_path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = std::fs::read_to_string(config_path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let contents = toml::to_string_pretty(self)?;
+        std::fs::write(Self::path()?, contents)?;
+        Ok(())
+    }
+
+    /// The share limits of a torrent with its own `limits` and `label`,
+    /// the ratio of the label's rules before `[share_limits]`.
+    pub fn share_limits(&self, limits: ShareLimits, label: Option<&str>) -> ShareLimits {
+        let ratio = label
+            .and_then(|label| self.labels.get(label))
+            .and_then(|rules| rules.ratio);
+        let limits = limits.or(ShareLimits {
+            ratio,
+            ..ShareLimits::default()
+        });
+        self.share_limits.resolve(limits, label)
+    }
+
+    /// The peer sources to use, none of them in anonymous mode.
+    pub fn discovery(&self) -> Discovery {
+        let discovery = Discovery {
+            dht: self.dht.enabled,
+            pex: self.pex.enabled,
+            lsd: self.lsd.enabled,
+        };
+        match self.privacy.anonymous {
+            true => Discovery::none(),
+            false => discovery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(toml: &str) -> Result<NetworkConfig, toml::de::Error> {
+        toml::from_str::<Config>(toml).map(|config| config.network)
+    }
+
+    #[test]
+    fn dscp_is_unset_by_default() {
+        let network = network("").unwrap();
+        assert_eq!(network.dscp, None);
+        assert_eq!(network.socket_options().dscp, None);
+    }
+
+    #[test]
+    fn dscp_values_up_to_63_mark_peer_sockets() {
+        for dscp in [0, 8, 63] {
+            let network = network(&format!("[network]\ndscp = {dscp}")).unwrap();
+            assert_eq!(network.socket_options().dscp, Some(dscp));
+        }
+    }
+
+    #[test]
+    fn dscp_values_above_63_are_rejected() {
+        let err = network("[network]\ndscp = 64").unwrap_err();
+        assert!(
+            err.to_string().contains("a DSCP value from 0 to 63"),
+            "{err}"
+        );
+        assert!(network("[network]\ndscp = 255").is_err());
+        assert!(network("[network]\ndscp = 256").is_err());
+    }
 
-    config_path.push(CONFIG_FILE_NAME);
-    if !config_path.exists() {
-        // Create the config file if it doesn't exist
-        let file = std::fs::File::create(&config_path)?;
-        Ok(file)
-        // writeln!(file, "# Default configuration")?;
-    } else {
-        todo!()
+    #[test]
+    fn an_unset_dscp_survives_saving_and_loading() {
+        let saved = toml::to_string(&Config::default()).unwrap();
+        assert_eq!(network(&saved).unwrap().dscp, None);
     }
 }
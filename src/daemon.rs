@@ -0,0 +1,333 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{Duration, Instant},
+};
+use torrent::{
+    cache::CacheStats,
+    discovery::Discovery,
+    info_hash::InfoHash,
+    interface::AddressFamily,
+    memory::{MemoryUsage, Subsystem},
+    operation::OperationProgress,
+    peer::{
+        connection::ConnectionUsage,
+        unchoke::{SlotUsage, UploadSlots},
+    },
+    rate_limit::RateLimits,
+    stats::TransferStats,
+    swarm::{Stall, StallAction},
+    units::HumanDuration,
+    upnp::MappingStatus,
+    web_seed::{SeedProtocol, WebSeed, MAX_FAILURES},
+};
+
+use crate::{
+    logging::{LogEvent, LogFilter},
+    power::PowerMode,
+    state::ScanReport,
+    turtle::SpeedMode,
+};
+
+/// A snapshot of what the daemon is doing, reported by `flud daemon status`.
+#[derive(Debug, Default)]
+pub struct DaemonStatus {
+    /// Approximate memory use per subsystem against the configured budget.
+    pub memory: MemoryUsage,
+    /// Whether the listen port is forwarded by the router (UPnP, NAT-PMP or PCP).
+    pub port_mapping: MappingStatus,
+    /// Which of the DHT, PEX and LSD the session uses.
+    pub discovery: Discovery,
+    /// Which IP versions the session uses.
+    pub address_family: AddressFamily,
+    /// Outgoing peer connection attempts in progress and waiting in line.
+    pub connections: ConnectionUsage,
+    /// How many blocks uploaded were served from memory.
+    pub read_cache: CacheStats,
+    /// Whether low-power mode's limits apply.
+    pub power: PowerMode,
+    /// The entries of the state store set aside on start.
+    pub state_scan: ScanReport,
+}
+
+fn mib(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+impl fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.memory.budget {
+            Some(budget) => writeln!(
+                f,
+                "memory: {:.1} MiB of {:.1} MiB",
+                mib(self.memory.total()),
+                mib(budget)
+            )?,
+            None => writeln!(f, "memory: {:.1} MiB", mib(self.memory.total()))?,
+        }
+
+        for subsystem in Subsystem::ALL {
+            writeln!(
+                f,
+                "  {}: {:.1} MiB",
+                subsystem,
+                mib(self.memory.get(subsystem))
+            )?;
+        }
+
+        writeln!(f, "port mapping: {}", self.port_mapping)?;
+        writeln!(f, "discovery: {}", self.discovery)?;
+        writeln!(f, "ip versions: {}", self.address_family)?;
+        writeln!(f, "peer connections: {}", self.connections)?;
+        writeln!(f, "read cache: {}", self.read_cache)?;
+        writeln!(f, "power: {}", self.power)?;
+        write!(f, "{}", self.state_scan)?;
+
+        Ok(())
+    }
+}
+
+/// Start the daemon when the user logs in, or stop doing so.
+///
+/// Uses an XDG autostart entry, so only desktop sessions on Linux (and the
+/// BSDs) are supported for now.
+pub fn set_start_at_login(enabled: bool) -> std::io::Result<()> {
+    if cfg!(any(target_os = "macos", target_os = "windows")) {
+        // TODO: launchd agent on macOS, registry Run key on Windows
+        return Err(std::io::ErrorKind::Unsupported.into());
+    }
+
+    let autostart = dirs::config_dir()
+        .ok_or(std::io::ErrorKind::NotFound)?
+        .join("autostart");
+    let entry = autostart.join(concat!(env!("CARGO_PKG_NAME"), ".desktop"));
+
+    if !enabled {
+        return match std::fs::remove_file(entry) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+    }
+
+    let exe = std::env::current_exe()?;
+    std::fs::create_dir_all(&autostart)?;
+    std::fs::write(
+        entry,
+        format!(
+            "[Desktop Entry]\nType=Application\nName={}\nExec=\"{}\" daemon start\nX-GNOME-Autostart-enabled=true\n",
+            env!("CARGO_PKG_NAME"),
+            exe.display()
+        ),
+    )
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error("the daemon is not running")]
+    NotRunning,
+    #[error("no torrent with info hash {0}")]
+    UnknownTorrent(InfoHash),
+    #[error("not connected to {0}")]
+    UnknownPeer(SocketAddr),
+    #[error("nothing is being checked or moved for {0}")]
+    NoOperation(InfoHash),
+    /// The trackers' `min interval` is not over, the re-announce happens
+    /// once it is.
+    #[error("the trackers allow a re-announce in {}, it is scheduled for then", HumanDuration(*.0))]
+    ReannounceTooSoon(Duration),
+}
+
+/// A peer of a torrent as the daemon sees it.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// The client the peer runs, e.g. `qBittorrent 4.6.2`, from its
+    /// extension handshake or peer id.
+    pub client: String,
+    /// How much of the torrent the peer has, `0.0..=1.0`.
+    pub progress: f64,
+    /// Bytes per second we receive from the peer.
+    pub download_rate: u64,
+    /// Bytes per second we send to the peer.
+    pub upload_rate: u64,
+    pub encrypted: bool,
+    /// Whether the peer holds one of the torrent's upload slots.
+    pub unchoked: bool,
+}
+
+impl PeerInfo {
+    pub fn is_seed(&self) -> bool {
+        self.progress >= 1.0
+    }
+}
+
+/// A torrent the daemon's DHT crawler came across (BEP 51).
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub info_hash: InfoHash,
+    /// From the torrent's metadata, fetched from one of its peers.
+    pub name: String,
+    /// Peers the DHT knows of.
+    pub peers: usize,
+}
+
+/// A web seed or http seed of a torrent as the daemon sees it.
+#[derive(Debug, Clone)]
+pub struct HttpSourceInfo {
+    pub url: String,
+    pub protocol: SeedProtocol,
+    /// Bytes of good pieces received from the source.
+    pub downloaded: u64,
+    /// Bytes per second we receive from the source.
+    pub download_rate: u64,
+    /// Failures in a row, the source is given up on at `MAX_FAILURES`.
+    pub failures: u32,
+    /// Whether pieces are being requested from the source right now.
+    pub available: bool,
+}
+
+impl HttpSourceInfo {
+    pub fn new(source: &WebSeed, download_rate: u64, now: Instant) -> Self {
+        Self {
+            url: source.url().to_string(),
+            protocol: source.protocol(),
+            downloaded: source.downloaded(),
+            download_rate,
+            failures: source.failures(),
+            available: source.is_available(now),
+        }
+    }
+
+    /// e.g. `ok`, `2 failures, waiting` or `given up`
+    pub fn status(&self) -> String {
+        match self.failures {
+            0 => "ok".to_owned(),
+            failures if failures >= MAX_FAILURES => "given up".to_owned(),
+            failures if !self.available => format!("{failures} failures, waiting"),
+            failures => format!("{failures} failures"),
+        }
+    }
+}
+
+/// A tracker of a torrent as the daemon sees it.
+#[derive(Debug, Clone)]
+pub struct TrackerInfo {
+    pub url: String,
+    /// The error of the last announce, `None` if it succeeded.
+    pub error: Option<String>,
+    /// Peers the tracker returned on the last announce.
+    pub peers: usize,
+    /// How long until the next scheduled announce.
+    pub next_announce: Duration,
+    /// How long until the user may force a re-announce, `None` if they may
+    /// right away.
+    pub cooldown: Option<Duration>,
+}
+
+impl TrackerInfo {
+    /// e.g. `ok` or the error of the last announce
+    pub fn status(&self) -> &str {
+        self.error.as_deref().unwrap_or("ok")
+    }
+}
+
+/// What the TUI and the CLI can ask a running daemon to do.
+pub trait DaemonApi {
+    fn peers(&self, info_hash: &InfoHash) -> Result<Vec<PeerInfo>, DaemonError>;
+
+    /// The web seeds and http seeds of a torrent.
+    fn http_sources(&self, info_hash: &InfoHash) -> Result<Vec<HttpSourceInfo>, DaemonError>;
+
+    fn trackers(&self, info_hash: &InfoHash) -> Result<Vec<TrackerInfo>, DaemonError>;
+
+    /// Announce a torrent to its trackers now, or as soon as their
+    /// `min interval` allows.
+    fn reannounce(&self, info_hash: &InfoHash) -> Result<(), DaemonError>;
+
+    /// What was sent and received for a torrent, and how much of it verified.
+    fn transfer_stats(&self, info_hash: &InfoHash) -> Result<TransferStats, DaemonError>;
+
+    /// The pieces of a torrent no one has, from what its peers announced,
+    /// `None` while every piece can be had.
+    fn stall(&self, info_hash: &InfoHash) -> Result<Option<Stall>, DaemonError>;
+
+    /// What to do with a stalled torrent now and whenever it stalls again,
+    /// e.g. to stop waiting for its missing pieces and pause it.
+    fn set_stall_action(
+        &self,
+        info_hash: &InfoHash,
+        action: StallAction,
+    ) -> Result<(), DaemonError>;
+
+    /// Hash every piece of a torrent again, behind the checks and moves
+    /// already queued.
+    fn recheck(&self, info_hash: &InfoHash) -> Result<(), DaemonError>;
+
+    /// Move a torrent's data to `to`, behind the checks and moves already
+    /// queued.
+    fn move_data(&self, info_hash: &InfoHash, to: &Path) -> Result<(), DaemonError>;
+
+    /// The recheck, move or initial check running or queued for a torrent.
+    fn operation(&self, info_hash: &InfoHash) -> Result<Option<OperationProgress>, DaemonError>;
+
+    /// Stop a torrent's recheck, move or initial check at the next piece or
+    /// chunk. A cancelled move puts back what it moved.
+    fn cancel_operation(&self, info_hash: &InfoHash) -> Result<(), DaemonError>;
+
+    /// How many of a torrent's upload slots are taken.
+    fn upload_slots(&self, info_hash: &InfoHash) -> Result<SlotUsage, DaemonError>;
+
+    /// Change how many peers a torrent uploads to at once, without
+    /// restarting it.
+    fn set_upload_slots(&self, info_hash: &InfoHash, slots: UploadSlots)
+        -> Result<(), DaemonError>;
+
+    fn disconnect_peer(&self, info_hash: &InfoHash, addr: SocketAddr) -> Result<(), DaemonError>;
+
+    /// Disconnect `ip` from every torrent and never connect to it again.
+    fn ban_peer(&self, ip: IpAddr) -> Result<(), DaemonError>;
+
+    /// Whether the listen port is forwarded by the router, so peers outside
+    /// the LAN can reach us.
+    fn port_mapping(&self) -> Result<MappingStatus, DaemonError>;
+
+    /// Start or stop using the DHT, PEX and LSD for every torrent, without
+    /// restarting. Private torrents never use them.
+    fn set_discovery(&self, discovery: Discovery) -> Result<(), DaemonError>;
+
+    /// Change the global download and upload caps, without restarting.
+    fn set_rate_limits(&self, limits: RateLimits) -> Result<(), DaemonError>;
+
+    /// Switch between the regular global limits and those of turtle mode,
+    /// until the schedule next switches.
+    fn set_speed_mode(&self, mode: SpeedMode) -> Result<(), DaemonError>;
+
+    /// Whether the regular global limits or those of turtle mode apply.
+    fn speed_mode(&self) -> Result<SpeedMode, DaemonError>;
+
+    /// Switch low-power mode on or off, until the laptop is plugged in or
+    /// unplugged.
+    fn set_power_mode(&self, mode: PowerMode) -> Result<(), DaemonError>;
+
+    /// Whether low-power mode's limits apply and rechecks are held.
+    fn power_mode(&self) -> Result<PowerMode, DaemonError>;
+
+    /// Change a torrent's own caps, which apply on top of the global ones.
+    fn set_torrent_rate_limits(
+        &self,
+        info_hash: &InfoHash,
+        limits: RateLimits,
+    ) -> Result<(), DaemonError>;
+
+    /// Change which events are logged, e.g. `info,torrent::peer=debug`,
+    /// until the daemon restarts.
+    fn set_log_filter(&self, filter: &LogFilter) -> Result<(), DaemonError>;
+
+    /// The last `limit` events the daemon logged, oldest first.
+    fn recent_logs(&self, limit: usize) -> Result<Vec<LogEvent>, DaemonError>;
+
+    /// Crawled torrents whose name contains `query`, ignoring case, with
+    /// the most peers first.
+    fn search(&self, query: &str) -> Result<Vec<SearchResult>, DaemonError>;
+}
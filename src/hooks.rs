@@ -0,0 +1,247 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{expand_home, HookConfig, HookEvent, HooksConfig},
+    state::StateStore,
+};
+
+// Hooks run as the user, so the limits here are not a security boundary.
+// They keep a misbehaving script from hanging forever, seeing more of the
+// daemon's environment than it needs or piling up when many torrents finish
+// at once.
+
+/// How often a running hook is checked for having exited or timed out.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to keep reading output after a hook exits. A process the hook
+/// left running in the background may hold its stdout open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum HookError {
+    #[error("hook has no command")]
+    EmptyCommand,
+    #[error("unable to start {0}: {1}")]
+    Spawn(String, std::io::Error),
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+}
+
+/// The torrent a hook runs for.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    /// Hex encoded.
+    pub info_hash: String,
+    pub name: String,
+    pub label: Option<String>,
+    /// The directory the torrent's data is written to.
+    pub output: Option<PathBuf>,
+    /// The tracker host the event is about, e.g. for `quota`.
+    pub tracker: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookStatus {
+    Exited(ExitStatus),
+    /// The hook was killed for running past its timeout.
+    TimedOut,
+}
+
+/// How a hook ended and what it printed.
+#[derive(Debug)]
+pub struct HookOutput {
+    pub status: HookStatus,
+    /// stdout and stderr lines in the order they were read, each prefixed
+    /// with the stream it came from.
+    pub lines: Vec<String>,
+}
+
+/// Runs the configured hooks, at most `max_concurrent` at a time.
+///
+/// Cheap to clone, every clone shares the same limit.
+#[derive(Debug, Clone)]
+pub struct HookRunner {
+    hooks: Arc<Vec<HookConfig>>,
+    max_concurrent: usize,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl HookRunner {
+    pub fn new(config: &HooksConfig) -> Self {
+        Self {
+            hooks: Arc::new(config.commands.clone()),
+            max_concurrent: config.max_concurrent.max(1),
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Run every hook for `event` in the background, writing what they
+    /// print and how they ended to the torrent's log.
+    pub fn fire(&self, event: HookEvent, context: &HookContext) {
+        for hook in self.hooks.iter().filter(|hook| hook.on == event) {
+            self.spawn(hook.clone(), event, context.clone());
+        }
+    }
+
+    /// Run `hook` for `event` in the background like `fire`, e.g. one from
+    /// a label's rules rather than `[hooks]`.
+    pub fn spawn(&self, hook: HookConfig, event: HookEvent, context: HookContext) {
+        let runner = self.clone();
+        thread::spawn(move || runner.run_logged(&hook, event, &context));
+    }
+
+    /// Run `hook` and wait for it, writing what it printed and how it ended
+    /// to the torrent's log.
+    pub fn run_logged(&self, hook: &HookConfig, event: HookEvent, context: &HookContext) {
+        let name = format!("hook {} {}", event.as_str(), hook.command.join(" "));
+        let lines = match self.run(hook, event, context) {
+            Ok(output) => {
+                let mut lines: Vec<String> = output
+                    .lines
+                    .into_iter()
+                    .map(|line| format!("{name}: {line}"))
+                    .collect();
+                lines.push(match output.status {
+                    HookStatus::Exited(status) => format!("{name}: {status}"),
+                    HookStatus::TimedOut => {
+                        format!("{name}: timed out after {}s", hook.timeout_secs)
+                    }
+                });
+                lines
+            }
+            Err(err) => vec![format!("{name}: {err}")],
+        };
+
+        let logged =
+            StateStore::open().and_then(|store| store.append_log(&context.info_hash, &lines));
+        if let Err(err) = logged {
+            eprintln!("unable to log hook output: {err}");
+        }
+    }
+
+    /// Run `hook` once fewer than `max_concurrent` hooks are running and
+    /// wait for it to exit or time out.
+    pub fn run(
+        &self,
+        hook: &HookConfig,
+        event: HookEvent,
+        context: &HookContext,
+    ) -> Result<HookOutput, HookError> {
+        let (program, args) = hook.command.split_first().ok_or(HookError::EmptyCommand)?;
+        let _slot = self.acquire();
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env_clear()
+            .envs(
+                hook.env
+                    .iter()
+                    .filter_map(|name| Some((name, std::env::var_os(name)?))),
+            )
+            .env("FLUD_EVENT", event.as_str())
+            .env("FLUD_INFO_HASH", &context.info_hash)
+            .env("FLUD_NAME", &context.name)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(label) = &context.label {
+            command.env("FLUD_LABEL", label);
+        }
+        if let Some(output) = &context.output {
+            command.env("FLUD_OUTPUT", output);
+        }
+        if let Some(tracker) = &context.tracker {
+            command.env("FLUD_TRACKER", tracker);
+        }
+        let working_dir = hook
+            .working_dir
+            .as_deref()
+            .and_then(expand_home)
+            .or_else(|| context.output.clone());
+        if let Some(dir) = working_dir {
+            command.current_dir(dir);
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|err| HookError::Spawn(program.clone(), err))?;
+
+        let (sender, receiver) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, "stdout", sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, "stderr", sender);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(hook.timeout_secs);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break HookStatus::Exited(status);
+            }
+            if Instant::now() >= deadline {
+                // Only the hook itself is killed, not anything it started
+                let _ = child.kill();
+                child.wait()?;
+                break HookStatus::TimedOut;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let mut lines = Vec::new();
+        let drained = Instant::now() + DRAIN_TIMEOUT;
+        while let Ok(line) =
+            receiver.recv_timeout(drained.saturating_duration_since(Instant::now()))
+        {
+            lines.push(line);
+        }
+
+        Ok(HookOutput { status, lines })
+    }
+
+    /// Wait for a free slot, it is given back when the returned guard is dropped.
+    fn acquire(&self) -> Slot<'_> {
+        let (running, freed) = &*self.running;
+        let mut count = running.lock().unwrap();
+        while *count >= self.max_concurrent {
+            count = freed.wait(count).unwrap();
+        }
+        *count += 1;
+        Slot(&self.running)
+    }
+}
+
+struct Slot<'a>(&'a (Mutex<usize>, Condvar));
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let (running, freed) = self.0;
+        *running.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
+}
+
+/// Send every line read from `stream` to `sender`, prefixed with `name`.
+fn forward_lines(
+    stream: impl Read + Send + 'static,
+    name: &'static str,
+    sender: mpsc::Sender<String>,
+) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(format!("{name}: {line}")).is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,210 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Mutex, OnceLock, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How many of the most recent events are kept for `flud daemon` clients.
+pub const RECENT_CAPACITY: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogFilterError {
+    #[error("invalid log level {0:?}, expected off, error, warn, info, debug or trace")]
+    InvalidLevel(String),
+    #[error("empty module in log filter {0:?}")]
+    EmptyTarget(String),
+}
+
+/// Which events are logged, as comma separated directives: a level for
+/// everything and `module=level` for a module and its submodules, e.g.
+/// `warn,torrent::peer=debug`. The most specific directive wins.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogFilter {
+    default: LevelFilter,
+    /// Longest module first, so the first match is the most specific.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            directives: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// The most verbose level logged for events from `target`.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most verbose level logged for any target.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = LogFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_level = |level: &str| {
+            LevelFilter::from_str(level.trim())
+                .map_err(|_| LogFilterError::InvalidLevel(level.trim().to_owned()))
+        };
+
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(LogFilterError::EmptyTarget(s.to_owned()));
+                    }
+                    let level = parse_level(level)?;
+                    filter.directives.retain(|(other, _)| other != module);
+                    filter.directives.push((module.to_owned(), level));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        filter
+            .directives
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+}
+
+impl TryFrom<String> for LogFilter {
+    type Error = LogFilterError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<LogFilter> for String {
+    fn from(filter: LogFilter) -> Self {
+        filter.to_string()
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (module, level) in &self.directives {
+            write!(f, ",{module}={}", level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that was logged.
+#[derive(Debug, Clone)]
+pub struct LogEvent {
+    pub time: SystemTime,
+    pub level: Level,
+    /// The module it was logged from, e.g. `torrent::peer::listener`.
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        write!(
+            f,
+            "{secs} {:<5} {}: {}",
+            self.level, self.target, self.message
+        )
+    }
+}
+
+/// Writes events to stderr and keeps the most recent ones in memory.
+struct Logger {
+    filter: RwLock<LogFilter>,
+    recent: Mutex<VecDeque<LogEvent>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let filter = self.filter.read().unwrap();
+        metadata.level() <= filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let event = LogEvent {
+            time: SystemTime::now(),
+            level: record.level(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        };
+        eprintln!("{event}");
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(event);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start logging with `filter`. Only the first call installs the logger,
+/// later ones change the filter.
+pub fn init(filter: LogFilter) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        filter: RwLock::new(LogFilter::default()),
+        recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+    });
+    // Only fails when the logger is already installed
+    let _ = log::set_logger(logger);
+    set_filter(filter);
+}
+
+/// Change which events are logged from now on, e.g. when a client asks the
+/// daemon to.
+pub fn set_filter(filter: LogFilter) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    log::set_max_level(filter.max_level());
+    *logger.filter.write().unwrap() = filter;
+}
+
+/// The last `limit` events logged, oldest first.
+pub fn recent(limit: usize) -> Vec<LogEvent> {
+    let Some(logger) = LOGGER.get() else {
+        return Vec::new();
+    };
+    let recent = logger.recent.lock().unwrap();
+    let skip = recent.len().saturating_sub(limit);
+    recent.iter().skip(skip).cloned().collect()
+}
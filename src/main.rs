@@ -1,12 +1,46 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{engine::ArgValueCandidates, CompleteEnv};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use torrent::{
+    info_hash::InfoHash,
+    lifecycle::StopCondition,
+    memory::MemoryBudget,
     meta_info::{self, MetaInfo},
-    tracker::Tracker,
+    operation::DiskScheduler,
+    peer::{connection::ConnectionManager, unchoke::UploadSlots},
+    rate_limit::RateLimiter,
+    share_limit::{ShareLimitAction, ShareLimits},
+    source::{ResolvedSource, TorrentSource},
+    stats::sparkline,
+    swarm::{format_duration, SeedPresence, StallAction},
+    tracker::{
+        filter::TrackerFilter,
+        scrape::{ScrapeScheduler, ScrapeStats},
+        Tracker,
+    },
+    units::{self, ByteSize, HumanDuration},
+    update::{self, UpdateAction, UpdateError},
+    verify,
 };
 pub mod client;
+pub mod completion;
 pub mod config;
+pub mod daemon;
+pub mod hooks;
+pub mod logging;
+pub mod power;
+pub mod rules;
+pub mod seed;
+pub mod setup;
+pub mod state;
 pub mod tui;
+pub mod turtle;
 
 /// A CLI/TUI for interacting with torrents.
 ///
@@ -18,12 +52,6 @@ struct Args {
     cmd: Option<Command>,
 }
 
-#[derive(Clone)]
-pub enum MagnetLinkOrFilePath {
-    MagnetLink(String),
-    TorrentFilePath(PathBuf),
-}
-
 #[derive(Subcommand)]
 enum DaemonCommands {
     /// Starts the flud daemon. This will be killed when the shell is closed or
@@ -33,12 +61,44 @@ enum DaemonCommands {
     ///
     /// starting with systemd etc
     Start {},
-    /// Accepts both magnet links as well as paths to torrent files.
+    /// Print what the daemon is doing, including its memory use.
+    Status,
+    /// List the torrents the daemon knows about.
+    List {
+        /// Print a JSON array instead, for scripts. Fields are never renamed
+        /// or removed.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show a torrent's status, where its data goes and what happened to it.
+    Details {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// Print a JSON object instead, for scripts. Fields are never renamed
+        /// or removed.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print a torrent's log, including the output of hooks run for it.
+    Log {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+    },
+    /// Change which events the running daemon logs, without restarting it.
+    ///
+    /// Takes a level for everything and `module=level` for a module and its
+    /// submodules, e.g. `info,torrent::peer=debug`. The levels are off,
+    /// error, warn, info, debug and trace.
+    LogLevel { filter: logging::LogFilter },
+    /// Accepts magnet links, info hashes, .torrent URLs and paths to torrent files.
     ///
     /// Will tell the daemon to add the provided magnet link
     /// or torrent file to its internal list of torrents.
     Add {
-        /// You can provide either a magnet link or the path to a torrent file.
+        /// A magnet link, info hash, .torrent URL or the path to a torrent file.
         torrent: String,
 
         /// Optionally set the port for where the flud daemon is listening.
@@ -47,10 +107,204 @@ enum DaemonCommands {
         #[clap(short = 'p', long)]
         daemon_port: Option<u16>,
 
-        /// If not told otherwise, flud writes download torrent data to `/Downloads`.
-        /// It can be instructed instead to save that data to a custom location using `-o` or `--output`q
+        /// If not told otherwise, flud writes download torrent data to the
+        /// download directory from the config (`~/Downloads` by default).
+        /// It can be instructed instead to save that data to a custom location using `-o` or `--output`
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Label the torrent, picking the label's download directory from the config.
+        #[clap(short, long, add = ArgValueCandidates::new(completion::labels))]
+        label: Option<String>,
+
+        /// The info hash the .torrent downloaded from a URL must have (hex),
+        /// it is not added otherwise.
+        #[clap(long, value_parser = parse_info_hash)]
+        info_hash: Option<InfoHash>,
+
+        /// Stop once the metadata of a magnet link has been received, so
+        /// files can be picked before any data is downloaded.
+        #[clap(long, conflicts_with = "stop_when_selected_complete")]
+        stop_after_metadata: bool,
+
+        /// Stop once the selected files are complete instead of seeding them.
+        #[clap(long)]
+        stop_when_selected_complete: bool,
+
+        /// Run the torrent in anonymous mode, whatever the config says.
+        #[clap(long)]
+        anonymous: bool,
+    },
+    /// Add every .torrent file in a directory, e.g. a bundle, with the same
+    /// label and output directory. Torrents already added are skipped.
+    AddDir {
+        /// The directory to look for .torrent files in.
+        path: PathBuf,
+
+        /// Look in subdirectories too.
+        #[clap(short, long)]
+        recursive: bool,
+
+        /// Save the data of every torrent here instead of the download
+        /// directory from the config.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Label every torrent, picking the label's download directory from the config.
+        #[clap(short, long, add = ArgValueCandidates::new(completion::labels))]
+        label: Option<String>,
+    },
+    /// Show a torrent's share limits, optionally setting them first.
+    ///
+    /// Limits not set for the torrent come from its label, then from the config.
+    ShareLimits {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// Stop seeding at this upload ratio, e.g. `2.0`.
+        #[clap(long, value_parser = units::parse_ratio)]
+        ratio: Option<f64>,
+
+        /// Stop seeding after this long, e.g. `48h` or `1d12h`.
+        #[clap(long)]
+        seed_time: Option<HumanDuration>,
+
+        /// What to do once a limit is reached: pause, stop, remove or
+        /// remove-with-data. Removing is announced before it happens.
+        #[clap(long)]
+        action: Option<ShareLimitAction>,
+    },
+    /// Show whether a torrent runs in anonymous mode, optionally changing it.
+    ///
+    /// Anonymous torrents leave the client out of peer ids and extension
+    /// handshakes and other addresses out of announces, and don't use the
+    /// DHT, PEX or LSD.
+    Anonymous {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        #[clap(long, conflicts_with_all = ["off", "follow_config"])]
+        on: bool,
+
+        #[clap(long, conflicts_with = "follow_config")]
+        off: bool,
+
+        /// Follow `privacy.anonymous` from the config again.
+        #[clap(long)]
+        follow_config: bool,
+    },
+    /// Show a torrent's own rate limits, optionally setting them first.
+    ///
+    /// They apply on top of the global limits, `0` is unlimited.
+    Limit {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// Maximum upload rate, e.g. `500KiB`.
+        #[clap(long)]
+        up: Option<ByteSize>,
+
+        /// Maximum download rate, e.g. `2MiB`.
+        #[clap(long)]
+        down: Option<ByteSize>,
+    },
+    /// Show how many peers a torrent uploads to at once, optionally
+    /// changing it.
+    UploadSlots {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// auto to scale them with the upload limit, or a number, `0` is
+        /// unlimited.
+        #[clap(conflicts_with = "follow_config")]
+        slots: Option<UploadSlots>,
+
+        /// Follow `rate_limits.upload_slots_per_torrent` from the config again.
+        #[clap(long)]
+        follow_config: bool,
+    },
+    /// Show what happens to a torrent once it stalls, optionally changing it.
+    ///
+    /// A torrent stalls when pieces it still needs are missing from every
+    /// peer it is connected to.
+    OnStall {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// keep-trying, or pause and fire the `stalled` hooks.
+        #[clap(conflicts_with = "follow_config")]
+        action: Option<StallAction>,
+
+        /// Follow `downloads.on_stall` from the config again.
+        #[clap(long)]
+        follow_config: bool,
+    },
+    /// Pause a torrent, it neither downloads nor seeds until resumed.
+    Pause {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+    },
+    /// Resume a paused torrent.
+    Resume {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+    },
+    /// Hash every piece of a torrent again, e.g. after its files were
+    /// touched by another program. `cancel` stops it.
+    Recheck {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents), value_parser = parse_info_hash)]
+        info_hash: InfoHash,
+    },
+    /// Cancel a torrent's recheck, move or initial check.
+    ///
+    /// A cancelled move puts the files it already moved back.
+    Cancel {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents), value_parser = parse_info_hash)]
+        info_hash: InfoHash,
+    },
+    /// Look for a newer version of a torrent at its `update-url` (BEP 39),
+    /// e.g. a season pack with a new episode.
+    ///
+    /// Depending on `downloads.on_update` the newer version replaces the
+    /// torrent right away or only once confirmed. Files both versions share
+    /// are kept.
+    CheckUpdate {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// Replace the torrent without asking.
         #[clap(short, long)]
-        output: PathBuf,
+        yes: bool,
+    },
+    /// List a torrent's trackers, optionally editing them first.
+    ///
+    /// Edits are stored alongside the torrent and never modify the original torrent file.
+    Trackers {
+        /// The info hash of the torrent (hex).
+        #[clap(add = ArgValueCandidates::new(completion::torrents))]
+        info_hash: String,
+
+        /// Add a tracker.
+        #[clap(long)]
+        add: Vec<String>,
+
+        /// Stop announcing to a tracker.
+        #[clap(long)]
+        remove: Vec<String>,
+
+        /// Replace one tracker with another.
+        #[clap(long, num_args = 2, value_names = ["OLD", "NEW"])]
+        replace: Vec<String>,
     },
 }
 
@@ -88,39 +342,426 @@ enum Command {
         path: PathBuf,
     },
 
+    /// Ask the torrents' trackers how many seeders and leechers they have.
+    ///
+    /// Torrents sharing a tracker are scraped from it in one request.
+    Scrape {
+        /// You can provide paths to one or more torrent files.
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Verify a single file of a torrent against the pieces covering it,
+    /// printing the byte ranges that are bad.
+    ///
+    /// Much faster than a full recheck when only one file is suspect.
+    VerifyFile {
+        /// You can provide a path to a torrent file.
+        path: PathBuf,
+
+        /// The directory the torrent was downloaded into.
+        data: PathBuf,
+
+        /// The file to verify, either its index or its path within the torrent.
+        file: String,
+    },
+
+    /// Print a magnet link for the provided torrent file.
+    Magnet {
+        /// You can provide a path to a torrent file.
+        path: PathBuf,
+    },
+
+    /// Seed a torrent in the foreground without the daemon, e.g. to quickly
+    /// serve a file to a friend on the LAN. Stop with ctrl+c.
+    Seed {
+        /// You can provide a path to a torrent file.
+        path: PathBuf,
+
+        /// The torrent's data, or the directory it is in.
+        data: PathBuf,
+
+        /// Stop once this many times the torrent's size has been uploaded.
+        #[clap(long, value_parser = units::parse_ratio)]
+        ratio: Option<f64>,
+
+        /// The port peers connect to, defaults to the listen port from the config.
+        #[clap(short, long)]
+        port: Option<u16>,
+
+        /// Start in low-power mode, until the laptop is plugged in or
+        /// unplugged. See `[low_power]` in the config.
+        #[clap(long)]
+        low_power: bool,
+    },
+
+    /// Print the session's statistics and the rates recorded by the
+    /// daemon, as sparklines or exported for graphing elsewhere.
+    Stats {
+        /// How far back to look, e.g. `24h` or `7d`.
+        #[clap(long, default_value = "24h")]
+        since: HumanDuration,
+
+        /// Print the recorded rates as `csv` or `json` instead, one sample
+        /// per minute in bytes per second.
+        #[clap(long)]
+        export: Option<ExportFormat>,
+    },
+
     /// Start downloading the provided magnet link or torrent file path
     Download {
-        /// You can provide either a magnet link or the path to a torrent file.
+        /// A magnet link, info hash, .torrent URL or the path to a torrent file.
         torrent: String,
     },
 }
 
+/// How many characters wide the sparklines of `flud stats` are.
+const SPARKLINE_WIDTH: usize = 48;
+
+/// How `flud stats --export` prints the recorded rates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("unknown format {s}, expected csv or json")),
+        }
+    }
+}
+
 fn main() {
+    // Answers shell completion requests (`COMPLETE=bash flud ...`) and exits
+    CompleteEnv::with_factory(Args::command).complete();
+
     let args = Args::parse();
 
-    // let config_file = config::get_or_create();
+    // A broken config file is reported once here, the commands go on with
+    // the defaults
+    let config = config::Config::load()
+        .inspect_err(|err| eprintln!("using the default settings, {err}"))
+        .unwrap_or_default();
+    logging::init(config.daemon.log_filter);
+    // Better to do nothing than to send traffic around the VPN
+    if let Err(err) = torrent::interface::set_outgoing(config.network.outgoing_interface()) {
+        eprintln!("unable to use outgoing interface: {err}");
+        std::process::exit(1);
+    }
+    if let Err(err) = torrent::interface::set_address_family(config.network.address_family) {
+        eprintln!("unable to restrict the IP version: {err}");
+        std::process::exit(1);
+    }
+    if let Some((proxy, traffic)) = config.proxy.proxy() {
+        if let Err(err) = torrent::proxy::set_proxy(proxy, traffic) {
+            eprintln!("unable to use the proxy: {err}");
+            std::process::exit(1);
+        }
+    }
+    match config.http_proxy.proxy() {
+        Ok(Some(proxy)) => {
+            if let Err(err) = torrent::proxy::set_http_proxy(proxy) {
+                eprintln!("unable to use the HTTP proxy: {err}");
+                std::process::exit(1);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => {
+            eprintln!("unable to use the HTTP proxy: {err}");
+            std::process::exit(1);
+        }
+    }
 
     if let Some(command) = args.cmd {
         match command {
-            Command::Open => tui::run(),
+            Command::Open => open_tui(),
             Command::Daemon {
                 port: _,
                 daemon_command,
             } => {
-                if let Some(_d_command) = daemon_command {
-                    todo!("run some command for the flud daemon")
+                if let Some(d_command) = daemon_command {
+                    match d_command {
+                        DaemonCommands::Trackers {
+                            info_hash,
+                            add,
+                            remove,
+                            replace,
+                        } => {
+                            if let Err(err) = edit_trackers(&info_hash, add, remove, replace) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Add {
+                            torrent,
+                            output,
+                            label,
+                            info_hash,
+                            stop_after_metadata,
+                            stop_when_selected_complete,
+                            anonymous,
+                            ..
+                        } => {
+                            let config = config::Config::load().unwrap_or_default();
+                            let labeler = match rules::AutoLabeler::new(&config) {
+                                Ok(labeler) => labeler,
+                                Err(err) => {
+                                    eprintln!("{err}");
+                                    return;
+                                }
+                            };
+
+                            let stop_condition = if stop_after_metadata {
+                                StopCondition::MetadataReceived
+                            } else if stop_when_selected_complete {
+                                StopCondition::SelectedFilesComplete
+                            } else {
+                                StopCondition::Never
+                            };
+
+                            let identity = config.privacy.identity(anonymous.then_some(true));
+
+                            let source =
+                                torrent
+                                    .parse::<TorrentSource>()
+                                    .map(|source| match info_hash {
+                                        Some(info_hash) => source.with_info_hash(info_hash),
+                                        None => source,
+                                    });
+                            let resolver = config.downloads.source_resolver();
+                            let resolved = match source.and_then(|source| resolver.resolve(source))
+                            {
+                                Ok(resolved) => resolved,
+                                Err(err) => {
+                                    eprintln!("unable to add torrent: {err:?}");
+                                    return;
+                                }
+                            };
+
+                            // A magnet link may only have a display name until the metadata arrives
+                            let name = match &resolved {
+                                ResolvedSource::MetaInfo(meta_info) => {
+                                    Some(meta_info.info().name())
+                                }
+                                ResolvedSource::Magnet(magnet) => magnet.display_name.as_deref(),
+                            };
+                            let target =
+                                match labeler.target(name, label, output, &config.downloads) {
+                                    Ok(target) => target,
+                                    Err(err) => {
+                                        eprintln!("{err}");
+                                        return;
+                                    }
+                                };
+
+                            // TODO: send it to the daemon once there is a connection to it
+                            log::debug!("not added: {stop_condition:?}, {target:?}, {identity:?}");
+                            eprintln!(
+                                "{}, {} not added",
+                                daemon::DaemonError::NotRunning,
+                                resolved.info_hash()
+                            )
+                        }
+                        DaemonCommands::AddDir {
+                            path,
+                            recursive,
+                            output,
+                            label,
+                        } => {
+                            let config = config::Config::load().unwrap_or_default();
+                            if let Err(err) = add_dir(&path, recursive, output, label, &config) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::ShareLimits {
+                            info_hash,
+                            ratio,
+                            seed_time,
+                            action,
+                        } => {
+                            let limits = ShareLimits {
+                                ratio,
+                                // Stored in minutes, rounded up so it never stops early
+                                seed_time_mins: seed_time
+                                    .map(|time| time.as_duration().as_secs().div_ceil(60)),
+                                action,
+                            };
+                            if let Err(err) = edit_share_limits(&info_hash, limits) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Anonymous {
+                            info_hash,
+                            on,
+                            off,
+                            follow_config,
+                        } => {
+                            let anonymous = match (on, off, follow_config) {
+                                (true, _, _) => Some(Some(true)),
+                                (_, true, _) => Some(Some(false)),
+                                (_, _, true) => Some(None),
+                                _ => None,
+                            };
+                            if let Err(err) = edit_anonymous(&info_hash, anonymous) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Limit {
+                            info_hash,
+                            up,
+                            down,
+                        } => {
+                            if let Err(err) = edit_rate_limits(&info_hash, up, down) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::UploadSlots {
+                            info_hash,
+                            slots,
+                            follow_config,
+                        } => {
+                            let slots = match (slots, follow_config) {
+                                (Some(slots), _) => Some(Some(slots)),
+                                (_, true) => Some(None),
+                                _ => None,
+                            };
+                            if let Err(err) = edit_upload_slots(&info_hash, slots) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::OnStall {
+                            info_hash,
+                            action,
+                            follow_config,
+                        } => {
+                            let action = match (action, follow_config) {
+                                (Some(action), _) => Some(Some(action)),
+                                (_, true) => Some(None),
+                                _ => None,
+                            };
+                            if let Err(err) = edit_stall_action(&info_hash, action) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Pause { info_hash } => {
+                            if let Err(err) = set_paused(&info_hash, true) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Resume { info_hash } => {
+                            if let Err(err) = set_paused(&info_hash, false) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::CheckUpdate { info_hash, yes } => {
+                            if let Err(err) = check_update(&info_hash, yes) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::List { json } => {
+                            if let Err(err) = list_torrents(json) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Details { info_hash, json } => {
+                            if let Err(err) = print_details(&info_hash, json) {
+                                eprintln!("{err}")
+                            }
+                        }
+                        DaemonCommands::Log { info_hash } => {
+                            match state::StateStore::open()
+                                .and_then(|store| store.read_log(&info_hash))
+                            {
+                                Ok(log) => print!("{log}"),
+                                Err(err) => eprintln!("{err}"),
+                            }
+                        }
+                        DaemonCommands::LogLevel { filter } => {
+                            // TODO: send it to the daemon once there is a connection to it
+                            eprintln!("{}, {filter} not applied", daemon::DaemonError::NotRunning)
+                        }
+                        DaemonCommands::Recheck { info_hash } => {
+                            // TODO: send it to the daemon once there is a connection to it
+                            eprintln!(
+                                "{}, {info_hash} not rechecked",
+                                daemon::DaemonError::NotRunning
+                            )
+                        }
+                        DaemonCommands::Cancel { info_hash } => {
+                            // TODO: send it to the daemon once there is a connection to it
+                            eprintln!(
+                                "{}, nothing cancelled for {info_hash}",
+                                daemon::DaemonError::NotRunning
+                            )
+                        }
+                        DaemonCommands::Start {} => {
+                            // Bad entries are set aside before anything loads them
+                            match state::StateStore::open().and_then(|store| store.scan()) {
+                                Ok(report) => eprint!("{report}"),
+                                Err(err) => eprintln!("{err}"),
+                            }
+                            // TODO: run the flud daemon
+                            eprintln!("running the daemon is not implemented yet");
+                            std::process::exit(1);
+                        }
+                        DaemonCommands::Status => {
+                            // TODO: ask the daemon once there is a connection to it
+                            eprintln!("{}", daemon::DaemonError::NotRunning)
+                        }
+                    }
                 } else {
-                    todo!("open tui while connecting to the flud daemon")
+                    // TODO: open the tui while connecting to the flud daemon
+                    eprintln!("connecting to the daemon is not implemented yet");
+                    std::process::exit(1);
                 }
             }
-            Command::Download { torrent: _ } => {
-                // allow ctrl+c to cancel and picking back up if reran
-                todo!()
+            Command::Download { torrent } => {
+                let resolver = config::Config::load()
+                    .unwrap_or_default()
+                    .downloads
+                    .source_resolver();
+                let _source = match resolver.resolve_str(&torrent) {
+                    Ok(source) => source,
+                    Err(err) => {
+                        eprintln!("unable to add torrent: {err:?}");
+                        return;
+                    }
+                };
+                // TODO: allow ctrl+c to cancel and picking back up if reran
+                eprintln!("downloading is not implemented yet");
+                std::process::exit(1);
             }
             Command::Info { path } => {
                 if let Ok(torrent) = MetaInfo::try_from(path) {
-                    println!("info hash: {}", torrent.info().hash());
-                    println!("piece length: {}", torrent.info().piece_length());
+                    let info_hash = torrent.info().hash().to_string();
+                    println!("info hash: {}", info_hash);
+                    println!(
+                        "piece length: {}",
+                        ByteSize(torrent.info().piece_length() as u64)
+                    );
+                    if torrent.info().has_non_utf8_names() {
+                        println!("warning: non-UTF-8 names, file names may not be shown correctly");
+                    }
+                    for (_, declared, renamed) in torrent.info().renamed_files() {
+                        println!(
+                            "warning: {} collides with another file, saved as {}",
+                            declared.display(),
+                            renamed.display()
+                        );
+                    }
+
+                    // Only torrents the daemon knows about have swarm history
+                    if let Ok(sidecar) =
+                        state::StateStore::open().and_then(|store| store.load_sidecar(&info_hash))
+                    {
+                        let presence = SeedPresence::new(sidecar.last_seen_complete);
+                        println!("{}", presence.describe(SystemTime::now()));
+                    }
                     // println!("piece hashes:");
                     // let _req = TrackerRequest::new_compact(&torrent);
 
@@ -131,21 +772,147 @@ fn main() {
                     eprintln!("unable to parse torrent file")
                 }
             }
+            Command::VerifyFile { path, data, file } => {
+                let Ok(torrent) = MetaInfo::try_from(path) else {
+                    eprintln!("unable to parse torrent file");
+                    return;
+                };
+
+                let info = torrent.info();
+                let paths = info.file_paths();
+                let file_index = file.parse::<usize>().ok().or_else(|| {
+                    paths
+                        .iter()
+                        .position(|path| path.ends_with(&file) || path == &PathBuf::from(&file))
+                });
+
+                let Some(file_index) = file_index.filter(|&index| index < paths.len()) else {
+                    eprintln!("no file {file} in torrent");
+                    return;
+                };
+
+                let ranges = verify::verify_file(info, &data, file_index);
+                if ranges.is_empty() {
+                    println!("{}: ok", paths[file_index].display());
+                }
+                for range in ranges {
+                    let reason = match range.check {
+                        verify::PieceCheck::Bad => "bad",
+                        _ => "unverifiable (neighboring file missing)",
+                    };
+                    println!(
+                        "{}: {}..{} {}",
+                        paths[file_index].display(),
+                        range.offset,
+                        range.offset + range.len,
+                        reason
+                    );
+                }
+            }
+            Command::Seed {
+                path,
+                data,
+                ratio,
+                port,
+                low_power,
+            } => {
+                let Ok(torrent) = MetaInfo::try_from(path) else {
+                    eprintln!("unable to parse torrent file");
+                    return;
+                };
+
+                let config = config::Config::load().unwrap_or_default();
+                let limiter = RateLimiter::default();
+                let scheduler = turtle::SpeedScheduler::new(
+                    &config.rate_limits,
+                    chrono::Local::now().naive_local(),
+                );
+                let limits = scheduler.limits();
+                let upload_slots = config
+                    .rate_limits
+                    .torrent_unchoker(&config.rate_limits.unchoker(limits), limits);
+                let scheduler = Arc::new(Mutex::new(scheduler));
+                turtle::spawn(scheduler.clone(), limiter.clone());
+                let mut monitor =
+                    power::PowerMonitor::new(&config.low_power, power::power_source());
+                if low_power {
+                    monitor.set_mode(power::PowerMode::LowPower);
+                }
+                let monitor = Arc::new(Mutex::new(monitor));
+                // The check before seeding is held in low-power mode, as
+                // the daemon's are
+                let disk = DiskScheduler::new();
+                power::spawn(
+                    monitor.clone(),
+                    scheduler,
+                    limiter.clone(),
+                    Some(disk.clone()),
+                );
+                let options = seed::SeedOptions {
+                    port: port.unwrap_or(config.network.listen_port),
+                    ratio,
+                    encryption: config.network.encryption,
+                    filter: config.trackers.filter(),
+                    connections: ConnectionManager::new(config.network.socket_options())
+                        .with_limits(config.network.connection_limits())
+                        .with_rate_limiter(limiter),
+                    identity: config.privacy.identity(None),
+                    upload_slots,
+                    quotas: config.trackers.quotas.clone(),
+                    quota_warning: config.trackers.quota_warning,
+                    hooks: hooks::HookRunner::new(&config.hooks),
+                    power: monitor,
+                    hashes: config.storage.hash_pool(),
+                    disk,
+                    storage: config.storage.clone(),
+                    memory: MemoryBudget::new(config.memory.budget_bytes()),
+                };
+                let root = seed::data_root(&torrent, &data);
+                if let Err(err) = seed::run(torrent, root, options) {
+                    eprintln!("{err}");
+                }
+            }
+            Command::Stats { since, export } => {
+                if let Err(err) = print_stats(since, export, &config.trackers) {
+                    eprintln!("{err}")
+                }
+            }
+            Command::Magnet { path } => {
+                if let Ok(torrent) = MetaInfo::try_from(path) {
+                    println!("{}", torrent.to_magnet_link());
+                } else {
+                    eprintln!("unable to parse torrent file")
+                }
+            }
+            Command::Scrape { paths } => {
+                let filter = config::Config::load().unwrap_or_default().trackers.filter();
+                scrape(&paths, &filter);
+            }
             Command::Peers { path } => {
                 match MetaInfo::try_from(path) {
                     Ok(torrent) => {
                         println!("info hash: {}", torrent.info().hash());
-                        println!("piece length: {}", torrent.info().piece_length());
+                        println!(
+                            "piece length: {}",
+                            ByteSize(torrent.info().piece_length() as u64)
+                        );
                         println!("{:#?}", torrent);
                         // println!("piece hashes:");
-                        let Ok(res) = Tracker::request(&torrent) else {
-                            todo!()
+                        let config = config::Config::load().unwrap_or_default();
+                        let identity = config.privacy.identity(None);
+                        let filter = config.trackers.filter();
+                        let res = match Tracker::request(&torrent, &identity, &filter) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                eprintln!("no tracker answered: {err:?}");
+                                return;
+                            }
                         };
 
                         match res {
                             torrent::tracker::TrackerResponse::Success(tracker_peer_response) => {
                                 for peer in tracker_peer_response.peers() {
-                                    println!("{}:{}", peer.ip(), peer.port())
+                                    println!("{}", peer.addr)
                                 }
                             }
                             torrent::tracker::TrackerResponse::Failure(
@@ -167,13 +934,647 @@ fn main() {
                         meta_info::MetaInfoError::BencodeParseFailed => {
                             eprintln!("bencode parse failed")
                         }
+                        other => eprintln!("unable to open torrent: {other:?}"),
                     },
                 }
             }
         }
     } else {
-        tui::run()
+        open_tui()
+    }
+}
+
+fn parse_info_hash(s: &str) -> Result<InfoHash, String> {
+    s.parse()
+        .map_err(|_| "expected 40 hex or 32 base32 characters".to_owned())
+}
+
+/// Open the TUI, running the setup wizard first if this is the first run.
+fn open_tui() {
+    if !config::Config::exists() {
+        match setup::run() {
+            Ok(Some(_)) => {}
+            // Quit before the end, nothing was saved
+            Ok(None) => return,
+            Err(err) => eprintln!("unable to save settings: {err}"),
+        }
+    }
+    tui::run()
+}
+
+#[derive(Debug, thiserror::Error)]
+enum AddError {
+    #[error(transparent)]
+    State(#[from] state::StateError),
+    #[error(transparent)]
+    Rule(#[from] rules::RuleError),
+    #[error(transparent)]
+    Output(#[from] config::OutputDirError),
+}
+
+/// Add every .torrent file under `dir` to the state store, printing what
+/// happened to each and a summary. Torrents without a `label` or `output`
+/// are labelled by the `[[auto_label]]` rules.
+fn add_dir(
+    dir: &Path,
+    recursive: bool,
+    output: Option<PathBuf>,
+    label: Option<String>,
+    config: &config::Config,
+) -> Result<(), AddError> {
+    let store = state::StateStore::open()?;
+    let labeler = rules::AutoLabeler::new(config)?;
+
+    let mut paths = Vec::new();
+    find_torrent_files(dir, recursive, &mut paths).map_err(state::StateError::from)?;
+    paths.sort();
+
+    let add = |path: &Path| -> Result<(Option<String>, Option<String>), AddError> {
+        let bytes = std::fs::read(path).map_err(state::StateError::from)?;
+        let torrent =
+            MetaInfo::from_bytes(&bytes).map_err(|_| state::StateError::InvalidTorrent)?;
+        let target = labeler.target(
+            Some(torrent.info().name()),
+            label.clone(),
+            output.clone(),
+            &config.downloads,
+        )?;
+        let sidecar = state::Sidecar {
+            label: target.label.clone(),
+            output: Some(target.output),
+            ..Default::default()
+        };
+        Ok((store.add(&bytes, &sidecar)?, target.label))
+    };
+
+    let (mut added, mut skipped, mut failed) = (0, 0, 0);
+    for path in paths {
+        match add(&path) {
+            Ok((Some(info_hash), label)) => {
+                println!(
+                    "added    {info_hash}  {}{}",
+                    path.display(),
+                    label.map(|label| format!(" [{label}]")).unwrap_or_default()
+                );
+                added += 1;
+            }
+            Ok((None, _)) => {
+                println!("skipped  {}  (already added)", path.display());
+                skipped += 1;
+            }
+            Err(err) => {
+                eprintln!("failed   {}  ({err})", path.display());
+                failed += 1;
+            }
+        }
+    }
+    println!("{added} added, {skipped} already added, {failed} failed");
+    Ok(())
+}
+
+fn find_torrent_files(
+    dir: &Path,
+    recursive: bool,
+    paths: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                find_torrent_files(&path, recursive, paths)?;
+            }
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("torrent"))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Move a torrent into the paused folder, or out of it to download again.
+fn set_paused(info_hash: &str, paused: bool) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let (status, _) = store.find(info_hash)?;
+    let to = match (paused, status) {
+        (true, _) => state::TorrentStatus::Paused,
+        (false, state::TorrentStatus::Paused) => state::TorrentStatus::Downloading,
+        (false, status) => {
+            println!("{info_hash} is {}, not paused", status.folder_name());
+            return Ok(());
+        }
+    };
+    // TODO: tell the daemon once there is a connection to it, it picks
+    // the move up when it starts
+    store.move_to(info_hash, to)?;
+    println!("{info_hash} {}", to.folder_name());
+    Ok(())
+}
+
+fn list_torrents(json: bool) -> Result<(), state::StateError> {
+    let entries = state::StateStore::open()?.list()?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&entries).expect("failed to serialize torrent list")
+        );
+        return Ok(());
+    }
+
+    // TODO: ask the daemon for running operations once there is a connection to it
+    for entry in entries {
+        let status = match entry.operation {
+            Some(operation) => operation.to_string(),
+            None => entry.status.folder_name().to_owned(),
+        };
+        println!(
+            "{}  {:<11}  {}{}",
+            entry.info_hash,
+            status,
+            entry.name.as_deref().unwrap_or("?"),
+            entry
+                .label
+                .map(|label| format!(" [{label}]"))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn print_stats(
+    since: HumanDuration,
+    export: Option<ExportFormat>,
+    trackers: &config::TrackersConfig,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let samples = store.rate_history(now.saturating_sub(since.as_duration().as_secs()))?;
+
+    match export {
+        Some(ExportFormat::Csv) => {
+            println!("at,download,upload");
+            for sample in &samples {
+                println!("{},{},{}", sample.at, sample.download, sample.upload);
+            }
+            return Ok(());
+        }
+        Some(ExportFormat::Json) => {
+            println!(
+                "{}",
+                serde_json::to_string(&samples).expect("failed to serialize rate history")
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let entries = store.list()?;
+    let counts: Vec<String> = state::TorrentStatus::ALL
+        .into_iter()
+        .map(|status| {
+            let count = entries
+                .iter()
+                .filter(|entry| entry.status == status)
+                .count();
+            format!("{count} {}", status.folder_name())
+        })
+        .collect();
+    println!("torrents: {}", counts.join(", "));
+    print_tracker_stats(&store, trackers)?;
+
+    // TODO: the daemon's current rates and totals once there is a connection to it
+    if samples.is_empty() {
+        println!("no rates recorded in the last {since}, the daemon records them while it runs");
+        return Ok(());
+    }
+
+    println!("rates over the last {since}:");
+    let interval = state::RATE_SAMPLE_INTERVAL.as_secs();
+    for (direction, rates) in [
+        (
+            "download",
+            samples
+                .iter()
+                .map(|sample| sample.download)
+                .collect::<Vec<u64>>(),
+        ),
+        (
+            "upload",
+            samples.iter().map(|sample| sample.upload).collect(),
+        ),
+    ] {
+        let average = rates.iter().sum::<u64>() / rates.len() as u64;
+        let peak = rates.iter().copied().max().unwrap_or(0);
+        let total = rates.iter().sum::<u64>() * interval;
+        println!(
+            "  {direction:<8}  {}  average {}, peak {}, {} in total",
+            sparkline(&rates, SPARKLINE_WIDTH),
+            rate(average),
+            rate(peak),
+            tui::size_cell(total)
+        );
+    }
+    Ok(())
+}
+
+/// Every tracker host's all-time totals, announce success rate and how
+/// much of its quotas is used.
+fn print_tracker_stats(
+    store: &state::StateStore,
+    trackers: &config::TrackersConfig,
+) -> Result<(), state::StateError> {
+    let stats = store.tracker_stats()?;
+    if stats.is_empty() {
+        return Ok(());
+    }
+
+    println!("trackers:");
+    let width = stats.keys().map(String::len).max().unwrap_or(0);
+    for (host, stats) in &stats {
+        let ratio = stats
+            .ratio()
+            .map_or_else(|| "-".to_owned(), |ratio| format!("{ratio:.2}"));
+        let success = stats
+            .success_rate()
+            .map_or_else(|| "-".to_owned(), |rate| format!("{:.0}%", rate * 100.0));
+        let mut line = format!(
+            "  {host:<width$}  up {}, down {}, ratio {ratio}, {success} of announces answered",
+            tui::size_cell(stats.uploaded),
+            tui::size_cell(stats.downloaded),
+        );
+        if let Some(quota) = trackers.quotas.get(host) {
+            for (direction, used, limit) in [
+                ("upload", stats.uploaded, quota.upload.bytes()),
+                ("download", stats.downloaded, quota.download.bytes()),
+            ] {
+                if limit > 0 {
+                    line += &format!(
+                        ", {:.0}% of {direction} quota",
+                        used as f64 / limit as f64 * 100.0
+                    );
+                }
+            }
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// The longest `flud scrape` waits for a tracker that asked to be scraped
+/// less often, the others are printed without it.
+const MAX_SCRAPE_WAIT: Duration = Duration::from_secs(10);
+
+/// Scrape every torrent in `paths` from the trackers `filter` allows and
+/// print what they know of each swarm, batching the torrents that share a
+/// tracker into as few requests as it takes.
+fn scrape(paths: &[PathBuf], filter: &TrackerFilter) {
+    let mut scheduler = ScrapeScheduler::default();
+    let mut torrents = Vec::new();
+    for path in paths {
+        let Ok(torrent) = MetaInfo::try_from(path.clone()) else {
+            eprintln!("unable to parse torrent file {}", path.display());
+            continue;
+        };
+        let info_hash = *torrent.info().hash().as_bytes();
+        for tracker in torrent.trackers() {
+            if filter.allows(&tracker) {
+                scheduler.queue(&tracker, info_hash);
+            }
+        }
+        torrents.push((torrent.info().name().to_owned(), info_hash));
+    }
+    // Torrents in the store keep what was found, for the TUI to show
+    let store = state::StateStore::open().ok();
+
+    let mut stats: HashMap<[u8; 20], ScrapeStats> = HashMap::new();
+    while let Some(due) = scheduler.next_due() {
+        if due > Instant::now() + MAX_SCRAPE_WAIT {
+            break;
+        }
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        let now = Instant::now();
+        for batch in scheduler.due(now) {
+            match Tracker::scrape_batch(&batch) {
+                Ok(response) => {
+                    for (info_hash, file) in &response.files {
+                        stats.entry(*info_hash).or_default().merge(file);
+                    }
+                    scheduler.completed(&batch, &response, now);
+                }
+                Err(err) => {
+                    eprintln!("unable to scrape {}: {err:?}", batch.scrape_url);
+                    scheduler.failed(&batch, now);
+                }
+            }
+        }
     }
+
+    for (name, info_hash) in &torrents {
+        let indent = match torrents.len() {
+            1 => "",
+            _ => {
+                println!("{name}:");
+                "  "
+            }
+        };
+        match stats.get(info_hash) {
+            Some(stats) => {
+                let hex = InfoHash::from(*info_hash).to_hex();
+                if let Some(store) = &store {
+                    if let Ok(mut sidecar) = store.load_sidecar(&hex) {
+                        sidecar.scrape = Some(*stats);
+                        if let Err(err) = store.save_sidecar(&hex, &sidecar) {
+                            eprintln!("unable to save the scrape of {name}: {err}");
+                        }
+                    }
+                }
+                println!("{indent}seeders: {}", stats.complete);
+                println!("{indent}leechers: {}", stats.incomplete);
+                println!("{indent}completed: {}", stats.downloaded);
+            }
+            None => println!("{indent}no tracker answered"),
+        }
+    }
+}
+
+/// A transfer rate, `0 B/s` when idle.
+fn rate(bytes_per_sec: u64) -> String {
+    match bytes_per_sec {
+        0 => "0 B/s".to_owned(),
+        _ => tui::rate_cell(bytes_per_sec),
+    }
+}
+
+fn print_details(info_hash: &str, json: bool) -> Result<(), state::StateError> {
+    let details = state::StateStore::open()?.details(info_hash)?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&details).expect("failed to serialize torrent details")
+        );
+        return Ok(());
+    }
+
+    let entry = &details.entry;
+    println!("{}", entry.name.as_deref().unwrap_or("?"));
+    println!("info hash: {}", entry.info_hash);
+    println!("status: {}", entry.status.folder_name());
+    if let Some(label) = &entry.label {
+        println!("label: {label}");
+    }
+    if let Some(output) = &details.output {
+        println!("output: {}", output.display());
+    }
+    if let Some(size) = entry.size {
+        match details.allocated {
+            Some(allocated) => println!(
+                "size: {}, {} on disk",
+                tui::size_cell(size),
+                tui::size_cell(allocated)
+            ),
+            None => println!("size: {}", tui::size_cell(size)),
+        }
+    }
+
+    println!("timeline:");
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for entry in &details.timeline {
+        let ago = Duration::from_secs(now.saturating_sub(entry.at));
+        println!("  {:>4} ago  {}", format_duration(ago), entry.event);
+    }
+    Ok(())
+}
+
+/// Set whether a torrent is anonymous if `anonymous` is given, `Some(None)`
+/// to follow the config, then print whether it is.
+fn edit_anonymous(
+    info_hash: &str,
+    anonymous: Option<Option<bool>>,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    if let Some(anonymous) = anonymous {
+        sidecar.anonymous = anonymous;
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let config = config::Config::load().unwrap_or_default();
+    let enabled = sidecar.anonymous.unwrap_or(config.privacy.anonymous);
+    let origin = match sidecar.anonymous {
+        Some(_) => "set for the torrent",
+        None => "from the config",
+    };
+    println!(
+        "anonymous: {} ({origin})",
+        if enabled { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+/// Set a torrent's own rate limits for the directions given, `0` removing
+/// the limit, then print them.
+fn edit_rate_limits(
+    info_hash: &str,
+    up: Option<ByteSize>,
+    down: Option<ByteSize>,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    let cap = |rate: ByteSize| (rate.bytes() > 0).then_some(rate.bytes());
+    if up.is_some() || down.is_some() {
+        if let Some(up) = up {
+            sidecar.rate_limits.upload = cap(up);
+        }
+        if let Some(down) = down {
+            sidecar.rate_limits.download = cap(down);
+        }
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let show = |rate: Option<u64>| match rate {
+        Some(rate) => format!("{}/s", ByteSize(rate)),
+        None => "unlimited".to_owned(),
+    };
+    println!("download: {}", show(sidecar.rate_limits.download));
+    println!("upload: {}", show(sidecar.rate_limits.upload));
+    Ok(())
+}
+
+/// Set what happens to a torrent once it stalls if `action` is given,
+/// `Some(None)` to follow the config, then print what does.
+fn edit_stall_action(
+    info_hash: &str,
+    action: Option<Option<StallAction>>,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    if let Some(action) = action {
+        sidecar.on_stall = action;
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let config = config::Config::load().unwrap_or_default();
+    let action = sidecar.on_stall.unwrap_or(config.downloads.on_stall);
+    let origin = match sidecar.on_stall {
+        Some(_) => "set for the torrent",
+        None => "from the config",
+    };
+    println!("on stall: {action} ({origin})");
+    Ok(())
+}
+
+fn edit_upload_slots(
+    info_hash: &str,
+    slots: Option<Option<UploadSlots>>,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    if let Some(slots) = slots {
+        sidecar.upload_slots = slots;
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let config = config::Config::load().unwrap_or_default();
+    let slots = sidecar
+        .upload_slots
+        .unwrap_or(config.rate_limits.upload_slots_per_torrent);
+    let origin = match sidecar.upload_slots {
+        Some(_) => "set for the torrent",
+        None => "from the config",
+    };
+    println!("upload slots: {slots} ({origin})");
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CheckUpdateError {
+    #[error(transparent)]
+    State(#[from] state::StateError),
+    #[error("{0}")]
+    Update(#[from] UpdateError),
+}
+
+fn check_update(info_hash: &str, yes: bool) -> Result<(), CheckUpdateError> {
+    let store = state::StateStore::open()?;
+    let (_, torrent_path) = store.find(info_hash)?;
+    let torrent =
+        MetaInfo::try_from(torrent_path).map_err(|_| state::StateError::InvalidTorrent)?;
+
+    let config = config::Config::load().unwrap_or_default();
+    let Some(update) = update::check_update(&torrent, &config.downloads.source_resolver())? else {
+        println!("{} is up to date", torrent.info().name());
+        return Ok(());
+    };
+    print!("{update}");
+
+    let apply = yes
+        || match config.downloads.on_update {
+            UpdateAction::Auto => true,
+            UpdateAction::Prompt | UpdateAction::Ignore => confirm("replace the torrent?"),
+        };
+    if apply {
+        let new = store.apply_update(info_hash, &update)?;
+        println!("replaced by {new}");
+    }
+    Ok(())
+}
+
+/// Ask a yes or no question on the terminal, no unless answered otherwise.
+fn confirm(question: &str) -> bool {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    print!("{question} [y/N] ");
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn edit_share_limits(info_hash: &str, limits: ShareLimits) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    if limits != ShareLimits::default() {
+        sidecar.share_limits = limits.or(sidecar.share_limits);
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let config = config::Config::load().unwrap_or_default();
+    let limits = config.share_limits(sidecar.share_limits, sidecar.label.as_deref());
+    match limits.ratio {
+        Some(ratio) => println!("ratio: {ratio}"),
+        None => println!("ratio: unlimited"),
+    }
+    match limits.seed_time_mins {
+        Some(mins) => println!(
+            "seed time: {}",
+            HumanDuration(Duration::from_secs(mins * 60))
+        ),
+        None => println!("seed time: unlimited"),
+    }
+    println!("action: {}", limits.action());
+
+    Ok(())
+}
+
+fn edit_trackers(
+    info_hash: &str,
+    add: Vec<String>,
+    remove: Vec<String>,
+    replace: Vec<String>,
+) -> Result<(), state::StateError> {
+    let store = state::StateStore::open()?;
+    let mut sidecar = store.load_sidecar(info_hash)?;
+
+    let before = sidecar.trackers.clone();
+    for url in add {
+        sidecar.trackers.add(url);
+    }
+    for url in remove {
+        sidecar.trackers.remove(url);
+    }
+    for pair in replace.chunks_exact(2) {
+        sidecar.trackers.replace(pair[0].clone(), pair[1].clone());
+    }
+    // Listing the trackers leaves the sidecar as it is
+    if sidecar.trackers != before {
+        store.save_sidecar(info_hash, &sidecar)?;
+    }
+
+    let (_, torrent_path) = store.find(info_hash)?;
+    let original = MetaInfo::try_from(torrent_path)
+        .map(|torrent| torrent.trackers())
+        .unwrap_or_default();
+
+    for (url, origin) in sidecar.trackers.apply(&original) {
+        match origin {
+            state::TrackerOrigin::MetaInfo => println!("{url}"),
+            state::TrackerOrigin::User => println!("{url} (added)"),
+        }
+    }
+    for url in &sidecar.trackers.removed {
+        if original.contains(url) {
+            println!("{url} (removed)");
+        }
+    }
+
+    Ok(())
 }
 
 // All, Downloading, Seeding, Active, Paused, Complete
@@ -194,7 +1595,7 @@ fn main() {
 
 // [########################################################                                     ]
 
-// ~/.flud/settings.toml (this is what the settings tab edits)
+// ~/.config/flud/config.toml (this is what the settings tab edits)
 
 // instead of a database we have a folder based state with .torrent files
 // ~/.flud/downloading
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use torrent::{
+    operation::DiskScheduler,
+    rate_limit::{RateLimiter, RateLimits},
+};
+
+use crate::{config::LowPowerConfig, turtle::SpeedScheduler};
+
+// Low-power mode keeps flud polite on a laptop: tighter rate limits, fewer
+// peers, fewer announces and no hashing of whole torrents, so the fans and
+// the radio get a rest. It is switched on by hand or on its own while the
+// laptop runs on battery, as told by the kernel's power supplies on Linux
+// (what upower reads) and `pmset` on macOS. Switching by hand lasts until
+// the laptop is plugged in or unplugged, like turtle mode's schedule.
+
+/// How often the power source is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+    /// Plugged in, or a machine without a battery.
+    Mains,
+    Battery,
+}
+
+/// Where the machine draws its power from, `None` if there is no way to
+/// tell, e.g. on an OS we don't know how to ask.
+#[cfg(target_os = "linux")]
+pub fn power_source() -> Option<PowerSource> {
+    let supplies = std::fs::read_dir("/sys/class/power_supply").ok()?;
+    let mut source = None;
+    for supply in supplies.flatten() {
+        let path = supply.path();
+        let read = |name: &str| {
+            std::fs::read_to_string(path.join(name))
+                .map(|value| value.trim().to_owned())
+                .unwrap_or_default()
+        };
+        match read("type").as_str() {
+            "Mains" | "USB" if read("online") == "1" => return Some(PowerSource::Mains),
+            // Not the battery of a mouse or headset
+            "Battery" if read("scope") != "Device" => {
+                if read("status") == "Discharging" {
+                    source = Some(PowerSource::Battery);
+                } else {
+                    source.get_or_insert(PowerSource::Mains);
+                }
+            }
+            _ => {}
+        }
+    }
+    source
+}
+
+/// Where the machine draws its power from, `None` if there is no way to
+/// tell, e.g. on an OS we don't know how to ask.
+#[cfg(target_os = "macos")]
+pub fn power_source() -> Option<PowerSource> {
+    // e.g. `Now drawing from 'Battery Power'`
+    let output = std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let first = output.lines().next()?;
+    if first.contains("'Battery Power'") {
+        Some(PowerSource::Battery)
+    } else if first.contains("'AC Power'") {
+        Some(PowerSource::Mains)
+    } else {
+        None
+    }
+}
+
+/// Where the machine draws its power from, `None` if there is no way to
+/// tell, e.g. on an OS we don't know how to ask.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn power_source() -> Option<PowerSource> {
+    None
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerMode {
+    #[default]
+    Normal,
+    /// The limits of `[low_power]`, with rechecks held.
+    LowPower,
+}
+
+impl PowerMode {
+    pub fn toggled(self) -> Self {
+        match self {
+            PowerMode::Normal => PowerMode::LowPower,
+            PowerMode::LowPower => PowerMode::Normal,
+        }
+    }
+}
+
+impl fmt::Display for PowerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PowerMode::Normal => "full power",
+            PowerMode::LowPower => "low power",
+        })
+    }
+}
+
+/// Picks the power mode for the power source and says what it allows.
+#[derive(Debug, Clone)]
+pub struct PowerMonitor {
+    on_battery: bool,
+    limits: RateLimits,
+    max_peers: usize,
+    announce_interval: Duration,
+    /// The power source when last checked.
+    source: Option<PowerSource>,
+    /// Chosen by hand, until the power source changes.
+    manual: Option<PowerMode>,
+}
+
+impl PowerMonitor {
+    pub fn new(config: &LowPowerConfig, source: Option<PowerSource>) -> Self {
+        Self {
+            on_battery: config.on_battery,
+            limits: config.limits(),
+            max_peers: config.max_peers,
+            announce_interval: config.announce_interval.as_duration(),
+            source,
+            manual: None,
+        }
+    }
+
+    /// The mode the power source asks for.
+    pub fn automatic_mode(&self) -> PowerMode {
+        match self.on_battery && self.source == Some(PowerSource::Battery) {
+            true => PowerMode::LowPower,
+            false => PowerMode::Normal,
+        }
+    }
+
+    pub fn mode(&self) -> PowerMode {
+        self.manual.unwrap_or_else(|| self.automatic_mode())
+    }
+
+    pub fn source(&self) -> Option<PowerSource> {
+        self.source
+    }
+
+    /// Switch to `mode` by hand, until the power source changes.
+    pub fn set_mode(&mut self, mode: PowerMode) {
+        self.manual = (mode != self.automatic_mode()).then_some(mode);
+    }
+
+    /// Follow the power source, returning the new mode if it changed.
+    pub fn tick(&mut self, source: Option<PowerSource>) -> Option<PowerMode> {
+        let before = self.mode();
+        if source != self.source {
+            self.source = source;
+            self.manual = None;
+        }
+        (self.mode() != before).then(|| self.mode())
+    }
+
+    /// The caps on top of the global limits, `None` at full power.
+    pub fn limits(&self) -> Option<RateLimits> {
+        (self.mode() == PowerMode::LowPower).then_some(self.limits)
+    }
+
+    /// Peers connected at once per torrent, `None` for no cap.
+    pub fn max_peers(&self) -> Option<usize> {
+        (self.mode() == PowerMode::LowPower && self.max_peers > 0).then_some(self.max_peers)
+    }
+
+    /// How long to wait before announcing again when a tracker asked for
+    /// `interval`.
+    pub fn announce_interval(&self, interval: Duration) -> Duration {
+        match self.mode() {
+            PowerMode::Normal => interval,
+            PowerMode::LowPower => interval.max(self.announce_interval),
+        }
+    }
+}
+
+/// Cap `speed`'s limits and hold `disk`'s rechecks for `monitor`'s mode,
+/// e.g. after it was switched by hand.
+pub fn apply(
+    monitor: &PowerMonitor,
+    speed: &Mutex<SpeedScheduler>,
+    limiter: &RateLimiter,
+    disk: Option<&DiskScheduler>,
+) {
+    let mut speed = speed.lock().unwrap();
+    speed.set_low_power(monitor.limits());
+    limiter.set_limits(speed.limits());
+    if let Some(disk) = disk {
+        disk.pause_hashing(monitor.mode() == PowerMode::LowPower);
+    }
+}
+
+/// Check the power source every `CHECK_INTERVAL` for as long as the process
+/// runs, applying the mode whenever it switches. The mode can still be
+/// switched by hand through `monitor`.
+pub fn spawn(
+    monitor: Arc<Mutex<PowerMonitor>>,
+    speed: Arc<Mutex<SpeedScheduler>>,
+    limiter: RateLimiter,
+    disk: Option<DiskScheduler>,
+) {
+    apply(&monitor.lock().unwrap(), &speed, &limiter, disk.as_ref());
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        let mut monitor = monitor.lock().unwrap();
+        if let Some(mode) = monitor.tick(power_source()) {
+            log::info!("switched to {mode}");
+            apply(&monitor, &speed, &limiter, disk.as_ref());
+        }
+    });
+}
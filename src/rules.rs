@@ -0,0 +1,262 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, path::PathBuf, str::FromStr};
+use torrent::{
+    meta_info::MetaInfo,
+    operation::{Operation, OperationKind},
+    storage::{move_files, MoveError},
+};
+
+use crate::{
+    config::{
+        expand_home, prepare_output_dir, AutoLabelRule, Config, DownloadsConfig, HookConfig,
+        HookEvent, LabelRules, OutputDirError, DEFAULT_HOOK_TIMEOUT_SECS,
+    },
+    hooks::{HookContext, HookRunner},
+    state::{StateError, StateStore},
+};
+
+// Labels sort torrents, their rules say what happens to them as they change
+// state: where the data goes once complete, a script to run then, the ratio
+// to seed to and when to archive them. The daemon asks the engine what to
+// do whenever a torrent completes or stops and carries it out in order, so
+// a script sees the data where it was moved to.
+//
+// Torrents can be labelled as they are added as well, by `[[auto_label]]`
+// rules matching their name, so a label doesn't have to be picked by hand.
+
+/// When a torrent with a label is archived, moved out of the daemon's way
+/// into the state store's `archive` folder. Its data is left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchivePolicy {
+    #[default]
+    Never,
+    /// Once it is complete, without seeding.
+    OnComplete,
+    /// Once it stops seeding, e.g. at its share limit.
+    OnStop,
+}
+
+impl FromStr for ArchivePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(ArchivePolicy::Never),
+            "on-complete" => Ok(ArchivePolicy::OnComplete),
+            "on-stop" => Ok(ArchivePolicy::OnStop),
+            _ => Err(format!(
+                "unknown archive policy {s}, expected never, on-complete or on-stop"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ArchivePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArchivePolicy::Never => "never",
+            ArchivePolicy::OnComplete => "on-complete",
+            ArchivePolicy::OnStop => "on-stop",
+        })
+    }
+}
+
+/// A change of a torrent's state the rules can act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateChange {
+    /// Every selected file was downloaded and verified.
+    Completed,
+    /// It stopped seeding and was moved to the completed torrents.
+    Stopped,
+}
+
+/// Something a label's rules ask for, carried out in order.
+#[derive(Debug, Clone)]
+pub enum RuleAction {
+    /// Move the torrent's data to this directory.
+    MoveData(PathBuf),
+    /// Run the command, like a hook for the change.
+    RunScript(HookConfig),
+    Archive,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError {
+    #[error(transparent)]
+    State(#[from] StateError),
+    #[error("{0}")]
+    Move(#[from] MoveError),
+    #[error("the torrent has no download directory to move the data from")]
+    NoOutput,
+    #[error("invalid auto_label pattern {0}: {1}")]
+    Pattern(String, regex::Error),
+}
+
+/// Decides what the rules of a torrent's label ask for when it changes
+/// state, and carries it out.
+#[derive(Debug, Clone, Default)]
+pub struct RulesEngine {
+    labels: BTreeMap<String, LabelRules>,
+}
+
+impl RulesEngine {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            labels: config.labels.clone(),
+        }
+    }
+
+    pub fn rules(&self, label: Option<&str>) -> Option<&LabelRules> {
+        self.labels.get(label?)
+    }
+
+    /// What the rules of `label` ask for once a torrent went through `change`.
+    pub fn evaluate(&self, label: Option<&str>, change: StateChange) -> Vec<RuleAction> {
+        let Some(rules) = self.rules(label) else {
+            return Vec::new();
+        };
+
+        let mut actions = Vec::new();
+        if change == StateChange::Completed {
+            if let Some(dir) = rules.move_to.as_deref().and_then(expand_home) {
+                actions.push(RuleAction::MoveData(dir));
+            }
+            if !rules.on_complete.is_empty() {
+                actions.push(RuleAction::RunScript(HookConfig {
+                    on: HookEvent::Completed,
+                    command: rules.on_complete.clone(),
+                    timeout_secs: DEFAULT_HOOK_TIMEOUT_SECS,
+                    env: Vec::new(),
+                    working_dir: None,
+                }));
+            }
+        }
+
+        let archive = match change {
+            StateChange::Completed => ArchivePolicy::OnComplete,
+            StateChange::Stopped => ArchivePolicy::OnStop,
+        };
+        if rules.archive == archive {
+            actions.push(RuleAction::Archive);
+        }
+        actions
+    }
+
+    /// Evaluate the rules for the torrent with `info_hash` and carry them
+    /// out, noting each action in its log. Blocks while data is moved and
+    /// scripts run.
+    pub fn on_change(
+        &self,
+        store: &StateStore,
+        hooks: &HookRunner,
+        info_hash: &str,
+        change: StateChange,
+    ) -> Result<(), RuleError> {
+        let sidecar = store.load_sidecar(info_hash)?;
+        let actions = self.evaluate(sidecar.label.as_deref(), change);
+        if actions.is_empty() {
+            return Ok(());
+        }
+
+        let (_, torrent_path) = store.find(info_hash)?;
+        let torrent = MetaInfo::try_from(torrent_path).map_err(|_| StateError::InvalidTorrent)?;
+        let mut context = HookContext {
+            info_hash: info_hash.to_lowercase(),
+            name: torrent.info().name().to_owned(),
+            label: sidecar.label,
+            output: sidecar.output,
+            tracker: None,
+        };
+
+        for action in actions {
+            match action {
+                RuleAction::MoveData(dir) => {
+                    let from = context.output.clone().ok_or(RuleError::NoOutput)?;
+                    std::fs::create_dir_all(&dir).map_err(StateError::from)?;
+                    let operation = Operation::new(OperationKind::Move, 0);
+                    move_files(torrent.info(), &from, &dir, &operation)?;
+
+                    let mut sidecar = store.load_sidecar(info_hash)?;
+                    sidecar.output = Some(dir.clone());
+                    store.save_sidecar(info_hash, &sidecar)?;
+                    store.append_log(
+                        info_hash,
+                        &[format!("rule: moved data to {}", dir.display())],
+                    )?;
+                    context.output = Some(dir);
+                }
+                RuleAction::RunScript(hook) => {
+                    let event = match change {
+                        StateChange::Completed => HookEvent::Completed,
+                        StateChange::Stopped => HookEvent::Removed,
+                    };
+                    hooks.run_logged(&hook, event, &context);
+                }
+                RuleAction::Archive => {
+                    store.append_log(info_hash, &["rule: archived".to_owned()])?;
+                    store.archive(info_hash)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a torrent being added goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddTarget {
+    pub label: Option<String>,
+    /// The download directory, created and checked to be writable.
+    pub output: PathBuf,
+}
+
+/// The `[[auto_label]]` rules, with their patterns compiled.
+#[derive(Debug, Clone, Default)]
+pub struct AutoLabeler {
+    rules: Vec<(Regex, AutoLabelRule)>,
+}
+
+impl AutoLabeler {
+    pub fn new(config: &Config) -> Result<Self, RuleError> {
+        let rules = config
+            .auto_label
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.pattern)
+                    .map(|pattern| (pattern, rule.clone()))
+                    .map_err(|err| RuleError::Pattern(rule.pattern.clone(), err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// The first rule matching a torrent called `name`.
+    pub fn matching(&self, name: &str) -> Option<&AutoLabelRule> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(name))
+            .map(|(_, rule)| rule)
+    }
+
+    /// Where a torrent called `name`, if it is known yet, goes. The `label`
+    /// and `output` given when adding it win over a matching rule's, a
+    /// label without a directory goes to its download directory.
+    pub fn target(
+        &self,
+        name: Option<&str>,
+        label: Option<String>,
+        output: Option<PathBuf>,
+        downloads: &DownloadsConfig,
+    ) -> Result<AddTarget, OutputDirError> {
+        let rule = name.and_then(|name| self.matching(name));
+        let label = label.or_else(|| rule.and_then(|rule| rule.label.clone()));
+        let output = output
+            .or_else(|| rule?.save_path.as_deref().and_then(expand_home))
+            .or_else(|| downloads.directory_for(label.as_deref()))
+            .ok_or(OutputDirError::NotConfigured)?;
+        prepare_output_dir(&output)?;
+        Ok(AddTarget { label, output })
+    }
+}
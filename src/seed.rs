@@ -0,0 +1,668 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{Ipv6Addr, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use torrent::{
+    cache::{ReadCache, DEFAULT_READ_CACHE},
+    disk::PieceWriter,
+    hash_pool::HashPool,
+    identity::Identity,
+    memory::MemoryBudget,
+    meta_info::MetaInfo,
+    operation::{Cancelled, DiskScheduler, Operation, OperationKind},
+    peer::{
+        connection::ConnectionManager,
+        have::RemotePieces,
+        listener::{global_ipv6, IncomingPeer, PeerListener},
+        mse::{EncryptedStream, EncryptionMode},
+        unchoke::Unchoker,
+        upload_queue::{BlockRequest, UploadQueue},
+        validation::{MessageValidator, ValidationMode, Verdict},
+        Handshake, Message, PeerError,
+    },
+    rate_limit::Direction,
+    stats::TransferStats,
+    tracker::{
+        filter::{self, TrackerFilter},
+        schedule::MIN_ANNOUNCE_INTERVAL,
+        stats::{AnnouncedTotals, TrackerQuota},
+        tiers::TrackerTiers,
+        Event, Tracker, TrackerRequest, TrackerResponse,
+    },
+    verify,
+};
+
+use crate::{
+    config::{HookEvent, StorageConfig},
+    hooks::{HookContext, HookRunner},
+    power::PowerMonitor,
+    state::StateStore,
+};
+
+// Seeding without the daemon: no resume data, nothing about the torrent is
+// written. Only the trackers' all-time stats in the state store are kept
+// up to date, so their quotas count this upload too, along with when a
+// complete copy was last seen for torrents the store knows. The data is checked
+// once, then every peer that connects is served until the process is
+// stopped or the ratio is reached.
+//
+// Every peer has a thread reading its messages, the requests go into one
+// queue that a single uploader serves round-robin.
+
+/// How often the announcer and the uploader check whether seeding should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Peers that send nothing for this long are disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Peers that don't take a block for this long are disconnected, so they
+/// don't hold up the uploads to everyone else.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait between announces when the tracker doesn't say.
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The write half of a peer connection, locked on its own so a peer slow
+/// to take a block doesn't hold up writes to the others.
+type PeerWriter = Arc<Mutex<EncryptedStream<TcpStream>>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeedError {
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    #[error("{bad} of {total} pieces are missing or bad, only complete data can be seeded")]
+    Incomplete { bad: usize, total: usize },
+    #[error("the check was cancelled")]
+    Cancelled(#[from] Cancelled),
+}
+
+/// Why seeding stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    /// Ctrl+C was pressed.
+    Interrupted,
+    RatioReached,
+    /// Peers could no longer be accepted.
+    ListenerFailed,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Interrupted => "stopped",
+            StopReason::RatioReached => "ratio reached",
+            StopReason::ListenerFailed => "unable to accept peers",
+        })
+    }
+}
+
+/// Asked for by Ctrl+C, the uploader or a failing listener, whichever
+/// comes first gives the reason.
+#[derive(Debug, Default)]
+struct Stop {
+    requested: AtomicBool,
+    reason: OnceLock<StopReason>,
+}
+
+impl Stop {
+    fn request(&self, reason: StopReason) {
+        let _ = self.reason.set(reason);
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedOptions {
+    /// The port peers connect to, the next free one of 6881-6889 is used
+    /// if it is one of them and taken.
+    pub port: u16,
+    /// Stop once this much of the torrent's size has been uploaded.
+    pub ratio: Option<f64>,
+    pub encryption: EncryptionMode,
+    pub filter: TrackerFilter,
+    pub connections: ConnectionManager,
+    pub identity: Identity,
+    /// Who of the interested peers is uploaded to.
+    pub upload_slots: Unchoker,
+    /// Soft limits by tracker host, see `TrackersConfig::quotas`.
+    pub quotas: BTreeMap<String, TrackerQuota>,
+    /// The share of a quota at which it is warned about first.
+    pub quota_warning: f64,
+    /// Run for `HookEvent::Quota`.
+    pub hooks: HookRunner,
+    /// Fewer peers and announces in low-power mode.
+    pub power: Arc<Mutex<PowerMonitor>>,
+    /// Where the data is checked before seeding.
+    pub hashes: HashPool,
+    /// Runs the check, after any other check of the process and held while
+    /// in low-power mode.
+    pub disk: DiskScheduler,
+    /// How the data is read.
+    pub storage: StorageConfig,
+    pub memory: MemoryBudget,
+}
+
+/// State shared between the accept loop, the peer threads, the uploader
+/// and the announcer.
+struct Shared {
+    torrent: Arc<MetaInfo>,
+    writer: PieceWriter,
+    uploaded: AtomicU64,
+    stop: Arc<Stop>,
+    options: SeedOptions,
+    /// Announced to trackers when peers can connect over IPv6.
+    ipv6: Option<Ipv6Addr>,
+    queue: Mutex<UploadQueue>,
+    /// Signalled when a request is queued.
+    queued: Condvar,
+    /// The write half of every peer connection.
+    streams: Mutex<HashMap<SocketAddr, PeerWriter>>,
+    /// Where the trackers' stats and the torrent's sidecar are kept, `None`
+    /// if it can't be opened.
+    store: Option<StateStore>,
+    /// What each tracker host was told was transferred so far.
+    announced: Mutex<AnnouncedTotals>,
+    /// Kept across announces, so the trackers that answered are asked first.
+    tiers: Mutex<TrackerTiers>,
+}
+
+impl Shared {
+    fn stats(&self) -> TransferStats {
+        let mut stats = TransferStats::with_verified(self.torrent.info().total_length() as u64);
+        stats.block_sent(self.uploaded.load(Ordering::Relaxed));
+        stats
+    }
+
+    fn tracker_request(&self, event: Option<Event>) -> TrackerRequest {
+        TrackerRequest::new_compact(&self.torrent, &self.options.identity)
+            .with_port(self.options.port)
+            .with_ipv6(self.ipv6)
+            .with_stats(&self.stats(), self.torrent.info().total_length() as u64)
+            .with_event(event)
+    }
+
+    /// The write half of the connection to `addr`, `None` once it is gone.
+    fn writer(&self, addr: SocketAddr) -> Option<PeerWriter> {
+        self.streams.lock().unwrap().get(&addr).cloned()
+    }
+}
+
+/// The directory the torrent's files are under, `data` may also be the
+/// torrent's file or folder itself.
+pub fn data_root(torrent: &MetaInfo, data: &Path) -> PathBuf {
+    match data.parent() {
+        Some(parent) if data.ends_with(torrent.info().name()) => parent.to_owned(),
+        _ => data.to_owned(),
+    }
+}
+
+/// Queue a check of every piece under `root` on `disk`, returning its
+/// handle and where it sends how many pieces are missing or bad.
+fn check(
+    torrent: &Arc<MetaInfo>,
+    root: &Path,
+    options: &SeedOptions,
+) -> (Operation, mpsc::Receiver<Result<usize, Cancelled>>) {
+    let (sender, checked) = mpsc::channel();
+    let torrent = Arc::clone(torrent);
+    let root = root.to_owned();
+    let hashes = options.hashes.clone();
+    let total = torrent.info().piece_count() as u64;
+    let operation = options
+        .disk
+        .submit(OperationKind::Check, total, move |operation| {
+            let bad = verify::recheck(torrent.info(), &root, operation, &hashes)
+                .map(|good| good.into_iter().filter(|&good| !good).count());
+            let _ = sender.send(bad);
+        });
+    (operation, checked)
+}
+
+/// Verify the data under `root` and seed it in the foreground, until the
+/// process is stopped or `options.ratio` is reached.
+pub fn run(torrent: MetaInfo, root: PathBuf, options: SeedOptions) -> Result<(), SeedError> {
+    // Ctrl+C cancels the check, or once seeding, ends it the same way as
+    // reaching the ratio does so trackers are told we stopped
+    let torrent = Arc::new(torrent);
+    let stop = Arc::new(Stop::default());
+    let (operation, checked) = check(&torrent, &root, &options);
+    {
+        let stop = Arc::clone(&stop);
+        let operation = operation.clone();
+        let interrupted = move || {
+            operation.cancel();
+            stop.request(StopReason::Interrupted);
+        };
+        if let Err(err) = ctrlc::set_handler(interrupted) {
+            eprintln!("trackers won't be told when seeding is stopped: {err}");
+        }
+    }
+
+    let total = torrent.info().piece_count();
+    if options.disk.is_hashing_paused() {
+        println!("the data is checked once low-power mode ends, ctrl+c to stop");
+    }
+    // Cancelled before it started, the task is dropped without sending
+    let bad = checked.recv().unwrap_or(Err(Cancelled))?;
+    if bad > 0 {
+        return Err(SeedError::Incomplete { bad, total });
+    }
+
+    let listener = PeerListener::bind(options.port)?
+        .with_connections(options.connections.clone())
+        .with_encryption(options.encryption);
+    let options = SeedOptions {
+        port: listener.port(),
+        ..options
+    };
+    println!(
+        "seeding {} on port {}, ctrl+c to stop",
+        torrent.info().name(),
+        options.port
+    );
+
+    let info_hash = *torrent.info().hash().as_bytes();
+    let mut tiers = TrackerTiers::from(&*torrent);
+    tiers.retain(&options.filter);
+    let shared = Arc::new(Shared {
+        writer: options
+            .storage
+            .writer(torrent.info(), root, &options.memory, &options.hashes)
+            .with_read_cache(ReadCache::new(DEFAULT_READ_CACHE)),
+        torrent,
+        uploaded: AtomicU64::new(0),
+        stop,
+        options,
+        ipv6: listener.has_ipv6().then(global_ipv6).flatten(),
+        queue: Mutex::new(UploadQueue::default()),
+        queued: Condvar::new(),
+        streams: Mutex::new(HashMap::new()),
+        store: StateStore::open()
+            .inspect_err(|err| eprintln!("tracker stats won't be recorded: {err}"))
+            .ok(),
+        announced: Mutex::new(AnnouncedTotals::default()),
+        tiers: Mutex::new(tiers),
+    });
+
+    if !shared.torrent.trackers().is_empty() {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || announce(&shared));
+    }
+    {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || upload(&shared));
+    }
+
+    let serving = Arc::clone(&shared);
+    let served = listener.run(
+        &shared.stop.requested,
+        || vec![info_hash],
+        move |peer| {
+            let addr = peer.addr;
+            match serve(peer, &serving) {
+                Ok(()) | Err(PeerError::Io(_)) => {}
+                Err(err) => eprintln!("{addr}: {err:?}"),
+            }
+        },
+    );
+    // Only returns on its own when it fails, the announcer and uploader
+    // stop along with it
+    shared.stop.request(StopReason::ListenerFailed);
+
+    let uploaded = shared.uploaded.load(Ordering::Relaxed);
+    if let Some(reason) = shared.stop.reason.get() {
+        println!("{reason}, uploaded {uploaded} bytes");
+    }
+    if let Some(stats) = shared.writer.read_cache_stats() {
+        println!("read cache: {stats}");
+    }
+    announce_tiers(&shared, Some(Event::Stopped));
+    Ok(served?)
+}
+
+/// Announce to the torrent's trackers until seeding stops, so peers other
+/// than the ones we tell about the port can find us.
+fn announce(shared: &Shared) {
+    let mut event = Some(Event::Started);
+    while !shared.stop.is_requested() {
+        let interval = match announce_tiers(shared, event) {
+            Some(interval) => {
+                event = None;
+                interval
+            }
+            None => DEFAULT_ANNOUNCE_INTERVAL,
+        };
+        let interval = shared
+            .options
+            .power
+            .lock()
+            .unwrap()
+            .announce_interval(interval);
+
+        let next = Instant::now() + interval;
+        while Instant::now() < next && !shared.stop.is_requested() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Returns the interval the tracker asked for, `None` if no tracker answered.
+fn announce_tiers(shared: &Shared, event: Option<Event>) -> Option<Duration> {
+    let request = shared.tracker_request(event);
+    let attempted = |url: &str, success| record_announce(shared, url, success);
+    let mut tiers = shared.tiers.lock().unwrap();
+    match Tracker::announce_tiers_with(&request, &mut tiers, attempted) {
+        Ok((_, TrackerResponse::Success(response))) => {
+            let interval = response
+                .interval()
+                .max(response.min_interval().unwrap_or(0));
+            Some(Duration::from_secs(interval as u64).max(MIN_ANNOUNCE_INTERVAL))
+        }
+        Ok((url, TrackerResponse::Failure(failure))) => {
+            eprintln!("{url}: {}", failure.failure_reason);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// A peer has every piece, keep when for `flud info`'s `last seen
+/// complete`. Only torrents in the store have a sidecar to keep it in.
+fn record_seen_complete(shared: &Shared) {
+    let Some(store) = &shared.store else {
+        return;
+    };
+    let info_hash = shared.torrent.info().hash().to_hex();
+    let Ok(mut sidecar) = store.load_sidecar(&info_hash) else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    sidecar.last_seen_complete = Some(now);
+    if let Err(err) = store.save_sidecar(&info_hash, &sidecar) {
+        eprintln!("unable to save when a complete copy was seen: {err}");
+    }
+}
+
+/// Count an announce to `url` towards its host's all-time stats, crediting
+/// it with what was transferred since the last announce it answered, and
+/// warn once a quota of the host is close to or entirely used up.
+fn record_announce(shared: &Shared, url: &str, success: bool) {
+    let (Some(store), Some(host)) = (&shared.store, filter::host(url)) else {
+        return;
+    };
+    // A failed announce didn't tell the tracker anything, the next one will
+    let (uploaded, downloaded) = if success {
+        let stats = shared.stats();
+        shared
+            .announced
+            .lock()
+            .unwrap()
+            .credit(&host, stats.uploaded, stats.downloaded)
+    } else {
+        (0, 0)
+    };
+
+    let updated = store.update_tracker_stats(&host, |stats| {
+        stats.uploaded += uploaded;
+        stats.downloaded += downloaded;
+        if success {
+            stats.announces += 1;
+        } else {
+            stats.failures += 1;
+        }
+    });
+    let (before, after) = match updated {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("{host}: unable to record tracker stats: {err}");
+            return;
+        }
+    };
+
+    let Some(quota) = shared.options.quotas.get(&host) else {
+        return;
+    };
+    for warning in quota.crossed(&before, &after, shared.options.quota_warning) {
+        eprintln!("{host}: {warning}");
+        let info = shared.torrent.info();
+        let context = HookContext {
+            info_hash: info.hash().to_hex(),
+            name: info.name().to_owned(),
+            label: None,
+            output: None,
+            tracker: Some(host.clone()),
+        };
+        shared.options.hooks.fire(HookEvent::Quota, &context);
+    }
+}
+
+/// Read the messages of a single peer until it disconnects or seeding
+/// stops, queueing its requests for the uploader.
+fn serve(peer: IncomingPeer, shared: &Shared) -> Result<(), PeerError> {
+    let IncomingPeer {
+        mut stream, addr, ..
+    } = peer;
+    stream.get_ref().set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.get_ref().set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+    // Turned away before the handshake, dropping the stream disconnects it
+    let max_peers = shared.options.power.lock().unwrap().max_peers();
+    if max_peers.is_some_and(|max| shared.streams.lock().unwrap().len() >= max) {
+        return Ok(());
+    }
+
+    let info = shared.torrent.info();
+    let info_hash = *info.hash().as_bytes();
+    Handshake::new(info_hash, shared.options.identity.peer_id()).write_to(&mut stream)?;
+
+    let mut bitfield = vec![0xFFu8; info.piece_count().div_ceil(8)];
+    let spare = bitfield.len() * 8 - info.piece_count();
+    if let Some(last) = bitfield.last_mut() {
+        *last <<= spare;
+    }
+    Message::Bitfield(bitfield).write_to(&mut stream)?;
+
+    let (mut reader, writer) = stream.try_split()?;
+    shared
+        .streams
+        .lock()
+        .unwrap()
+        .insert(addr, Arc::new(Mutex::new(writer)));
+    let result = receive(&mut reader, addr, shared);
+    shared.streams.lock().unwrap().remove(&addr);
+    shared.queue.lock().unwrap().remove_peer(addr);
+    unchoke(shared, shared.options.upload_slots.leave(addr));
+    result
+}
+
+fn receive(
+    reader: &mut EncryptedStream<TcpStream>,
+    addr: SocketAddr,
+    shared: &Shared,
+) -> Result<(), PeerError> {
+    let info = shared.torrent.info();
+    let mut validator = MessageValidator::new(
+        ValidationMode::Strict,
+        info.piece_count() as u32,
+        info.piece_length() as u64,
+        info.total_length() as u64,
+    )
+    .with_addr(addr);
+    let mut pieces = RemotePieces::new(info.piece_count());
+    let upload_slots = &shared.options.upload_slots;
+
+    while !shared.stop.is_requested() {
+        let message = Message::read_from(reader)?;
+        // It may have been given a slot freed by another peer meanwhile
+        validator.set_choking(!upload_slots.is_unchoked(addr));
+        if let Verdict::Disconnect(violation) = validator.validate(&message) {
+            eprintln!("{addr}: {violation:?}");
+            return Ok(());
+        }
+
+        match message {
+            Message::Bitfield(bitfield) => {
+                pieces.set_bitfield(&bitfield);
+                upload_slots.progress(addr, pieces.count(), info.piece_count());
+                if pieces.count() == info.piece_count() {
+                    record_seen_complete(shared);
+                }
+            }
+            Message::Have(index) => {
+                let had = pieces.count();
+                pieces.insert(index as usize);
+                upload_slots.progress(addr, pieces.count(), info.piece_count());
+                // It just finished downloading
+                if pieces.count() > had && pieces.count() == info.piece_count() {
+                    record_seen_complete(shared);
+                }
+            }
+            Message::HaveAll => {
+                pieces.set_all();
+                upload_slots.progress(addr, pieces.count(), info.piece_count());
+                record_seen_complete(shared);
+            }
+            Message::Interested => {
+                // Forgotten if it lost interest before
+                upload_slots.progress(addr, pieces.count(), info.piece_count());
+                // Otherwise it waits for a slot and is unchoked once it frees up
+                let unchoked = upload_slots.interested(addr);
+                unchoke(shared, unchoked.then_some(addr).into_iter().collect());
+            }
+            Message::NotInterested => {
+                if upload_slots.is_unchoked(addr) {
+                    shared.queue.lock().unwrap().remove_peer(addr);
+                    if let Some(writer) = shared.writer(addr) {
+                        Message::Choke.write_to(&mut *writer.lock().unwrap())?;
+                    }
+                }
+                unchoke(shared, upload_slots.leave(addr));
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                // Requests over the peer's share are dropped, it asks again
+                if shared.queue.lock().unwrap().push(addr, request) {
+                    shared.queued.notify_one();
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                shared.queue.lock().unwrap().cancel(addr, request);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Unchoke the peers that were given a slot, a peer that fails to take the
+/// message is disconnected by its reader soon enough.
+fn unchoke(shared: &Shared, peers: Vec<SocketAddr>) {
+    for addr in peers {
+        if let Some(writer) = shared.writer(addr) {
+            let _ = Message::Unchoke.write_to(&mut *writer.lock().unwrap());
+        }
+    }
+}
+
+/// Serve the queued requests of every peer in turn until seeding stops.
+fn upload(shared: &Shared) {
+    let info = shared.torrent.info();
+    let ratio_bytes = shared
+        .options
+        .ratio
+        .map(|ratio| (ratio * info.total_length() as f64) as u64);
+
+    while !shared.stop.is_requested() {
+        let next = {
+            let queue = shared.queue.lock().unwrap();
+            let (mut queue, _) = shared
+                .queued
+                .wait_timeout_while(queue, POLL_INTERVAL, |queue| queue.is_empty())
+                .unwrap();
+            (queue.pop(), queue.peek())
+        };
+        let (Some((addr, request)), ahead) = next else {
+            continue;
+        };
+
+        let block =
+            match shared
+                .writer
+                .read_block(info, request.index, request.begin, request.length)
+            {
+                Ok(block) => block,
+                Err(err) => {
+                    eprintln!("{addr}: {err}");
+                    continue;
+                }
+            };
+        // Most likely a block of the same piece, read the next one while
+        // this block is sent
+        if let Some(ahead) = ahead {
+            if let Err(err) = shared.writer.prefetch(info, ahead.index) {
+                eprintln!("prefetching piece {}: {err}", ahead.index);
+            }
+        }
+
+        // Before locking, so only this upload waits for the cap
+        shared
+            .options
+            .connections
+            .rate_limiter()
+            .throttle(Direction::Upload, block.len());
+
+        // The peer disconnected while the block was read
+        let Some(writer) = shared.writer(addr) else {
+            continue;
+        };
+        let piece = Message::Piece {
+            index: request.index,
+            begin: request.begin,
+            block,
+        };
+        let mut writer = writer.lock().unwrap();
+        if piece.write_to(&mut *writer).is_err() {
+            // Its reader notices and cleans up
+            let _ = writer.get_ref().shutdown(std::net::Shutdown::Both);
+            drop(writer);
+            shared.streams.lock().unwrap().remove(&addr);
+            continue;
+        }
+        drop(writer);
+
+        let length = request.length as u64;
+        let uploaded = shared.uploaded.fetch_add(length, Ordering::Relaxed) + length;
+        if ratio_bytes.is_some_and(|limit| uploaded >= limit) {
+            shared.stop.request(StopReason::RatioReached);
+        }
+    }
+}
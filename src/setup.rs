@@ -0,0 +1,302 @@
+use crossterm::event;
+use ratatui::{
+    crossterm::event::*, layout::*, style::*, text::*, widgets::*, DefaultTerminal, Frame,
+};
+use strum::{EnumCount, FromRepr};
+use torrent::units::ByteSize;
+
+use crate::{
+    config::{self, Config, ConfigError},
+    daemon,
+};
+
+/// Run the first-run setup wizard and write the config file, `config.toml`
+/// in the flud config directory.
+///
+/// Skipping the wizard with [esc] on the first step still writes the
+/// defaults, so it is only ever shown once. Quitting with [ctrl+c] writes
+/// nothing and returns `None`, the wizard is shown again next time.
+pub fn run() -> Result<Option<Config>, ConfigError> {
+    let terminal = ratatui::init();
+    let result = Wizard::default().run(terminal);
+    ratatui::restore();
+
+    let Some(config) = result? else {
+        return Ok(None);
+    };
+    config.save()?;
+
+    if config.daemon.start_at_login {
+        if let Err(err) = daemon::set_start_at_login(true) {
+            eprintln!("unable to start the daemon at login: {err}");
+        }
+    }
+
+    Ok(Some(config))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumCount, FromRepr)]
+enum Step {
+    DownloadDirectory,
+    ListenPort,
+    Dht,
+    DownloadLimit,
+    UploadLimit,
+    StartAtLogin,
+}
+
+impl Step {
+    fn question(self) -> &'static str {
+        match self {
+            Step::DownloadDirectory => "Where should downloads be saved?",
+            Step::ListenPort => "Which port should peers connect to?",
+            Step::Dht => "Find peers through the DHT?",
+            Step::DownloadLimit => "Limit the download rate (per second)?",
+            Step::UploadLimit => "Limit the upload rate (per second)?",
+            Step::StartAtLogin => "Start the daemon when you log in?",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Step::DownloadDirectory => "Created if it doesn't exist. `~` is your home directory.",
+            Step::ListenPort => "Forward this port on your router to reach more peers.",
+            Step::Dht => {
+                "Needed for magnet links without trackers. Some private trackers forbid it."
+            }
+            Step::DownloadLimit | Step::UploadLimit => "e.g. 500KiB or 1.5MiB, 0 is unlimited.",
+            Step::StartAtLogin => "Torrents keep downloading and seeding without the TUI open.",
+        }
+    }
+
+    fn is_toggle(self) -> bool {
+        matches!(self, Step::Dht | Step::StartAtLogin)
+    }
+
+    /// The current value from `config`, as it is edited.
+    fn value(self, config: &Config) -> String {
+        let yes_no = |value: bool| if value { "yes" } else { "no" }.to_owned();
+        match self {
+            Step::DownloadDirectory => config
+                .downloads
+                .directory
+                .clone()
+                .or_else(|| {
+                    let dir = config.downloads.directory_for(None)?;
+                    Some(dir.display().to_string())
+                })
+                .unwrap_or_default(),
+            Step::ListenPort => config.network.listen_port.to_string(),
+            Step::Dht => yes_no(config.dht.enabled),
+            Step::DownloadLimit => config.rate_limits.download.to_string(),
+            Step::UploadLimit => config.rate_limits.upload.to_string(),
+            Step::StartAtLogin => yes_no(config.daemon.start_at_login),
+        }
+    }
+
+    /// Store `input` in `config`, or explain why it is not valid.
+    fn apply(self, config: &mut Config, input: &str) -> Result<(), String> {
+        let input = input.trim();
+        let rate = |input: &str| match input {
+            "" => Ok(ByteSize(0)),
+            _ => input
+                .parse::<ByteSize>()
+                .map_err(|err| format!("Enter a rate such as 1.5MiB, {err}")),
+        };
+
+        match self {
+            Step::DownloadDirectory => {
+                let directory = (!input.is_empty()).then(|| input.to_owned());
+                let downloads = config::DownloadsConfig {
+                    directory,
+                    labels: std::mem::take(&mut config.downloads.labels),
+                    max_torrent_size: config.downloads.max_torrent_size,
+                    on_stall: config.downloads.on_stall,
+                    on_update: config.downloads.on_update,
+                };
+                let checked = downloads
+                    .directory_for(None)
+                    .ok_or(config::OutputDirError::NotConfigured)
+                    .and_then(|dir| config::prepare_output_dir(&dir));
+                config.downloads = downloads;
+                checked.map_err(|err| err.to_string())
+            }
+            Step::ListenPort => {
+                config.network.listen_port = input
+                    .parse()
+                    .ok()
+                    .filter(|&port| port > 0)
+                    .ok_or("Enter a port between 1 and 65535")?;
+                Ok(())
+            }
+            Step::Dht => {
+                config.dht.enabled = input == "yes";
+                Ok(())
+            }
+            Step::DownloadLimit => {
+                config.rate_limits.download = rate(input)?;
+                Ok(())
+            }
+            Step::UploadLimit => {
+                config.rate_limits.upload = rate(input)?;
+                Ok(())
+            }
+            Step::StartAtLogin => {
+                config.daemon.start_at_login = input == "yes";
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Wizard {
+    config: Config,
+    step: Step,
+    input: String,
+    error: Option<String>,
+}
+
+impl Default for Wizard {
+    fn default() -> Self {
+        let config = Config::default();
+        let step = Step::DownloadDirectory;
+        Self {
+            input: step.value(&config),
+            config,
+            step,
+            error: None,
+        }
+    }
+}
+
+impl Wizard {
+    fn go_to(&mut self, step: Step) {
+        self.step = step;
+        self.input = step.value(&self.config);
+        self.error = None;
+    }
+
+    fn toggle(&mut self) {
+        self.input = if self.input == "yes" { "no" } else { "yes" }.to_owned();
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let vertical = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]);
+        let [body_area, keymap_area] = vertical.areas(frame.area());
+
+        let block = Block::bordered()
+            .title(format!(
+                "flud setup ({}/{})",
+                self.step as usize + 1,
+                Step::COUNT
+            ))
+            .style(Style::default().dark_gray());
+        let inner = block.inner(body_area);
+        frame.render_widget(block, body_area);
+
+        let vertical = Layout::vertical([
+            Constraint::Length(1), // question
+            Constraint::Length(1), // help
+            Constraint::Length(3), // input
+            Constraint::Length(1), // error
+        ])
+        .spacing(1);
+        let [question_area, help_area, input_area, error_area] =
+            vertical.areas(inner.inner(Margin::new(1, 1)));
+
+        frame.render_widget(
+            Line::from(self.step.question()).white().bold(),
+            question_area,
+        );
+        frame.render_widget(Line::from(self.step.help()).dark_gray(), help_area);
+
+        let input = Paragraph::new(self.input.as_str())
+            .style(Style::default().yellow())
+            .block(Block::bordered());
+        frame.render_widget(input, input_area);
+        if !self.step.is_toggle() {
+            #[allow(clippy::cast_possible_truncation)]
+            frame.set_cursor_position(Position::new(
+                input_area.x + self.input.chars().count() as u16 + 1,
+                input_area.y + 1,
+            ));
+        }
+
+        if let Some(error) = &self.error {
+            frame.render_widget(Line::from(error.as_str()).red(), error_area);
+        }
+
+        let mut binds = vec![];
+        if self.step.is_toggle() {
+            binds.push("Toggle [space]");
+        }
+        binds.push(if self.step as usize + 1 == Step::COUNT {
+            "Save [enter]"
+        } else {
+            "Next [enter]"
+        });
+        binds.push(if self.step == Step::DownloadDirectory {
+            "Skip [esc]"
+        } else {
+            "Back [esc]"
+        });
+
+        let separator = Span::from(" ");
+        let spans: Vec<Span> = binds
+            .into_iter()
+            .map(|bind| Span::from(bind).on_blue().gray())
+            .fold(Vec::new(), |mut acc, span| {
+                if !acc.is_empty() {
+                    acc.push(separator.clone());
+                }
+                acc.push(span);
+                acc
+            });
+        frame.render_widget(Line::from(spans), keymap_area);
+    }
+
+    /// The config the user went through the wizard with, `None` if they
+    /// quit it.
+    fn run(mut self, mut terminal: DefaultTerminal) -> Result<Option<Config>, ConfigError> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match (key.code, key.modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(None),
+                (KeyCode::Esc, _) => match Step::from_repr((self.step as usize).wrapping_sub(1)) {
+                    Some(previous) => self.go_to(previous),
+                    None => return Ok(Some(Config::default())),
+                },
+                (KeyCode::Enter, _) => {
+                    if let Err(error) = self.step.apply(&mut self.config, &self.input) {
+                        self.error = Some(error);
+                        continue;
+                    }
+                    match Step::from_repr(self.step as usize + 1) {
+                        Some(next) => self.go_to(next),
+                        None => return Ok(Some(self.config)),
+                    }
+                }
+                (KeyCode::Char(' ') | KeyCode::Left | KeyCode::Right, _)
+                    if self.step.is_toggle() =>
+                {
+                    self.toggle()
+                }
+                (KeyCode::Char('y'), _) if self.step.is_toggle() => self.input = "yes".to_owned(),
+                (KeyCode::Char('n'), _) if self.step.is_toggle() => self.input = "no".to_owned(),
+                (KeyCode::Char(c), _) if !self.step.is_toggle() => self.input.push(c),
+                (KeyCode::Backspace, _) if !self.step.is_toggle() => {
+                    self.input.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+}
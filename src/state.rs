@@ -0,0 +1,959 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use torrent::{
+    disk::{Durability, PieceWriter},
+    info_hash::InfoHash,
+    lifecycle::StopCondition,
+    meta_info::MetaInfo,
+    operation::OperationProgress,
+    peer::unchoke::UploadSlots,
+    rate_limit::RateLimits,
+    share_limit::{ShareLimitAction, ShareLimits},
+    stats::RateSample,
+    swarm::{Stall, StallAction},
+    timeline::{Timeline, TimelineEntry, TimelineEvent},
+    tracker::{scrape::ScrapeStats, stats::TrackerStats},
+    update::TorrentUpdate,
+};
+
+// Instead of a database we have a folder based state with .torrent files:
+//
+// ~/.flud/downloading/<info hash>.torrent
+// ~/.flud/downloading/<info hash>.toml     <- sidecar with everything we know about it
+// ~/.flud/downloading/<info hash>.log      <- what happened to it, e.g. hook output
+//
+// Moving a torrent between folders moves its sidecar and log along with it.
+//
+// ~/.flud/dht.dat                          <- DHT node id and good nodes, saved on shutdown
+// ~/.flud/stats.csv                        <- the session's rates, one `at,download,upload` line per sample
+// ~/.flud/trackers.toml                    <- all-time transfer and announce counts, by tracker host
+//
+// Entries that fail the check on daemon start are set aside rather than
+// stopping it, with a note saying what is wrong:
+//
+// ~/.flud/errored/<info hash>.torrent
+// ~/.flud/errored/<info hash>.reason
+
+static STATE_DIR_NAME: &str = ".flud";
+static DHT_STATE_FILE_NAME: &str = "dht.dat";
+static STATS_FILE_NAME: &str = "stats.csv";
+static TRACKER_STATS_FILE_NAME: &str = "trackers.toml";
+static ERRORED_DIR_NAME: &str = "errored";
+static ARCHIVE_DIR_NAME: &str = "archive";
+
+/// Files kept next to each torrent's .torrent file.
+const COMPANION_EXTENSIONS: [&str; 2] = ["toml", "log"];
+
+/// How often the daemon records the session's rates.
+pub const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long recorded rates are kept.
+pub const RATE_HISTORY_RETENTION: Duration = Duration::from_secs(30 * 86400);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    #[error("unable to parse sidecar file")]
+    ParseError(#[from] toml::de::Error),
+    #[error("unable to serialize sidecar")]
+    SerializeError(#[from] toml::ser::Error),
+    #[error("home directory not found")]
+    NoHomeDir,
+    #[error("no torrent with info hash {0}")]
+    NotFound(String),
+    #[error("{0} is not an info hash")]
+    InvalidInfoHash(String),
+    #[error("not a valid .torrent file")]
+    InvalidTorrent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentStatus {
+    /// The torrent has not finished downloading
+    Paused,
+    /// When we still have parts of the file to download.
+    Downloading,
+    /// This is when the download has finished and we are now just uploading.
+    Seeding,
+    /// This is when the desired ratio has been hit and the download finished it stops all network traffic.
+    Completed,
+}
+
+impl TorrentStatus {
+    pub const ALL: [TorrentStatus; 4] = [
+        TorrentStatus::Downloading,
+        TorrentStatus::Paused,
+        TorrentStatus::Seeding,
+        TorrentStatus::Completed,
+    ];
+
+    /// The folder in the state store torrents with this status live in.
+    pub fn folder_name(&self) -> &'static str {
+        match self {
+            TorrentStatus::Paused => "paused",
+            TorrentStatus::Downloading => "downloading",
+            TorrentStatus::Seeding => "seeding",
+            TorrentStatus::Completed => "completed",
+        }
+    }
+}
+
+/// Everything we persist about a torrent beyond its .torrent file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Sidecar {
+    pub trackers: TrackerEdits,
+    /// When a complete copy of the torrent was last seen in the swarm,
+    /// in seconds since the unix epoch.
+    pub last_seen_complete: Option<u64>,
+    /// Where the torrent stops on its own, chosen when it was added.
+    pub stop_condition: StopCondition,
+    /// The label the torrent was added with.
+    pub label: Option<String>,
+    /// The directory the torrent's data is written to.
+    pub output: Option<PathBuf>,
+    /// Limits set for this torrent, overriding those of its label.
+    pub share_limits: ShareLimits,
+    /// Whether the torrent runs in anonymous mode, `None` to follow the config.
+    pub anonymous: Option<bool>,
+    /// What to do once the torrent stalls, `None` to follow the config.
+    pub on_stall: Option<StallAction>,
+    /// Caps for this torrent alone, on top of the global ones.
+    pub rate_limits: RateLimits,
+    /// Peers uploaded to at once, `None` to follow the config.
+    pub upload_slots: Option<UploadSlots>,
+    /// Notable events, e.g. when it completed or a tracker failed.
+    pub timeline: Timeline,
+    /// What the trackers said of the swarm when `flud scrape` last asked.
+    pub scrape: Option<ScrapeStats>,
+}
+
+/// A torrent in the state store, as printed by `flud daemon list --json`.
+///
+/// This is read by scripts and shell completions, so fields are only ever added.
+#[derive(Debug, Serialize)]
+pub struct TorrentEntry {
+    /// Hex encoded.
+    pub info_hash: String,
+    /// `None` if the .torrent file can't be parsed.
+    pub name: Option<String>,
+    pub status: TorrentStatus,
+    pub label: Option<String>,
+    /// The length of every file together, in bytes.
+    pub size: Option<u64>,
+    /// A recheck, move or initial check running for the torrent, only the
+    /// daemon knows of these.
+    pub operation: Option<OperationProgress>,
+}
+
+/// A single torrent, as printed by `flud daemon details --json`.
+///
+/// Like `TorrentEntry`, fields are only ever added.
+#[derive(Debug, Serialize)]
+pub struct TorrentDetails {
+    #[serde(flatten)]
+    pub entry: TorrentEntry,
+    pub output: Option<PathBuf>,
+    /// What the files take up on disk so far, less than what was downloaded
+    /// while they are sparse.
+    pub allocated: Option<u64>,
+    /// Oldest first.
+    pub timeline: Vec<TimelineEntry>,
+}
+
+/// Changes the user made to a torrent's trackers at runtime. The original
+/// .torrent file is never touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackerEdits {
+    /// Trackers added by the user, announced after the original ones.
+    pub added: Vec<String>,
+    /// Trackers from the metainfo the user does not want to announce to.
+    pub removed: Vec<String>,
+}
+
+/// Where a tracker in the effective list came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerOrigin {
+    /// Listed in the .torrent file.
+    MetaInfo,
+    /// Added by the user.
+    User,
+}
+
+impl TrackerEdits {
+    pub fn add(&mut self, url: String) {
+        self.removed.retain(|removed| removed != &url);
+        if !self.added.contains(&url) {
+            self.added.push(url);
+        }
+    }
+
+    pub fn remove(&mut self, url: String) {
+        self.added.retain(|added| added != &url);
+        if !self.removed.contains(&url) {
+            self.removed.push(url);
+        }
+    }
+
+    pub fn replace(&mut self, old: String, new: String) {
+        self.remove(old);
+        self.add(new);
+    }
+
+    /// The trackers to announce to: the original ones minus any removed,
+    /// followed by the ones the user added.
+    pub fn apply(&self, original: &[String]) -> Vec<(String, TrackerOrigin)> {
+        let mut trackers: Vec<(String, TrackerOrigin)> = original
+            .iter()
+            .filter(|tracker| !self.removed.contains(tracker))
+            .map(|tracker| (tracker.clone(), TrackerOrigin::MetaInfo))
+            .collect();
+
+        for tracker in &self.added {
+            if !trackers.iter().any(|(t, _)| t == tracker) {
+                trackers.push((tracker.clone(), TrackerOrigin::User));
+            }
+        }
+
+        trackers
+    }
+}
+
+/// What is wrong with a quarantined entry of the state store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    InvalidTorrent,
+    /// The file is named after another info hash than the torrent's.
+    WrongInfoHash {
+        actual: String,
+    },
+    InvalidSidecar(String),
+    /// The directory the torrent's data goes to doesn't exist.
+    MissingOutput(PathBuf),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::InvalidTorrent => write!(f, "not a valid .torrent file"),
+            Corruption::WrongInfoHash { actual } => {
+                write!(f, "the torrent's info hash is {actual}")
+            }
+            Corruption::InvalidSidecar(err) => write!(f, "invalid sidecar: {err}"),
+            Corruption::MissingOutput(dir) => {
+                write!(f, "download directory {} doesn't exist", dir.display())
+            }
+        }
+    }
+}
+
+/// The outcome of checking the state store on daemon start.
+#[derive(Debug, Default, Clone)]
+pub struct ScanReport {
+    /// Entries that passed.
+    pub ok: usize,
+    /// The info hash (hex) of each entry moved to `errored`, and why.
+    pub quarantined: Vec<(String, Corruption)>,
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "state store: {} ok, {} quarantined",
+            self.ok,
+            self.quarantined.len()
+        )?;
+        for (info_hash, corruption) in &self.quarantined {
+            writeln!(f, "  {info_hash}: {corruption}")?;
+        }
+        Ok(())
+    }
+}
+
+fn check_entry(torrent_path: &Path, info_hash: &str) -> Result<(), Corruption> {
+    let torrent =
+        MetaInfo::try_from(torrent_path.to_owned()).map_err(|_| Corruption::InvalidTorrent)?;
+    let actual = torrent.info().hash().to_hex();
+    if !actual.eq_ignore_ascii_case(info_hash) {
+        return Err(Corruption::WrongInfoHash { actual });
+    }
+
+    let sidecar_path = torrent_path.with_extension("toml");
+    if !sidecar_path.exists() {
+        return Ok(());
+    }
+    let sidecar = std::fs::read_to_string(sidecar_path)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| {
+            toml::from_str::<Sidecar>(&contents).map_err(|err| err.message().to_owned())
+        })
+        .map_err(Corruption::InvalidSidecar)?;
+    match sidecar.output {
+        Some(output) if !output.is_dir() => Err(Corruption::MissingOutput(output)),
+        _ => Ok(()),
+    }
+}
+
+/// The on-disk store of every torrent the daemon knows about.
+pub struct StateStore {
+    root: PathBuf,
+}
+
+impl StateStore {
+    /// Open the state store in `~/.flud`, creating its folders if needed.
+    pub fn open() -> Result<Self, StateError> {
+        let root = dirs::home_dir()
+            .ok_or(StateError::NoHomeDir)?
+            .join(STATE_DIR_NAME);
+        Self::at(root)
+    }
+
+    pub fn at(root: PathBuf) -> Result<Self, StateError> {
+        for status in TorrentStatus::ALL {
+            std::fs::create_dir_all(root.join(status.folder_name()))?;
+        }
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where entries that failed `scan` are moved.
+    pub fn errored_dir(&self) -> PathBuf {
+        self.root.join(ERRORED_DIR_NAME)
+    }
+
+    /// Where archived torrents are kept, out of the way of the daemon.
+    pub fn archive_dir(&self) -> PathBuf {
+        self.root.join(ARCHIVE_DIR_NAME)
+    }
+
+    /// Move the torrent, its sidecar and log to `archive`, where the daemon
+    /// no longer loads it. Its data is left alone.
+    pub fn archive(&self, info_hash: &str) -> Result<(), StateError> {
+        let (_, torrent_path) = self.find(info_hash)?;
+        let archive = self.archive_dir();
+        std::fs::create_dir_all(&archive)?;
+
+        let destination = archive.join(torrent_path.file_name().unwrap());
+        for extension in COMPANION_EXTENSIONS {
+            let path = torrent_path.with_extension(extension);
+            if path.exists() {
+                std::fs::rename(&path, destination.with_extension(extension))?;
+            }
+        }
+        std::fs::rename(torrent_path, destination)?;
+        Ok(())
+    }
+
+    /// Check every torrent in the store: its .torrent file parses and is
+    /// named after its info hash, its sidecar is valid and its download
+    /// directory exists. Those that fail are moved to `errored` along with
+    /// a `.reason` file, so one bad entry doesn't keep the daemon from
+    /// starting.
+    pub fn scan(&self) -> Result<ScanReport, StateError> {
+        let mut report = ScanReport::default();
+        for status in TorrentStatus::ALL {
+            let paths: Vec<PathBuf> = std::fs::read_dir(self.root.join(status.folder_name()))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "torrent"))
+                .collect();
+
+            for path in paths {
+                let info_hash = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                match check_entry(&path, &info_hash) {
+                    Ok(()) => report.ok += 1,
+                    Err(corruption) => {
+                        self.quarantine(&path, &corruption)?;
+                        report.quarantined.push((info_hash, corruption));
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Move the entry of `torrent_path` to `errored`, noting why.
+    fn quarantine(&self, torrent_path: &Path, corruption: &Corruption) -> Result<(), StateError> {
+        let errored = self.errored_dir();
+        std::fs::create_dir_all(&errored)?;
+
+        let destination = errored.join(torrent_path.file_name().unwrap());
+        std::fs::write(
+            destination.with_extension("reason"),
+            format!("{corruption}\n"),
+        )?;
+        for extension in COMPANION_EXTENSIONS {
+            let path = torrent_path.with_extension(extension);
+            if path.exists() {
+                std::fs::rename(&path, destination.with_extension(extension))?;
+            }
+        }
+        std::fs::rename(torrent_path, destination)?;
+        Ok(())
+    }
+
+    /// Where the DHT node is saved on shutdown and restored from at startup.
+    pub fn dht_path(&self) -> PathBuf {
+        self.root.join(DHT_STATE_FILE_NAME)
+    }
+
+    /// Where the session's rates are recorded, see `record_rates`.
+    pub fn stats_path(&self) -> PathBuf {
+        self.root.join(STATS_FILE_NAME)
+    }
+
+    /// Append `sample` to the recorded rates.
+    pub fn record_rates(&self, sample: RateSample) -> Result<(), StateError> {
+        let mut stats = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.stats_path())?;
+        writeln!(stats, "{},{},{}", sample.at, sample.download, sample.upload)?;
+        Ok(())
+    }
+
+    /// The rates recorded at or after `since` (seconds since the unix
+    /// epoch), oldest first. Lines that don't parse, e.g. one cut short by
+    /// a crash, are skipped.
+    pub fn rate_history(&self, since: u64) -> Result<Vec<RateSample>, StateError> {
+        let stats = match std::fs::read_to_string(self.stats_path()) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            stats => stats?,
+        };
+
+        Ok(stats
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(',').map(|field| field.trim().parse().ok());
+                let sample = RateSample {
+                    at: fields.next()??,
+                    download: fields.next()??,
+                    upload: fields.next()??,
+                };
+                fields.next().is_none().then_some(sample)
+            })
+            .filter(|sample| sample.at >= since)
+            .collect())
+    }
+
+    /// Drop the recorded rates older than `RATE_HISTORY_RETENTION`.
+    pub fn prune_rate_history(&self) -> Result<(), StateError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let since = now.saturating_sub(RATE_HISTORY_RETENTION.as_secs());
+
+        let kept: String = self
+            .rate_history(since)?
+            .into_iter()
+            .map(|sample| format!("{},{},{}\n", sample.at, sample.download, sample.upload))
+            .collect();
+        std::fs::write(self.stats_path(), kept)?;
+        Ok(())
+    }
+
+    /// The all-time stats of every tracker host announced to, by host.
+    pub fn tracker_stats(&self) -> Result<BTreeMap<String, TrackerStats>, StateError> {
+        match std::fs::read_to_string(self.root.join(TRACKER_STATS_FILE_NAME)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            contents => Ok(toml::from_str(&contents?)?),
+        }
+    }
+
+    /// Apply `update` to the stats of the tracker `host`, returning them
+    /// from before and after.
+    pub fn update_tracker_stats(
+        &self,
+        host: &str,
+        update: impl FnOnce(&mut TrackerStats),
+    ) -> Result<(TrackerStats, TrackerStats), StateError> {
+        let mut stats = self.tracker_stats()?;
+        let entry = stats.entry(host.to_owned()).or_default();
+        let before = *entry;
+        update(entry);
+        let after = *entry;
+        std::fs::write(
+            self.root.join(TRACKER_STATS_FILE_NAME),
+            toml::to_string_pretty(&stats)?,
+        )?;
+        Ok((before, after))
+    }
+
+    /// Find the folder the torrent with the (hex) `info_hash` is currently in.
+    pub fn find(&self, info_hash: &str) -> Result<(TorrentStatus, PathBuf), StateError> {
+        // Anything else could name a file outside the store
+        let parsed: InfoHash = info_hash
+            .parse()
+            .map_err(|_| StateError::InvalidInfoHash(info_hash.to_owned()))?;
+        let file_name = format!("{}.torrent", parsed.to_hex());
+        TorrentStatus::ALL
+            .into_iter()
+            .map(|status| {
+                (
+                    status,
+                    self.root.join(status.folder_name()).join(&file_name),
+                )
+            })
+            .find(|(_, path)| path.exists())
+            .ok_or_else(|| StateError::NotFound(info_hash.to_owned()))
+    }
+
+    /// Every torrent in the store, grouped by status.
+    pub fn list(&self) -> Result<Vec<TorrentEntry>, StateError> {
+        let mut entries = Vec::new();
+        for status in TorrentStatus::ALL {
+            let mut paths: Vec<PathBuf> = std::fs::read_dir(self.root.join(status.folder_name()))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "torrent"))
+                .collect();
+            paths.sort();
+
+            for path in paths {
+                let Some(info_hash) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let torrent = MetaInfo::try_from(path.clone()).ok();
+                let name = torrent
+                    .as_ref()
+                    .map(|torrent| torrent.info().name().to_owned());
+                let size = torrent.map(|torrent| torrent.info().total_length() as u64);
+                let label = std::fs::read_to_string(path.with_extension("toml"))
+                    .ok()
+                    .and_then(|contents| toml::from_str::<Sidecar>(&contents).ok())
+                    .and_then(|sidecar| sidecar.label);
+
+                entries.push(TorrentEntry {
+                    info_hash: info_hash.to_owned(),
+                    name,
+                    status,
+                    label,
+                    size,
+                    operation: None,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Move the torrent and its sidecar to the folder for `status`.
+    pub fn move_to(&self, info_hash: &str, status: TorrentStatus) -> Result<(), StateError> {
+        let (current, torrent_path) = self.find(info_hash)?;
+        if current == status {
+            return Ok(());
+        }
+
+        let folder = self.root.join(status.folder_name());
+        for extension in COMPANION_EXTENSIONS {
+            let path = torrent_path.with_extension(extension);
+            if path.exists() {
+                std::fs::rename(&path, folder.join(path.file_name().unwrap()))?;
+            }
+        }
+        std::fs::rename(
+            &torrent_path,
+            folder.join(torrent_path.file_name().unwrap()),
+        )?;
+        Ok(())
+    }
+
+    /// Store the .torrent file `bytes` to be downloaded, along with its
+    /// sidecar. Returns the info hash (hex), or `None` if the torrent is
+    /// already in the store.
+    pub fn add(&self, bytes: &[u8], sidecar: &Sidecar) -> Result<Option<String>, StateError> {
+        let meta_info = MetaInfo::from_bytes(bytes).map_err(|_| StateError::InvalidTorrent)?;
+        let info_hash = meta_info.info().hash().to_hex();
+        if self.find(&info_hash).is_ok() {
+            return Ok(None);
+        }
+
+        let torrent_path = self
+            .root
+            .join(TorrentStatus::Downloading.folder_name())
+            .join(format!("{info_hash}.torrent"));
+        // The sidecar first, the torrent isn't listed until its file exists
+        std::fs::write(
+            torrent_path.with_extension("toml"),
+            toml::to_string_pretty(sidecar)?,
+        )?;
+        std::fs::write(torrent_path, bytes)?;
+        self.record_event(&info_hash, TimelineEvent::Added)?;
+        Ok(Some(info_hash))
+    }
+
+    /// Forget the torrent, its data is left alone.
+    pub fn remove(&self, info_hash: &str) -> Result<(), StateError> {
+        let (_, torrent_path) = self.find(info_hash)?;
+        for extension in COMPANION_EXTENSIONS {
+            match std::fs::remove_file(torrent_path.with_extension(extension)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        std::fs::remove_file(torrent_path)?;
+        Ok(())
+    }
+
+    /// Carry out what a torrent's share limit asks for.
+    pub fn apply_share_limit(
+        &self,
+        info_hash: &str,
+        action: ShareLimitAction,
+    ) -> Result<(), StateError> {
+        match action {
+            ShareLimitAction::Pause => self.move_to(info_hash, TorrentStatus::Paused),
+            ShareLimitAction::Stop => self.move_to(info_hash, TorrentStatus::Completed),
+            ShareLimitAction::Remove => self.remove(info_hash),
+            ShareLimitAction::RemoveWithData => {
+                let sidecar = self.load_sidecar(info_hash)?;
+                let (_, torrent_path) = self.find(info_hash)?;
+                if let (Some(output), Ok(torrent)) =
+                    (sidecar.output, MetaInfo::try_from(torrent_path))
+                {
+                    // Only the torrent's own file or directory, never the output directory itself
+                    let name = torrent.info().name();
+                    if !name.is_empty() && !name.contains(['/', '\\']) && name != ".." {
+                        let data = output.join(name);
+                        if data.is_dir() {
+                            std::fs::remove_dir_all(data)?;
+                        } else if data.exists() {
+                            std::fs::remove_file(data)?;
+                        }
+                    }
+                }
+                self.remove(info_hash)
+            }
+        }
+    }
+
+    /// Record that a torrent stalled and carry out what `action` asks for.
+    pub fn apply_stall_action(
+        &self,
+        info_hash: &str,
+        stall: &Stall,
+        action: StallAction,
+    ) -> Result<(), StateError> {
+        self.record_event(
+            info_hash,
+            TimelineEvent::Stalled {
+                missing: stall.missing as u32,
+                pieces: stall.pieces as u32,
+            },
+        )?;
+        match action {
+            StallAction::KeepTrying => Ok(()),
+            StallAction::Pause => self.move_to(info_hash, TorrentStatus::Paused),
+        }
+    }
+
+    /// Replace a torrent with the newer version in `update`, keeping its
+    /// settings and output directory. Returns the info hash (hex) of the
+    /// new version.
+    pub fn apply_update(
+        &self,
+        info_hash: &str,
+        update: &TorrentUpdate,
+    ) -> Result<String, StateError> {
+        let sidecar = Sidecar {
+            timeline: Timeline::default(),
+            ..self.load_sidecar(info_hash)?
+        };
+        let new = match self.add(&update.bytes, &sidecar)? {
+            Some(new) => new,
+            // Added by hand already, its own settings win
+            None => update.new.info().hash().to_hex(),
+        };
+        self.record_event(
+            &new,
+            TimelineEvent::Updated {
+                from: info_hash.to_lowercase(),
+            },
+        )?;
+        self.remove(info_hash)?;
+        Ok(new)
+    }
+
+    fn sidecar_path(&self, info_hash: &str) -> Result<PathBuf, StateError> {
+        let (_, torrent_path) = self.find(info_hash)?;
+        Ok(torrent_path.with_extension("toml"))
+    }
+
+    pub fn load_sidecar(&self, info_hash: &str) -> Result<Sidecar, StateError> {
+        let path = self.sidecar_path(info_hash)?;
+        if !path.exists() {
+            return Ok(Sidecar::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    pub fn save_sidecar(&self, info_hash: &str, sidecar: &Sidecar) -> Result<(), StateError> {
+        let path = self.sidecar_path(info_hash)?;
+        std::fs::write(path, toml::to_string_pretty(sidecar)?)?;
+        Ok(())
+    }
+
+    /// Add `event` to the torrent's timeline.
+    pub fn record_event(&self, info_hash: &str, event: TimelineEvent) -> Result<(), StateError> {
+        let mut sidecar = self.load_sidecar(info_hash)?;
+        sidecar.timeline.record(event);
+        self.save_sidecar(info_hash, &sidecar)
+    }
+
+    /// Everything the store knows about a single torrent.
+    pub fn details(&self, info_hash: &str) -> Result<TorrentDetails, StateError> {
+        let (status, torrent_path) = self.find(info_hash)?;
+        let sidecar = self.load_sidecar(info_hash)?;
+        let torrent = MetaInfo::try_from(torrent_path).ok();
+        let name = torrent
+            .as_ref()
+            .map(|torrent| torrent.info().name().to_owned());
+        let size = torrent
+            .as_ref()
+            .map(|torrent| torrent.info().total_length() as u64);
+        let allocated = torrent
+            .zip(sidecar.output.clone())
+            .and_then(|(torrent, output)| {
+                PieceWriter::new(torrent.info(), output, Durability::default())
+                    .allocated(torrent.info())
+                    .ok()
+            });
+
+        Ok(TorrentDetails {
+            entry: TorrentEntry {
+                info_hash: info_hash.to_lowercase(),
+                name,
+                status,
+                label: sidecar.label,
+                size,
+                operation: None,
+            },
+            output: sidecar.output,
+            allocated,
+            timeline: sidecar.timeline.entries().cloned().collect(),
+        })
+    }
+
+    /// Append `lines` to the torrent's log, each prefixed with the time in
+    /// seconds since the unix epoch.
+    pub fn append_log(&self, info_hash: &str, lines: &[String]) -> Result<(), StateError> {
+        let (_, torrent_path) = self.find(info_hash)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(torrent_path.with_extension("log"))?;
+        for line in lines {
+            writeln!(log, "{now} {line}")?;
+        }
+        Ok(())
+    }
+
+    /// The torrent's log, empty if nothing has been logged yet.
+    pub fn read_log(&self, info_hash: &str) -> Result<String, StateError> {
+        let (_, torrent_path) = self.find(info_hash)?;
+        match std::fs::read_to_string(torrent_path.with_extension("log")) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            log => Ok(log?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty store in its own temporary directory, removed on drop.
+    struct TempStore(StateStore);
+
+    impl TempStore {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("flud-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            Self(StateStore::at(root).unwrap())
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.root());
+        }
+    }
+
+    /// A one piece torrent, named `name`.
+    fn torrent(name: &str) -> Vec<u8> {
+        let mut bytes = b"d8:announce15:udp://tracker/04:infod6:lengthi3e4:name".to_vec();
+        bytes.extend(format!("{}:{name}", name.len()).as_bytes());
+        bytes.extend(b"12:piece lengthi16384e6:pieces20:");
+        bytes.extend([0; 20]);
+        bytes.extend(b"ee");
+        bytes
+    }
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn edits_drop_removed_trackers_and_append_added_ones() {
+        let mut edits = TrackerEdits::default();
+        edits.remove("udp://a".into());
+        edits.add("udp://c".into());
+
+        assert_eq!(
+            edits.apply(&urls(&["udp://a", "udp://b"])),
+            vec![
+                ("udp://b".to_string(), TrackerOrigin::MetaInfo),
+                ("udp://c".to_string(), TrackerOrigin::User),
+            ]
+        );
+    }
+
+    #[test]
+    fn adding_a_tracker_undoes_its_removal() {
+        let mut edits = TrackerEdits::default();
+        edits.remove("udp://a".into());
+        edits.add("udp://a".into());
+        edits.add("udp://a".into());
+
+        assert!(edits.removed.is_empty());
+        assert_eq!(edits.added, urls(&["udp://a"]));
+        // Already in the metainfo, so it keeps its place and origin
+        assert_eq!(
+            edits.apply(&urls(&["udp://a"])),
+            vec![("udp://a".to_string(), TrackerOrigin::MetaInfo)]
+        );
+    }
+
+    #[test]
+    fn replacing_a_tracker_removes_the_old_and_adds_the_new() {
+        let mut edits = TrackerEdits::default();
+        edits.add("udp://b".into());
+        edits.replace("udp://b".into(), "udp://c".into());
+        edits.replace("udp://a".into(), "udp://d".into());
+
+        assert_eq!(edits.added, urls(&["udp://c", "udp://d"]));
+        assert_eq!(edits.removed, urls(&["udp://b", "udp://a"]));
+        assert_eq!(
+            edits.apply(&urls(&["udp://a", "udp://b"])),
+            vec![
+                ("udp://c".to_string(), TrackerOrigin::User),
+                ("udp://d".to_string(), TrackerOrigin::User),
+            ]
+        );
+    }
+
+    #[test]
+    fn edits_survive_the_sidecar() {
+        let mut sidecar = Sidecar::default();
+        sidecar.trackers.add("udp://c".into());
+        sidecar.trackers.remove("udp://a".into());
+
+        let saved = toml::to_string_pretty(&sidecar).unwrap();
+        let loaded: Sidecar = toml::from_str(&saved).unwrap();
+        assert_eq!(loaded.trackers, sidecar.trackers);
+    }
+
+    #[test]
+    fn scanning_keeps_valid_entries() {
+        let store = TempStore::new("scan-valid");
+        let sidecar = Sidecar {
+            output: Some(store.0.root().to_owned()),
+            ..Sidecar::default()
+        };
+        let info_hash = store.0.add(&torrent("a"), &sidecar).unwrap().unwrap();
+
+        let report = store.0.scan().unwrap();
+        assert_eq!(report.ok, 1);
+        assert!(report.quarantined.is_empty());
+        assert!(store.0.find(&info_hash).is_ok());
+    }
+
+    #[test]
+    fn scanning_quarantines_corrupt_entries_with_a_reason() {
+        let store = TempStore::new("scan-corrupt");
+        let downloading = store
+            .0
+            .root()
+            .join(TorrentStatus::Downloading.folder_name());
+        let valid = store
+            .0
+            .add(&torrent("a"), &Sidecar::default())
+            .unwrap()
+            .unwrap();
+
+        let missing = store.0.root().join("missing");
+        let sidecar = Sidecar {
+            output: Some(missing.clone()),
+            ..Sidecar::default()
+        };
+        let no_output = store.0.add(&torrent("b"), &sidecar).unwrap().unwrap();
+
+        let bad_sidecar = store
+            .0
+            .add(&torrent("c"), &Sidecar::default())
+            .unwrap()
+            .unwrap();
+        std::fs::write(downloading.join(format!("{bad_sidecar}.toml")), "label = 1").unwrap();
+
+        let renamed = "0".repeat(40);
+        std::fs::write(downloading.join(format!("{renamed}.torrent")), torrent("d")).unwrap();
+        let garbage = "f".repeat(40);
+        std::fs::write(downloading.join(format!("{garbage}.torrent")), "garbage").unwrap();
+
+        let report = store.0.scan().unwrap();
+        assert_eq!(report.ok, 1);
+        assert!(store.0.find(&valid).is_ok());
+
+        let reason = |info_hash: &str| {
+            report
+                .quarantined
+                .iter()
+                .find(|(quarantined, _)| quarantined == info_hash)
+                .map(|(_, corruption)| corruption.clone())
+        };
+        assert_eq!(reason(&no_output), Some(Corruption::MissingOutput(missing)));
+        assert!(matches!(
+            reason(&bad_sidecar),
+            Some(Corruption::InvalidSidecar(_))
+        ));
+        assert!(matches!(
+            reason(&renamed),
+            Some(Corruption::WrongInfoHash { .. })
+        ));
+        assert_eq!(reason(&garbage), Some(Corruption::InvalidTorrent));
+        assert_eq!(report.quarantined.len(), 4);
+
+        let errored = store.0.errored_dir();
+        for info_hash in [&no_output, &bad_sidecar, &renamed, &garbage] {
+            assert!(store.0.find(info_hash).is_err());
+            assert!(errored.join(format!("{info_hash}.torrent")).exists());
+            assert!(errored.join(format!("{info_hash}.reason")).exists());
+        }
+        // The sidecar goes along with its torrent
+        assert!(errored.join(format!("{no_output}.toml")).exists());
+
+        let report = store.0.scan().unwrap();
+        assert_eq!(report.ok, 1);
+        assert!(report.quarantined.is_empty());
+    }
+}
@@ -2,12 +2,63 @@ use crossterm::event;
 use ratatui::{
     crossterm::event::*, layout::*, style::*, text::*, widgets::*, DefaultTerminal, Frame,
 };
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use strum::{EnumIter, FromRepr, IntoEnumIterator};
+use torrent::{
+    info_hash::InfoHash,
+    meta_info::MetaInfo,
+    operation::OperationProgress,
+    peer::unchoke::SlotUsage,
+    rate_limit::{Direction, RateLimits},
+    stats::TransferStats,
+    swarm::{format_duration, Stall, StallAction},
+    timeline::{Timeline, TimelineEvent},
+    tracker::scrape::ScrapeStats,
+    units::{ByteSize, HumanDuration},
+    upnp::MappingStatus,
+};
+
+use crate::{
+    config::Config,
+    daemon::{DaemonApi, HttpSourceInfo, PeerInfo, SearchResult, TrackerInfo},
+    power::PowerMode,
+    state::{Sidecar, StateStore, TorrentEntry},
+    turtle::SpeedMode,
+};
 
 pub fn run() {
     // Standalone TUI does NOT run
     let terminal = ratatui::init();
-    let _ = App::default().run(terminal);
+    let store = StateStore::open().ok();
+    let torrents = store
+        .as_ref()
+        .and_then(|store| store.list().ok())
+        .unwrap_or_default();
+    let swarms = store
+        .as_ref()
+        .map(|store| {
+            torrents
+                .iter()
+                .filter_map(|entry| {
+                    let scrape = store.load_sidecar(&entry.info_hash).ok()?.scrape?;
+                    Some((entry.info_hash.clone(), scrape))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let app = App {
+        // A broken config file is reported by the commands, the TUI still opens
+        config: Config::load().unwrap_or_default(),
+        store,
+        torrents,
+        swarms,
+        ..Default::default()
+    };
+    let _ = app.run(terminal);
     ratatui::restore();
 }
 #[derive(PartialEq, Default, EnumIter, FromRepr, Clone, Copy)]
@@ -16,6 +67,75 @@ pub enum Tab {
     Torrents,
     Settings,
     Search,
+    Log,
+}
+
+/// The seeders column, `connected (in swarm)` e.g. `27 (80)`. The swarm
+/// total comes from scraping the trackers and is left out until we have it,
+/// the connected ones are `n/a` without a daemon.
+pub fn seeders_cell(connected: Option<usize>, swarm: Option<&ScrapeStats>) -> String {
+    swarm_cell(connected, swarm.map(|stats| stats.complete))
+}
+
+/// The peers column, `connected (in swarm)` e.g. `5 (8)`.
+pub fn peers_cell(connected: Option<usize>, swarm: Option<&ScrapeStats>) -> String {
+    swarm_cell(connected, swarm.map(|stats| stats.incomplete))
+}
+
+fn swarm_cell(connected: Option<usize>, swarm: Option<usize>) -> String {
+    let connected = connected.map_or_else(|| "n/a".to_owned(), |n| n.to_string());
+    match swarm {
+        Some(swarm) => format!("{connected} ({swarm})"),
+        None => connected,
+    }
+}
+
+/// The done column, only counting verified data so it never shows 100% on
+/// pieces that may still fail their hash check.
+pub fn done_cell(stats: &TransferStats, total_length: u64) -> String {
+    format!("{}%", stats.percent_done(total_length))
+}
+
+/// The status column, e.g. `downloading`, a running recheck or move, or why
+/// the torrent can't finish.
+pub fn status_cell(
+    status: &str,
+    stall: Option<&Stall>,
+    operation: Option<&OperationProgress>,
+) -> String {
+    match (operation, stall) {
+        (Some(operation), _) => operation.to_string(),
+        (None, Some(stall)) => stall.to_string(),
+        (None, None) => status.to_owned(),
+    }
+}
+
+/// A transfer rate such as `595.6 KiB/s`, empty when idle.
+pub fn rate_cell(bytes_per_sec: u64) -> String {
+    match bytes_per_sec {
+        0 => String::new(),
+        1..1024 => format!("{bytes_per_sec} B/s"),
+        1024..1_048_576 => format!("{:.1} KiB/s", bytes_per_sec as f64 / 1024.0),
+        _ => format!("{:.1} MiB/s", bytes_per_sec as f64 / 1_048_576.0),
+    }
+}
+
+/// A rate limit setting such as `1.5MiB/s`, or `unlimited`.
+pub fn rate_setting_cell(rate: ByteSize) -> String {
+    match rate.bytes() {
+        0 => "unlimited".to_owned(),
+        _ => format!("{rate}/s"),
+    }
+}
+
+/// An amount of data such as `1.4 GiB`.
+pub fn size_cell(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        1_048_576..1_073_741_824 => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+        _ => format!("{:.1} GiB", bytes as f64 / 1_073_741_824.0),
+    }
 }
 
 pub fn num_length(n: usize) -> usize {
@@ -44,26 +164,185 @@ impl std::fmt::Display for Tab {
             Tab::Torrents => write!(f, "Torrents [1]"),
             Tab::Settings => write!(f, "Settings [2]"),
             Tab::Search => write!(f, "Search DHT [3]"),
+            Tab::Log => write!(f, "Log [4]"),
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, EnumIter, FromRepr)]
 pub enum Details {
     General,
     Trackers,
     Peers,
     HttpSources,
+    Timeline,
     Content,
 }
 
+impl Details {
+    /// The pane after this one, wrapping around.
+    fn next(self) -> Self {
+        Self::from_repr(self as usize + 1).unwrap_or(Details::General)
+    }
+
+    /// The pane before this one, wrapping around.
+    fn previous(self) -> Self {
+        match (self as usize).checked_sub(1) {
+            Some(index) => Self::from_repr(index).unwrap_or(self),
+            None => Details::Content,
+        }
+    }
+}
+
+impl std::fmt::Display for Details {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Details::General => write!(f, "General [1]"),
+            Details::Trackers => write!(f, "Trackers [2]"),
+            Details::Peers => write!(f, "Peers [3]"),
+            Details::HttpSources => write!(f, "HTTP Sources [4]"),
+            Details::Timeline => write!(f, "Timeline [5]"),
+            Details::Content => write!(f, "Content [6]"),
+        }
+    }
+}
+
+/// Which part of the Torrents tab the keys go to.
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Focus {
+    #[default]
+    Table,
+    Details,
+}
+
+/// What the peers pane is sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, FromRepr)]
+pub enum PeerSort {
+    #[default]
+    DownloadRate,
+    UploadRate,
+    Progress,
+    Client,
+}
+
+impl PeerSort {
+    /// The next sort order, wrapping around.
+    fn next(self) -> Self {
+        Self::from_repr(self as usize + 1).unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for PeerSort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerSort::DownloadRate => write!(f, "download"),
+            PeerSort::UploadRate => write!(f, "upload"),
+            PeerSort::Progress => write!(f, "progress"),
+            PeerSort::Client => write!(f, "client"),
+        }
+    }
+}
+
+/// How the peers pane is sorted and filtered, and which peer is selected.
+#[derive(Default)]
+pub struct PeerView {
+    pub sort: PeerSort,
+    pub seeds_only: bool,
+    pub encrypted_only: bool,
+    pub selected: usize,
+}
+
+impl PeerView {
+    /// The peers to show, in order.
+    pub fn apply<'a>(&self, peers: &'a [PeerInfo]) -> Vec<&'a PeerInfo> {
+        let mut visible: Vec<&PeerInfo> = peers
+            .iter()
+            .filter(|peer| !self.seeds_only || peer.is_seed())
+            .filter(|peer| !self.encrypted_only || peer.encrypted)
+            .collect();
+
+        // Fastest and most complete first, clients alphabetically
+        match self.sort {
+            PeerSort::DownloadRate => {
+                visible.sort_by_key(|peer| std::cmp::Reverse(peer.download_rate))
+            }
+            PeerSort::UploadRate => visible.sort_by_key(|peer| std::cmp::Reverse(peer.upload_rate)),
+            PeerSort::Progress => visible.sort_by(|a, b| b.progress.total_cmp(&a.progress)),
+            PeerSort::Client => visible.sort_by_key(|peer| peer.client.to_lowercase()),
+        }
+        visible
+    }
+}
+
+/// A switch in the Settings tab.
+#[derive(Debug, Clone, Copy, PartialEq, EnumIter, FromRepr)]
+pub enum Setting {
+    Dht,
+    Pex,
+    Lsd,
+    HttpProxy,
+    DownloadLimit,
+    UploadLimit,
+}
+
+impl Setting {
+    fn label(self) -> &'static str {
+        match self {
+            Setting::Dht => "DHT",
+            Setting::Pex => "Peer exchange (PEX)",
+            Setting::Lsd => "Local peer discovery (LSD)",
+            Setting::HttpProxy => "HTTP proxy for trackers",
+            Setting::DownloadLimit => "Download limit",
+            Setting::UploadLimit => "Upload limit",
+        }
+    }
+
+    /// Whether the switch is on, `None` for settings that aren't switches.
+    fn enabled(self, config: &Config) -> Option<bool> {
+        match self {
+            Setting::Dht => Some(config.dht.enabled),
+            Setting::Pex => Some(config.pex.enabled),
+            Setting::Lsd => Some(config.lsd.enabled),
+            Setting::HttpProxy => Some(config.http_proxy.enabled),
+            Setting::DownloadLimit | Setting::UploadLimit => None,
+        }
+    }
+
+    fn toggle(self, config: &mut Config) {
+        let enabled = match self {
+            Setting::Dht => &mut config.dht.enabled,
+            Setting::Pex => &mut config.pex.enabled,
+            Setting::Lsd => &mut config.lsd.enabled,
+            Setting::HttpProxy => &mut config.http_proxy.enabled,
+            Setting::DownloadLimit | Setting::UploadLimit => return,
+        };
+        *enabled = !*enabled;
+    }
+
+    /// The rate limit, `None` for settings that aren't one.
+    fn rate_mut(self, config: &mut Config) -> Option<&mut ByteSize> {
+        match self {
+            Setting::DownloadLimit => Some(&mut config.rate_limits.download),
+            Setting::UploadLimit => Some(&mut config.rate_limits.upload),
+            _ => None,
+        }
+    }
+
+    fn rate(self, config: &Config) -> Option<ByteSize> {
+        match self {
+            Setting::DownloadLimit => Some(config.rate_limits.download),
+            Setting::UploadLimit => Some(config.rate_limits.upload),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct SearchInput {
     value: String,
     cursor_index: usize,
 }
 
-const ITEM_HEIGHT: usize = 2;
-
 #[derive(Default)]
 struct App {
     /// This is true when the user is typing within the search bar
@@ -72,6 +351,31 @@ struct App {
 
     selected_tab: Tab,
     item_index: usize,
+
+    /// The pane below the torrent list, if open.
+    details: Option<Details>,
+    focus: Focus,
+    peers: PeerView,
+
+    config: Config,
+    /// The selected row of the Settings tab.
+    setting_index: usize,
+    /// What is being typed for the selected setting, e.g. a rate limit.
+    setting_input: Option<String>,
+    /// The rate limit being typed for the selected torrent.
+    limit_input: Option<(Direction, String)>,
+
+    /// What the TUI shows of the torrents while the daemon isn't running.
+    store: Option<StateStore>,
+    /// Every torrent in the store, as listed when the TUI opened.
+    torrents: Vec<TorrentEntry>,
+    /// What `flud scrape` last found of each torrent's swarm, by info hash.
+    swarms: HashMap<String, ScrapeStats>,
+
+    // TODO: connect to the daemon
+    daemon: Option<Box<dyn DaemonApi>>,
+    /// The outcome of the last action, shown next to the keybinds.
+    status: Option<String>,
 }
 
 impl App {
@@ -80,11 +384,437 @@ impl App {
     }
 
     pub fn move_up(&mut self) {
-        // self.selected_tab = self.selected_tab.next();
+        if self.selected_tab == Tab::Settings {
+            self.setting_index = self.setting_index.saturating_sub(1);
+            return;
+        }
+        if self.focus == Focus::Details {
+            if self.details == Some(Details::Peers) {
+                self.peers.selected = self.peers.selected.saturating_sub(1);
+            }
+            return;
+        }
+        self.item_index = self.item_index.saturating_sub(1);
     }
 
     pub fn move_down(&mut self) {
-        // self.selected_tab = self.selected_tab.next();
+        if self.selected_tab == Tab::Settings {
+            let count = Setting::iter().count();
+            self.setting_index = (self.setting_index + 1).min(count - 1);
+            return;
+        }
+        if self.focus == Focus::Details {
+            if self.details == Some(Details::Peers) {
+                let count = self.peers.apply(&self.selected_torrent_peers()).len();
+                self.peers.selected = (self.peers.selected + 1).min(count.saturating_sub(1));
+            }
+            return;
+        }
+        self.item_index = (self.item_index + 1).min(self.torrents.len().saturating_sub(1));
+    }
+
+    /// Open the details of the selected torrent, starting with its peers
+    /// and taking the keys, or close them.
+    fn toggle_details(&mut self) {
+        (self.details, self.focus) = match self.details {
+            Some(_) => (None, Focus::Table),
+            None => (Some(Details::Peers), Focus::Details),
+        };
+    }
+
+    /// Give the keys to the details pane, or show its next pane if it has
+    /// them already.
+    fn focus_next_pane(&mut self) {
+        match self.focus {
+            Focus::Table => self.focus = Focus::Details,
+            Focus::Details => self.details = self.details.map(Details::next),
+        }
+    }
+
+    /// Show the details pane with number `key`, e.g. `'3'` for the peers.
+    fn select_pane(&mut self, key: char) {
+        let pane = key
+            .to_digit(10)
+            .and_then(|n| (n as usize).checked_sub(1))
+            .and_then(Details::from_repr);
+        if pane.is_some() {
+            self.details = pane;
+        }
+    }
+
+    /// The border of the details pane, highlighted while it has the keys.
+    fn details_block<'a>(&self, title: impl Into<Line<'a>>) -> Block<'a> {
+        let block = Block::bordered().title(title).dark_gray();
+        match self.focus {
+            Focus::Details => block.border_style(Style::default().yellow()),
+            Focus::Table => block,
+        }
+    }
+
+    fn selected_torrent(&self) -> Option<&TorrentEntry> {
+        self.torrents.get(self.item_index)
+    }
+
+    fn selected_info_hash(&self) -> Option<InfoHash> {
+        self.selected_torrent()?.info_hash.parse().ok()
+    }
+
+    /// The selected torrent's .torrent file, as kept in the store.
+    fn selected_meta_info(&self) -> Option<MetaInfo> {
+        let store = self.store.as_ref()?;
+        let (_, path) = store.find(&self.selected_torrent()?.info_hash).ok()?;
+        MetaInfo::try_from(path).ok()
+    }
+
+    /// What the store keeps of the selected torrent beyond its .torrent file.
+    fn selected_sidecar(&self) -> Option<Sidecar> {
+        let store = self.store.as_ref()?;
+        store.load_sidecar(&self.selected_torrent()?.info_hash).ok()
+    }
+
+    /// The daemon's peers of the selected torrent, none without a daemon.
+    fn selected_torrent_peers(&self) -> Vec<PeerInfo> {
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return Vec::new();
+        };
+        daemon.peers(&info_hash).unwrap_or_default()
+    }
+
+    /// How the daemon shares the selected torrent's upload slots.
+    fn selected_torrent_upload_slots(&self) -> Option<SlotUsage> {
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return None;
+        };
+        daemon.upload_slots(&info_hash).ok()
+    }
+
+    /// The daemon's trackers of the selected torrent, none without a daemon.
+    fn selected_torrent_trackers(&self) -> Vec<TrackerInfo> {
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return Vec::new();
+        };
+        daemon.trackers(&info_hash).unwrap_or_default()
+    }
+
+    /// The daemon's web seeds and http seeds of the selected torrent, or
+    /// those its .torrent file lists, untried, without a daemon.
+    fn selected_torrent_http_sources(&self) -> Vec<HttpSourceInfo> {
+        if let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) {
+            return daemon.http_sources(&info_hash).unwrap_or_default();
+        }
+        let Some(torrent) = self.selected_meta_info() else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        torrent
+            .http_sources()
+            .iter()
+            .map(|source| HttpSourceInfo::new(source, 0, now))
+            .collect()
+    }
+
+    /// The events saved for the selected torrent, the daemon records them.
+    fn selected_torrent_timeline(&self) -> Timeline {
+        self.selected_sidecar()
+            .map(|sidecar| sidecar.timeline)
+            .unwrap_or_default()
+    }
+
+    /// Only the daemon knows which pieces the peers have.
+    fn selected_torrent_stall(&self) -> Option<Stall> {
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return None;
+        };
+        daemon.stall(&info_hash).ok().flatten()
+    }
+
+    /// The daemon's recheck, move or initial check of the selected torrent.
+    fn selected_torrent_operation(&self) -> Option<OperationProgress> {
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return None;
+        };
+        daemon.operation(&info_hash).ok().flatten()
+    }
+
+    /// The caps saved for the selected torrent, which the daemon applies.
+    fn selected_torrent_rate_limits(&self) -> RateLimits {
+        self.selected_sidecar()
+            .map(|sidecar| sidecar.rate_limits)
+            .unwrap_or_default()
+    }
+
+    fn selected_peer(&self) -> Option<SocketAddr> {
+        let peers = self.selected_torrent_peers();
+        let visible = self.peers.apply(&peers);
+        visible.get(self.peers.selected).map(|peer| peer.addr)
+    }
+
+    /// What the daemon's DHT crawler found matching the search bar.
+    fn search_results(&self) -> Vec<SearchResult> {
+        let Some(daemon) = &self.daemon else {
+            return Vec::new();
+        };
+        daemon.search(&self.search.value).unwrap_or_default()
+    }
+
+    /// Flip the selected setting, or start typing its value, save it and
+    /// apply it to the daemon.
+    fn toggle_selected_setting(&mut self) {
+        let Some(setting) = Setting::from_repr(self.setting_index) else {
+            return;
+        };
+        if let Some(rate) = setting.rate(&self.config) {
+            let current = match rate.bytes() {
+                0 => String::new(),
+                _ => rate.to_string(),
+            };
+            self.setting_input = Some(current);
+            return;
+        }
+
+        setting.toggle(&mut self.config);
+        let state = if setting.enabled(&self.config) == Some(true) {
+            "enabled"
+        } else {
+            "disabled"
+        };
+
+        if let Err(err) = self.config.save() {
+            self.status = Some(format!("could not save the config: {err}"));
+            return;
+        }
+        // The proxy is picked once, when the process starts
+        if setting == Setting::HttpProxy {
+            self.status = Some(match &self.config.http_proxy.url {
+                Some(url) => format!("{} {state} ({url}), applies on restart", setting.label()),
+                None => format!("{} {state}, set its url in the config", setting.label()),
+            });
+            return;
+        }
+        // Without a daemon the change applies whenever it starts
+        let applied = self
+            .daemon
+            .as_ref()
+            .map(|daemon| daemon.set_discovery(self.config.discovery()));
+        self.status = Some(match applied {
+            Some(Err(err)) => format!("{} {state}, {err}", setting.label()),
+            _ => format!("{} {state}", setting.label()),
+        });
+    }
+
+    /// Announce the selected torrent now, or once the trackers'
+    /// `min interval` is over.
+    fn reannounce_selected_torrent(&mut self) {
+        let Some(info_hash) = self.selected_info_hash() else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        self.status = Some(match daemon.reannounce(&info_hash) {
+            Ok(()) => "re-announcing".to_owned(),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Keep waiting for the selected torrent's missing pieces, or pause it.
+    fn set_selected_stall_action(&mut self, action: StallAction) {
+        if self.selected_torrent_stall().is_none() {
+            return;
+        }
+        let (Some(daemon), Some(info_hash)) = (&self.daemon, self.selected_info_hash()) else {
+            return;
+        };
+        self.status = Some(match daemon.set_stall_action(&info_hash, action) {
+            Ok(()) if action == StallAction::Pause => "paused".to_owned(),
+            Ok(()) => "waiting for the missing pieces".to_owned(),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Hash every piece of the selected torrent again.
+    fn recheck_selected_torrent(&mut self) {
+        let Some(info_hash) = self.selected_info_hash() else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        self.status = Some(match daemon.recheck(&info_hash) {
+            Ok(()) => "rechecking".to_owned(),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Stop the selected torrent's recheck, move or initial check.
+    fn cancel_selected_operation(&mut self) {
+        let (Some(operation), Some(info_hash)) =
+            (self.selected_torrent_operation(), self.selected_info_hash())
+        else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        self.status = Some(match daemon.cancel_operation(&info_hash) {
+            Ok(()) => format!("{} cancelled", operation.kind),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Set the rate limit being typed, save it and apply it to the daemon.
+    fn apply_setting_input(&mut self) {
+        let Some(input) = self.setting_input.take() else {
+            return;
+        };
+        let Some(setting) = Setting::from_repr(self.setting_index) else {
+            return;
+        };
+        // Nothing typed is no limit
+        let rate = match input.trim() {
+            "" => Ok(ByteSize(0)),
+            input => input.parse::<ByteSize>(),
+        };
+        let rate = match rate {
+            Ok(rate) => rate,
+            Err(err) => {
+                self.status = Some(format!("{}: {err}", setting.label()));
+                return;
+            }
+        };
+        if let Some(value) = setting.rate_mut(&mut self.config) {
+            *value = rate;
+        }
+
+        if let Err(err) = self.config.save() {
+            self.status = Some(format!("could not save the config: {err}"));
+            return;
+        }
+        let applied = self
+            .daemon
+            .as_ref()
+            .map(|daemon| daemon.set_rate_limits(self.config.rate_limits.limits()));
+        let rate = rate_setting_cell(rate);
+        self.status = Some(match applied {
+            Some(Err(err)) => format!("{} {rate}, {err}", setting.label()),
+            _ => format!("{} {rate}", setting.label()),
+        });
+    }
+
+    /// Switch between the regular global limits and turtle mode's.
+    fn toggle_turtle_mode(&mut self) {
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        let toggled = daemon
+            .speed_mode()
+            .map(SpeedMode::toggled)
+            .and_then(|mode| daemon.set_speed_mode(mode).map(|_| mode));
+        self.status = Some(match toggled {
+            Ok(mode) => mode.to_string(),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Switch low-power mode on or off.
+    fn toggle_low_power(&mut self) {
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        let toggled = daemon
+            .power_mode()
+            .map(PowerMode::toggled)
+            .and_then(|mode| daemon.set_power_mode(mode).map(|_| mode));
+        self.status = Some(match toggled {
+            Ok(mode) => mode.to_string(),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Start typing a rate limit of the selected torrent.
+    fn edit_selected_torrent_limit(&mut self, direction: Direction) {
+        let limits = self.selected_torrent_rate_limits();
+        let current = match direction {
+            Direction::Download => limits.download,
+            Direction::Upload => limits.upload,
+        };
+        let current = current.map(|rate| ByteSize(rate).to_string());
+        self.limit_input = Some((direction, current.unwrap_or_default()));
+    }
+
+    /// Set the rate limit being typed for the selected torrent.
+    fn apply_limit_input(&mut self) {
+        let Some((direction, input)) = self.limit_input.take() else {
+            return;
+        };
+        let name = match direction {
+            Direction::Download => "Download limit",
+            Direction::Upload => "Upload limit",
+        };
+        // Nothing typed is no limit
+        let rate = match input.trim() {
+            "" => Ok(ByteSize(0)),
+            input => input.parse::<ByteSize>(),
+        };
+        let rate = match rate {
+            Ok(rate) => rate,
+            Err(err) => {
+                self.status = Some(format!("{name}: {err}"));
+                return;
+            }
+        };
+        let Some(info_hash) = self.selected_info_hash() else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+
+        let mut limits = self.selected_torrent_rate_limits();
+        let cap = (rate.bytes() > 0).then_some(rate.bytes());
+        match direction {
+            Direction::Download => limits.download = cap,
+            Direction::Upload => limits.upload = cap,
+        }
+        self.status = Some(match daemon.set_torrent_rate_limits(&info_hash, limits) {
+            Ok(()) => format!("{name} {}", rate_setting_cell(rate)),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    fn disconnect_selected_peer(&mut self) {
+        let (Some(addr), Some(info_hash)) = (self.selected_peer(), self.selected_info_hash())
+        else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        self.status = Some(match daemon.disconnect_peer(&info_hash, addr) {
+            Ok(()) => format!("disconnected {addr}"),
+            Err(err) => err.to_string(),
+        });
+    }
+
+    fn ban_selected_peer(&mut self) {
+        let Some(addr) = self.selected_peer() else {
+            return;
+        };
+        let Some(daemon) = &self.daemon else {
+            self.status = Some("the daemon is not running".to_owned());
+            return;
+        };
+        self.status = Some(match daemon.ban_peer(addr.ip()) {
+            Ok(()) => format!("banned {}", addr.ip()),
+            Err(err) => err.to_string(),
+        });
     }
 
     pub fn previous_tab(&mut self) {
@@ -104,9 +834,6 @@ impl App {
         new_cursor_pos.clamp(0, self.search.value.chars().count())
     }
 
-    fn reset_cursor(&mut self) {
-        self.search.cursor_index = 0;
-    }
     /// Returns the byte index based on the character position.
     ///
     /// Since each character in a string can be contain multiple bytes, it's necessary to calculate
@@ -206,6 +933,21 @@ impl App {
             .style(Style::default().dark_gray())
             .highlight_style(Style::default().yellow().underlined());
         frame.render_widget(tabs, area);
+
+        // Whether peers outside the LAN can reach us, on the right
+        let Some(status) = self
+            .daemon
+            .as_ref()
+            .and_then(|daemon| daemon.port_mapping().ok())
+        else {
+            return;
+        };
+        let port = match &status {
+            MappingStatus::Mapped { .. } => Span::from(status.to_string()).green(),
+            MappingStatus::Failed(_) => Span::from(format!("port mapping {status}")).red(),
+            _ => Span::from(format!("port mapping {status}")).dark_gray(),
+        };
+        frame.render_widget(Line::from(port).right_aligned(), area);
     }
 
     fn render_torrent_table_compact(&self, frame: &mut Frame, area: Rect) {
@@ -235,25 +977,87 @@ impl App {
         // end of the tab list so that it doesnt take up its own row
         //
 
-        let row_data = vec![
-            Cell::new("1"),
-            Cell::new("55%"),
-            Cell::new("ubuntu-24.10-live-server-amd64.iso"),
-            Cell::new("downloading"),
-            Cell::new("595.6 KiB/s").green(),
-            Cell::new("12.3 KiB/s").red(),
-            Cell::new("27 (80)").green(),
-            Cell::new("5 (8)").red(),
-            Cell::new("0.6"),
-        ];
-
-        let rows = vec![Row::new(row_data)];
+        let mut status_width = 11;
+        let rows: Vec<Row> = self
+            .torrents
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let daemon = self
+                    .daemon
+                    .as_ref()
+                    .zip(entry.info_hash.parse::<InfoHash>().ok());
+                let operation = daemon
+                    .as_ref()
+                    .and_then(|(daemon, info_hash)| daemon.operation(info_hash).ok().flatten());
+                let stall = daemon
+                    .as_ref()
+                    .and_then(|(daemon, info_hash)| daemon.stall(info_hash).ok().flatten());
+                let peers = daemon
+                    .as_ref()
+                    .and_then(|(daemon, info_hash)| daemon.peers(info_hash).ok());
+                let seeds = peers
+                    .as_ref()
+                    .map(|peers| peers.iter().filter(|peer| peer.is_seed()).count());
+                let leechers = peers
+                    .as_ref()
+                    .zip(seeds)
+                    .map(|(peers, seeds)| peers.len() - seeds);
+                let swarm = self.swarms.get(&entry.info_hash);
+                // Nothing is transferred while the daemon isn't running
+                let stats = daemon
+                    .as_ref()
+                    .and_then(|(daemon, info_hash)| daemon.transfer_stats(info_hash).ok());
+                let done = stats
+                    .as_ref()
+                    .zip(entry.size)
+                    .map_or_else(|| "n/a".to_owned(), |(stats, size)| done_cell(stats, size));
+                let ratio = stats
+                    .as_ref()
+                    .map_or_else(|| "n/a".to_owned(), |stats| format!("{:.1}", stats.ratio()));
+                let (download, upload) = match &peers {
+                    Some(peers) => (
+                        rate_cell(peers.iter().map(|peer| peer.download_rate).sum()),
+                        rate_cell(peers.iter().map(|peer| peer.upload_rate).sum()),
+                    ),
+                    None => ("n/a".to_owned(), "n/a".to_owned()),
+                };
+
+                let status = status_cell(
+                    entry.status.folder_name(),
+                    stall.as_ref(),
+                    operation.as_ref(),
+                );
+                status_width = status_width.max(status.len());
+                let status = match (operation, stall) {
+                    (Some(_), _) => Cell::new(status).cyan(),
+                    (None, Some(_)) => Cell::new(status).yellow(),
+                    (None, None) => Cell::new(status),
+                };
+
+                let row = Row::new([
+                    Cell::new((index + 1).to_string()),
+                    Cell::new(done),
+                    Cell::new(entry.name.as_deref().unwrap_or("?")),
+                    status,
+                    Cell::new(download).green(),
+                    Cell::new(upload).red(),
+                    Cell::new(seeders_cell(seeds, swarm)).green(),
+                    Cell::new(peers_cell(leechers, swarm)).red(),
+                    Cell::new(ratio),
+                ]);
+                match index == self.item_index {
+                    true => row.reversed(),
+                    false => row,
+                }
+            })
+            .collect();
 
         let widths = [
-            Constraint::Length(3),  // TODO: find the length of the number of torrents
-            Constraint::Length(4),  // ...%
-            Constraint::Min(10),    // growable
-            Constraint::Length(11), //
+            Constraint::Length(num_length(self.torrents.len()).max(1) as u16),
+            Constraint::Length(4), // ...%
+            Constraint::Min(10),   // growable
+            Constraint::Length(status_width as u16),
             Constraint::Length(11),
             Constraint::Length(11),
             Constraint::Length(12),
@@ -261,105 +1065,325 @@ impl App {
             Constraint::Length(5),
         ];
 
-        let table = Table::new(rows, widths).header(header).block(
-            Block::bordered()
-                // .border_style(Borders::BOTTOM)
-                // .border_style(Borders::TOP)
-                .style(Style::default().dark_gray()),
-        );
+        let block = Block::bordered()
+            // .border_style(Borders::BOTTOM)
+            // .border_style(Borders::TOP)
+            .style(Style::default().dark_gray());
+        // Highlighted while it has the keys and the details are open
+        let block = match (self.details, self.focus) {
+            (Some(_), Focus::Table) => block.border_style(Style::default().yellow()),
+            _ => block,
+        };
+        let table = Table::new(rows, widths).header(header).block(block);
 
         frame.render_widget(table, area);
     }
 
-    fn render_torrent_table(&self, frame: &mut Frame, area: Rect) {
-        // TODO: this likely will require some custom widget to render a custom table row.
-        //   #   | name            | status      | down         | up         | done | seeders | peers | ratio
-        // ubuntu.iso
-        // 10001 | downloading | 595.6 KiB/s  | 12.3 KiB/s | 55%  | 27 (80) | 5 (8) | 0.6
+    fn render_general(&self, frame: &mut Frame, area: Rect) {
+        let details = self
+            .store
+            .as_ref()
+            .zip(self.selected_torrent())
+            .and_then(|(store, entry)| store.details(&entry.info_hash).ok());
+        let n_a = || "n/a".to_owned();
+        // How much is done and the ratio are only known to the daemon
+        let fields = match details {
+            Some(details) => vec![
+                ("name", details.entry.name.unwrap_or_else(n_a)),
+                ("info hash", details.entry.info_hash),
+                (
+                    "size",
+                    details.entry.size.map(size_cell).unwrap_or_else(n_a),
+                ),
+                ("done", n_a()),
+                ("ratio", n_a()),
+                ("label", details.entry.label.unwrap_or_default()),
+                (
+                    "output",
+                    details
+                        .output
+                        .map(|output| output.display().to_string())
+                        .unwrap_or_else(n_a),
+                ),
+            ],
+            None => Vec::new(),
+        };
+        let rows: Vec<Row> = fields
+            .into_iter()
+            .map(|(field, value)| Row::new([Cell::new(field).dark_gray(), Cell::new(value)]))
+            .collect();
+
+        let widths = [Constraint::Length(10), Constraint::Min(20)];
+        let table = Table::new(rows, widths).block(self.details_block("General"));
+        frame.render_widget(table, area);
+    }
+
+    fn render_content(&self, frame: &mut Frame, area: Rect) {
+        let header = Row::new([Cell::new("file"), Cell::new("size"), Cell::new("done")])
+            .dark_gray()
+            .bold();
+
+        // Which pieces of each file are done is only known to the daemon
+        let files: Vec<_> = self
+            .selected_meta_info()
+            .map(|torrent| {
+                let info = torrent.info();
+                info.file_paths()
+                    .into_iter()
+                    .zip(info.file_lengths())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let rows: Vec<Row> = files
+            .into_iter()
+            .map(|(path, size)| {
+                Row::new([
+                    Cell::new(path.display().to_string()),
+                    Cell::new(size_cell(size as u64)),
+                    Cell::new("n/a"),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Min(20),
+            Constraint::Length(10),
+            Constraint::Length(5),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(self.details_block("Content"));
+        frame.render_widget(table, area);
+    }
 
+    /// The panes of the details, the one shown highlighted.
+    fn render_details_tabs(&self, details: Details, frame: &mut Frame, area: Rect) {
+        let panes: Vec<Span> = Details::iter()
+            .map(|pane| Span::from(pane.to_string()))
+            .collect();
+        let highlight = match self.focus {
+            Focus::Details => Style::default().yellow().underlined(),
+            Focus::Table => Style::default().gray().underlined(),
+        };
+        let tabs = Tabs::new(panes)
+            .select(details as usize)
+            .divider(" | ")
+            .style(Style::default().dark_gray())
+            .highlight_style(highlight);
+        frame.render_widget(tabs, area);
+    }
+
+    fn render_trackers(&self, frame: &mut Frame, area: Rect) {
         let header = Row::new([
-            Cell::new("#"),
-            Cell::new("done"),
-            Cell::new("name"),
+            Cell::new("url"),
             Cell::new("status"),
-            Cell::new("download"),
-            Cell::new("upload"),
-            Cell::new("seeders"),
             Cell::new("peers"),
-            Cell::new("ratio"),
+            Cell::new("next announce"),
+            Cell::new("reannounce"),
         ])
         .dark_gray()
         .bold();
 
-        // TODO: merge downloading and seeders/active seeders
-        // and merge upload with peers,active peers
-        //
-        // TODO: do the same thing with the total network activity "595.6 KiBs of 3(10)" and
-        // move the bar at the top to at then bottom right or at the other
-        // end of the tab list so that it doesnt take up its own row
-        //
+        let rows: Vec<Row> = self
+            .selected_torrent_trackers()
+            .into_iter()
+            .map(|tracker| {
+                let status = Cell::new(tracker.status().to_owned());
+                let status = match tracker.error {
+                    Some(_) => status.red(),
+                    None => status,
+                };
+                // Counts down to when the tracker's `min interval` allows it
+                let cooldown = match tracker.cooldown {
+                    Some(cooldown) => Cell::new(format!("in {}", HumanDuration(cooldown))).yellow(),
+                    None => Cell::new("now").green(),
+                };
+                Row::new([
+                    Cell::new(tracker.url.clone()),
+                    status,
+                    Cell::new(tracker.peers.to_string()),
+                    Cell::new(format!("in {}", HumanDuration(tracker.next_announce))),
+                    cooldown,
+                ])
+            })
+            .collect();
 
-        // let row_data = vec![
-        //     Cell::new("ubuntu-24.10-live-server-amd64.iso"),
-        //     Cell::new("1"),
-        //     Cell::new("55%"),
-        //     Cell::new("downloading"),
-        //     Cell::new("595.6 KiB/s").green(),
-        //     Cell::new("12.3 KiB/s").red(),
-        //     Cell::new("27 (80)").green(),
-        //     Cell::new("5 (8)").red(),
-        //     Cell::new("0.6"),
-        // ];
-
-        let rows = vec![
-            // Row::new(row_data).height(2).on_gray(),
-            //
-            Row::new(Text::raw("ubuntu-24.10-live-server-amd64.iso")),
-            Row::new(vec![
-                Cell::new("1"),
-                Cell::new("55%"),
-                Cell::new("downloading"),
-                Cell::new("595.6 KiB/s").green(),
-                Cell::new("12.3 KiB/s").red(),
-                Cell::new("27 (80)").green(),
-                Cell::new("5 (8)").red(),
-                Cell::new("0.6"),
-                // Text::raw("asd asd asd asd asd"),
-            ]),
-            // Row::new(vec![
-            //     Text::raw("Cell3-Line1Cell3-Line2Cell3-Line3"),
-            //     Text::raw("Cell4-Line1Cell4-Line2Cell4-Line3"),
-            // ])
-            // .height(3),
+        let widths = [
+            Constraint::Min(20),
+            Constraint::Length(20),
+            Constraint::Length(6),
+            Constraint::Length(14),
+            Constraint::Length(11),
         ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(self.details_block("Trackers"));
+        frame.render_widget(table, area);
+    }
+
+    fn render_http_sources(&self, frame: &mut Frame, area: Rect) {
+        let header = Row::new([
+            Cell::new("url"),
+            Cell::new("type"),
+            Cell::new("downloaded"),
+            Cell::new("download"),
+            Cell::new("status"),
+        ])
+        .dark_gray()
+        .bold();
+
+        let rows: Vec<Row> = self
+            .selected_torrent_http_sources()
+            .into_iter()
+            .map(|source| {
+                Row::new([
+                    Cell::new(source.url.clone()),
+                    Cell::new(source.protocol.name()),
+                    Cell::new(size_cell(source.downloaded)),
+                    Cell::new(rate_cell(source.download_rate)).green(),
+                    Cell::new(source.status()),
+                ])
+            })
+            .collect();
 
         let widths = [
-            Constraint::Percentage(100), // growable
+            Constraint::Min(20),
+            Constraint::Length(9),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(20),
         ];
-        // let table = Table::new(rows, widths)
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(self.details_block("HTTP Sources"));
+        frame.render_widget(table, area);
+    }
 
-        let table = Table::default()
-            .rows(rows)
-            // .header(header)
-            .widths(widths)
-            .column_spacing(2)
-            .highlight_symbol(">");
+    fn render_timeline(&self, frame: &mut Frame, area: Rect) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let timeline = self.selected_torrent_timeline();
+        let rows: Vec<Row> = timeline
+            .entries()
+            .rev()
+            .map(|entry| {
+                let ago = format_duration(Duration::from_secs(now.saturating_sub(entry.at)));
+                let row = Row::new([
+                    Cell::new(format!("{ago} ago")),
+                    Cell::new(entry.event.to_string()),
+                ]);
+                match entry.event {
+                    TimelineEvent::TrackerError { .. } => row.red(),
+                    TimelineEvent::Stalled { .. } => row.yellow(),
+                    _ => row,
+                }
+            })
+            .collect();
 
+        let widths = [Constraint::Length(9), Constraint::Min(20)];
+        let table = Table::new(rows, widths).block(self.details_block("Timeline, newest first"));
+        frame.render_widget(table, area);
+    }
+
+    fn render_peers(&self, frame: &mut Frame, area: Rect) {
+        let header = Row::new([
+            Cell::new("address"),
+            Cell::new("client"),
+            Cell::new("done"),
+            Cell::new("download"),
+            Cell::new("upload"),
+            Cell::new("flags"),
+        ])
+        .dark_gray()
+        .bold();
+
+        let peers = self.selected_torrent_peers();
+        let rows: Vec<Row> = self
+            .peers
+            .apply(&peers)
+            .into_iter()
+            .enumerate()
+            .map(|(index, peer)| {
+                let mut flags = String::new();
+                if peer.is_seed() {
+                    flags.push('S');
+                }
+                if peer.encrypted {
+                    flags.push('E');
+                }
+                if peer.unchoked {
+                    flags.push('U');
+                }
+
+                let row = Row::new([
+                    Cell::new(peer.addr.to_string()),
+                    Cell::new(peer.client.clone()),
+                    Cell::new(format!("{}%", (peer.progress * 100.0).floor())),
+                    Cell::new(rate_cell(peer.download_rate)).green(),
+                    Cell::new(rate_cell(peer.upload_rate)).red(),
+                    Cell::new(flags),
+                ]);
+                if index == self.peers.selected {
+                    row.reversed()
+                } else {
+                    row
+                }
+            })
+            .collect();
+
+        let mut title = format!("Peers, by {}", self.peers.sort);
+        if self.peers.seeds_only {
+            title.push_str(", seeds only");
+        }
+        if self.peers.encrypted_only {
+            title.push_str(", encrypted only");
+        }
+        match self.selected_torrent_upload_slots() {
+            Some(slots) => title.push_str(&format!(", upload slots {slots}")),
+            None => title.push_str(", upload slots unknown"),
+        }
+
+        let widths = [
+            Constraint::Length(22),
+            Constraint::Min(10),
+            Constraint::Length(5),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(5),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(self.details_block(title));
         frame.render_widget(table, area);
     }
 
     fn render_settings(&self, frame: &mut Frame, area: Rect) {
-        let msgs: Vec<String> = vec![];
-        let messages: Vec<ListItem> = msgs
-            .iter()
+        let items: Vec<ListItem> = Setting::iter()
             .enumerate()
-            .map(|(i, m)| {
-                let content = Line::from(Span::raw(format!("{i}: {m}")));
-                ListItem::new(content)
+            .map(|(index, setting)| {
+                let value = match (setting.enabled(&self.config), &self.setting_input) {
+                    (Some(true), _) => Span::from("on").green(),
+                    (Some(false), _) => Span::from("off").red(),
+                    (None, Some(input)) if index == self.setting_index => {
+                        Span::from(format!("{input}_")).yellow()
+                    }
+                    (None, _) => Span::from(rate_setting_cell(
+                        setting.rate(&self.config).unwrap_or_default(),
+                    )),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{:<28}", setting.label())),
+                    value,
+                ]))
             })
             .collect();
-        let messages = List::new(messages).block(Block::bordered().title("Settings"));
-        frame.render_widget(messages, area);
+        let settings = List::new(items)
+            .block(Block::bordered().title("Settings"))
+            .highlight_style(Style::default().on_dark_gray());
+        let mut state = ListState::default().with_selected(Some(self.setting_index));
+        frame.render_stateful_widget(settings, area, &mut state);
     }
 
     fn render_search_input(&self, frame: &mut Frame, area: Rect) {
@@ -431,25 +1455,86 @@ impl App {
 
         self.render_search_input(frame, input_area);
 
-        let msgs: Vec<String> = vec!["Nothing Found".to_string()];
-        let messages: Vec<ListItem> = msgs
-            .iter()
-            .enumerate()
-            .map(|(i, m)| {
-                let content = Line::from(Span::raw(format!("{i}: {m}")));
-                ListItem::new(content)
-            })
-            .collect();
+        let results = self.search_results();
+        let messages: Vec<ListItem> = if results.is_empty() {
+            vec![ListItem::new(Line::from("Nothing Found"))]
+        } else {
+            results
+                .iter()
+                .enumerate()
+                .map(|(i, result)| {
+                    let content = Line::from(vec![
+                        Span::raw(format!("{i}: {}", result.name)),
+                        Span::raw(format!("  {} peers  ", result.peers)).dark_gray(),
+                        Span::raw(result.info_hash.to_hex()).dark_gray(),
+                    ]);
+                    ListItem::new(content)
+                })
+                .collect()
+        };
         let messages =
             List::new(messages).block(Block::bordered().title("Search Results [r]").dark_gray());
         frame.render_widget(messages, results_area);
     }
 
+    /// The daemon's most recent log events, as many as fit, newest at the bottom.
+    fn render_log(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Log").dark_gray();
+        let Some(daemon) = &self.daemon else {
+            let message = Paragraph::new("The daemon is not running").block(block);
+            frame.render_widget(message, area);
+            return;
+        };
+
+        let limit = area.height.saturating_sub(2) as usize;
+        let lines: Vec<ListItem> = match daemon.recent_logs(limit) {
+            Ok(events) => events
+                .iter()
+                .map(|event| {
+                    let level = Span::raw(format!("{:<5} ", event.level));
+                    let level = match event.level {
+                        log::Level::Error => level.red(),
+                        log::Level::Warn => level.yellow(),
+                        _ => level,
+                    };
+                    ListItem::new(Line::from(vec![
+                        level,
+                        Span::raw(format!("{} ", event.target)).dark_gray(),
+                        Span::raw(event.message.clone()),
+                    ]))
+                })
+                .collect(),
+            Err(err) => vec![ListItem::new(err.to_string())],
+        };
+        frame.render_widget(List::new(lines).block(block), area);
+    }
+
     fn render_body(&self, frame: &mut Frame, area: Rect) {
         match self.selected_tab {
-            Tab::Torrents => self.render_torrent_table_compact(frame, area),
+            Tab::Torrents => match self.details {
+                Some(details) => {
+                    let vertical = Layout::vertical([
+                        Constraint::Percentage(50),
+                        Constraint::Length(1),
+                        Constraint::Min(3),
+                    ]);
+                    let [table_area, tabs_area, details_area] = vertical.areas(area);
+                    self.render_torrent_table_compact(frame, table_area);
+                    self.render_details_tabs(details, frame, tabs_area);
+                    match details {
+                        Details::General => self.render_general(frame, details_area),
+                        Details::Trackers => self.render_trackers(frame, details_area),
+                        Details::Peers => self.render_peers(frame, details_area),
+                        Details::HttpSources => self.render_http_sources(frame, details_area),
+                        Details::Timeline => self.render_timeline(frame, details_area),
+                        Details::Content => self.render_content(frame, details_area),
+                    }
+                }
+                None => self.render_torrent_table_compact(frame, area),
+            },
             Tab::Settings => self.render_settings(frame, area),
             Tab::Search => self.render_search(frame, area),
+            Tab::Log => self.render_log(frame, area),
         }
     }
 
@@ -465,6 +1550,10 @@ impl App {
         // The idea is that if you don't know they you look bottom left and it
         // will inform based on state
         match &self.selected_tab {
+            Tab::Torrents if self.limit_input.is_some() => {
+                binds.push("Apply [enter]");
+                binds.push("Cancel [esc]");
+            }
             Tab::Torrents => {
                 // TODO: have internal torrent_list().len()
                 let torrent_list: Vec<()> = vec![];
@@ -479,6 +1568,40 @@ impl App {
                     }
                 }
 
+                match self.selected_torrent_operation() {
+                    Some(_) => binds.push("Cancel Check/Move [C]"),
+                    None => binds.push("Recheck [R]"),
+                }
+                if self.selected_torrent_stall().is_some() {
+                    binds.push("Keep Trying [K]");
+                    binds.push("Pause When Stalled [P]");
+                }
+                if self.details == Some(Details::Trackers) {
+                    binds.push("Reannounce [r]");
+                }
+                if self.details == Some(Details::Peers) {
+                    binds.push("Sort [s]");
+                    binds.push("Seeds Only [S]");
+                    binds.push("Encrypted Only [E]");
+                    binds.push("Disconnect [x]");
+                    binds.push("Ban [B]");
+                }
+                if self.details.is_some() {
+                    match self.focus {
+                        Focus::Details => {
+                            binds.push("Next Pane [tab]");
+                            binds.push("Pane [1-6]");
+                            binds.push("Focus Torrents [esc]");
+                        }
+                        Focus::Table => binds.push("Focus Details [tab]"),
+                    }
+                    binds.push("Close Details [i]");
+                } else {
+                    binds.push("Details [i]");
+                }
+
+                binds.push("Download Limit [D]");
+                binds.push("Upload Limit [U]");
                 binds.push("Move Up [↑] ");
                 binds.push("Move Down [↓] ");
 
@@ -499,23 +1622,33 @@ impl App {
                 } else {
                     binds.push("Search [/]");
                 }
-                let result_list: Vec<()> = vec![()];
-                if !result_list.is_empty() {
+                if !self.search_results().is_empty() {
                     // TODO: get index for selected item from dhtresults
                     binds.push("Add [a]");
                 }
             }
             Tab::Settings => {
-                binds.push("Edit [enter]");
+                let selected = Setting::from_repr(self.setting_index);
+                if self.setting_input.is_some() {
+                    binds.push("Apply [enter]");
+                    binds.push("Cancel [esc]");
+                } else if selected.is_some_and(|setting| setting.rate(&self.config).is_some()) {
+                    binds.push("Edit [enter]");
+                } else {
+                    binds.push("Toggle [enter]");
+                }
             }
+            Tab::Log => {}
         };
 
+        binds.push("Turtle Mode [t]");
+        binds.push("Low Power [b]");
         // TODO: quit button
         binds.push("Quit [q]");
 
         let separator = Span::from(" ");
 
-        binds
+        let mut spans = binds
             .into_iter()
             .map(|bind| Span::from(bind).on_blue().gray())
             .fold(Vec::new(), |mut acc, span| {
@@ -524,7 +1657,20 @@ impl App {
                 }
                 acc.push(span); // Add transformed Span
                 acc
-            })
+            });
+
+        if let Some((direction, input)) = &self.limit_input {
+            let name = match direction {
+                Direction::Download => "download limit",
+                Direction::Upload => "upload limit",
+            };
+            spans.push(separator);
+            spans.push(Span::from(format!("{name}: {input}_")));
+        } else if let Some(status) = &self.status {
+            spans.push(separator);
+            spans.push(Span::from(status.as_str()).dark_gray());
+        }
+        spans
     }
 
     fn render_keybinds(&self, frame: &mut Frame, area: Rect) {
@@ -532,6 +1678,24 @@ impl App {
         let text = Text::from(Line::from(keybind_spans)).patch_style(Style::default());
         // let help_message = Paragraph::new(text);
         frame.render_widget(text, area);
+
+        // Which global limits apply, on the right
+        let Some(daemon) = &self.daemon else {
+            return;
+        };
+        let mut modes = Vec::new();
+        if let Ok(PowerMode::LowPower) = daemon.power_mode() {
+            modes.push(Span::from(PowerMode::LowPower.to_string()).yellow());
+            modes.push(Span::from(" "));
+        }
+        let Ok(mode) = daemon.speed_mode() else {
+            return;
+        };
+        modes.push(match mode {
+            SpeedMode::Normal => Span::from(mode.to_string()).dark_gray(),
+            SpeedMode::Turtle => Span::from(mode.to_string()).yellow(),
+        });
+        frame.render_widget(Line::from(modes).right_aligned(), area);
     }
 
     fn draw(&self, frame: &mut Frame) {
@@ -554,6 +1718,33 @@ impl App {
             terminal.draw(|frame| self.draw(frame)).expect("msg");
             let key_event = event::read().expect("msg");
 
+            // Typing a setting's value, e.g. `1.5MiB`
+            if let (Some(input), Event::Key(key)) = (&mut self.setting_input, &key_event) {
+                match key.code {
+                    KeyCode::Enter => self.apply_setting_input(),
+                    KeyCode::Esc => self.setting_input = None,
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+            // Typing a torrent's rate limit
+            if let (Some((_, input)), Event::Key(key)) = (&mut self.limit_input, &key_event) {
+                match key.code {
+                    KeyCode::Enter => self.apply_limit_input(),
+                    KeyCode::Esc => self.limit_input = None,
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+
             match self.editing {
                 true => {
                     // put into search
@@ -603,14 +1794,29 @@ impl App {
                                 Tab::Search => {
                                     self.editing = true;
                                 }
+                                Tab::Log => {}
                             },
+                            KeyCode::Esc
+                                if self.selected_tab == Tab::Torrents
+                                    && self.focus == Focus::Details =>
+                            {
+                                self.focus = Focus::Table
+                            }
                             KeyCode::Esc => match self.selected_tab {
-                                Tab::Torrents => todo!(),
-                                Tab::Settings => todo!(),
+                                Tab::Torrents => {}
+                                Tab::Settings => {}
                                 Tab::Search => {
                                     self.editing = false;
                                 }
+                                Tab::Log => {}
                             },
+                            KeyCode::Char(key @ '1'..='6')
+                                if self.selected_tab == Tab::Torrents
+                                    && self.details.is_some()
+                                    && self.focus == Focus::Details =>
+                            {
+                                self.select_pane(key)
+                            }
                             KeyCode::Char('1') => {
                                 self.selected_tab = Tab::Torrents;
                             }
@@ -620,7 +1826,69 @@ impl App {
                             KeyCode::Char('3') => {
                                 self.selected_tab = Tab::Search;
                             }
+                            KeyCode::Char('4') => {
+                                self.selected_tab = Tab::Log;
+                            }
+
+                            KeyCode::Enter if self.selected_tab == Tab::Settings => {
+                                self.toggle_selected_setting()
+                            }
+                            KeyCode::Char('i') if self.selected_tab == Tab::Torrents => {
+                                self.toggle_details()
+                            }
+                            KeyCode::Tab
+                                if self.selected_tab == Tab::Torrents && self.details.is_some() =>
+                            {
+                                self.focus_next_pane()
+                            }
+                            KeyCode::BackTab
+                                if self.selected_tab == Tab::Torrents
+                                    && self.focus == Focus::Details =>
+                            {
+                                self.details = self.details.map(Details::previous)
+                            }
+                            KeyCode::Char('D') if self.selected_tab == Tab::Torrents => {
+                                self.edit_selected_torrent_limit(Direction::Download)
+                            }
+                            KeyCode::Char('U') if self.selected_tab == Tab::Torrents => {
+                                self.edit_selected_torrent_limit(Direction::Upload)
+                            }
+                            KeyCode::Char('K') if self.selected_tab == Tab::Torrents => {
+                                self.set_selected_stall_action(StallAction::KeepTrying)
+                            }
+                            KeyCode::Char('P') if self.selected_tab == Tab::Torrents => {
+                                self.set_selected_stall_action(StallAction::Pause)
+                            }
+                            KeyCode::Char('R') if self.selected_tab == Tab::Torrents => {
+                                self.recheck_selected_torrent()
+                            }
+                            KeyCode::Char('C') if self.selected_tab == Tab::Torrents => {
+                                self.cancel_selected_operation()
+                            }
+                            KeyCode::Char('r') if self.details == Some(Details::Trackers) => {
+                                self.reannounce_selected_torrent()
+                            }
+                            KeyCode::Char('s') if self.details == Some(Details::Peers) => {
+                                self.peers.sort = self.peers.sort.next();
+                                self.peers.selected = 0;
+                            }
+                            KeyCode::Char('S') if self.details == Some(Details::Peers) => {
+                                self.peers.seeds_only = !self.peers.seeds_only;
+                                self.peers.selected = 0;
+                            }
+                            KeyCode::Char('E') if self.details == Some(Details::Peers) => {
+                                self.peers.encrypted_only = !self.peers.encrypted_only;
+                                self.peers.selected = 0;
+                            }
+                            KeyCode::Char('x') if self.details == Some(Details::Peers) => {
+                                self.disconnect_selected_peer()
+                            }
+                            KeyCode::Char('B') if self.details == Some(Details::Peers) => {
+                                self.ban_selected_peer()
+                            }
 
+                            KeyCode::Char('t') => self.toggle_turtle_mode(),
+                            KeyCode::Char('b') => self.toggle_low_power(),
                             KeyCode::Char('q') => {
                                 return Ok(());
                             }
@@ -633,9 +1901,7 @@ impl App {
     }
 }
 
-// TODO: press i to toggle opening bottom half torrent info
-// otherwise you have to click enter to select a torrent
-// and it will open torrent info in a modal
+// TODO: enter on a selected torrent opens its torrent info in a modal
 
 // TODO: backspace or d on a selected torrent to get a confirm
 // popup to remove/delete the torrent
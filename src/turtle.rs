@@ -0,0 +1,260 @@
+use chrono::{Datelike, NaiveDateTime, Timelike, Weekday};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use torrent::rate_limit::{RateLimiter, RateLimits};
+
+use crate::config::RateLimitConfig;
+
+// Turtle mode swaps the global rate limits for a slower pair, by hand or on
+// a weekly schedule, e.g. limited during work hours and full speed at
+// night. Switching by hand lasts until the schedule next switches, like a
+// thermostat. The schedule is in local time and checked by the daemon, so
+// it applies whether or not the TUI is open.
+
+/// How often the schedule is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedMode {
+    /// The regular global limits.
+    #[default]
+    Normal,
+    /// The alternative limits of `[rate_limits.turtle]`.
+    Turtle,
+}
+
+impl SpeedMode {
+    pub fn toggled(self) -> Self {
+        match self {
+            SpeedMode::Normal => SpeedMode::Turtle,
+            SpeedMode::Turtle => SpeedMode::Normal,
+        }
+    }
+}
+
+impl fmt::Display for SpeedMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpeedMode::Normal => "full speed",
+            SpeedMode::Turtle => "turtle mode",
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimeOfDayError {
+    #[error("{0:?} is not a time, expected e.g. 09:00 or 17:30")]
+    Invalid(String),
+}
+
+/// A time of day in local time, `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    /// Minutes since midnight.
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u16, minute: u16) -> Option<Self> {
+        // 24:00 is the end of the day
+        ((hour < 24 && minute < 60) || (hour == 24 && minute == 0)).then_some(Self {
+            minutes: hour * 60 + minute,
+        })
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = TimeOfDayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TimeOfDayError::Invalid(s.to_owned());
+        let (hour, minute) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let hour = hour.parse().map_err(|_| invalid())?;
+        let minute = minute.parse().map_err(|_| invalid())?;
+        Self::new(hour, minute).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Weekday> for Day {
+    fn from(weekday: Weekday) -> Self {
+        match weekday {
+            Weekday::Mon => Day::Mon,
+            Weekday::Tue => Day::Tue,
+            Weekday::Wed => Day::Wed,
+            Weekday::Thu => Day::Thu,
+            Weekday::Fri => Day::Fri,
+            Weekday::Sat => Day::Sat,
+            Weekday::Sun => Day::Sun,
+        }
+    }
+}
+
+/// When turtle mode is on, e.g.
+///
+/// ```toml
+/// [[rate_limits.turtle.schedule]]
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// from = "09:00"
+/// to = "17:30"
+/// ```
+///
+/// A window ending before it starts runs past midnight, into the next day.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TurtleWindow {
+    /// The days the window starts on, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Day>,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+}
+
+impl TurtleWindow {
+    fn starts_on(&self, day: Day) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether `now` is in the window.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let minutes = (now.hour() * 60 + now.minute()) as u16;
+        let time = TimeOfDay { minutes };
+        let today = Day::from(now.weekday());
+        let yesterday = Day::from(now.weekday().pred());
+
+        if self.from <= self.to {
+            self.starts_on(today) && self.from <= time && time < self.to
+        } else {
+            (self.starts_on(today) && self.from <= time)
+                || (self.starts_on(yesterday) && time < self.to)
+        }
+    }
+}
+
+/// Picks the global rate limits for the current mode and switches modes
+/// when the schedule says so.
+#[derive(Debug, Clone)]
+pub struct SpeedScheduler {
+    normal: RateLimits,
+    turtle: RateLimits,
+    schedule: Vec<TurtleWindow>,
+    /// What the schedule asked for when last checked.
+    scheduled: SpeedMode,
+    /// Chosen by hand, until the schedule switches.
+    manual: Option<SpeedMode>,
+    /// Low-power mode's caps on top of either mode's limits.
+    low_power: Option<RateLimits>,
+}
+
+impl SpeedScheduler {
+    pub fn new(config: &RateLimitConfig, now: NaiveDateTime) -> Self {
+        let mut scheduler = Self {
+            normal: config.limits(),
+            turtle: config.turtle.limits(),
+            schedule: config.turtle.schedule.clone(),
+            scheduled: SpeedMode::Normal,
+            manual: None,
+            low_power: None,
+        };
+        scheduler.scheduled = scheduler.scheduled_mode(now);
+        scheduler
+    }
+
+    /// The mode the schedule asks for at `now`.
+    pub fn scheduled_mode(&self, now: NaiveDateTime) -> SpeedMode {
+        match self.schedule.iter().any(|window| window.contains(now)) {
+            true => SpeedMode::Turtle,
+            false => SpeedMode::Normal,
+        }
+    }
+
+    pub fn mode(&self) -> SpeedMode {
+        self.manual.unwrap_or(self.scheduled)
+    }
+
+    /// The global limits of the current mode.
+    pub fn limits(&self) -> RateLimits {
+        let limits = match self.mode() {
+            SpeedMode::Normal => self.normal,
+            SpeedMode::Turtle => self.turtle,
+        };
+        match self.low_power {
+            Some(caps) => limits.min(caps),
+            None => limits,
+        }
+    }
+
+    /// Cap the limits of both modes with `caps`, `None` to lift them.
+    pub fn set_low_power(&mut self, caps: Option<RateLimits>) {
+        self.low_power = caps;
+    }
+
+    /// Switch to `mode` by hand, until the schedule next switches.
+    pub fn set_mode(&mut self, mode: SpeedMode) {
+        self.manual = (mode != self.scheduled).then_some(mode);
+    }
+
+    /// Follow the schedule at `now`, returning the new limits if the mode
+    /// changed.
+    pub fn tick(&mut self, now: NaiveDateTime) -> Option<RateLimits> {
+        let before = self.mode();
+        let scheduled = self.scheduled_mode(now);
+        if scheduled != self.scheduled {
+            self.scheduled = scheduled;
+            self.manual = None;
+        }
+        (self.mode() != before).then(|| self.limits())
+    }
+}
+
+/// Check the schedule every `CHECK_INTERVAL` for as long as the process
+/// runs, changing `limiter`'s caps whenever the mode switches. The mode can
+/// still be switched by hand through `scheduler`.
+pub fn spawn(scheduler: Arc<Mutex<SpeedScheduler>>, limiter: RateLimiter) {
+    limiter.set_limits(scheduler.lock().unwrap().limits());
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        let mut scheduler = scheduler.lock().unwrap();
+        if let Some(limits) = scheduler.tick(chrono::Local::now().naive_local()) {
+            log::info!("switched to {}", scheduler.mode());
+            limiter.set_limits(limits);
+        }
+    });
+}
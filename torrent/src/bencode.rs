@@ -0,0 +1,334 @@
+use std::{fmt, ops::Range};
+
+// https://www.bittorrent.org/beps/bep_0003.html#bencoding
+
+// A small bencode decoder that borrows from its input instead of copying,
+// and remembers where in the input every value came from. That is what
+// serde cannot give us: the exact bytes of the info dictionary to hash, or
+// where a ut_metadata header ends and the piece data begins.
+//
+// Typed (de)serialization still goes through serde_bencode on top of this.
+
+/// Nesting deeper than this is rejected instead of overflowing the stack.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BencodeError {
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// A byte that cannot start or continue a value, at this position.
+    InvalidByte(usize),
+    /// A malformed integer or string length, at this position.
+    InvalidInteger(usize),
+    /// A dictionary key at this position is not a byte string.
+    InvalidKey(usize),
+    /// There is data after the value.
+    TrailingData(usize),
+    TooDeep,
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BencodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            BencodeError::InvalidByte(at) => write!(f, "invalid byte at {at}"),
+            BencodeError::InvalidInteger(at) => write!(f, "invalid integer at {at}"),
+            BencodeError::InvalidKey(at) => write!(f, "dictionary key at {at} is not a string"),
+            BencodeError::TrailingData(at) => write!(f, "trailing data at {at}"),
+            BencodeError::TooDeep => write!(f, "nested more than {MAX_DEPTH} levels deep"),
+        }
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+/// A decoded value, strings and keys borrow from the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Node<'a>>),
+    /// Entries in the order they appear in the input.
+    Dict(Vec<(&'a [u8], Node<'a>)>),
+}
+
+/// A value along with the exact bytes it was decoded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node<'a> {
+    pub value: Value<'a>,
+    /// Where the value is in the input.
+    pub span: Range<usize>,
+    raw: &'a [u8],
+}
+
+impl<'a> Node<'a> {
+    /// The encoded value exactly as it appears in the input.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Look up `key` if this is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&Node<'a>> {
+        match &self.value {
+            Value::Dict(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self.value {
+            Value::Int(int) => Some(int),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.value {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Node<'a>]> {
+        match &self.value {
+            Value::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&[(&'a [u8], Node<'a>)]> {
+        match &self.value {
+            Value::Dict(entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+/// Decode `bytes`, which must hold exactly one value.
+pub fn decode(bytes: &[u8]) -> Result<Node<'_>, BencodeError> {
+    let (node, end) = decode_prefix(bytes)?;
+    if end != bytes.len() {
+        return Err(BencodeError::TrailingData(end));
+    }
+    Ok(node)
+}
+
+/// Decode the value at the start of `bytes`, returning it and where it ends.
+/// Anything after it is left alone, e.g. the piece data after a ut_metadata header.
+pub fn decode_prefix(bytes: &[u8]) -> Result<(Node<'_>, usize), BencodeError> {
+    let node = Decoder { input: bytes }.value(0, 0)?;
+    let end = node.span.end;
+    Ok((node, end))
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&self, at: usize) -> Result<u8, BencodeError> {
+        self.input
+            .get(at)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEnd)
+    }
+
+    fn node(&self, value: Value<'a>, span: Range<usize>) -> Node<'a> {
+        Node {
+            value,
+            raw: &self.input[span.clone()],
+            span,
+        }
+    }
+
+    fn value(&self, start: usize, depth: usize) -> Result<Node<'a>, BencodeError> {
+        if depth > MAX_DEPTH {
+            return Err(BencodeError::TooDeep);
+        }
+
+        match self.byte(start)? {
+            b'i' => {
+                let (int, end) = self.integer(start + 1, b'e')?;
+                Ok(self.node(Value::Int(int), start..end))
+            }
+            b'0'..=b'9' => {
+                let (bytes, end) = self.bytes(start)?;
+                Ok(self.node(Value::Bytes(bytes), start..end))
+            }
+            b'l' => {
+                let mut items = Vec::new();
+                let mut position = start + 1;
+                while self.byte(position)? != b'e' {
+                    let item = self.value(position, depth + 1)?;
+                    position = item.span.end;
+                    items.push(item);
+                }
+                Ok(self.node(Value::List(items), start..position + 1))
+            }
+            b'd' => {
+                let mut entries = Vec::new();
+                let mut position = start + 1;
+                while self.byte(position)? != b'e' {
+                    if !self.byte(position)?.is_ascii_digit() {
+                        return Err(BencodeError::InvalidKey(position));
+                    }
+                    let (key, key_end) = self.bytes(position)?;
+                    let value = self.value(key_end, depth + 1)?;
+                    position = value.span.end;
+                    entries.push((key, value));
+                }
+                Ok(self.node(Value::Dict(entries), start..position + 1))
+            }
+            _ => Err(BencodeError::InvalidByte(start)),
+        }
+    }
+
+    /// Parse the integer starting at `start` up to `terminator`, returning
+    /// it and the position after the terminator.
+    fn integer(&self, start: usize, terminator: u8) -> Result<(i64, usize), BencodeError> {
+        let rest = self.input.get(start..).ok_or(BencodeError::UnexpectedEnd)?;
+        let len = rest
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or(BencodeError::UnexpectedEnd)?;
+        let digits = &rest[..len];
+
+        // No sign but a minus, no leading zeros and no negative zero, every
+        // integer has one encoding
+        let invalid = matches!(
+            digits,
+            [] | [b'-'] | [b'+', ..] | [b'-', b'0', ..] | [b'0', _, ..]
+        );
+        let int = std::str::from_utf8(digits)
+            .ok()
+            .filter(|_| !invalid)
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(BencodeError::InvalidInteger(start))?;
+
+        Ok((int, start + len + 1))
+    }
+
+    fn bytes(&self, start: usize) -> Result<(&'a [u8], usize), BencodeError> {
+        let (len, data_start) = self.integer(start, b':')?;
+        let len = usize::try_from(len).map_err(|_| BencodeError::InvalidInteger(start))?;
+        let end = data_start
+            .checked_add(len)
+            .ok_or(BencodeError::InvalidInteger(start))?;
+        let bytes = self
+            .input
+            .get(data_start..end)
+            .ok_or(BencodeError::UnexpectedEnd)?;
+        Ok((bytes, end))
+    }
+}
+
+impl Value<'_> {
+    /// Encode with dictionary keys in sorted order, as the spec requires.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(int) => {
+                out.push(b'i');
+                out.extend_from_slice(int.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(entries) => {
+                let mut sorted: Vec<_> = entries.iter().collect();
+                sorted.sort_by_key(|(key, _)| *key);
+
+                out.push(b'd');
+                for (key, value) in sorted {
+                    encode_bytes(key, out);
+                    value.value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_have_a_single_encoding() {
+        assert_eq!(decode(b"i42e").unwrap().as_int(), Some(42));
+        assert_eq!(decode(b"i-42e").unwrap().as_int(), Some(-42));
+        assert_eq!(decode(b"i0e").unwrap().as_int(), Some(0));
+        for invalid in [
+            &b"i+5e"[..],
+            b"i-0e",
+            b"i05e",
+            b"i-05e",
+            b"ie",
+            b"i-e",
+            b"i 5e",
+        ] {
+            assert_eq!(decode(invalid), Err(BencodeError::InvalidInteger(1)));
+        }
+        assert_eq!(decode(b"i5"), Err(BencodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn string_lengths_are_integers_too() {
+        assert_eq!(decode(b"3:abc").unwrap().as_bytes(), Some(&b"abc"[..]));
+        assert_eq!(decode(b"0:").unwrap().as_bytes(), Some(&b""[..]));
+        assert_eq!(decode(b"+3:abc"), Err(BencodeError::InvalidByte(0)));
+        assert_eq!(decode(b"03:abc"), Err(BencodeError::InvalidInteger(0)));
+        assert_eq!(decode(b"4:abc"), Err(BencodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn values_keep_the_bytes_they_came_from() {
+        // Out of order keys are kept as written, only encoding sorts them
+        let input = b"d1:bli1ei2ee1:ad1:xi3eee";
+        let root = decode(input).unwrap();
+        assert_eq!(root.raw(), input);
+        assert_eq!(root.get(b"a").unwrap().raw(), b"d1:xi3ee");
+        assert_eq!(root.get(b"b").unwrap().span, 4..12);
+        assert_eq!(root.value.encode(), b"d1:ad1:xi3ee1:bli1ei2eee");
+    }
+
+    #[test]
+    fn malformed_input_is_refused() {
+        assert_eq!(decode(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+        assert_eq!(decode(b"di1ei2ee"), Err(BencodeError::InvalidKey(1)));
+        assert_eq!(decode(b"x"), Err(BencodeError::InvalidByte(0)));
+        assert_eq!(decode(b"l"), Err(BencodeError::UnexpectedEnd));
+
+        let deep = [vec![b'l'; MAX_DEPTH + 2], vec![b'e'; MAX_DEPTH + 2]].concat();
+        assert_eq!(decode(&deep), Err(BencodeError::TooDeep));
+    }
+
+    #[test]
+    fn a_prefix_leaves_the_rest_alone() {
+        let (node, end) = decode_prefix(b"d1:ai0eepiece data").unwrap();
+        assert_eq!(node.get(b"a").unwrap().as_int(), Some(0));
+        assert_eq!(end, 8);
+    }
+}
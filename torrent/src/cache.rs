@@ -0,0 +1,404 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    memory::{self, MemoryBudget, Subsystem},
+    meta_info::Info,
+    storage::{BlockWrite, Storage},
+};
+
+// Peers send 16 KiB blocks in whatever order they arrive. Writing each one
+// as it comes costs a seek and a small write per block, which spinning
+// disks are terrible at. The write cache keeps blocks in memory, merged
+// with their neighbours into runs, and hands them to the storage as large
+// sequential writes: once the piece they belong to verified, once the cache
+// is over its budget or once its oldest data has waited `max_age`.
+//
+// Seeding is the other way round: hundreds of peers ask for blocks of the same
+// few pieces. The read cache keeps whole pieces that were read recently, so
+// the blocks after the first come from memory, and the uploader prefetches
+// the piece of the request it serves next.
+
+/// Memory the write cache may hold by default.
+pub const DEFAULT_WRITE_CACHE: usize = 32 * 1024 * 1024;
+/// How long data may wait in the write cache by default.
+pub const DEFAULT_WRITE_CACHE_AGE: Duration = Duration::from_secs(30);
+
+/// Memory the read cache may hold by default.
+pub const DEFAULT_READ_CACHE: usize = 64 * 1024 * 1024;
+
+/// When the write cache hands its data to the storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteCachePolicy {
+    /// Bytes held before everything is written, `0` disables the cache.
+    /// Halved under memory pressure, see `MemoryBudget::cache_limit`.
+    pub budget: usize,
+    /// The longest data waits before everything is written.
+    pub max_age: Duration,
+}
+
+impl Default for WriteCachePolicy {
+    fn default() -> Self {
+        Self {
+            budget: DEFAULT_WRITE_CACHE,
+            max_age: DEFAULT_WRITE_CACHE_AGE,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Dirty {
+    /// Runs of data not written yet by file, keyed by their offset. Runs
+    /// never overlap or touch, neighbours are merged.
+    files: BTreeMap<usize, BTreeMap<u64, Vec<u8>>>,
+    /// Since when the oldest of them waits.
+    since: Option<Instant>,
+    /// The bytes held, accounted as piece buffers.
+    memory: memory::Allocation,
+}
+
+/// A storage that buffers writes to another one in memory and writes them
+/// back in large runs.
+///
+/// Reads see the buffered data. Anything still buffered is written back
+/// when the cache is dropped.
+#[derive(Debug)]
+pub struct WriteCache {
+    inner: Box<dyn Storage>,
+    policy: WriteCachePolicy,
+    budget: MemoryBudget,
+    dirty: Mutex<Dirty>,
+}
+
+impl WriteCache {
+    pub fn new(inner: impl Storage + 'static, policy: WriteCachePolicy) -> Self {
+        Self::with_memory(inner, policy, MemoryBudget::default())
+    }
+
+    /// Account the data held to `budget`, and hold less while it is under
+    /// pressure.
+    pub fn with_memory(
+        inner: impl Storage + 'static,
+        policy: WriteCachePolicy,
+        budget: MemoryBudget,
+    ) -> Self {
+        Self {
+            inner: Box::new(inner),
+            policy,
+            dirty: Mutex::new(Dirty {
+                files: BTreeMap::new(),
+                since: None,
+                memory: budget.allocate(Subsystem::PieceBuffers, 0),
+            }),
+            budget,
+        }
+    }
+
+    /// The bytes waiting to be written.
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty.lock().unwrap().memory.bytes()
+    }
+
+    /// Write everything back if the oldest data has waited `max_age`, e.g.
+    /// from a timer while no blocks arrive.
+    pub fn write_back_expired(&self) -> io::Result<()> {
+        let mut dirty = self.dirty.lock().unwrap();
+        match dirty.since {
+            Some(since) if since.elapsed() >= self.policy.max_age => {
+                self.write_back_all(&mut dirty)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_back_file(&self, dirty: &mut Dirty, file: usize) -> io::Result<()> {
+        let Some(runs) = dirty.files.remove(&file) else {
+            return Ok(());
+        };
+        let writes: Vec<BlockWrite> = runs
+            .iter()
+            .map(|(&offset, data)| BlockWrite { file, offset, data })
+            .collect();
+        if let Err(err) = self.inner.write_blocks(&writes) {
+            // Keep all of it to try again, writing a run twice is harmless
+            dirty.files.insert(file, runs);
+            return Err(err);
+        }
+
+        let written: usize = runs.values().map(Vec::len).sum();
+        let held = dirty.memory.bytes() - written;
+        dirty.memory.resize(held);
+        if dirty.files.is_empty() {
+            dirty.since = None;
+        }
+        Ok(())
+    }
+
+    fn write_back_all(&self, dirty: &mut Dirty) -> io::Result<()> {
+        let files: Vec<usize> = dirty.files.keys().copied().collect();
+        for file in files {
+            self.write_back_file(dirty, file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for WriteCache {
+    fn read_block(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let end = offset + buf.len() as u64;
+        let cached = dirty
+            .files
+            .get(&file)
+            .and_then(|runs| runs.range(..=offset).next_back())
+            .filter(|(start, data)| **start + data.len() as u64 >= end);
+        if let Some((start, data)) = cached {
+            let from = (offset - start) as usize;
+            buf.copy_from_slice(&data[from..from + buf.len()]);
+            return Ok(());
+        }
+
+        // Partly cached, it's simpler to have the storage put it together
+        self.write_back_file(&mut dirty, file)?;
+        drop(dirty);
+        self.inner.read_block(file, offset, buf)
+    }
+
+    fn write_block(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let limit = self.budget.cache_limit(self.policy.budget);
+        if limit == 0 {
+            return self.inner.write_block(file, offset, data);
+        }
+
+        let mut dirty = self.dirty.lock().unwrap();
+        let runs = dirty.files.entry(file).or_default();
+
+        // Merge with every run this overlaps or touches
+        let end = offset + data.len() as u64;
+        let neighbours: Vec<u64> = runs
+            .range(..=end)
+            .rev()
+            .take_while(|(start, run)| **start + run.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect();
+        let start = neighbours.last().map_or(offset, |first| offset.min(*first));
+        let mut merged_end = end;
+        let mut merged = Vec::new();
+        let mut replaced = 0;
+        for neighbour in neighbours.into_iter().rev() {
+            let run = runs.remove(&neighbour).unwrap();
+            replaced += run.len();
+            merged_end = merged_end.max(neighbour + run.len() as u64);
+            merged.resize((merged_end - start) as usize, 0);
+            let at = (neighbour - start) as usize;
+            merged[at..at + run.len()].copy_from_slice(&run);
+        }
+        merged.resize((merged_end - start) as usize, 0);
+        let at = (offset - start) as usize;
+        merged[at..at + data.len()].copy_from_slice(data);
+
+        let held = dirty.memory.bytes() - replaced + merged.len();
+        dirty.files.entry(file).or_default().insert(start, merged);
+        dirty.memory.resize(held);
+        let since = *dirty.since.get_or_insert_with(Instant::now);
+
+        if held > limit || since.elapsed() >= self.policy.max_age {
+            self.write_back_all(&mut dirty)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back what `file` has buffered, then flushes it.
+    fn flush(&self, file: usize) -> io::Result<()> {
+        self.write_back(file)?;
+        self.inner.flush(file)
+    }
+
+    fn write_back(&self, file: usize) -> io::Result<()> {
+        let mut dirty = self.dirty.lock().unwrap();
+        self.write_back_file(&mut dirty, file)
+    }
+
+    fn preallocate(&self, file: usize, len: u64) -> io::Result<()> {
+        self.inner.preallocate(file, len)
+    }
+
+    fn allocated(&self, file: usize) -> io::Result<u64> {
+        self.inner.allocated(file)
+    }
+
+    fn finish(&self, info: &Info) -> io::Result<()> {
+        self.write_back_all(&mut self.dirty.lock().unwrap())?;
+        self.inner.finish(info)
+    }
+}
+
+impl Drop for WriteCache {
+    fn drop(&mut self) {
+        let mut dirty = self.dirty.lock().unwrap();
+        if let Err(err) = self.write_back_all(&mut dirty) {
+            log::warn!("unable to write back cached data: {err}");
+        }
+    }
+}
+
+/// How well a read cache does, e.g. `91.2% hits (1824 of 2000), 12.5 MiB
+/// cached`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Reads served from memory.
+    pub hits: u64,
+    /// Reads that had to go to the disk.
+    pub misses: u64,
+    /// Pieces read ahead of being asked for.
+    pub prefetched: u64,
+    /// Bytes held.
+    pub cached: usize,
+}
+
+impl CacheStats {
+    /// `0.0..=1.0`, `0.0` before the first read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% hits ({} of {}), {:.1} MiB cached",
+            self.hit_rate() * 100.0,
+            self.hits,
+            self.hits + self.misses,
+            self.cached as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Pieces {
+    data: HashMap<u32, Arc<Vec<u8>>>,
+    /// Least recently used first.
+    order: VecDeque<u32>,
+    /// The bytes held, accounted as the read cache.
+    memory: memory::Allocation,
+}
+
+/// Whole pieces kept in memory for uploading, the least recently used
+/// dropped once over budget.
+#[derive(Debug)]
+pub struct ReadCache {
+    /// Bytes held at most, `0` disables the cache. Halved under memory
+    /// pressure, see `MemoryBudget::cache_limit`.
+    budget: usize,
+    memory: MemoryBudget,
+    pieces: Mutex<Pieces>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetched: AtomicU64,
+}
+
+impl ReadCache {
+    pub fn new(budget: usize) -> Self {
+        Self::with_memory(budget, MemoryBudget::default())
+    }
+
+    /// Account the pieces held to `memory`, and hold fewer while it is
+    /// under pressure.
+    pub fn with_memory(budget: usize, memory: MemoryBudget) -> Self {
+        Self {
+            budget,
+            pieces: Mutex::new(Pieces {
+                data: HashMap::new(),
+                order: VecDeque::new(),
+                memory: memory.allocate(Subsystem::ReadCache, 0),
+            }),
+            memory,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetched: AtomicU64::new(0),
+        }
+    }
+
+    /// The piece at `index` if it is cached, counting a hit or a miss.
+    pub fn get(&self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let Some(data) = pieces.data.get(&index).cloned() else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        pieces.order.retain(|&cached| cached != index);
+        pieces.order.push_back(index);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(data)
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.pieces.lock().unwrap().data.contains_key(&index)
+    }
+
+    /// Keep the piece at `index`, dropping the least recently used ones to
+    /// make room. Pieces larger than the whole cache are not kept.
+    pub fn insert(&self, index: u32, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let data = Arc::new(data);
+        let limit = self.memory.cache_limit(self.budget);
+        if data.len() > limit {
+            return data;
+        }
+
+        let mut pieces = self.pieces.lock().unwrap();
+        let mut held = pieces.memory.bytes();
+        if let Some(old) = pieces.data.remove(&index) {
+            held -= old.len();
+            pieces.order.retain(|&cached| cached != index);
+        }
+        while held + data.len() > limit {
+            let Some(oldest) = pieces.order.pop_front() else {
+                break;
+            };
+            if let Some(old) = pieces.data.remove(&oldest) {
+                held -= old.len();
+            }
+        }
+        pieces.data.insert(index, Arc::clone(&data));
+        pieces.order.push_back(index);
+        pieces.memory.resize(held + data.len());
+        data
+    }
+
+    /// Forget the piece at `index`, e.g. once it was written again.
+    pub fn invalidate(&self, index: u32) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if let Some(old) = pieces.data.remove(&index) {
+            pieces.order.retain(|&cached| cached != index);
+            let held = pieces.memory.bytes() - old.len();
+            pieces.memory.resize(held);
+        }
+    }
+
+    /// Count a piece read ahead of being asked for.
+    pub fn record_prefetch(&self) {
+        self.prefetched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            prefetched: self.prefetched.load(Ordering::Relaxed),
+            cached: self.pieces.lock().unwrap().memory.bytes(),
+        }
+    }
+}
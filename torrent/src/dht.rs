@@ -0,0 +1,25 @@
+// https://www.bittorrent.org/beps/bep_0005.html
+
+pub mod announce;
+pub mod crawl;
+pub mod dual;
+pub mod krpc;
+pub mod node;
+pub mod peers;
+pub mod persist;
+pub mod routing;
+pub mod secure;
+pub mod token;
+
+/// Nodes and info hashes share the same 160-bit keyspace.
+pub type NodeId = [u8; 20];
+
+/// The XOR distance between two ids, compared as big-endian numbers.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// A random id for a new node.
+pub fn random_id() -> NodeId {
+    rand::random()
+}
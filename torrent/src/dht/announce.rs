@@ -0,0 +1,175 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{krpc::Query, NodeId};
+
+/// Tokens handed out in `get_peers` responses are only accepted by the
+/// issuing node for a limited time, BEP 5 suggests up to ten minutes.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How often every torrent is announced to the DHT. Nodes expire stored
+/// peers after roughly half an hour so we announce twice within that window.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Arguments of an `announce_peer` query.
+#[derive(Debug, Serialize)]
+pub(crate) struct AnnouncePeer<'a> {
+    #[serde(with = "serde_bytes")]
+    pub id: &'a [u8],
+    /// When set, the receiver ignores `port` and uses the source port of the
+    /// UDP packet instead. This is what peers behind a NAT, or using uTP over
+    /// the same socket as the DHT, want.
+    pub implied_port: u8,
+    #[serde(with = "serde_bytes")]
+    pub info_hash: &'a [u8],
+    pub port: u16,
+    #[serde(with = "serde_bytes")]
+    pub token: &'a [u8],
+}
+
+/// How the port we are reachable on should be announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnouncePort {
+    /// Peers can reach us on this TCP port.
+    Explicit(u16),
+    /// Let the receiving node use the source port of our DHT packets. `port`
+    /// is still sent for nodes that don't understand `implied_port`.
+    Implied { port: u16 },
+}
+
+impl AnnouncePort {
+    pub fn port(self) -> u16 {
+        match self {
+            AnnouncePort::Explicit(port) | AnnouncePort::Implied { port } => port,
+        }
+    }
+
+    pub fn is_implied(self) -> bool {
+        matches!(self, AnnouncePort::Implied { .. })
+    }
+}
+
+/// Build a bencoded `announce_peer` query.
+pub fn announce_peer_query(
+    transaction_id: &[u8],
+    our_id: &NodeId,
+    info_hash: &[u8; 20],
+    port: AnnouncePort,
+    token: &[u8],
+) -> Vec<u8> {
+    let args = AnnouncePeer {
+        id: our_id,
+        implied_port: port.is_implied() as u8,
+        info_hash,
+        port: port.port(),
+        token,
+    };
+    Query::new(transaction_id, "announce_peer", args).to_bytes()
+}
+
+/// Write tokens received from `get_peers` responses, keyed by the node that
+/// issued them and the info hash they were issued for.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    tokens: HashMap<(SocketAddr, [u8; 20]), (Vec<u8>, Instant)>,
+}
+
+impl TokenStore {
+    pub fn insert(&mut self, node: SocketAddr, info_hash: [u8; 20], token: Vec<u8>, now: Instant) {
+        self.tokens.insert((node, info_hash), (token, now));
+    }
+
+    /// The token `node` gave us for `info_hash`, if it has not expired yet.
+    pub fn get(&self, node: SocketAddr, info_hash: &[u8; 20], now: Instant) -> Option<&[u8]> {
+        let (token, received) = self.tokens.get(&(node, *info_hash))?;
+        (now.duration_since(*received) < TOKEN_LIFETIME).then_some(token.as_slice())
+    }
+
+    /// Every node holding a still valid token for `info_hash`.
+    pub fn nodes_for(&self, info_hash: &[u8; 20], now: Instant) -> Vec<(SocketAddr, &[u8])> {
+        self.tokens
+            .iter()
+            .filter(|((_, hash), (_, received))| {
+                hash == info_hash && now.duration_since(*received) < TOKEN_LIFETIME
+            })
+            .map(|((node, _), (token, _))| (*node, token.as_slice()))
+            .collect()
+    }
+
+    /// Drop every expired token.
+    pub fn expire(&mut self, now: Instant) {
+        self.tokens
+            .retain(|_, (_, received)| now.duration_since(*received) < TOKEN_LIFETIME);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+/// Keeps track of when each torrent should next be announced to the DHT.
+#[derive(Debug)]
+pub struct AnnounceScheduler {
+    interval: Duration,
+    next_announce: HashMap<[u8; 20], Instant>,
+}
+
+impl Default for AnnounceScheduler {
+    fn default() -> Self {
+        Self::new(ANNOUNCE_INTERVAL)
+    }
+}
+
+impl AnnounceScheduler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_announce: HashMap::new(),
+        }
+    }
+
+    /// Start announcing `info_hash`, the first announce is due immediately.
+    pub fn add(&mut self, info_hash: [u8; 20], now: Instant) {
+        self.next_announce.entry(info_hash).or_insert(now);
+    }
+
+    pub fn remove(&mut self, info_hash: &[u8; 20]) {
+        self.next_announce.remove(info_hash);
+    }
+
+    /// Every torrent whose announce is due at `now`.
+    pub fn due(&self, now: Instant) -> Vec<[u8; 20]> {
+        self.next_announce
+            .iter()
+            .filter(|(_, next)| **next <= now)
+            .map(|(info_hash, _)| *info_hash)
+            .collect()
+    }
+
+    /// Record that `info_hash` was announced and schedule the next announce.
+    pub fn announced(&mut self, info_hash: &[u8; 20], now: Instant) {
+        if let Some(next) = self.next_announce.get_mut(info_hash) {
+            *next = now + self.interval;
+        }
+    }
+
+    /// Announcing failed (e.g. no tokens yet), try again sooner than a full interval.
+    pub fn retry(&mut self, info_hash: &[u8; 20], now: Instant) {
+        if let Some(next) = self.next_announce.get_mut(info_hash) {
+            *next = now + self.interval / 15;
+        }
+    }
+
+    /// When the next announce of any torrent is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.next_announce.values().min().copied()
+    }
+}
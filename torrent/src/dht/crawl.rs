@@ -0,0 +1,104 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{node::DhtNode, random_id, NodeId};
+use crate::{
+    identity::Identity,
+    meta_info::MetaInfo,
+    peer::{connection::ConnectionManager, ut_metadata::fetch_metadata},
+};
+
+// https://www.bittorrent.org/beps/bep_0051.html
+
+// The crawler walks the DHT asking nodes for samples of the info hashes
+// they store peers for, following the nodes they return. A torrent's name
+// is only known once its metadata is fetched from one of its peers.
+
+/// Nodes are asked again no sooner than this, whatever their interval.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Nodes asked at once in every step.
+pub const BATCH: usize = 8;
+
+/// Nodes waiting to be asked, the rest are dropped.
+pub const MAX_QUEUED: usize = 1000;
+
+/// Peers tried when fetching a torrent's name.
+pub const MAX_NAME_PEERS: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct Crawler {
+    queue: VecDeque<(NodeId, SocketAddr)>,
+    /// Nodes asked recently, and when they may be asked again.
+    not_before: HashMap<SocketAddr, Instant>,
+    seen: HashSet<[u8; 20]>,
+    info_hashes: Vec<[u8; 20]>,
+}
+
+impl Crawler {
+    /// Every info hash found, oldest first.
+    pub fn info_hashes(&self) -> &[[u8; 20]] {
+        &self.info_hashes
+    }
+
+    /// Ask the next few nodes for samples, returning the info hashes not
+    /// found before. Starts from `node`'s routing table, or a lookup of a
+    /// random id, whenever it runs out of nodes.
+    pub async fn step(&mut self, node: &mut DhtNode) -> Vec<[u8; 20]> {
+        let now = Instant::now();
+        self.not_before.retain(|_, not_before| *not_before > now);
+        if self.queue.is_empty() {
+            self.queue.extend(node.good_nodes());
+        }
+        if self.queue.is_empty() {
+            self.queue.extend(node.find_node(random_id()).await);
+        }
+
+        let mut batch = Vec::new();
+        while batch.len() < BATCH {
+            let Some((_, addr)) = self.queue.pop_front() else {
+                break;
+            };
+            if !self.not_before.contains_key(&addr) && !batch.contains(&addr) {
+                batch.push(addr);
+            }
+        }
+
+        let mut found = Vec::new();
+        for (addr, samples) in node.sample_infohashes(&batch, random_id()).await {
+            self.not_before
+                .insert(addr, now + samples.interval.max(MIN_INTERVAL));
+            for info_hash in samples.info_hashes {
+                if self.seen.insert(info_hash) {
+                    self.info_hashes.push(info_hash);
+                    found.push(info_hash);
+                }
+            }
+            for next in samples.nodes {
+                if self.queue.len() < MAX_QUEUED && !self.not_before.contains_key(&next.1) {
+                    self.queue.push_back(next);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// The name of the torrent with `info_hash`, from the metadata of the first
+/// of `peers` that has it. Blocks, so call it off the DHT's task.
+pub fn fetch_name(
+    connections: &ConnectionManager,
+    peers: &[SocketAddr],
+    info_hash: [u8; 20],
+    identity: &Identity,
+    timeout: Duration,
+) -> Option<String> {
+    peers.iter().take(MAX_NAME_PEERS).find_map(|peer| {
+        let metadata = fetch_metadata(connections, *peer, info_hash, identity, timeout).ok()?;
+        let meta_info = MetaInfo::from_metadata(&metadata, Vec::new()).ok()?;
+        Some(meta_info.info().name().to_owned())
+    })
+}
@@ -0,0 +1,189 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use super::{
+    announce::AnnouncePort, krpc::Want, node::DhtNode, persist::DhtState, random_id, NodeId,
+};
+use crate::interface::{address_family, AddressFamily};
+
+// https://www.bittorrent.org/beps/bep_0032.html
+
+// IPv4 and IPv6 nodes form separate DHTs, a dual-stack client runs a node in
+// each with the same id. Both ask for nodes of either family, the other
+// family's nodes are handed to the other node, and the results of a lookup
+// are the results of both.
+//
+// The nodes take turns, the one not running a lookup doesn't answer queries
+// until it gets its turn or `serve` runs.
+
+/// How long each node answers queries before the other gets a turn.
+const SERVE_TURN: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct DualStackDht {
+    id: NodeId,
+    /// `None` when IPv4 is turned off.
+    v4: Option<DhtNode>,
+    /// `None` when the host has no IPv6 or it is turned off.
+    v6: Option<DhtNode>,
+}
+
+impl DualStackDht {
+    /// Listen on `port` on every IPv4 and IPv6 address, restoring `state`
+    /// if given. Failing to listen on IPv6 is only an error when it is the
+    /// one IP version in use, see `interface::address_family`.
+    pub async fn bind(port: u16, state: Option<&DhtState>) -> io::Result<Self> {
+        let state = state
+            .cloned()
+            .unwrap_or_else(|| DhtState::new(random_id(), &[]));
+        let family = address_family();
+        let v4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+        let v6_addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+        // A node of the one family in use has no use for nodes of the other
+        let want = match family {
+            AddressFamily::Both => Want::both(),
+            _ if family.allows_ipv4() => Want::same_as(&v4_addr),
+            _ => Want::same_as(&v6_addr),
+        };
+
+        let (v4, v6) = if family.allows_ipv4() {
+            let v4 = DhtNode::bind_restored(v4_addr, &state).await?;
+            let v6 = match family.allows_ipv6() {
+                true => DhtNode::bind_restored(v6_addr, &state).await.ok(),
+                false => None,
+            };
+            (Some(v4), v6)
+        } else {
+            (None, Some(DhtNode::bind_restored(v6_addr, &state).await?))
+        };
+        let id = *v4.as_ref().or(v6.as_ref()).expect("one node is bound").id();
+        Ok(Self {
+            id,
+            v4: v4.map(|v4| v4.with_want(want)),
+            v6: v6.map(|v6| v6.with_want(want)),
+        })
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    pub fn v4(&self) -> Option<&DhtNode> {
+        self.v4.as_ref()
+    }
+
+    pub fn v6(&self) -> Option<&DhtNode> {
+        self.v6.as_ref()
+    }
+
+    fn nodes_mut(&mut self) -> impl Iterator<Item = &mut DhtNode> {
+        self.v4.iter_mut().chain(&mut self.v6)
+    }
+
+    /// Bootstrap both nodes, returning the number of nodes each knows.
+    pub async fn bootstrap(&mut self, routers: &[&str]) -> (usize, usize) {
+        let v4 = match &mut self.v4 {
+            Some(v4) => v4.bootstrap(routers).await,
+            None => 0,
+        };
+        let v6 = match &mut self.v6 {
+            Some(v6) => v6.bootstrap(routers).await,
+            None => 0,
+        };
+        (v4, v6)
+    }
+
+    /// Peers of the torrent with `info_hash` from both DHTs, without
+    /// duplicates.
+    pub async fn get_peers(&mut self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for node in self.nodes_mut() {
+            merge(&mut peers, node.get_peers(info_hash).await);
+        }
+        self.exchange_nodes().await;
+        peers
+    }
+
+    /// Announce to both DHTs, returning how many nodes accepted and the
+    /// peers found on the way.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        port: AnnouncePort,
+    ) -> (usize, Vec<SocketAddr>) {
+        let mut accepted = 0;
+        let mut peers = Vec::new();
+        for node in self.nodes_mut() {
+            let (node_accepted, node_peers) = node.announce(info_hash, port).await;
+            accepted += node_accepted;
+            merge(&mut peers, node_peers);
+        }
+        self.exchange_nodes().await;
+        (accepted, peers)
+    }
+
+    /// Answer queries on both nodes for `duration`, taking turns often so
+    /// neither leaves queries unanswered for long.
+    pub async fn serve(&mut self, duration: Duration) {
+        let (Some(v4), Some(v6)) = (&mut self.v4, &mut self.v6) else {
+            for node in self.nodes_mut() {
+                node.serve(duration).await;
+            }
+            return;
+        };
+        let deadline = Instant::now() + duration;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if left.is_zero() {
+                break;
+            }
+            v4.serve(left.min(SERVE_TURN)).await;
+            v6.serve(left.min(SERVE_TURN)).await;
+        }
+    }
+
+    pub async fn maintain(&mut self) {
+        for node in self.nodes_mut() {
+            node.maintain().await;
+        }
+    }
+
+    /// The id and both nodes' good nodes.
+    pub fn state(&self) -> DhtState {
+        let nodes: Vec<_> = self
+            .v4
+            .iter()
+            .chain(&self.v6)
+            .flat_map(DhtNode::good_nodes)
+            .collect();
+        DhtState::new(self.id, &nodes)
+    }
+
+    /// Hand the nodes each node learned of the other family to the other.
+    async fn exchange_nodes(&mut self) {
+        let (Some(v4), Some(v6)) = (&mut self.v4, &mut self.v6) else {
+            return;
+        };
+        let for_v6 = v4.take_other_family();
+        let for_v4 = v6.take_other_family();
+        if !for_v6.is_empty() {
+            v6.add_nodes(for_v6).await;
+        }
+        if !for_v4.is_empty() {
+            v4.add_nodes(for_v4).await;
+        }
+    }
+}
+
+/// Add the peers of `more` not in `peers` yet, leaving out those of an IP
+/// version that is turned off.
+fn merge(peers: &mut Vec<SocketAddr>, more: Vec<SocketAddr>) {
+    let family = address_family();
+    for peer in more {
+        if family.allows(peer.ip()) && !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::{announce::AnnouncePeer, NodeId};
+use crate::bencode::{self, Node};
+
+/// Error codes of KRPC error messages.
+pub const GENERIC_ERROR: i64 = 201;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC query: a dictionary with a transaction id `t`, the message type
+/// `y` (always `q` for queries), the method name `q` and its arguments `a`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Query<'a, A> {
+    #[serde(with = "serde_bytes")]
+    pub t: &'a [u8],
+    pub y: &'static str,
+    pub q: &'static str,
+    pub a: A,
+}
+
+impl<'a, A: Serialize> Query<'a, A> {
+    pub fn new(transaction_id: &'a [u8], method: &'static str, args: A) -> Self {
+        Self {
+            t: transaction_id,
+            y: "q",
+            q: method,
+            a: args,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("failed to bencode krpc query")
+    }
+}
+
+/// Arguments of `ping`, which only carry the querying node's id.
+#[derive(Debug, Serialize)]
+pub(crate) struct Ping<'a> {
+    #[serde(with = "serde_bytes")]
+    pub id: &'a [u8],
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct FindNode<'a> {
+    #[serde(with = "serde_bytes")]
+    pub id: &'a [u8],
+    #[serde(with = "serde_bytes")]
+    pub target: &'a [u8],
+    pub want: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetPeers<'a> {
+    #[serde(with = "serde_bytes")]
+    pub id: &'a [u8],
+    #[serde(with = "serde_bytes")]
+    pub info_hash: &'a [u8],
+    pub want: Vec<&'static str>,
+}
+
+// https://www.bittorrent.org/beps/bep_0032.html
+
+/// The address families a node wants nodes of in `find_node` and
+/// `get_peers` answers: `n4` for `nodes`, `n6` for `nodes6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Want {
+    pub v4: bool,
+    pub v6: bool,
+}
+
+impl Want {
+    /// Nodes of the same family as `addr`, what a node that doesn't say
+    /// gets.
+    pub fn same_as(addr: &SocketAddr) -> Self {
+        Self {
+            v4: addr.is_ipv4(),
+            v6: addr.is_ipv6(),
+        }
+    }
+
+    pub fn both() -> Self {
+        Self { v4: true, v6: true }
+    }
+
+    /// The `want` argument of a query, `None` if there is none.
+    pub fn parse(args: &Node) -> Option<Self> {
+        let want = args.get(b"want")?.as_list()?;
+        let has = |name: &[u8]| want.iter().any(|item| item.as_bytes() == Some(name));
+        Some(Self {
+            v4: has(b"n4"),
+            v6: has(b"n6"),
+        })
+    }
+
+    fn names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.v4 {
+            names.push("n4");
+        }
+        if self.v6 {
+            names.push("n6");
+        }
+        names
+    }
+}
+
+/// A query we send, encoded once its transaction id is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    FindNode(NodeId),
+    GetPeers([u8; 20]),
+    /// BEP 51, `target` only picks the nodes returned along with the samples.
+    SampleInfohashes(NodeId),
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// `want` is only sent with `find_node` and `get_peers`.
+    pub fn to_bytes(&self, transaction_id: &[u8], our_id: &NodeId, want: Want) -> Vec<u8> {
+        match self {
+            Request::Ping => Query::new(transaction_id, "ping", Ping { id: our_id }).to_bytes(),
+            Request::FindNode(target) => {
+                let args = FindNode {
+                    id: our_id,
+                    target,
+                    want: want.names(),
+                };
+                Query::new(transaction_id, "find_node", args).to_bytes()
+            }
+            Request::SampleInfohashes(target) => {
+                let args = FindNode {
+                    id: our_id,
+                    target,
+                    want: want.names(),
+                };
+                Query::new(transaction_id, "sample_infohashes", args).to_bytes()
+            }
+            Request::GetPeers(info_hash) => {
+                let args = GetPeers {
+                    id: our_id,
+                    info_hash,
+                    want: want.names(),
+                };
+                Query::new(transaction_id, "get_peers", args).to_bytes()
+            }
+            Request::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                let args = AnnouncePeer {
+                    id: our_id,
+                    implied_port: *implied_port as u8,
+                    info_hash,
+                    port: *port,
+                    token,
+                };
+                Query::new(transaction_id, "announce_peer", args).to_bytes()
+            }
+        }
+    }
+}
+
+/// The values of a response `r`, every key but `id` depends on the query.
+#[derive(Debug, Default, Serialize)]
+pub struct ResponseValues {
+    #[serde(with = "serde_bytes")]
+    pub id: Vec<u8>,
+    /// Compact node info of the closest nodes we know.
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<u8>>,
+    /// The same for IPv6 nodes.
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<Vec<u8>>,
+    /// The token needed to `announce_peer` to us (`get_peers` only).
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Vec<u8>>,
+    /// Compact peer info of peers for the info hash (`get_peers` only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    /// Seconds before asking us for samples again (`sample_infohashes` only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<i64>,
+    /// How many info hashes we have (`sample_infohashes` only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num: Option<i64>,
+    /// Some of those info hashes, 20 bytes each (`sample_infohashes` only).
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Response<'a> {
+    /// The querying node's address as we see it (BEP 42).
+    #[serde(with = "serde_bytes")]
+    ip: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    t: &'a [u8],
+    y: &'static str,
+    r: ResponseValues,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorMessage<'a> {
+    #[serde(with = "serde_bytes")]
+    t: &'a [u8],
+    y: &'static str,
+    e: (i64, &'a str),
+}
+
+/// The response to a query from `requester`.
+pub fn response(transaction_id: &[u8], values: ResponseValues, requester: SocketAddr) -> Vec<u8> {
+    let response = Response {
+        ip: encode_peer(&requester),
+        t: transaction_id,
+        y: "r",
+        r: values,
+    };
+    serde_bencode::to_bytes(&response).expect("failed to bencode krpc response")
+}
+
+pub fn error(transaction_id: &[u8], code: i64, message: &str) -> Vec<u8> {
+    let error = ErrorMessage {
+        t: transaction_id,
+        y: "e",
+        e: (code, message),
+    };
+    serde_bencode::to_bytes(&error).expect("failed to bencode krpc error")
+}
+
+/// A received KRPC message, borrowing from the packet.
+#[derive(Debug)]
+pub enum Message<'a> {
+    Query {
+        transaction_id: &'a [u8],
+        method: &'a [u8],
+        /// The arguments dictionary `a`.
+        args: Node<'a>,
+    },
+    Response {
+        transaction_id: &'a [u8],
+        values: Reply,
+    },
+    Error {
+        transaction_id: &'a [u8],
+        code: i64,
+        message: String,
+    },
+}
+
+/// What a node answered, parsed out of the response so it can outlive the packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reply {
+    pub id: NodeId,
+    /// From both `nodes` and `nodes6`.
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    /// Our address as the node sees it (BEP 42).
+    pub external_addr: Option<SocketAddr>,
+    /// Info hashes the node has peers for (BEP 51).
+    pub samples: Vec<[u8; 20]>,
+    /// Seconds before asking the node for samples again.
+    pub interval: Option<i64>,
+    /// How many info hashes the node has in total.
+    pub num: Option<i64>,
+}
+
+/// Parse a packet, `None` if it is not a well formed KRPC message.
+pub fn parse(packet: &[u8]) -> Option<Message<'_>> {
+    let root = bencode::decode(packet).ok()?;
+    let transaction_id = root.get(b"t")?.as_bytes()?;
+
+    match root.get(b"y")?.as_bytes()? {
+        b"q" => {
+            let args = root.get(b"a")?;
+            args.as_dict()?;
+            Some(Message::Query {
+                transaction_id,
+                method: root.get(b"q")?.as_bytes()?,
+                args: args.clone(),
+            })
+        }
+        b"r" => {
+            let r = root.get(b"r")?;
+            let mut nodes: Vec<(NodeId, SocketAddr)> = r
+                .get(b"nodes")
+                .and_then(Node::as_bytes)
+                .map(decode_nodes)
+                .unwrap_or_default();
+            if let Some(nodes6) = r.get(b"nodes6").and_then(Node::as_bytes) {
+                nodes.extend(decode_nodes6(nodes6));
+            }
+            let values = Reply {
+                id: node_id(r.get(b"id")?)?,
+                nodes,
+                values: r
+                    .get(b"values")
+                    .and_then(Node::as_list)
+                    .map(|values| {
+                        values
+                            .iter()
+                            .filter_map(|value| decode_peer(value.as_bytes()?))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                token: r.get(b"token").and_then(Node::as_bytes).map(<[u8]>::to_vec),
+                external_addr: root
+                    .get(b"ip")
+                    .and_then(Node::as_bytes)
+                    .and_then(decode_peer),
+                samples: r
+                    .get(b"samples")
+                    .and_then(Node::as_bytes)
+                    .map(|samples| {
+                        samples
+                            .chunks_exact(20)
+                            .map(|sample| sample.try_into().expect("chunk is 20 bytes"))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                interval: r.get(b"interval").and_then(Node::as_int),
+                num: r.get(b"num").and_then(Node::as_int),
+            };
+            Some(Message::Response {
+                transaction_id,
+                values,
+            })
+        }
+        b"e" => {
+            let e = root.get(b"e")?.as_list()?;
+            Some(Message::Error {
+                transaction_id,
+                code: e.first()?.as_int()?,
+                message: e
+                    .get(1)
+                    .and_then(Node::as_bytes)
+                    .map(|message| String::from_utf8_lossy(message).into_owned())
+                    .unwrap_or_default(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A 20 byte node id or info hash.
+pub fn node_id(node: &Node) -> Option<NodeId> {
+    node.as_bytes()?.try_into().ok()
+}
+
+/// Compact node info: 20 byte id, 4 byte IPv4 address and 2 byte port each.
+/// IPv6 nodes are left out.
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    encode_family(nodes, SocketAddr::is_ipv4)
+}
+
+/// `nodes6`: 20 byte id, 16 byte IPv6 address and 2 byte port each. IPv4
+/// nodes are left out.
+pub fn encode_nodes6(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    encode_family(nodes, SocketAddr::is_ipv6)
+}
+
+fn encode_family(nodes: &[(NodeId, SocketAddr)], family: fn(&SocketAddr) -> bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (id, addr) in nodes.iter().filter(|(_, addr)| family(addr)) {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&encode_peer(addr));
+    }
+    out
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    decode_family(bytes, 26)
+}
+
+pub fn decode_nodes6(bytes: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    decode_family(bytes, 38)
+}
+
+fn decode_family(bytes: &[u8], len: usize) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(len)
+        .filter_map(|node| {
+            let id = node[..20].try_into().ok()?;
+            Some((id, decode_peer(&node[20..])?))
+        })
+        .collect()
+}
+
+/// Compact peer info: 4 byte IPv4 or 16 byte IPv6 address and 2 byte port.
+pub fn encode_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match bytes.len() {
+        6 => {
+            let ip: [u8; 4] = bytes[..4].try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(ip)), &bytes[4..])
+        }
+        18 => {
+            let ip: [u8; 16] = bytes[..16].try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(ip)), &bytes[16..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    (port != 0).then_some(SocketAddr::new(ip, port))
+}
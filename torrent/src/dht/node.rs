@@ -0,0 +1,712 @@
+use rand::seq::SliceRandom;
+use serde_bytes::ByteBuf;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::{
+    announce::{AnnouncePort, TokenStore},
+    distance,
+    krpc::{self, Message, Reply, Request, ResponseValues, Want},
+    peers::PeerStore,
+    persist::DhtState,
+    random_id,
+    routing::{Insert, RoutingTable, K, MAX_FAILURES},
+    secure::{is_secure, secure_id, IpVotes},
+    token::TokenIssuer,
+    NodeId,
+};
+use crate::{bencode::Node, interface::outgoing};
+
+/// Well-known nodes a new node finds its first neighbors through.
+pub const BOOTSTRAP_ROUTERS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// How long to wait for an answer to a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many queries a lookup has in flight at once.
+pub const ALPHA: usize = 3;
+
+/// KRPC messages fit in a single UDP packet of at most this size.
+const MAX_PACKET: usize = 2048;
+
+/// Nodes of the other family kept until they are taken.
+const MAX_OTHER_FAMILY: usize = 64;
+
+/// How long nodes should wait before asking us for samples again.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Info hashes in one `sample_infohashes` answer, so it fits a UDP packet.
+pub const MAX_SAMPLES: usize = 20;
+
+/// What a node answered to `sample_infohashes`.
+#[derive(Debug, Clone, Default)]
+pub struct Samples {
+    pub info_hashes: Vec<[u8; 20]>,
+    /// How long to wait before asking the node again.
+    pub interval: Duration,
+    /// How many info hashes the node has in total.
+    pub num: usize,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+/// What a lookup found.
+#[derive(Debug, Default)]
+pub struct Lookup {
+    /// The closest nodes to the target that answered, closest first.
+    pub closest: Vec<(NodeId, SocketAddr)>,
+    /// Peers for the info hash (`get_peers` lookups only).
+    pub peers: Vec<SocketAddr>,
+    /// Nodes of the other address family the answers included, for a
+    /// dual-stack client's other node.
+    pub other_family: Vec<(NodeId, SocketAddr)>,
+}
+
+/// A DHT node on a UDP socket.
+///
+/// The node answers other nodes' queries while any of its methods is
+/// running, between lookups the daemon keeps `serve` running.
+#[derive(Debug)]
+pub struct DhtNode {
+    id: NodeId,
+    socket: UdpSocket,
+    table: RoutingTable,
+    issuer: TokenIssuer,
+    /// Tokens other nodes gave us, to announce to them.
+    tokens: TokenStore,
+    /// Peers other nodes announced to us.
+    peers: PeerStore,
+    /// Nodes waiting for a full bucket's questionable node to be pinged:
+    /// the questionable node's address and the node to replace it with.
+    replacements: Vec<(SocketAddr, NodeId, SocketAddr)>,
+    /// Nodes saved by a previous run, tried before the routers.
+    saved: Vec<(NodeId, SocketAddr)>,
+    /// Which families of nodes we ask for.
+    want: Want,
+    /// Nodes of the other family lookups came across, see `take_other_family`.
+    other_family: Vec<(NodeId, SocketAddr)>,
+    /// What nodes say our IP is.
+    votes: IpVotes,
+    external_ip: Option<IpAddr>,
+    /// Keep nodes whose id is not secure for their IP out of the table.
+    enforce_secure_ids: bool,
+    next_transaction: u16,
+}
+
+impl DhtNode {
+    /// Listen on `addr` with a new random node id.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with_id(addr, random_id()).await
+    }
+
+    /// Listen on `addr` keeping the id from a previous run.
+    pub async fn bind_with_id(addr: SocketAddr, id: NodeId) -> io::Result<Self> {
+        let now = Instant::now();
+        Ok(Self {
+            id,
+            socket: bind_socket(addr)?,
+            table: RoutingTable::new(id),
+            issuer: TokenIssuer::new(now),
+            tokens: TokenStore::default(),
+            peers: PeerStore::default(),
+            replacements: Vec::new(),
+            saved: Vec::new(),
+            want: Want::same_as(&addr),
+            other_family: Vec::new(),
+            votes: IpVotes::default(),
+            external_ip: None,
+            enforce_secure_ids: false,
+            next_transaction: rand::random(),
+        })
+    }
+
+    /// Ask for nodes of these families, e.g. both when another node runs
+    /// on the other family. Only nodes of our own family are queried.
+    pub fn with_want(mut self, want: Want) -> Self {
+        self.want = want;
+        self
+    }
+
+    /// Only add nodes with secure ids (BEP 42) to the routing table, instead
+    /// of just preferring them. Insecure nodes are still queried in lookups.
+    pub fn with_enforced_secure_ids(mut self) -> Self {
+        self.enforce_secure_ids = true;
+        self
+    }
+
+    /// Listen on `addr` as the node that saved `state`, `bootstrap` then
+    /// tries the saved nodes first.
+    pub async fn bind_restored(addr: SocketAddr, state: &DhtState) -> io::Result<Self> {
+        let mut node = Self::bind_with_id(addr, state.id().unwrap_or_else(random_id)).await?;
+        node.saved = state
+            .nodes()
+            .into_iter()
+            .filter(|(_, saved)| saved.is_ipv4() == addr.is_ipv4())
+            .collect();
+        Ok(node)
+    }
+
+    /// Our id and the good nodes, to restore from on the next start.
+    pub fn state(&self) -> DhtState {
+        DhtState::new(self.id, &self.good_nodes())
+    }
+
+    /// The nodes in the routing table that are not bad, most recently seen
+    /// first.
+    pub fn good_nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes: Vec<_> = self.table.nodes().filter(|entry| !entry.is_bad()).collect();
+        nodes.sort_by_key(|entry| std::cmp::Reverse(entry.last_seen));
+        nodes
+            .into_iter()
+            .map(|entry| (entry.id, entry.addr))
+            .collect()
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    /// Our IP as most nodes see it, once enough agree.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Fill the routing table by looking up our own id, starting from the
+    /// nodes of a previous run if any answer, from `routers` (e.g.
+    /// `BOOTSTRAP_ROUTERS`) otherwise. Returns the number of nodes known.
+    pub async fn bootstrap(&mut self, routers: &[&str]) -> usize {
+        let saved = std::mem::take(&mut self.saved);
+        if !saved.is_empty() {
+            self.lookup(self.id, false, saved).await;
+            if !self.table.is_empty() {
+                return self.table.len();
+            }
+        }
+
+        let mut addrs = Vec::new();
+        for router in routers {
+            if let Ok(resolved) = lookup_host(router).await {
+                addrs.extend(resolved.filter(|addr| self.is_own_family(addr)));
+            }
+        }
+
+        // Routers only point at other nodes, they don't go in the table
+        let queries = addrs
+            .into_iter()
+            .map(|addr| (addr, Request::FindNode(self.id)))
+            .collect();
+        let seeds = self
+            .query_all(queries)
+            .await
+            .into_iter()
+            .filter_map(|(_, reply)| reply)
+            .flat_map(|reply| reply.nodes)
+            .collect();
+
+        self.lookup(self.id, false, seeds).await;
+        self.table.len()
+    }
+
+    /// The nodes of the other family found since the last call, when
+    /// asking for both.
+    pub fn take_other_family(&mut self) -> Vec<(NodeId, SocketAddr)> {
+        std::mem::take(&mut self.other_family)
+    }
+
+    /// Ping `nodes` learned elsewhere, e.g. from the other family's node,
+    /// adding those that answer. Returns how many did.
+    pub async fn add_nodes(&mut self, nodes: Vec<(NodeId, SocketAddr)>) -> usize {
+        let queries = nodes
+            .into_iter()
+            .filter(|(_, addr)| self.is_own_family(addr))
+            .map(|(_, addr)| (addr, Request::Ping))
+            .collect();
+
+        let mut added = 0;
+        for (addr, reply) in self.query_all(queries).await {
+            if let Some(reply) = reply {
+                self.insert_node(reply.id, addr, Instant::now());
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Ping `addr`, returning its id if it answered.
+    pub async fn ping(&mut self, addr: SocketAddr) -> Option<NodeId> {
+        let (_, reply) = self.query_all(vec![(addr, Request::Ping)]).await.pop()?;
+        match reply {
+            Some(reply) => {
+                self.insert_node(reply.id, addr, Instant::now());
+                Some(reply.id)
+            }
+            None => {
+                self.table.mark_failed(addr);
+                None
+            }
+        }
+    }
+
+    /// The closest nodes to `target` in the whole DHT.
+    pub async fn find_node(&mut self, target: NodeId) -> Vec<(NodeId, SocketAddr)> {
+        self.lookup(target, false, Vec::new()).await.closest
+    }
+
+    /// Peers of the torrent with `info_hash`.
+    pub async fn get_peers(&mut self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true, Vec::new()).await.peers
+    }
+
+    /// Ask each of `nodes` for a sample of the info hashes it has peers for
+    /// (BEP 51), returning the nodes that answered.
+    pub async fn sample_infohashes(
+        &mut self,
+        nodes: &[SocketAddr],
+        target: NodeId,
+    ) -> Vec<(SocketAddr, Samples)> {
+        let queries = nodes
+            .iter()
+            .filter(|addr| self.is_own_family(addr))
+            .map(|addr| (*addr, Request::SampleInfohashes(target)))
+            .collect();
+
+        let mut answered = Vec::new();
+        for (addr, reply) in self.query_all(queries).await {
+            let Some(reply) = reply else {
+                self.table.mark_failed(addr);
+                continue;
+            };
+            self.insert_node(reply.id, addr, Instant::now());
+            let samples = Samples {
+                info_hashes: reply.samples,
+                interval: Duration::from_secs(reply.interval.unwrap_or(0).max(0) as u64),
+                num: reply.num.unwrap_or(0).max(0) as usize,
+                nodes: reply.nodes,
+            };
+            answered.push((addr, samples));
+        }
+        answered
+    }
+
+    /// Tell the nodes closest to `info_hash` that we are a peer, returning
+    /// how many accepted. Also returns the peers found on the way.
+    pub async fn announce(
+        &mut self,
+        info_hash: [u8; 20],
+        port: AnnouncePort,
+    ) -> (usize, Vec<SocketAddr>) {
+        let lookup = self.lookup(info_hash, true, Vec::new()).await;
+
+        let now = Instant::now();
+        let queries = lookup
+            .closest
+            .iter()
+            .filter_map(|(_, addr)| {
+                let token = self.tokens.get(*addr, &info_hash, now)?;
+                let request = Request::AnnouncePeer {
+                    info_hash,
+                    port: port.port(),
+                    implied_port: port.is_implied(),
+                    token: token.to_vec(),
+                };
+                Some((*addr, request))
+            })
+            .collect();
+
+        let accepted = self
+            .query_all(queries)
+            .await
+            .iter()
+            .filter(|(_, reply)| reply.is_some())
+            .count();
+        (accepted, lookup.peers)
+    }
+
+    /// Answer queries for `duration`.
+    pub async fn serve(&mut self, duration: Duration) {
+        let deadline = tokio::time::Instant::now() + duration;
+        let mut buf = [0; MAX_PACKET];
+        loop {
+            let Ok(received) =
+                tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+            else {
+                return;
+            };
+            // e.g. ICMP port unreachable for an earlier packet
+            let Ok((len, from)) = received else {
+                continue;
+            };
+            if let Some(Message::Query {
+                transaction_id,
+                method,
+                args,
+            }) = krpc::parse(&buf[..len])
+            {
+                let answer = self.answer(transaction_id, method, &args, from);
+                let _ = self.socket.send_to(&answer, from).await;
+            }
+        }
+    }
+
+    /// Housekeeping, every few minutes: expire tokens and peers, and give
+    /// questionable nodes in full buckets a chance before replacing them.
+    pub async fn maintain(&mut self) {
+        let now = Instant::now();
+        self.tokens.expire(now);
+        self.peers.expire(now);
+
+        for (old, id, addr) in std::mem::take(&mut self.replacements) {
+            let mut answered = false;
+            for _ in 0..MAX_FAILURES {
+                if self.ping(old).await.is_some() {
+                    answered = true;
+                    break;
+                }
+            }
+            if !answered {
+                self.insert_node(id, addr, Instant::now());
+            }
+        }
+    }
+
+    /// `voter` told us our IP. Once enough agree on one our id has to be
+    /// secure for, we take a new id and sort the table under it.
+    fn vote_external_ip(&mut self, voter: SocketAddr, ip: IpAddr) {
+        let now = Instant::now();
+        self.votes.vote(voter, ip, now);
+        let Some(ip) = self.votes.winner(now) else {
+            return;
+        };
+        self.external_ip = Some(ip);
+        if is_secure(&self.id, ip) {
+            return;
+        }
+
+        self.id = secure_id(ip);
+        let old = std::mem::replace(&mut self.table, RoutingTable::new(self.id));
+        for entry in old.nodes() {
+            self.table.insert(entry.id, entry.addr, entry.last_seen);
+        }
+    }
+
+    /// Whether `addr` can be reached from our socket.
+    fn is_own_family(&self, addr: &SocketAddr) -> bool {
+        match self.socket.local_addr() {
+            Ok(local) => local.is_ipv4() == addr.is_ipv4(),
+            Err(_) => false,
+        }
+    }
+
+    fn insert_node(&mut self, id: NodeId, addr: SocketAddr, now: Instant) {
+        if self.enforce_secure_ids && !is_secure(&id, addr.ip()) {
+            return;
+        }
+        if let Insert::Questionable(old) = self.table.insert(id, addr, now) {
+            if !self.replacements.iter().any(|(_, _, new)| *new == addr) {
+                self.replacements.push((old, id, addr));
+            }
+        }
+    }
+
+    /// Iteratively query the nodes closest to `target`, starting from our
+    /// routing table and `seeds`, until the `K` closest have all answered.
+    async fn lookup(
+        &mut self,
+        target: NodeId,
+        get_peers: bool,
+        seeds: Vec<(NodeId, SocketAddr)>,
+    ) -> Lookup {
+        let mut candidates: Vec<(NodeId, SocketAddr)> = Vec::new();
+        let known = self.table.closest(&target, K).into_iter();
+        for (id, addr) in seeds
+            .into_iter()
+            .chain(known.map(|entry| (entry.id, entry.addr)))
+        {
+            if !candidates.iter().any(|(_, known)| *known == addr) {
+                candidates.push((id, addr));
+            }
+        }
+
+        let request = match get_peers {
+            true => Request::GetPeers(target),
+            false => Request::FindNode(target),
+        };
+        let mut queried: Vec<SocketAddr> = Vec::new();
+        let mut lookup = Lookup::default();
+
+        loop {
+            candidates.sort_by_key(|(id, _)| distance(id, &target));
+            let queries: Vec<(SocketAddr, Request)> = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .map(|(_, addr)| (*addr, request.clone()))
+                .collect();
+            if queries.is_empty() {
+                break;
+            }
+            queried.extend(queries.iter().map(|(addr, _)| *addr));
+
+            for (addr, reply) in self.query_all(queries).await {
+                let Some(reply) = reply else {
+                    self.table.mark_failed(addr);
+                    candidates.retain(|(_, candidate)| *candidate != addr);
+                    continue;
+                };
+
+                let now = Instant::now();
+                self.insert_node(reply.id, addr, now);
+                lookup.closest.push((reply.id, addr));
+                if let Some(token) = reply.token {
+                    self.tokens.insert(addr, target, token, now);
+                }
+                for peer in reply.values {
+                    if !lookup.peers.contains(&peer) {
+                        lookup.peers.push(peer);
+                    }
+                }
+                for (id, node) in reply.nodes {
+                    if id == self.id {
+                        continue;
+                    }
+                    if !self.is_own_family(&node) {
+                        if !lookup.other_family.iter().any(|(_, known)| *known == node) {
+                            lookup.other_family.push((id, node));
+                        }
+                    } else if !candidates.iter().any(|(_, known)| *known == node) {
+                        candidates.push((id, node));
+                    }
+                }
+            }
+        }
+
+        lookup.closest.sort_by_key(|(id, _)| distance(id, &target));
+        lookup.closest.truncate(K);
+        for node in &lookup.other_family {
+            if self.other_family.len() < MAX_OTHER_FAMILY && !self.other_family.contains(node) {
+                self.other_family.push(*node);
+            }
+        }
+        lookup
+    }
+
+    /// Send every query and wait up to `QUERY_TIMEOUT` for the answers,
+    /// answering other nodes' queries in the meantime. Returns each
+    /// address with its reply, `None` if it didn't answer in time.
+    async fn query_all(
+        &mut self,
+        queries: Vec<(SocketAddr, Request)>,
+    ) -> Vec<(SocketAddr, Option<Reply>)> {
+        let mut results: Vec<(SocketAddr, Option<Reply>)> =
+            queries.iter().map(|(addr, _)| (*addr, None)).collect();
+
+        let mut pending: HashMap<[u8; 2], usize> = HashMap::new();
+        for (index, (addr, request)) in queries.iter().enumerate() {
+            let transaction_id = self.next_transaction.to_be_bytes();
+            self.next_transaction = self.next_transaction.wrapping_add(1);
+
+            let packet = request.to_bytes(&transaction_id, &self.id, self.want);
+            if self.socket.send_to(&packet, addr).await.is_ok() {
+                pending.insert(transaction_id, index);
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + QUERY_TIMEOUT;
+        let mut buf = [0; MAX_PACKET];
+        while !pending.is_empty() {
+            let Ok(received) =
+                tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+            else {
+                break;
+            };
+            let Ok((len, from)) = received else {
+                continue;
+            };
+
+            match krpc::parse(&buf[..len]) {
+                Some(Message::Query {
+                    transaction_id,
+                    method,
+                    args,
+                }) => {
+                    let answer = self.answer(transaction_id, method, &args, from);
+                    let _ = self.socket.send_to(&answer, from).await;
+                }
+                Some(Message::Response {
+                    transaction_id,
+                    values,
+                }) => {
+                    // Only from the node we asked, anyone could guess a transaction id
+                    if let Some(index) = take_pending(&mut pending, transaction_id, |index| {
+                        results[index].0 == from
+                    }) {
+                        if let Some(external) = values.external_addr {
+                            self.vote_external_ip(from, external.ip());
+                        }
+                        results[index].1 = Some(values);
+                    }
+                }
+                Some(Message::Error { transaction_id, .. }) => {
+                    take_pending(&mut pending, transaction_id, |index| {
+                        results[index].0 == from
+                    });
+                }
+                None => {}
+            }
+        }
+
+        results
+    }
+
+    /// The response to a query from `from`.
+    fn answer(
+        &mut self,
+        transaction_id: &[u8],
+        method: &[u8],
+        args: &Node,
+        from: SocketAddr,
+    ) -> Vec<u8> {
+        let now = Instant::now();
+        let Some(id) = args.get(b"id").and_then(krpc::node_id) else {
+            return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "missing id");
+        };
+        self.insert_node(id, from, now);
+
+        let mut values = ResponseValues {
+            id: self.id.to_vec(),
+            ..Default::default()
+        };
+        let want = Want::parse(args).unwrap_or_else(|| Want::same_as(&from));
+        match method {
+            b"ping" => {}
+            b"find_node" => {
+                let Some(target) = args.get(b"target").and_then(krpc::node_id) else {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "missing target");
+                };
+                self.add_closest(&mut values, &target, want);
+            }
+            b"get_peers" => {
+                let Some(info_hash) = args.get(b"info_hash").and_then(krpc::node_id) else {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "missing info_hash");
+                };
+                values.token = Some(self.issuer.issue(from.ip(), now));
+
+                let peers: Vec<ByteBuf> = self
+                    .peers
+                    .get(&info_hash, now)
+                    .into_iter()
+                    .map(|peer| ByteBuf::from(krpc::encode_peer(&peer)))
+                    .collect();
+                if peers.is_empty() {
+                    self.add_closest(&mut values, &info_hash, want);
+                } else {
+                    values.values = Some(peers);
+                }
+            }
+            b"sample_infohashes" => {
+                let Some(target) = args.get(b"target").and_then(krpc::node_id) else {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "missing target");
+                };
+                let info_hashes = self.peers.info_hashes();
+                let samples: Vec<u8> = info_hashes
+                    .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES)
+                    .flatten()
+                    .copied()
+                    .collect();
+                values.samples = Some(samples);
+                values.interval = Some(SAMPLE_INTERVAL.as_secs() as i64);
+                values.num = Some(info_hashes.len() as i64);
+                self.add_closest(&mut values, &target, want);
+            }
+            b"announce_peer" => {
+                let info_hash = args.get(b"info_hash").and_then(krpc::node_id);
+                let token = args.get(b"token").and_then(Node::as_bytes);
+                let (Some(info_hash), Some(token)) = (info_hash, token) else {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "missing argument");
+                };
+                if !self.issuer.is_valid(token, from.ip(), now) {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "bad token");
+                }
+
+                let implied = args.get(b"implied_port").and_then(Node::as_int) == Some(1);
+                let port = match implied {
+                    true => Some(from.port()),
+                    false => args
+                        .get(b"port")
+                        .and_then(Node::as_int)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .filter(|&port| port != 0),
+                };
+                let Some(port) = port else {
+                    return krpc::error(transaction_id, krpc::PROTOCOL_ERROR, "bad port");
+                };
+                self.peers
+                    .insert(info_hash, SocketAddr::new(from.ip(), port), now);
+            }
+            _ => return krpc::error(transaction_id, krpc::METHOD_UNKNOWN, "method unknown"),
+        }
+        krpc::response(transaction_id, values, from)
+    }
+
+    /// Our closest nodes to `target` as `nodes` and `nodes6`, as `want`ed.
+    /// We only know nodes of our own family, so one of them stays empty.
+    fn add_closest(&self, values: &mut ResponseValues, target: &NodeId, want: Want) {
+        let closest: Vec<(NodeId, SocketAddr)> = self
+            .table
+            .closest(target, K)
+            .into_iter()
+            .map(|entry| (entry.id, entry.addr))
+            .collect();
+        if want.v4 {
+            values.nodes = Some(krpc::encode_nodes(&closest));
+        }
+        if want.v6 {
+            values.nodes6 = Some(krpc::encode_nodes6(&closest));
+        }
+    }
+}
+
+/// An IPv6 socket only takes IPv6, so an IPv4 socket can listen on the
+/// same port.
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    outgoing().bind(&socket, addr)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Remove and return the index of the query with `transaction_id`, if
+/// `matches` accepts it.
+fn take_pending(
+    pending: &mut HashMap<[u8; 2], usize>,
+    transaction_id: &[u8],
+    matches: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    let transaction_id: [u8; 2] = transaction_id.try_into().ok()?;
+    let index = *pending.get(&transaction_id)?;
+    if !matches(index) {
+        return None;
+    }
+    pending.remove(&transaction_id)
+}
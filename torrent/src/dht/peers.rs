@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Peers announced to us are forgotten after this long, they re-announce
+/// well within it.
+pub const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// At most this many peers are returned for one `get_peers`, so the
+/// response fits a UDP packet.
+pub const MAX_VALUES: usize = 50;
+
+/// At most this many info hashes are kept peers for, and this many peers
+/// for each, so nodes announcing made up torrents or addresses can't grow
+/// the store without end. The least recently announced go first.
+pub const MAX_INFO_HASHES: usize = 4096;
+pub const MAX_PEERS_PER_INFO_HASH: usize = 512;
+
+/// Peers other nodes announced to us with `announce_peer`.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl PeerStore {
+    pub fn insert(&mut self, info_hash: [u8; 20], peer: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_INFO_HASHES {
+            self.evict_info_hash();
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+        match peers.iter_mut().find(|(addr, _)| *addr == peer) {
+            Some((_, announced)) => *announced = now,
+            None => {
+                if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+                    if let Some(oldest) = peers
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, (_, announced))| *announced)
+                        .map(|(index, _)| index)
+                    {
+                        peers.swap_remove(oldest);
+                    }
+                }
+                peers.push((peer, now));
+            }
+        }
+    }
+
+    /// Drop the info hash whose latest announce is the oldest.
+    fn evict_info_hash(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peers)| peers.iter().map(|(_, announced)| *announced).max())
+            .map(|(info_hash, _)| *info_hash);
+        if let Some(info_hash) = oldest {
+            self.peers.remove(&info_hash);
+        }
+    }
+
+    /// The most recently announced peers for `info_hash`.
+    pub fn get(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        let Some(peers) = self.peers.get(info_hash) else {
+            return Vec::new();
+        };
+
+        let mut fresh: Vec<&(SocketAddr, Instant)> = peers
+            .iter()
+            .filter(|(_, announced)| now.duration_since(*announced) < PEER_LIFETIME)
+            .collect();
+        fresh.sort_by_key(|(_, announced)| std::cmp::Reverse(*announced));
+        fresh
+            .into_iter()
+            .take(MAX_VALUES)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Every info hash with peers.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.peers.keys().copied().collect()
+    }
+
+    /// Drop every peer that has not re-announced in time.
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|(_, announced)| now.duration_since(*announced) < PEER_LIFETIME);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
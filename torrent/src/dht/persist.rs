@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{io, net::SocketAddr, path::Path};
+
+use super::{krpc, NodeId};
+
+/// At most this many nodes are saved, plenty to bootstrap from.
+pub const MAX_SAVED_NODES: usize = 200;
+
+/// What a node keeps across restarts, bencoded: its id, so it keeps its
+/// place in other nodes' tables, and nodes that were good when it stopped,
+/// so it can rejoin without asking the public routers.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DhtState {
+    id: ByteBuf,
+    /// Compact node info.
+    nodes: ByteBuf,
+    #[serde(default)]
+    nodes6: ByteBuf,
+}
+
+impl DhtState {
+    pub fn new(id: NodeId, nodes: &[(NodeId, SocketAddr)]) -> Self {
+        let nodes = &nodes[..nodes.len().min(MAX_SAVED_NODES)];
+        Self {
+            id: ByteBuf::from(id.to_vec()),
+            nodes: ByteBuf::from(krpc::encode_nodes(nodes)),
+            nodes6: ByteBuf::from(krpc::encode_nodes6(nodes)),
+        }
+    }
+
+    pub fn id(&self) -> Option<NodeId> {
+        self.id.as_slice().try_into().ok()
+    }
+
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes = krpc::decode_nodes(&self.nodes);
+        nodes.extend(krpc::decode_nodes6(&self.nodes6));
+        nodes
+    }
+
+    /// Read the state saved at `path`, `None` if there is none or it is corrupt.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = std::fs::read(path).ok()?;
+        serde_bencode::from_bytes(&bytes).ok()
+    }
+
+    /// Write the state to `path`, replacing the previous one only once the
+    /// new one is complete.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = serde_bencode::to_bytes(self).expect("failed to bencode dht state");
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(partial, path)
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{distance, secure::is_secure, NodeId};
+
+/// How many nodes a bucket holds, and how many nodes a lookup returns.
+pub const K: usize = 8;
+
+/// A node that has not been heard from for this long is questionable and
+/// gets replaced by new nodes if it does not answer a ping.
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A node that failed to answer this many queries in a row is bad.
+pub const MAX_FAILURES: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeEntry {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    /// Queries in a row the node did not answer.
+    pub failures: u32,
+    /// Whether the id is secure for the node's IP (BEP 42).
+    pub secure: bool,
+}
+
+impl NodeEntry {
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn is_questionable(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) >= QUESTIONABLE_AFTER
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insert {
+    Added,
+    /// The node was known, it is marked as seen.
+    Updated,
+    /// The bucket is full of good nodes, the node was dropped.
+    Full,
+    /// The bucket is full but this node has not been heard from in a while.
+    /// If it doesn't answer a ping, mark it failed and insert again.
+    Questionable(SocketAddr),
+}
+
+/// The nodes we know, bucketed by how many leading bits their id shares
+/// with ours. Buckets close to our id are as fine grained as BEP 5's split
+/// buckets, so we know our own neighborhood well and the rest coarsely.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<NodeEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        let distance = distance(&self.id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(160, |index| {
+                index * 8 + distance[index].leading_zeros() as usize
+            });
+        leading_zeros.min(159)
+    }
+
+    /// A node answered us or sent us a query.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> Insert {
+        if id == self.id {
+            return Insert::Full;
+        }
+
+        let index = self.bucket_index(&id);
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.id == id) {
+            entry.addr = addr;
+            entry.last_seen = now;
+            entry.failures = 0;
+            return Insert::Updated;
+        }
+
+        let entry = NodeEntry {
+            id,
+            addr,
+            last_seen: now,
+            failures: 0,
+            secure: is_secure(&id, addr.ip()),
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return Insert::Added;
+        }
+
+        if let Some(bad) = bucket.iter().position(NodeEntry::is_bad) {
+            bucket[bad] = entry;
+            return Insert::Added;
+        }
+
+        // Secure nodes are preferred over any insecure one
+        if entry.secure {
+            if let Some(insecure) = bucket.iter().position(|entry| !entry.secure) {
+                bucket[insecure] = entry;
+                return Insert::Added;
+            }
+        }
+
+        match bucket
+            .iter()
+            .filter(|entry| entry.is_questionable(now))
+            .min_by_key(|entry| entry.last_seen)
+        {
+            Some(oldest) => Insert::Questionable(oldest.addr),
+            None => Insert::Full,
+        }
+    }
+
+    /// A node did not answer a query.
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `count` good nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeEntry> {
+        let mut nodes: Vec<&NodeEntry> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .collect();
+        nodes.sort_by_key(|entry| distance(&entry.id, target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+
+    /// Every node in the table.
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeEntry> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use super::NodeId;
+
+// https://www.bittorrent.org/beps/bep_0042.html
+
+// A node id is tied to the node's external IP: its first 21 bits come from
+// a CRC32-C of the masked IP and the random number in the id's last byte.
+// Nodes can't pick ids next to an info hash to take over its lookups
+// without owning IPs that hash there.
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Nodes telling us the same external IP before we believe them.
+pub const MIN_VOTES: usize = 3;
+/// Votes are forgotten after this long, e.g. once the router got a new IP.
+pub const VOTE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// At most this many votes are kept, the oldest goes first.
+pub const MAX_VOTES: usize = 128;
+
+/// A new id that is secure for `ip`.
+pub fn secure_id(ip: IpAddr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let r = id[19] & 0x07;
+    let crc = ip_crc(ip, r);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Whether `id` is secure for `ip`. Nodes on local networks can't know
+/// their external IP and are exempt.
+pub fn is_secure(id: &NodeId, ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = ip_crc(ip, id[19] & 0x07);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            (0..4).map(|i| octets[i] & V4_MASK[i]).collect::<Vec<u8>>()
+        }
+        IpAddr::V6(ip) => {
+            let octets = ip.octets();
+            (0..8).map(|i| octets[i] & V6_MASK[i]).collect()
+        }
+    };
+    masked[0] |= r << 5;
+    crc32c(&masked)
+}
+
+/// CRC32-C (Castagnoli), bit by bit, it only ever hashes 8 bytes.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Our external IP as told by the nodes we query (the `ip` key of their
+/// responses), one vote per network: a /24 for IPv4, a /64 for IPv6. A
+/// single host with many ports or addresses can't outvote the rest.
+#[derive(Debug, Clone, Default)]
+pub struct IpVotes {
+    /// The voter's network, and the IP it voted for and when.
+    votes: HashMap<IpAddr, (IpAddr, Instant)>,
+}
+
+impl IpVotes {
+    /// `voter` says at `now` that our IP is `ip`.
+    pub fn vote(&mut self, voter: SocketAddr, ip: IpAddr, now: Instant) {
+        self.votes
+            .retain(|_, (_, voted)| now.duration_since(*voted) < VOTE_LIFETIME);
+
+        let network = voter_network(voter.ip());
+        if !self.votes.contains_key(&network) && self.votes.len() >= MAX_VOTES {
+            let oldest = self
+                .votes
+                .iter()
+                .min_by_key(|(_, (_, voted))| *voted)
+                .map(|(network, _)| *network);
+            if let Some(oldest) = oldest {
+                self.votes.remove(&oldest);
+            }
+        }
+        self.votes.insert(network, (ip, now));
+    }
+
+    /// The IP most networks told us within `VOTE_LIFETIME` of `now`, once
+    /// at least `MIN_VOTES` did.
+    pub fn winner(&self, now: Instant) -> Option<IpAddr> {
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for (ip, voted) in self.votes.values() {
+            if now.duration_since(*voted) < VOTE_LIFETIME {
+                *counts.entry(*ip).or_default() += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_VOTES)
+            .max_by_key(|(_, count)| *count)
+            .map(|(ip, _)| ip)
+    }
+}
+
+/// The /24 or /64 `ip` is in.
+fn voter_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            octets[8..].fill(0);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(hex: &str) -> NodeId {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn the_bep_examples_are_secure() {
+        let examples = [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a"),
+        ];
+        for (ip, hex) in examples {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = id(hex);
+            assert!(is_secure(&id, ip), "{ip}");
+            // Another random number in the last byte needs another prefix
+            id[19] ^= 0x01;
+            assert!(!is_secure(&id, ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn new_ids_are_secure_for_their_ip() {
+        for ip in ["124.31.75.21", "2001:db8:85a3::8a2e:370:7334"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            for _ in 0..16 {
+                assert!(is_secure(&secure_id(ip), ip));
+            }
+        }
+        let id = secure_id("124.31.75.21".parse().unwrap());
+        assert!(!is_secure(&id, "21.75.31.124".parse().unwrap()));
+    }
+
+    #[test]
+    fn local_networks_are_exempt() {
+        let id = [0xff; 20];
+        for ip in ["192.168.1.2", "10.0.0.1", "127.0.0.1", "::1", "fd00::1"] {
+            assert!(is_secure(&id, ip.parse().unwrap()), "{ip}");
+        }
+        assert!(!is_secure(&id, "2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn each_network_votes_once() {
+        let ours: IpAddr = "203.0.113.7".parse().unwrap();
+        let now = Instant::now();
+        let mut votes = IpVotes::default();
+        // Many ports and hosts of one /24 are a single vote
+        for host in 1..10 {
+            votes.vote(
+                SocketAddr::new([198, 51, 100, host].into(), 6881),
+                ours,
+                now,
+            );
+        }
+        assert_eq!(votes.winner(now), None);
+
+        // So are the addresses of one /64
+        votes.vote("[2001:db8::1]:6881".parse().unwrap(), ours, now);
+        votes.vote("[2001:db8::2]:6881".parse().unwrap(), ours, now);
+        assert_eq!(votes.winner(now), None);
+        votes.vote("192.0.2.1:6881".parse().unwrap(), ours, now);
+        assert_eq!(votes.winner(now), Some(ours));
+
+        // Forgotten once they are too old
+        assert_eq!(votes.winner(now + VOTE_LIFETIME), None);
+    }
+
+    #[test]
+    fn the_oldest_votes_make_room() {
+        let now = Instant::now();
+        let mut votes = IpVotes::default();
+        let stale: IpAddr = "203.0.113.1".parse().unwrap();
+        let fresh: IpAddr = "203.0.113.2".parse().unwrap();
+        for network in 0..MAX_VOTES as u32 {
+            let ip = if network < MIN_VOTES as u32 {
+                stale
+            } else {
+                fresh
+            };
+            let voter = SocketAddr::new(Ipv4Addr::from(network << 8).into(), 6881);
+            votes.vote(voter, ip, now + Duration::from_secs(network.into()));
+        }
+        assert_eq!(votes.votes.len(), MAX_VOTES);
+
+        votes.vote(
+            "192.0.2.1:6881".parse().unwrap(),
+            fresh,
+            now + VOTE_LIFETIME / 2,
+        );
+        assert_eq!(votes.votes.len(), MAX_VOTES);
+        assert_eq!(
+            votes.votes.values().filter(|(ip, _)| *ip == stale).count(),
+            MIN_VOTES - 1
+        );
+    }
+}
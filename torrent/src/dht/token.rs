@@ -0,0 +1,67 @@
+use rand::RngCore;
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// How often the secret tokens are derived from changes. Tokens from the
+/// previous secret are still accepted, so a token is good for 5 to 10 minutes.
+pub const SECRET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Hands out the tokens we require in `announce_peer`, proving the announcing
+/// node asked us with `get_peers` from the same IP recently.
+#[derive(Debug)]
+pub struct TokenIssuer {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl TokenIssuer {
+    pub fn new(now: Instant) -> Self {
+        let secret = random_secret();
+        Self {
+            secret,
+            previous: secret,
+            rotated: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= SECRET_LIFETIME {
+            self.previous = self.secret;
+            self.secret = random_secret();
+            self.rotated = now;
+        }
+    }
+
+    /// The token for a node at `ip`.
+    pub fn issue(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        token_for(&self.secret, ip).to_vec()
+    }
+
+    /// Whether `token` was issued to `ip` recently.
+    pub fn is_valid(&mut self, token: &[u8], ip: IpAddr, now: Instant) -> bool {
+        self.rotate(now);
+        token == token_for(&self.secret, ip) || token == token_for(&self.previous, ip)
+    }
+}
+
+fn random_secret() -> [u8; 16] {
+    let mut secret = [0; 16];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+fn token_for(secret: &[u8; 16], ip: IpAddr) -> [u8; 8] {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(&ip.octets()),
+        IpAddr::V6(ip) => hasher.update(&ip.octets()),
+    }
+    hasher.digest().bytes()[..8]
+        .try_into()
+        .expect("sha1 is 20 bytes")
+}
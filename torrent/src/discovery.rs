@@ -0,0 +1,76 @@
+use std::fmt;
+
+use crate::peer::registry::PeerSource;
+
+// Trackers are always used, they are what the torrent asks for. The other
+// ways of finding peers can be turned off for the whole session, e.g. on
+// networks where broadcasting to the LAN or talking to the DHT is unwelcome.
+
+/// Which peer sources besides trackers are used.
+///
+/// A disabled source is not announced to or queried, peers it would have
+/// found are ignored, and it is not advertised to peers: no `Port` message
+/// without the DHT and no `ut_pex` in the extension handshake without PEX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discovery {
+    /// The mainline DHT (BEP 5).
+    pub dht: bool,
+    /// Peer exchange (BEP 11).
+    pub pex: bool,
+    /// Local service discovery (BEP 14).
+    pub lsd: bool,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            dht: true,
+            pex: true,
+            lsd: true,
+        }
+    }
+}
+
+impl Discovery {
+    /// Only trackers.
+    pub fn none() -> Self {
+        Self {
+            dht: false,
+            pex: false,
+            lsd: false,
+        }
+    }
+
+    /// The sources to use for a torrent. Private torrents (BEP 27) only get
+    /// peers from their trackers, whatever the session allows.
+    pub fn for_torrent(self, private: bool) -> Self {
+        if private {
+            Self::none()
+        } else {
+            self
+        }
+    }
+
+    /// Whether peers found through `source` are used.
+    pub fn allows(&self, source: PeerSource) -> bool {
+        match source {
+            PeerSource::Dht => self.dht,
+            PeerSource::Pex => self.pex,
+            PeerSource::Lsd => self.lsd,
+            PeerSource::Tracker | PeerSource::Holepunch | PeerSource::Incoming => true,
+        }
+    }
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        write!(
+            f,
+            "dht {}, pex {}, lsd {}",
+            on_off(self.dht),
+            on_off(self.pex),
+            on_off(self.lsd)
+        )
+    }
+}
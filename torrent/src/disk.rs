@@ -0,0 +1,505 @@
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, io, path::PathBuf};
+
+use crate::{
+    cache::{CacheStats, ReadCache},
+    hash_pool::HashPool,
+    meta_info::{FileSpan, Info},
+    storage::{Allocation, BlockRead, BlockWrite, FileStorage, Storage},
+};
+
+/// How hard to try to make sure a piece is really on disk before telling
+/// peers (with `have`) and the resume data that we have it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// The piece has been handed to the OS. A crash of flud loses nothing,
+    /// a power loss may lose pieces we already advertised.
+    Fast,
+    /// Every file the piece touches is fsync'd before it is advertised.
+    #[default]
+    Safe,
+    /// Like `Safe`, then the piece is read back and hashed again.
+    Paranoid,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WriteError {
+    /// The data does not match the piece hash, nothing was written.
+    HashMismatch,
+    /// The piece read back from disk did not match its hash (`Paranoid`).
+    ReadBackMismatch,
+    Io(io::Error),
+}
+
+impl From<io::Error> for WriteError {
+    fn from(err: io::Error) -> Self {
+        WriteError::Io(err)
+    }
+}
+
+/// A piece that was verified and written with the configured durability.
+///
+/// Only `PieceWriter` can make one, and advertising a piece or counting it
+/// as done requires one, so a piece can't be announced before it is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommittedPiece {
+    index: u32,
+    len: u64,
+}
+
+impl CommittedPiece {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Writes complete pieces into a torrent's files, kept by a `Storage`.
+#[derive(Debug)]
+pub struct PieceWriter {
+    storage: Box<dyn Storage>,
+    durability: Durability,
+    read_cache: Option<ReadCache>,
+    hash_pool: Option<HashPool>,
+}
+
+impl PieceWriter {
+    /// Write into the torrent's files under `root`.
+    pub fn new(info: &Info, root: PathBuf, durability: Durability) -> Self {
+        Self::from_storage(FileStorage::new(info, root), durability)
+    }
+
+    /// Write into the torrent's files under `root`, reserving room for
+    /// them with `allocation` on `preallocate`.
+    pub fn with_allocation(
+        info: &Info,
+        root: PathBuf,
+        durability: Durability,
+        allocation: Allocation,
+    ) -> Self {
+        Self::from_storage(
+            FileStorage::new(info, root).with_allocation(allocation),
+            durability,
+        )
+    }
+
+    /// Write into any storage, e.g. `MemoryStorage` for tests.
+    pub fn from_storage(storage: impl Storage + 'static, durability: Durability) -> Self {
+        Self {
+            storage: Box::new(storage),
+            durability,
+            read_cache: None,
+            hash_pool: None,
+        }
+    }
+
+    /// Hash pieces on `pool` rather than the calling thread, so the hashing
+    /// of every torrent shares the same threads.
+    pub fn with_hash_pool(mut self, pool: HashPool) -> Self {
+        self.hash_pool = Some(pool);
+        self
+    }
+
+    /// Serve `read_block` from whole pieces kept in `cache`, for seeding.
+    pub fn with_read_cache(mut self, cache: ReadCache) -> Self {
+        self.read_cache = Some(cache);
+        self
+    }
+
+    /// How well the read cache does, `None` without one.
+    pub fn read_cache_stats(&self) -> Option<CacheStats> {
+        self.read_cache.as_ref().map(ReadCache::stats)
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    /// Check `data` against the hash of the piece at `index` and write it.
+    ///
+    /// Returns once the piece is as durable as configured, only then may it
+    /// be advertised to peers or recorded as complete.
+    pub fn write_piece(
+        &self,
+        info: &Info,
+        index: u32,
+        data: &[u8],
+    ) -> Result<CommittedPiece, WriteError> {
+        let digest = self.sha1(Cow::Borrowed(data));
+        if info.pieces().get(index as usize) != Some(&digest)
+            || data.len() != info.piece_len(index as usize)
+        {
+            return Err(WriteError::HashMismatch);
+        }
+
+        let spans = info.piece_spans(index as usize);
+        self.invalidate(index);
+        self.write_spans(info, &spans, data)?;
+        self.flush_spans(info, &spans)?;
+
+        if self.durability == Durability::Paranoid {
+            let read_back = self.read_stored(info, &spans)?;
+            if self.sha1(Cow::Owned(read_back)) != digest {
+                return Err(WriteError::ReadBackMismatch);
+            }
+        }
+
+        Ok(CommittedPiece {
+            index,
+            len: data.len() as u64,
+        })
+    }
+
+    /// Write a block of the piece at `index` as it arrives, before the
+    /// piece is complete. A block crossing the end of a file is split
+    /// between it and the next. `commit_blocks` checks the piece once every
+    /// block is written.
+    pub fn write_block(&self, info: &Info, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let spans = info
+            .block_spans(index as usize, begin, data.len() as u32)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "block outside the piece")
+            })?;
+        self.invalidate(index);
+        self.write_spans(info, &spans, data)
+    }
+
+    /// Check the piece at `index` written with `write_block` against its
+    /// hash and make it as durable as configured.
+    pub fn commit_blocks(&self, info: &Info, index: u32) -> Result<CommittedPiece, WriteError> {
+        let len = info.piece_len(index as usize);
+        let spans = info.piece_spans(index as usize);
+        let data = self.read_stored(info, &spans)?;
+        if info.pieces().get(index as usize) != Some(&self.sha1(Cow::Owned(data))) {
+            return Err(WriteError::HashMismatch);
+        }
+
+        self.flush_spans(info, &spans)?;
+
+        Ok(CommittedPiece {
+            index,
+            len: len as u64,
+        })
+    }
+
+    /// The SHA-1 digest of `data`, on the hash pool if there is one.
+    fn sha1(&self, data: Cow<'_, [u8]>) -> [u8; 20] {
+        match &self.hash_pool {
+            Some(pool) => pool.sha1(data.into_owned()).0,
+            None => sha1_smol::Sha1::from(&data).digest().bytes(),
+        }
+    }
+
+    /// Read `len` bytes at `begin` in the piece at `index`, e.g. to serve a
+    /// peer's request, across as many files as they span. With a read
+    /// cache the whole piece is read and kept for the blocks that follow.
+    pub fn read_block(&self, info: &Info, index: u32, begin: u32, len: u32) -> io::Result<Vec<u8>> {
+        let spans = info
+            .block_spans(index as usize, begin, len)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "block outside the piece")
+            })?;
+        let Some(cache) = &self.read_cache else {
+            return self.read_stored(info, &spans);
+        };
+
+        let piece = match cache.get(index) {
+            Some(piece) => piece,
+            None => cache.insert(
+                index,
+                self.read_stored(info, &info.piece_spans(index as usize))?,
+            ),
+        };
+        let range = begin as usize..(begin + len) as usize;
+        Ok(piece[range].to_vec())
+    }
+
+    /// Read the piece at `index` into the read cache ahead of a peer asking
+    /// for it, unless it is there already. Does nothing without a cache.
+    pub fn prefetch(&self, info: &Info, index: u32) -> io::Result<()> {
+        let Some(cache) = &self.read_cache else {
+            return Ok(());
+        };
+        if index as usize >= info.pieces().len() || cache.contains(index) {
+            return Ok(());
+        }
+        cache.insert(
+            index,
+            self.read_stored(info, &info.piece_spans(index as usize))?,
+        );
+        cache.record_prefetch();
+        Ok(())
+    }
+
+    /// Read `spans` from the storage in one batch, past the read cache.
+    fn read_stored(&self, info: &Info, spans: &[FileSpan]) -> io::Result<Vec<u8>> {
+        let attributes = info.file_attributes();
+        let mut data = vec![0; spans.iter().map(|span| span.len as usize).sum()];
+        let mut reads = Vec::with_capacity(spans.len());
+        let mut rest = data.as_mut_slice();
+        for span in spans {
+            let (buf, tail) = rest.split_at_mut(span.len as usize);
+            rest = tail;
+            // Pad files are zeros and never stored
+            if !attributes[span.file_index].padding {
+                reads.push(BlockRead {
+                    file: span.file_index,
+                    offset: span.offset,
+                    buf,
+                });
+            }
+        }
+        self.storage.read_blocks(&mut reads)?;
+        Ok(data)
+    }
+
+    /// Create every file of the torrent, in its directories, and reserve
+    /// room for it before downloading.
+    pub fn preallocate(&self, info: &Info) -> io::Result<()> {
+        let lengths = info.file_lengths();
+        for (index, attributes) in info.file_attributes().into_iter().enumerate() {
+            if !attributes.padding && !attributes.symlink {
+                self.storage.preallocate(index, lengths[index] as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many bytes the torrent's files take up on disk, less than what
+    /// was downloaded or preallocated while they are sparse. Pad files
+    /// are never stored.
+    pub fn allocated(&self, info: &Info) -> io::Result<u64> {
+        let mut allocated = 0;
+        for (index, attributes) in info.file_attributes().into_iter().enumerate() {
+            if !attributes.padding {
+                allocated += self.storage.allocated(index)?;
+            }
+        }
+        Ok(allocated)
+    }
+
+    /// Drop the piece at `index` from the read cache before it changes.
+    fn invalidate(&self, index: u32) {
+        if let Some(cache) = &self.read_cache {
+            cache.invalidate(index);
+        }
+    }
+
+    /// Write `data` into `spans` in one batch, skipping pad files.
+    fn write_spans(&self, info: &Info, spans: &[FileSpan], data: &[u8]) -> io::Result<()> {
+        let attributes = info.file_attributes();
+        let mut writes = Vec::with_capacity(spans.len());
+        let mut written = 0;
+        for span in spans {
+            let end = written + span.len as usize;
+            if !attributes[span.file_index].padding {
+                writes.push(BlockWrite {
+                    file: span.file_index,
+                    offset: span.offset,
+                    data: &data[written..end],
+                });
+            }
+            written = end;
+        }
+        self.storage.write_blocks(&writes)
+    }
+
+    /// Make every file `spans` touch as durable as configured, skipping
+    /// pad files. Even `Fast` hands the data cached for them to the OS.
+    fn flush_spans(&self, info: &Info, spans: &[FileSpan]) -> io::Result<()> {
+        let attributes = info.file_attributes();
+        for span in spans {
+            if attributes[span.file_index].padding {
+                continue;
+            }
+            match self.durability {
+                Durability::Fast => self.storage.write_back(span.file_index)?,
+                Durability::Safe | Durability::Paranoid => self.storage.flush(span.file_index)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Once every piece is written, e.g. create symlinks and mark
+    /// executables as such (BEP 47). Pad files are left out entirely.
+    pub fn finish(&self, info: &Info) -> io::Result<()> {
+        self.storage.finish(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta_info::MetaInfo;
+    use std::path::Path;
+
+    const PIECE_LENGTH: usize = 16;
+
+    /// `a` (10), `empty` (0), `b` (20) and `c` (5): piece 0 crosses from `a`
+    /// over `empty` into `b`, piece 1 from `b` into `c`, and the last piece
+    /// is only 3 bytes.
+    const FILES: [(&str, usize); 4] = [("a", 10), ("empty", 0), ("b", 20), ("c", 5)];
+
+    fn data() -> Vec<u8> {
+        let total = FILES.iter().map(|(_, length)| length).sum();
+        (0..total).map(|byte| byte as u8).collect()
+    }
+
+    fn torrent(data: &[u8]) -> MetaInfo {
+        let mut info = b"d5:filesl".to_vec();
+        for (path, length) in FILES {
+            info.extend(format!("d6:lengthi{length}e4:pathl{}:{path}ee", path.len()).bytes());
+        }
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| sha1_smol::Sha1::from(piece).digest().bytes())
+            .collect();
+        info.extend(format!("e4:name5:multi12:piece lengthi{PIECE_LENGTH}e6:pieces").bytes());
+        info.extend(format!("{}:", pieces.len()).bytes());
+        info.extend(pieces);
+        info.push(b'e');
+
+        let mut torrent = b"d8:announce0:4:info".to_vec();
+        torrent.extend(info);
+        torrent.push(b'e');
+        MetaInfo::from_bytes(&torrent).unwrap()
+    }
+
+    /// A directory of its own under the system's temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("torrent-disk-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn span(file_index: usize, offset: u64, len: u64) -> FileSpan {
+        FileSpan {
+            file_index,
+            offset,
+            len,
+        }
+    }
+
+    #[test]
+    fn block_spans_split_across_files_and_skip_empty_ones() {
+        let torrent = torrent(&data());
+        let info = torrent.info();
+
+        assert_eq!(
+            info.block_spans(0, 8, 8),
+            Some(vec![span(0, 8, 2), span(2, 0, 6)])
+        );
+        assert_eq!(
+            info.block_spans(1, 0, 16),
+            Some(vec![span(2, 6, 14), span(3, 0, 2)])
+        );
+        assert_eq!(info.block_spans(0, 0, 10), Some(vec![span(0, 0, 10)]));
+    }
+
+    #[test]
+    fn block_spans_of_the_short_last_piece() {
+        let torrent = torrent(&data());
+        let info = torrent.info();
+
+        assert_eq!(info.piece_len(2), 3);
+        assert_eq!(info.block_spans(2, 0, 3), Some(vec![span(3, 2, 3)]));
+        assert_eq!(info.block_spans(2, 1, 3), None);
+        assert_eq!(info.block_spans(3, 0, 1), None);
+    }
+
+    #[test]
+    fn blocks_written_across_files_read_back() {
+        let data = data();
+        let torrent = torrent(&data);
+        let info = torrent.info();
+        let dir = TempDir::new("roundtrip");
+        let writer = PieceWriter::new(info, dir.path().to_owned(), Durability::Safe);
+
+        for (index, piece) in data.chunks(PIECE_LENGTH).enumerate() {
+            let index = index as u32;
+            // Two blocks per piece, the first of piece 0 ends inside `a`
+            let (first, second) = piece.split_at(piece.len().min(8));
+            writer.write_block(info, index, 0, first).unwrap();
+            writer
+                .write_block(info, index, first.len() as u32, second)
+                .unwrap();
+            let committed = writer.commit_blocks(info, index).unwrap();
+            assert_eq!(committed.index(), index);
+            assert_eq!(committed.len(), piece.len() as u64);
+        }
+
+        assert_eq!(writer.read_block(info, 0, 6, 10).unwrap(), &data[6..16]);
+        assert_eq!(writer.read_block(info, 1, 12, 4).unwrap(), &data[28..32]);
+        assert_eq!(writer.read_block(info, 2, 0, 3).unwrap(), &data[32..]);
+
+        let read = |file: &str| std::fs::read(dir.path().join("multi").join(file)).unwrap();
+        assert_eq!(read("a"), &data[..10]);
+        assert_eq!(read("b"), &data[10..30]);
+        assert_eq!(read("c"), &data[30..]);
+    }
+
+    #[test]
+    fn commit_blocks_checks_the_piece_hash() {
+        let data = data();
+        let torrent = torrent(&data);
+        let info = torrent.info();
+        let dir = TempDir::new("mismatch");
+        let writer = PieceWriter::new(info, dir.path().to_owned(), Durability::Fast);
+
+        let mut piece = data[..PIECE_LENGTH].to_vec();
+        piece[3] ^= 0xff;
+        writer.write_block(info, 0, 0, &piece).unwrap();
+        assert!(matches!(
+            writer.commit_blocks(info, 0),
+            Err(WriteError::HashMismatch)
+        ));
+
+        writer
+            .write_block(info, 0, 0, &data[..PIECE_LENGTH])
+            .unwrap();
+        assert!(writer.commit_blocks(info, 0).is_ok());
+    }
+
+    #[test]
+    fn blocks_outside_the_piece_are_refused() {
+        let data = data();
+        let torrent = torrent(&data);
+        let info = torrent.info();
+        let dir = TempDir::new("outside");
+        let writer = PieceWriter::new(info, dir.path().to_owned(), Durability::Fast);
+
+        let err = writer.write_block(info, 2, 0, &[0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = writer.read_block(info, 0, 10, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::proxy::{blocking_http_client_builder, Traffic};
+
+/// How long a successful lookup is reused. The system resolver does not
+/// expose record TTLs, so this is an upper bound rather than the real TTL.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a failed lookup is remembered, so a dead tracker host does not
+/// cost a blocking lookup on every announce.
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Caches DNS lookups of tracker hosts so repeated announces and scrapes
+/// don't each block on the resolver.
+#[derive(Clone)]
+pub struct DnsCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Arc::default(),
+        }
+    }
+
+    /// Resolve `host`, using the cached addresses if they have not expired.
+    pub fn lookup(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.lock().unwrap().get(host) {
+            if entry.expires > now {
+                return if entry.addrs.is_empty() {
+                    Err(std::io::ErrorKind::NotFound.into())
+                } else {
+                    Ok(entry.addrs.clone())
+                };
+            }
+        }
+
+        // Port 0 is replaced with the port of the URL by the HTTP client.
+        let result = (host, 0)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>());
+        let (addrs, ttl) = match &result {
+            Ok(addrs) if !addrs.is_empty() => (addrs.clone(), self.ttl),
+            _ => (Vec::new(), NEGATIVE_TTL.min(self.ttl)),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        entries.insert(
+            host.to_owned(),
+            Entry {
+                addrs: addrs.clone(),
+                expires: now + ttl,
+            },
+        );
+
+        match result {
+            Ok(_) if addrs.is_empty() => Err(std::io::ErrorKind::NotFound.into()),
+            Ok(_) => Ok(addrs),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Resolve for DnsCache {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.clone();
+        Box::pin(async move {
+            // The system resolver blocks, keep it off the async workers.
+            let host = name.as_str().to_owned();
+            let addrs = tokio::task::spawn_blocking(move || cache.lookup(&host)).await??;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The HTTP client shared by tracker announces, scrapes and .torrent
+/// downloads, resolving hosts through a `DnsCache`.
+pub(crate) fn http_client() -> &'static reqwest::blocking::Client {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        blocking_http_client_builder(Traffic::Trackers)
+            .dns_resolver(Arc::new(DnsCache::default()))
+            .build()
+            .expect("failed to build http client")
+    })
+}
+
+/// Like `http_client`, but redirects are returned instead of followed, so
+/// announces can follow them with their own hop limit and query.
+pub(crate) fn tracker_http_client() -> &'static reqwest::blocking::Client {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        blocking_http_client_builder(Traffic::Trackers)
+            .dns_resolver(Arc::new(DnsCache::default()))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build http client")
+    })
+}
@@ -0,0 +1,60 @@
+use crate::{
+    bencode,
+    meta_info::MetaInfo,
+    peer::{Handshake, Message, HANDSHAKE_LEN},
+};
+
+// Entry points for the cargo-fuzz targets in `fuzz/`, built with the
+// `fuzzing` feature. Each feeds arbitrary bytes to a parser that reads
+// untrusted input from the network and panics if what it decoded breaks an
+// invariant, e.g. doesn't encode back to what was read. Returning is
+// success, whether the input parsed or not.
+
+/// The bencode decoder: whatever decodes encodes to something that decodes
+/// to the same encoding again.
+pub fn bencode(data: &[u8]) {
+    let Ok(node) = bencode::decode(data) else {
+        return;
+    };
+    assert_eq!(&data[node.span.clone()], node.raw());
+
+    let encoded = node.value.encode();
+    let decoded = bencode::decode(&encoded).expect("an encoded value decodes");
+    assert_eq!(decoded.value.encode(), encoded);
+}
+
+/// The handshake: one that is read is written back byte for byte.
+pub fn handshake(data: &[u8]) {
+    if let Ok(handshake) = Handshake::read_from(&mut &data[..]) {
+        assert_eq!(handshake.to_bytes()[..], data[..HANDSHAKE_LEN]);
+    }
+}
+
+/// The message decoder: a message that is read encodes to a message that
+/// decodes to the same one.
+pub fn message(data: &[u8]) {
+    let Ok(message) = Message::read_from(&mut &data[..]) else {
+        return;
+    };
+    let encoded = message.to_bytes();
+    let decoded = Message::read_from(&mut &encoded[..]).expect("an encoded message decodes");
+    assert_eq!(decoded, message);
+}
+
+/// The .torrent parser: the files of a torrent that parses never lead out
+/// of the download directory.
+pub fn metainfo(data: &[u8]) {
+    let Ok(torrent) = MetaInfo::from_bytes(data) else {
+        return;
+    };
+    let info = torrent.info();
+    for path in info.file_paths() {
+        assert!(path.components().all(|component| matches!(
+            component,
+            std::path::Component::Normal(_)
+        ) || component == std::path::Component::CurDir));
+    }
+    for index in 0..info.piece_count() {
+        info.piece_spans(index);
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+// Hashing is what keeps a core busy while checking data, a recheck of a
+// large torrent on a single thread is bound by SHA-1 long before the disk.
+// One set of threads hashes for every torrent of the process: rechecks hand
+// it their pieces and go on reading the next, and pieces completed by peers
+// are verified on it as well, so a burst of them doesn't take a core each
+// away from everything else.
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads hashing pieces, for as long as a clone of the
+/// pool is around. Cheap to clone, every clone shares the same threads.
+#[derive(Clone)]
+pub struct HashPool {
+    jobs: mpsc::Sender<Job>,
+    threads: usize,
+}
+
+impl fmt::Debug for HashPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashPool")
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HashPool {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl HashPool {
+    /// Start `threads` hashing threads, `0` is one per core.
+    pub fn new(threads: usize) -> Self {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for index in 0..threads {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("hash-{index}"))
+                .spawn(move || loop {
+                    // The lock is released before the job runs
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        // Every clone of the pool is gone
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn a hashing thread");
+        }
+        Self { jobs, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Run `job` on the next free thread, after the jobs queued before it.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // The threads only stop once every sender is gone
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    /// The SHA-1 digest of `data`, hashed on the pool. The data is handed
+    /// back along with it.
+    pub fn sha1(&self, data: Vec<u8>) -> ([u8; 20], Vec<u8>) {
+        let (sender, digest) = mpsc::sync_channel(1);
+        self.execute(move || {
+            let _ = sender.send((sha1_smol::Sha1::from(&data).digest().bytes(), data));
+        });
+        digest.recv().expect("a hashing thread panicked")
+    }
+}
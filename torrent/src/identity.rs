@@ -0,0 +1,86 @@
+use rand::Rng;
+
+use crate::{discovery::Discovery, tracker::random_peer_id};
+
+// What a session gives away about itself. Normally the peer id and the
+// extension handshake tell peers which client and version we are, and
+// announces may carry our other addresses.
+//
+// Anonymous mode leaves all of that out and turns off the DHT, PEX and LSD,
+// which broadcast our presence beyond the trackers. HTTP requests carry no
+// User-Agent in either mode. The address peers and trackers see us at is
+// not hidden, that takes a proxy or a VPN.
+
+/// How the session identifies itself to peers and trackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    peer_id: [u8; 20],
+    anonymous: bool,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Identity {
+    /// Identify as flud, with a `-FL0100-` peer id.
+    pub fn new() -> Self {
+        Self {
+            peer_id: to_peer_id(&random_peer_id()),
+            anonymous: false,
+        }
+    }
+
+    /// Identify as nothing in particular: the peer id gets a random
+    /// Azureus style prefix, e.g. `-QX4821-`, picked anew every session.
+    pub fn anonymous() -> Self {
+        let mut rng = rand::thread_rng();
+        let letters: String = (0..2).map(|_| rng.gen_range('A'..='Z')).collect();
+        let digits: String = (0..4).map(|_| rng.gen_range('0'..='9')).collect();
+        let random: String = (&mut rng)
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        Self {
+            peer_id: to_peer_id(&format!("-{letters}{digits}-{random}")),
+            anonymous: true,
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// The peer id as trackers get it, it is always alphanumeric.
+    pub fn peer_id_str(&self) -> &str {
+        std::str::from_utf8(&self.peer_id).expect("peer ids are ascii")
+    }
+
+    /// The client name sent in the extension handshake, none when anonymous.
+    pub fn client_name(&self) -> Option<&'static str> {
+        (!self.anonymous).then_some(crate::CLIENT_NAME)
+    }
+
+    /// The peer sources `discovery` allows that this identity may use.
+    pub fn discovery(&self, discovery: Discovery) -> Discovery {
+        if self.anonymous {
+            Discovery::none()
+        } else {
+            discovery
+        }
+    }
+}
+
+fn to_peer_id(peer_id: &str) -> [u8; 20] {
+    peer_id
+        .as_bytes()
+        .try_into()
+        .expect("peer ids are 20 bytes")
+}
@@ -0,0 +1,211 @@
+use std::{fmt, str::FromStr};
+
+use crate::tracker::url_encode_bytes;
+
+/// The SHA-1 hash of a torrent's bencoded info dictionary, which identifies
+/// the torrent everywhere: trackers, the DHT, peer handshakes and magnet links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash([u8; 20]);
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InfoHashError {
+    /// Neither 40 hex characters nor 32 base32 characters.
+    InvalidLength,
+    InvalidCharacter,
+}
+
+impl InfoHash {
+    pub const fn new(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Lowercase hex, as used in magnet links and state file names.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Percent-encode the raw bytes for a tracker query string, every byte
+    /// outside `0-9a-zA-Z.-_~` becomes `%XX`.
+    pub fn url_encoded(&self) -> String {
+        url_encode_bytes(&self.0)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, InfoHashError> {
+        if hex.len() != 40 {
+            return Err(InfoHashError::InvalidLength);
+        }
+        let bytes = hex::decode(hex).map_err(|_| InfoHashError::InvalidCharacter)?;
+        Ok(Self(
+            bytes.try_into().expect("40 hex characters are 20 bytes"),
+        ))
+    }
+
+    /// Parse the unpadded RFC 4648 base32 form some magnet links use.
+    pub fn from_base32(base32: &str) -> Result<Self, InfoHashError> {
+        if base32.len() != 32 {
+            return Err(InfoHashError::InvalidLength);
+        }
+        let bytes = decode_base32(base32).ok_or(InfoHashError::InvalidCharacter)?;
+        Ok(Self(
+            bytes.try_into().expect("32 base32 characters are 20 bytes"),
+        ))
+    }
+}
+
+impl FromStr for InfoHash {
+    type Err = InfoHashError;
+
+    /// Parse either the 40 character hex or the 32 character base32 form.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            40 => Self::from_hex(s),
+            32 => Self::from_base32(s),
+            _ => Err(InfoHashError::InvalidLength),
+        }
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<InfoHash> for [u8; 20] {
+    fn from(info_hash: InfoHash) -> Self {
+        info_hash.0
+    }
+}
+
+impl From<sha1_smol::Digest> for InfoHash {
+    fn from(digest: sha1_smol::Digest) -> Self {
+        Self(digest.bytes())
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The SHA-256 of a v2 (BEP 52) info dictionary.
+///
+/// Trackers and the peer handshake only have room for 20 bytes, there the
+/// hash is truncated, see `truncated`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHashV2([u8; 32]);
+
+impl InfoHashV2 {
+    pub const fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, InfoHashError> {
+        if hex.len() != 64 {
+            return Err(InfoHashError::InvalidLength);
+        }
+        let bytes = hex::decode(hex).map_err(|_| InfoHashError::InvalidCharacter)?;
+        Ok(Self(
+            bytes.try_into().expect("64 hex characters are 32 bytes"),
+        ))
+    }
+
+    /// The first 20 bytes, which identify the v2 swarm to trackers and peers.
+    pub fn truncated(&self) -> InfoHash {
+        let mut bytes = [0; 20];
+        bytes.copy_from_slice(&self.0[..20]);
+        InfoHash(bytes)
+    }
+}
+
+impl fmt::Display for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl From<[u8; 32]> for InfoHashV2 {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Which hashes of a torrent, and with them which piece hashes, a swarm uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashVersion {
+    V1,
+    V2,
+}
+
+/// Every info hash a torrent is known by. Hybrid torrents (BEP 52) are in
+/// two swarms at once, one per hash, sharing the same data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoHashes {
+    pub v1: InfoHash,
+    pub v2: Option<InfoHashV2>,
+}
+
+impl InfoHashes {
+    /// The 20 byte hashes to announce and handshake under, v1 first.
+    pub fn swarms(&self) -> Vec<InfoHash> {
+        std::iter::once(self.v1)
+            .chain(self.v2.map(|v2| v2.truncated()))
+            .collect()
+    }
+
+    /// Which swarm `info_hash` (e.g. from a peer's handshake) belongs to,
+    /// `None` if it isn't this torrent.
+    pub fn version_of(&self, info_hash: &InfoHash) -> Option<HashVersion> {
+        if *info_hash == self.v1 {
+            Some(HashVersion::V1)
+        } else if self.v2.is_some_and(|v2| v2.truncated() == *info_hash) {
+            Some(HashVersion::V2)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decode unpadded RFC 4648 base32 (case-insensitive).
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
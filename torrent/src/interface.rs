@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+// VPN users want every peer, tracker and DHT packet to go through the
+// tunnel, and none at all while it is down rather than out of the default
+// route. Sockets bound to the tunnel's device or address get exactly that
+// from the kernel: once it disappears, connecting and sending fail.
+//
+// Router port mapping (UPnP, NAT-PMP) is only LAN traffic and is not bound.
+// Host names are still resolved by the system resolver.
+//
+// On networks where one IP version is broken or not allowed, the session
+// can stick to the other: sockets of the other family refuse to bind, so no
+// listener, DHT node or connection of that family is ever made.
+
+/// Where outgoing traffic is sent from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutgoingInterface {
+    /// Wherever the routing table sends it.
+    #[default]
+    Any,
+    /// A network interface by name, e.g. `tun0`. Only supported on Linux.
+    Device(String),
+    /// One of the host's addresses, e.g. the address a VPN assigned.
+    Address(IpAddr),
+}
+
+impl FromStr for OutgoingInterface {
+    type Err = std::convert::Infallible;
+
+    /// An address, an interface name, or nothing for `Any`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Ok(if s.is_empty() {
+            OutgoingInterface::Any
+        } else if let Ok(ip) = s.parse() {
+            OutgoingInterface::Address(ip)
+        } else {
+            OutgoingInterface::Device(s.to_owned())
+        })
+    }
+}
+
+impl fmt::Display for OutgoingInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutgoingInterface::Any => write!(f, "any"),
+            OutgoingInterface::Device(name) => write!(f, "{name}"),
+            OutgoingInterface::Address(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+static OUTGOING: OnceLock<OutgoingInterface> = OnceLock::new();
+
+/// Send all outgoing traffic of the process from `interface`. Has to be
+/// called before the first connection, which otherwise settles on `Any`.
+///
+/// Fails when the platform can't bind to an interface by name, or when it
+/// was already set to something else.
+pub fn set_outgoing(interface: OutgoingInterface) -> io::Result<()> {
+    if matches!(interface, OutgoingInterface::Device(_)) && !DEVICE_SUPPORTED {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to an interface by name is only supported on Linux, use its address",
+        ));
+    }
+    if *OUTGOING.get_or_init(|| interface.clone()) != interface {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the outgoing interface is already set",
+        ));
+    }
+    Ok(())
+}
+
+/// The interface outgoing traffic is sent from.
+pub fn outgoing() -> &'static OutgoingInterface {
+    OUTGOING.get_or_init(OutgoingInterface::default)
+}
+
+/// Which IP versions the session uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFamily {
+    #[default]
+    Both,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl AddressFamily {
+    pub fn allows_ipv4(&self) -> bool {
+        *self != AddressFamily::Ipv6Only
+    }
+
+    pub fn allows_ipv6(&self) -> bool {
+        *self != AddressFamily::Ipv4Only
+    }
+
+    /// The address that binds a socket to the one IP version allowed,
+    /// `None` if both are.
+    fn unspecified(&self) -> Option<IpAddr> {
+        match self {
+            AddressFamily::Both => None,
+            AddressFamily::Ipv4Only => Some(std::net::Ipv4Addr::UNSPECIFIED.into()),
+            AddressFamily::Ipv6Only => Some(std::net::Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
+
+    /// Whether traffic to or from `ip` may be sent. IPv4-mapped IPv6
+    /// addresses count as IPv4.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => self.allows_ipv4(),
+            IpAddr::V6(_) => self.allows_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::Both => "IPv4 and IPv6",
+            AddressFamily::Ipv4Only => "IPv4 only",
+            AddressFamily::Ipv6Only => "IPv6 only",
+        })
+    }
+}
+
+static ADDRESS_FAMILY: OnceLock<AddressFamily> = OnceLock::new();
+
+/// Only use the IP versions `family` allows for the rest of the process.
+/// Has to be called before the first socket, which otherwise settles on
+/// `Both`.
+///
+/// Fails when the outgoing interface is an address of the other family, or
+/// when it was already set to something else.
+pub fn set_address_family(family: AddressFamily) -> io::Result<()> {
+    if let Some(OutgoingInterface::Address(ip)) = OUTGOING.get() {
+        if !family.allows(*ip) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the outgoing address {ip} is not allowed with {family}"),
+            ));
+        }
+    }
+    if *ADDRESS_FAMILY.get_or_init(|| family) != family {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the address family is already set",
+        ));
+    }
+    Ok(())
+}
+
+/// The IP versions the session uses.
+pub fn address_family() -> AddressFamily {
+    *ADDRESS_FAMILY.get_or_init(AddressFamily::default)
+}
+
+const DEVICE_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "fuchsia",
+    target_os = "linux"
+));
+
+impl OutgoingInterface {
+    /// Fail with `NotConnected` if the interface is gone, e.g. the VPN dropped.
+    pub fn check(&self) -> io::Result<()> {
+        let down = |what: String| {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("{what} is down, refusing to send traffic elsewhere"),
+            ))
+        };
+        match self {
+            OutgoingInterface::Any => Ok(()),
+            OutgoingInterface::Device(name) => {
+                if DEVICE_SUPPORTED && !std::path::Path::new("/sys/class/net").join(name).exists() {
+                    return down(format!("interface {name}"));
+                }
+                Ok(())
+            }
+            // Binding fails once the address is no longer assigned
+            OutgoingInterface::Address(ip) => match UdpSocket::bind((*ip, 0)) {
+                Err(err) if err.kind() == io::ErrorKind::AddrNotAvailable => {
+                    down(format!("address {ip}"))
+                }
+                result => result.map(|_| ()),
+            },
+        }
+    }
+
+    /// Bind `socket` to the interface and `local`, whose IP is only used
+    /// for `Any` and `Device`.
+    pub fn bind(&self, socket: &Socket, local: SocketAddr) -> io::Result<()> {
+        let allowed = address_family();
+        if !allowed.allows(local.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} traffic is turned off ({allowed})", family(local)),
+            ));
+        }
+        self.check()?;
+        match self {
+            OutgoingInterface::Any => socket.bind(&local.into()),
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            OutgoingInterface::Device(name) => {
+                socket.bind_device(Some(name.as_bytes()))?;
+                socket.bind(&local.into())
+            }
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            OutgoingInterface::Device(_) => Err(io::ErrorKind::Unsupported.into()),
+            OutgoingInterface::Address(ip) => {
+                if ip.is_ipv4() != local.is_ipv4() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("{ip} can't reach {} addresses", family(local)),
+                    ));
+                }
+                socket.bind(&SocketAddr::new(*ip, local.port()).into())
+            }
+        }
+    }
+
+    /// Connect to `addr` from the interface.
+    pub fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        self.bind(&socket, unspecified(addr))?;
+        socket.connect_timeout(&addr.into(), timeout)?;
+        Ok(socket.into())
+    }
+
+    /// A UDP socket on the interface for talking to `remote`.
+    pub fn udp_socket(&self, remote: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(remote),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        self.bind(&socket, unspecified(remote))?;
+        Ok(socket.into())
+    }
+
+    /// Make HTTP clients connect from the interface, over the IP versions
+    /// in use.
+    pub fn http(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self {
+            OutgoingInterface::Any => match address_family().unspecified() {
+                Some(ip) => builder.local_address(ip),
+                None => builder,
+            },
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            OutgoingInterface::Device(name) => builder.interface(name),
+            // `set_outgoing` refuses devices elsewhere
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            OutgoingInterface::Device(_) => builder,
+            OutgoingInterface::Address(ip) => builder.local_address(*ip),
+        }
+    }
+
+    /// `http` for blocking clients.
+    pub fn blocking_http(
+        &self,
+        builder: reqwest::blocking::ClientBuilder,
+    ) -> reqwest::blocking::ClientBuilder {
+        match self {
+            OutgoingInterface::Any => match address_family().unspecified() {
+                Some(ip) => builder.local_address(ip),
+                None => builder,
+            },
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            OutgoingInterface::Device(name) => builder.interface(name),
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            OutgoingInterface::Device(_) => builder,
+            OutgoingInterface::Address(ip) => builder.local_address(*ip),
+        }
+    }
+}
+
+/// The unspecified address of `addr`'s family, with port 0.
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+fn family(addr: SocketAddr) -> &'static str {
+    if addr.is_ipv4() {
+        "IPv4"
+    } else {
+        "IPv6"
+    }
+}
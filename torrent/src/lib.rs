@@ -1,12 +1,81 @@
 use serde::{
     de::{self, Deserializer, Unexpected},
-    Deserialize,
+    Deserialize, Serializer,
 };
 
+pub mod bencode;
+pub mod cache;
+pub mod dht;
+pub mod discovery;
+pub mod disk;
+pub mod dns;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod hash_pool;
+pub mod identity;
+pub mod info_hash;
+pub mod interface;
+pub mod lifecycle;
+pub mod magnet;
+pub mod memory;
+pub mod merkle;
 pub mod meta_info;
+pub mod natpmp;
+pub mod operation;
+pub mod peer;
+pub mod proxy;
+pub mod rate_limit;
+pub mod share_limit;
+pub mod source;
+pub mod stats;
+pub mod storage;
+pub mod swarm;
+pub mod timeline;
 pub mod tracker;
+pub mod units;
+pub mod update;
+pub mod upnp;
+pub mod verify;
+pub mod web_seed;
 
-pub fn bool_from_int<'de, D>(deserializer: D) -> Result<bool, D::Error>
+/// The types most users of the crate need, `use torrent::prelude::*`.
+///
+/// There is no `Torrent`, `Session` or `TorrentHandle` to re-export yet:
+/// running torrents is up to the user of the crate, flud's daemon does it
+/// from `MetaInfo`, `PieceWriter` and the tracker and peer modules. They
+/// belong here once the crate has them.
+pub mod prelude {
+    pub use crate::{
+        bencode::BencodeError,
+        disk::{PieceWriter, WriteError},
+        info_hash::{InfoHash, InfoHashError, InfoHashes},
+        magnet::{MagnetLink, MagnetLinkError},
+        meta_info::{Info, MetaInfo, MetaInfoError},
+        natpmp::NatPmpError,
+        peer::{
+            mse::{EncryptionMode, MseError},
+            PeerError,
+        },
+        proxy::ProxyError,
+        source::{ResolvedSource, SourceError, SourceResolver, TorrentSource},
+        storage::{FileStorage, Storage},
+        tracker::client::{TrackerClient, TrackerError},
+        upnp::UpnpError,
+        web_seed::{WebSeed, WebSeedError},
+    };
+}
+
+/// How this client identifies itself to peers, e.g. in the extension handshake.
+pub const CLIENT_NAME: &str = concat!("flud ", env!("CARGO_PKG_VERSION"));
+
+pub(crate) fn bool_to_int<S>(value: &bool, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u8(*value as u8)
+}
+
+pub(crate) fn bool_from_int<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -37,8 +106,6 @@ where
             )),
             None => Ok(None), // If the field is missing, return `None`
         },
-        Err(err) => {
-            todo!()
-        }
+        Err(err) => Err(err),
     }
 }
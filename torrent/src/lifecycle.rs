@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+/// Where a torrent should stop on its own, chosen when it is added.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StopCondition {
+    /// Download everything and keep seeding.
+    #[default]
+    Never,
+    /// Stop as soon as the metadata of a magnet link has been received, so
+    /// the user can pick files before any data is downloaded.
+    MetadataReceived,
+    /// Stop once the selected files are complete instead of seeding them.
+    SelectedFilesComplete,
+}
+
+/// The phases a torrent moves through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Added from a magnet link, waiting for the info dictionary from peers.
+    FetchingMetadata,
+    Downloading,
+    Seeding,
+    /// Stopped by its stop condition or by the user.
+    Stopped(StopReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StopReason {
+    /// The stop condition was reached.
+    Condition(StopCondition),
+    /// Seeding reached its ratio or seed time limit.
+    ShareLimit,
+    User,
+}
+
+/// Something that happened to a torrent that may move it to another phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LifecycleEvent {
+    MetadataReceived,
+    /// Every selected file has been downloaded and verified.
+    SelectedFilesComplete,
+    Stop,
+    /// The share limit was reached while seeding.
+    ShareLimitReached,
+    /// Start again after being stopped, e.g. once the user picked files.
+    Resume,
+}
+
+/// The per-torrent state machine. The stop condition is an explicit target
+/// state: reaching it moves the torrent to `Phase::Stopped` instead of the
+/// phase it would normally go to next.
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    phase: Phase,
+    stop_condition: StopCondition,
+    has_metadata: bool,
+    complete: bool,
+}
+
+impl Lifecycle {
+    /// `has_metadata` is false for torrents added from a magnet link or info hash.
+    pub fn new(has_metadata: bool, stop_condition: StopCondition) -> Self {
+        let phase = if has_metadata {
+            Phase::Downloading
+        } else {
+            Phase::FetchingMetadata
+        };
+
+        Self {
+            phase,
+            stop_condition,
+            has_metadata,
+            complete: false,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn stop_condition(&self) -> StopCondition {
+        self.stop_condition
+    }
+
+    pub fn set_stop_condition(&mut self, stop_condition: StopCondition) {
+        self.stop_condition = stop_condition;
+    }
+
+    /// Whether peers should be asked for piece data.
+    pub fn wants_data(&self) -> bool {
+        self.phase == Phase::Downloading
+    }
+
+    /// Whether the torrent should be connected to the swarm at all.
+    pub fn is_active(&self) -> bool {
+        !matches!(self.phase, Phase::Stopped(_))
+    }
+
+    /// Apply `event`, returning the new phase.
+    pub fn handle(&mut self, event: LifecycleEvent) -> Phase {
+        self.phase = match (self.phase, event) {
+            (Phase::FetchingMetadata, LifecycleEvent::MetadataReceived) => {
+                self.has_metadata = true;
+                self.stop_or(StopCondition::MetadataReceived, Phase::Downloading)
+            }
+            (Phase::Downloading, LifecycleEvent::SelectedFilesComplete) => {
+                self.complete = true;
+                self.stop_or(StopCondition::SelectedFilesComplete, Phase::Seeding)
+            }
+            (Phase::Seeding, LifecycleEvent::ShareLimitReached) => {
+                Phase::Stopped(StopReason::ShareLimit)
+            }
+            (_, LifecycleEvent::Stop) => Phase::Stopped(StopReason::User),
+            (Phase::Stopped(reason), LifecycleEvent::Resume) => {
+                // A condition that has been reached once must not stop the
+                // torrent again right away.
+                if let StopReason::Condition(condition) = reason {
+                    if condition == self.stop_condition {
+                        self.stop_condition = StopCondition::Never;
+                    }
+                }
+                self.resumed_phase()
+            }
+            (phase, _) => phase,
+        };
+        self.phase
+    }
+
+    fn stop_or(&self, condition: StopCondition, next: Phase) -> Phase {
+        if self.stop_condition == condition {
+            Phase::Stopped(StopReason::Condition(condition))
+        } else {
+            next
+        }
+    }
+
+    fn resumed_phase(&self) -> Phase {
+        if !self.has_metadata {
+            Phase::FetchingMetadata
+        } else if self.complete {
+            Phase::Seeding
+        } else {
+            Phase::Downloading
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    info_hash::{InfoHash, InfoHashV2},
+    tracker::url_encode_bytes,
+};
+
+// https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format
+
+const INFO_HASH_PREFIX: &str = "urn:btih:";
+/// A multihash, `1220` marks 32 bytes of SHA-256 (BEP 52).
+const INFO_HASH_V2_PREFIX: &str = "urn:btmh:1220";
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MagnetLinkError {
+    /// The string does not start with `magnet:?`.
+    NotAMagnetLink,
+    /// The query string could not be decoded.
+    InvalidQuery,
+    /// There is no `xt=urn:btih:` parameter.
+    MissingInfoHash,
+    /// The info hash is neither 40 hex characters nor 32 base32 characters.
+    InvalidInfoHash,
+}
+
+/// A parsed `magnet:?xt=urn:btih:...` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    /// The info hash of the torrent, `xt`.
+    pub info_hash: InfoHash,
+    /// The v2 info hash of a hybrid torrent, a second `xt`.
+    pub info_hash_v2: Option<InfoHashV2>,
+    /// The display name that may be used by the client while waiting for metadata, `dn`.
+    pub display_name: Option<String>,
+    /// Tracker URLs, `tr`, in the order they appear.
+    pub trackers: Vec<String>,
+    /// Peer addresses to connect to directly, `x.pe`, as `host:port`.
+    pub peers: Vec<String>,
+    /// Web seed URLs, `ws` (BEP 19).
+    pub web_seeds: Vec<String>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetLinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetLinkError::NotAMagnetLink)?;
+
+        let Ok(params) = serde_urlencoded::from_str::<Vec<(String, String)>>(query) else {
+            return Err(MagnetLinkError::InvalidQuery);
+        };
+
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        let mut web_seeds = Vec::new();
+
+        for (key, value) in params {
+            match key.as_str() {
+                // Hybrid magnets carry both hashes, v2 only magnets are not supported
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(INFO_HASH_PREFIX) {
+                        info_hash = Some(
+                            hash.parse::<InfoHash>()
+                                .map_err(|_| MagnetLinkError::InvalidInfoHash)?,
+                        );
+                    } else if let Some(hash) = value.strip_prefix(INFO_HASH_V2_PREFIX) {
+                        info_hash_v2 = Some(
+                            InfoHashV2::from_hex(hash)
+                                .map_err(|_| MagnetLinkError::InvalidInfoHash)?,
+                        );
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                "ws" => web_seeds.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetLinkError::MissingInfoHash)?,
+            info_hash_v2,
+            display_name,
+            trackers,
+            peers,
+            web_seeds,
+        })
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt={}{}", INFO_HASH_PREFIX, self.info_hash)?;
+        if let Some(info_hash_v2) = &self.info_hash_v2 {
+            write!(f, "&xt={}{}", INFO_HASH_V2_PREFIX, info_hash_v2)?;
+        }
+
+        if let Some(name) = &self.display_name {
+            write!(f, "&dn={}", url_encode_bytes(name.as_bytes()))?;
+        }
+
+        let params = [
+            ("tr", &self.trackers),
+            ("ws", &self.web_seeds),
+            ("x.pe", &self.peers),
+        ];
+        for (key, values) in params {
+            for value in values {
+                write!(f, "&{}={}", key, url_encode_bytes(value.as_bytes()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
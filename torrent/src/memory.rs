@@ -0,0 +1,198 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// The parts of the engine whose memory use is accounted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    /// Blocks of pieces that have been received but not yet written/verified.
+    PieceBuffers,
+    /// Info dictionaries held for `ut_metadata` and magnet links.
+    MetadataCache,
+    /// The DHT routing table and stored peers.
+    DhtTables,
+    /// Per-connection send and receive buffers.
+    PeerBuffers,
+    /// Pieces kept in memory for uploading.
+    ReadCache,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [
+        Subsystem::PieceBuffers,
+        Subsystem::MetadataCache,
+        Subsystem::DhtTables,
+        Subsystem::PeerBuffers,
+        Subsystem::ReadCache,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subsystem::PieceBuffers => write!(f, "piece buffers"),
+            Subsystem::MetadataCache => write!(f, "metadata cache"),
+            Subsystem::DhtTables => write!(f, "dht tables"),
+            Subsystem::PeerBuffers => write!(f, "peer buffers"),
+            Subsystem::ReadCache => write!(f, "read cache"),
+        }
+    }
+}
+
+/// How close the accounted memory is to the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Pressure {
+    /// Below 75% of the budget.
+    Normal,
+    /// Between 75% and 100% of the budget, start shedding load.
+    High,
+    /// Over budget, shed as much as possible.
+    Critical,
+}
+
+/// A point in time copy of the accounting, e.g. for the daemon status.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryUsage {
+    /// Bytes in use per subsystem, indexed like `Subsystem::ALL`.
+    pub used: [usize; 5],
+    /// The budget in bytes, `None` if unlimited.
+    pub budget: Option<usize>,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.used.iter().sum()
+    }
+
+    pub fn get(&self, subsystem: Subsystem) -> usize {
+        self.used[subsystem.index()]
+    }
+}
+
+#[derive(Debug, Default)]
+struct Accounting {
+    used: [AtomicUsize; 5],
+    budget: AtomicUsize,
+}
+
+/// Approximate accounting of the memory used by each subsystem against a
+/// configurable budget. Cheap to clone, every clone shares the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    inner: Arc<Accounting>,
+}
+
+impl MemoryBudget {
+    /// `budget` is in bytes, `0` means unlimited.
+    pub fn new(budget: usize) -> Self {
+        let memory = Self::default();
+        memory.set_budget(budget);
+        memory
+    }
+
+    pub fn set_budget(&self, budget: usize) {
+        self.inner.budget.store(budget, Ordering::Relaxed);
+    }
+
+    pub fn budget(&self) -> Option<usize> {
+        match self.inner.budget.load(Ordering::Relaxed) {
+            0 => None,
+            budget => Some(budget),
+        }
+    }
+
+    /// Account for `bytes` used by `subsystem` until the returned guard is dropped.
+    pub fn allocate(&self, subsystem: Subsystem, bytes: usize) -> Allocation {
+        self.inner.used[subsystem.index()].fetch_add(bytes, Ordering::Relaxed);
+        Allocation {
+            memory: self.clone(),
+            subsystem,
+            bytes,
+        }
+    }
+
+    pub fn used(&self, subsystem: Subsystem) -> usize {
+        self.inner.used[subsystem.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        Subsystem::ALL.iter().map(|s| self.used(*s)).sum()
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            used: Subsystem::ALL.map(|s| self.used(s)),
+            budget: self.budget(),
+        }
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        let Some(budget) = self.budget() else {
+            return Pressure::Normal;
+        };
+
+        let total = self.total();
+        if total > budget {
+            Pressure::Critical
+        } else if total > budget / 4 * 3 {
+            Pressure::High
+        } else {
+            Pressure::Normal
+        }
+    }
+
+    /// How many requests to keep in flight per peer given the current pressure.
+    pub fn pipeline_depth(&self, preferred: usize) -> usize {
+        match self.pressure() {
+            Pressure::Normal => preferred,
+            Pressure::High => (preferred / 2).max(1),
+            Pressure::Critical => 1,
+        }
+    }
+
+    /// How large a cache may grow given the current pressure.
+    pub fn cache_limit(&self, preferred: usize) -> usize {
+        match self.pressure() {
+            Pressure::Normal => preferred,
+            Pressure::High => preferred / 2,
+            Pressure::Critical => 0,
+        }
+    }
+}
+
+/// Memory accounted to a subsystem, released when dropped.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: MemoryBudget,
+    subsystem: Subsystem,
+    bytes: usize,
+}
+
+impl Allocation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Grow or shrink the allocation, e.g. when a buffer is resized.
+    pub fn resize(&mut self, bytes: usize) {
+        let used = &self.memory.inner.used[self.subsystem.index()];
+        if bytes > self.bytes {
+            used.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            used.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.memory.inner.used[self.subsystem.index()].fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+
+// https://www.bittorrent.org/beps/bep_0052.html
+
+// v2 torrents hash every file separately as a binary merkle tree of SHA-256
+// hashes over 16 KiB blocks. Leaves past the end of the file are zero, the
+// metainfo carries the root of every file and, for files larger than a
+// piece, the layer of the tree where each node covers one piece.
+
+/// The size of the blocks the leaves of the tree are the hashes of.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+/// The hash of every 16 KiB block of `data`, the last block may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+/// The root of the tree over `leaves`, filled up to `width` (a power of
+/// two) with `pad`.
+pub fn root(leaves: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = leaves.to_vec();
+    layer.resize(width.max(1), pad);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+
+    layer[0]
+}
+
+/// The root of a tree of `width` zero leaves, what a piece past the end of
+/// a file hashes to in the piece layer.
+pub fn pad_hash(width: usize) -> Hash {
+    root(&[], width, [0; 32])
+}
+
+/// The hash of one piece of a file, `data` being that piece's bytes.
+pub fn piece_hash(data: &[u8], piece_length: usize) -> Hash {
+    root(&block_hashes(data), piece_length / BLOCK_SIZE, [0; 32])
+}
+
+/// The `pieces root` of a file no larger than one piece, `data` being the
+/// whole file.
+pub fn file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    root(&leaves, leaves.len().next_power_of_two(), [0; 32])
+}
+
+/// Whether `layer`, the concatenated piece hashes of a file, belongs to the
+/// tree with `pieces_root`. The piece layers are outside the info dictionary,
+/// so nothing else vouches for them.
+pub fn layer_matches(layer: &[u8], pieces_root: &Hash, piece_length: usize) -> bool {
+    if layer.is_empty() || !layer.len().is_multiple_of(32) {
+        return false;
+    }
+
+    let hashes: Vec<Hash> = layer
+        .chunks_exact(32)
+        .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+        .collect();
+    let pad = pad_hash(piece_length / BLOCK_SIZE);
+    root(&hashes, hashes.len().next_power_of_two(), pad) == *pieces_root
+}
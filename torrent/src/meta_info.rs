@@ -1,15 +1,29 @@
 use serde::{
-    de::{self, Deserializer, MapAccess, Visitor},
+    de::{self, Deserializer, Visitor},
     Deserialize, Serialize, Serializer,
 };
-use std::{fmt, path::PathBuf};
+use serde_bencode::value::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    bencode,
+    info_hash::{HashVersion, InfoHash, InfoHashV2, InfoHashes},
+    magnet::MagnetLink,
+    merkle,
+    web_seed::WebSeed,
+};
 
 // https://www.bittorrent.org/beps/bep_0003.html
 // https://wiki.theory.org/BitTorrentSpecification#Metainfo_File_Structure
 
-// TODO: unit tests
-// TODO: is it worth our own bencode impl for speed?
-
 /// MetaInfo files (also known as .torrent files) are bencoded dictionaries.
 /// All strings in a .torrent file that contains text must be UTF-8 encoded.
 #[derive(Debug, Deserialize, Serialize)]
@@ -27,17 +41,57 @@ pub struct MetaInfo {
     #[serde(rename = "creation date")]
     creation_date: Option<u64>,
     /// (optional) free-form textual comments of the author (string)
-    comment: Option<String>,
+    comment: Option<TorrentString>,
     /// (optional) name and version of the program used to create the .torrent (string)
     #[serde(rename = "created by")]
-    created_by: Option<String>,
+    created_by: Option<TorrentString>,
     /// (optional) the string encoding format used to generate the pieces part of the info dictionary in the .torrent metafile (string)
     encoding: Option<String>,
+    /// v2 and hybrid torrents (BEP 52): the piece layer of each file larger
+    /// than one piece, keyed by the file's `pieces root`.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    /// (optional) web seeds (BEP 19), a single URL or a list of them.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "string_or_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    url_list: Vec<String>,
+    /// (optional) http seeds (BEP 17), a list of URLs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    httpseeds: Vec<String>,
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
 }
 
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum MetaInfoError {
     InvalidPath,
     UnableToReadFile,
+    /// Not bencoded, or not a valid torrent, e.g. a `piece length` of zero
+    /// or a hash missing for some of its pieces.
     BencodeParseFailed,
 }
 
@@ -53,17 +107,80 @@ impl TryFrom<PathBuf> for MetaInfo {
             return Err(MetaInfoError::UnableToReadFile);
         };
 
-        match serde_bencode::from_bytes(&torrent_file_bytes) {
-            Ok(meta_info) => Ok(meta_info),
+        MetaInfo::from_bytes(&torrent_file_bytes)
+    }
+}
+
+impl MetaInfo {
+    /// Parse the contents of a .torrent file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetaInfoError> {
+        match serde_bencode::from_bytes::<MetaInfo>(bytes) {
+            Ok(mut meta_info) => {
+                // The info hash is over the dictionary as it was written,
+                // re-encoding would drop unknown keys and reorder the rest
+                let root = bencode::decode(bytes).map_err(|_| MetaInfoError::BencodeParseFailed)?;
+                let info = root.get(b"info").ok_or(MetaInfoError::BencodeParseFailed)?;
+                meta_info.info.raw = Some(RawInfo(info.raw().to_vec()));
+                if let Some(encoding) = &meta_info.encoding {
+                    meta_info.info.decode_names(encoding);
+                }
+
+                // Nothing vouches for piece layers that don't hash up to a
+                // file's root, treat them as missing
+                let piece_length = meta_info.info.piece_length;
+                let roots: Vec<merkle::Hash> = meta_info
+                    .info
+                    .v2_files()
+                    .iter()
+                    .filter_map(|file| file.pieces_root)
+                    .collect();
+                meta_info.piece_layers.retain(|root, layer| {
+                    <[u8; 32]>::try_from(root.as_slice()).is_ok_and(|root| {
+                        roots.contains(&root) && merkle::layer_matches(layer, &root, piece_length)
+                    })
+                });
+
+                Ok(meta_info)
+            }
             Err(err) => {
-                eprintln!("{:#?}", err);
+                log::debug!("{:#?}", err);
                 Err(MetaInfoError::BencodeParseFailed)
             }
         }
     }
-}
 
-impl MetaInfo {
+    /// Build a `MetaInfo` from an info dictionary received from peers (BEP 9),
+    /// as happens when a torrent is added from a magnet link.
+    pub fn from_metadata(metadata: &[u8], trackers: Vec<String>) -> Result<Self, MetaInfoError> {
+        let Ok(mut info) = serde_bencode::from_bytes::<Info>(metadata) else {
+            return Err(MetaInfoError::BencodeParseFailed);
+        };
+        info.raw = Some(RawInfo(metadata.to_vec()));
+
+        let announce = trackers.first().cloned().unwrap_or_default();
+        let announce_list =
+            (trackers.len() > 1).then(|| trackers.into_iter().map(|t| vec![t]).collect());
+
+        Ok(Self {
+            info,
+            announce,
+            announce_list,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+            piece_layers: BTreeMap::new(),
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+        })
+    }
+
+    /// Keep the web seeds of the magnet link a torrent was added from.
+    pub fn with_web_seeds(mut self, urls: Vec<String>) -> Self {
+        self.url_list = urls;
+        self
+    }
+
     pub fn info(&self) -> &Info {
         &self.info
     }
@@ -72,12 +189,122 @@ impl MetaInfo {
         &self.announce
     }
 
-    /// Length of the file
-    pub const fn len(&self) -> usize {
-        match self.info.key {
-            Key::SingleFile { length } => length,
-            Key::MultiFile { files: _ } => todo!(),
+    /// The tracker tiers as listed in the metainfo. If `announce-list` is
+    /// present `announce` is ignored (BEP 12), otherwise it is the only tier.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers.clone(),
+            _ if self.announce.is_empty() => Vec::new(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    /// Every tracker URL, `announce` first followed by each tier of
+    /// `announce-list` in order, without duplicates.
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers = Vec::new();
+        let tiers = self.announce_list.iter().flatten().flatten();
+        for tracker in std::iter::once(&self.announce).chain(tiers) {
+            if !tracker.is_empty() && !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
         }
+        trackers
+    }
+
+    /// Web seed URLs (BEP 19), empty ones left out.
+    pub fn web_seeds(&self) -> Vec<String> {
+        self.url_list
+            .iter()
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Http seed URLs (BEP 17), empty ones left out.
+    pub fn http_seeds(&self) -> Vec<String> {
+        self.httpseeds
+            .iter()
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Every web seed and http seed, the ones that are valid URLs.
+    pub fn http_sources(&self) -> Vec<WebSeed> {
+        let info_hash = self.info.hash();
+        let web_seeds = self.web_seeds().into_iter().map(|url| WebSeed::new(&url));
+        let http_seeds = self
+            .http_seeds()
+            .into_iter()
+            .map(|url| WebSeed::http_seed(&url, info_hash));
+        web_seeds.chain(http_seeds).filter_map(Result::ok).collect()
+    }
+
+    /// A magnet link that can be shared instead of the .torrent file.
+    pub fn to_magnet_link(&self) -> MagnetLink {
+        MagnetLink {
+            info_hash: self.info.hash(),
+            info_hash_v2: self.info.hash_v2(),
+            display_name: Some(self.info.name().to_owned()),
+            trackers: self.trackers(),
+            peers: Vec::new(),
+            web_seeds: self.web_seeds(),
+        }
+    }
+
+    /// Check `data` against the hash of the piece at `index`, using the
+    /// hashes of the swarm (`version`) it was downloaded from.
+    ///
+    /// Pieces are aligned to files in hybrid torrents, so every piece is
+    /// covered by both hash sets. v2 falls back to v1 where the piece layer
+    /// is unknown, e.g. when the torrent was added from a magnet link.
+    pub fn verify_piece(&self, index: usize, data: &[u8], version: HashVersion) -> bool {
+        if version == HashVersion::V2 {
+            if let Some(valid) = self.verify_piece_v2(index, data) {
+                return valid;
+            }
+        }
+
+        let digest = sha1_smol::Sha1::from(data).digest().bytes();
+        self.info.pieces().get(index) == Some(&digest)
+    }
+
+    fn verify_piece_v2(&self, index: usize, data: &[u8]) -> Option<bool> {
+        let piece_length = self.info.piece_length;
+        let v1_paths = self.info.file_paths();
+        let v2_files = self.info.v2_files();
+
+        // The piece's file, skipping pad files which only exist in v1
+        let mut start = 0;
+        let (span, file) = self.info.piece_spans(index).into_iter().find_map(|span| {
+            let path = &v1_paths[span.file_index];
+            match v2_files.iter().find(|file| &file.path == path) {
+                Some(file) => Some((span, file)),
+                None => {
+                    start += span.len as usize;
+                    None
+                }
+            }
+        })?;
+        let pieces_root = file.pieces_root?;
+        let data = data.get(start..start + span.len as usize)?;
+
+        if file.length <= piece_length as u64 {
+            return Some(merkle::file_root(data) == pieces_root);
+        }
+
+        let layer = self
+            .piece_layers
+            .get(&ByteBuf::from(pieces_root.to_vec()))?;
+        let piece = (span.offset / piece_length as u64) as usize;
+        let expected = layer.get(piece * 32..piece * 32 + 32)?;
+        Some(merkle::piece_hash(data, piece_length) == expected)
+    }
+
+    /// Total length of the torrent's content, the sum of every file in the multi-file case.
+    pub fn len(&self) -> usize {
+        self.info.total_length()
     }
 
     #[must_use]
@@ -88,20 +315,20 @@ impl MetaInfo {
 
 // TODO: should info actually be enum?
 // enum Info {SingleFile, MultiFile}
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "InfoFields")]
 pub struct Info {
     /// The name key maps to a UTF-8 encoded string which is the suggested name
     /// to save the file (or directory) as. It is purely advisory.
     ///
     /// In the single file case, the name key is the name of a file,
     /// in the muliple file case, it's the name of a directory.
-    name: String,
+    name: TorrentString,
     /// piece length maps to the number of bytes in each piece the file is split
     /// into. For the purposes of transfer, files are split into fixed-size
     /// pieces which are all the same length except for possibly the last one
     /// which may be truncated. piece length is almost always a power of two, most
     /// commonly 2 18 = 256 K (BitTorrent prior to version 3.2 uses 2 20 = 1 M as default).
-    #[serde(rename = "piece length")]
     piece_length: usize,
     /// pieces maps to a string whose length is a multiple of 20.
     /// It is to be subdivided into strings of length 20, each of which
@@ -111,11 +338,403 @@ pub struct Info {
     // #[serde(deserialize_with = "bool_from_optional_int")]
     private: Option<u8>,
 
-    #[serde(flatten)]
     key: Key,
+
+    /// Keys we don't know about (e.g. `source`, `x_cross_seed`), kept so
+    /// they survive a round-trip.
+    extra: BTreeMap<String, Value>,
+
+    /// The info dictionary exactly as it appeared in the .torrent file or
+    /// was received from peers. The info hash must be computed over these
+    /// bytes, re-encoding may drop or reorder data.
+    raw: Option<RawInfo>,
+    /// The v2 `file tree`, decoded from `raw` the first time it is needed
+    /// rather than for every piece verified.
+    v2_files: OnceLock<Vec<V2File>>,
+}
+
+/// The fields of the info dictionary as they are bencoded.
+#[derive(Deserialize)]
+struct InfoFields {
+    name: TorrentString,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: Hashes,
+    private: Option<u8>,
+    length: Option<usize>,
+    files: Option<Vec<File>>,
+    #[serde(flatten)]
+    extra: BTreeMap<String, Value>,
+}
+
+/// Files nested deeper than this are refused, real torrents come nowhere
+/// near it and most file systems would fail on the path anyway.
+pub const MAX_PATH_DEPTH: usize = 64;
+
+/// Whether `component` stays in its directory when joined onto it.
+fn is_safe_component(component: &str) -> bool {
+    component != ".." && !component.contains(['/', '\\'])
+}
+
+impl TryFrom<InfoFields> for Info {
+    type Error = &'static str;
+
+    fn try_from(fields: InfoFields) -> Result<Self, Self::Error> {
+        // Names and paths come from strangers and are joined onto the
+        // download directory, none of them may lead out of it
+        let name_utf8 = match fields.extra.get("name.utf-8") {
+            Some(Value::Bytes(name)) => std::str::from_utf8(name).ok(),
+            _ => None,
+        };
+        if !is_safe_component(fields.name.as_str()) || !name_utf8.is_none_or(is_safe_component) {
+            return Err("`name` leaves the download directory");
+        }
+        for file in fields.files.iter().flatten() {
+            for path in std::iter::once(&file.path).chain(&file.path_utf8) {
+                if path.len() > MAX_PATH_DEPTH {
+                    return Err("a file path is nested too deep");
+                }
+                if !path.iter().all(|part| is_safe_component(part.as_str())) {
+                    return Err("a file path leaves the torrent's directory");
+                }
+            }
+        }
+
+        // There is a key length or a key files, but not both or neither.
+        let key = match (fields.length, fields.files) {
+            (Some(length), None) => Key::SingleFile { length },
+            (None, Some(files)) => Key::MultiFile { files },
+            (Some(_), Some(_)) => return Err("info has both `length` and `files`"),
+            (None, None) => return Err("missing field `length or files`"),
+        };
+
+        // Everything mapping pieces onto files divides by the piece length
+        // and expects a hash for every piece of the content
+        if fields.piece_length == 0 {
+            return Err("`piece length` is zero");
+        }
+        let total_length = match &key {
+            Key::SingleFile { length } => Some(*length),
+            Key::MultiFile { files } => files
+                .iter()
+                .try_fold(0usize, |total, file| total.checked_add(file.length)),
+        }
+        .ok_or("the files are longer than can be addressed")?;
+        if fields.pieces.0.len() != total_length.div_ceil(fields.piece_length) {
+            return Err("`pieces` doesn't have a hash for every piece");
+        }
+
+        Ok(Self {
+            name: fields.name,
+            piece_length: fields.piece_length,
+            pieces: fields.pieces,
+            private: fields.private,
+            key,
+            extra: fields.extra,
+            raw: None,
+            v2_files: OnceLock::new(),
+        })
+    }
+}
+
+impl Serialize for Info {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct InfoFieldsRef<'a> {
+            name: &'a TorrentString,
+            #[serde(rename = "piece length")]
+            piece_length: usize,
+            pieces: &'a Hashes,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            private: Option<u8>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            length: Option<usize>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            files: Option<&'a Vec<File>>,
+            #[serde(flatten)]
+            extra: &'a BTreeMap<String, Value>,
+        }
+
+        let (length, files) = match &self.key {
+            Key::SingleFile { length } => (Some(*length), None),
+            Key::MultiFile { files } => (None, Some(files)),
+        };
+
+        InfoFieldsRef {
+            name: &self.name,
+            piece_length: self.piece_length,
+            pieces: &self.pieces,
+            private: self.private,
+            length,
+            files,
+            extra: &self.extra,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A string from the metainfo, which should be UTF-8 but often is not in
+/// older torrents. The raw bytes are kept so re-encoding is lossless.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TorrentString {
+    raw: Vec<u8>,
+    text: String,
+    /// Whether `text` had to replace invalid bytes.
+    lossy: bool,
+}
+
+impl TorrentString {
+    fn new(raw: Vec<u8>) -> Self {
+        let (text, lossy) = match std::str::from_utf8(&raw) {
+            Ok(text) => (text.to_owned(), false),
+            Err(_) => (String::from_utf8_lossy(&raw).into_owned(), true),
+        };
+        Self { raw, text, lossy }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn is_lossy(&self) -> bool {
+        self.lossy
+    }
+
+    /// Decode with `encoding` instead, unless the string is valid UTF-8.
+    fn decode(&mut self, encoding: &'static encoding_rs::Encoding) {
+        if !self.lossy {
+            return;
+        }
+        let (text, had_errors) = encoding.decode_without_bom_handling(&self.raw);
+        self.text = text.into_owned();
+        self.lossy = had_errors;
+    }
+}
+
+impl fmt::Debug for TorrentString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.text, f)
+    }
+}
+
+impl fmt::Display for TorrentString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl<'de> Deserialize<'de> for TorrentString {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        Ok(Self::new(raw.into_vec()))
+    }
+}
+
+impl Serialize for TorrentString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.raw)
+    }
+}
+
+/// Raw bencoded bytes, only their length is shown when debug printing.
+#[derive(Clone)]
+struct RawInfo(Vec<u8>);
+
+impl fmt::Debug for RawInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RawInfo({} bytes)", self.0.len())
+    }
 }
 
 impl Info {
+    /// The suggested name to save the file (or directory) as.
+    pub fn name(&self) -> &str {
+        self.name_utf8().unwrap_or(self.name.as_str())
+    }
+
+    /// BitComet style `name.utf-8`, which some torrents with names in a
+    /// legacy encoding carry next to `name`.
+    fn name_utf8(&self) -> Option<&str> {
+        match self.extra.get("name.utf-8") {
+            Some(Value::Bytes(name)) => std::str::from_utf8(name).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether any name or path is not valid UTF-8 (in its declared
+    /// encoding) and was decoded lossily, so what is shown and written to
+    /// disk may not match what the creator intended.
+    pub fn has_non_utf8_names(&self) -> bool {
+        let name = self.name_utf8().is_none() && self.name.is_lossy();
+        let paths = match &self.key {
+            Key::SingleFile { .. } => false,
+            Key::MultiFile { files } => files
+                .iter()
+                .any(|file| file.path_utf8.is_none() && file.path.iter().any(|p| p.is_lossy())),
+        };
+        name || paths
+    }
+
+    /// Decode names and paths that are not valid UTF-8 with `encoding`,
+    /// the `encoding` key of the metainfo.
+    fn decode_names(&mut self, encoding: &str) {
+        let Some(encoding) = encoding_rs::Encoding::for_label(encoding.as_bytes()) else {
+            return;
+        };
+
+        self.name.decode(encoding);
+        // The v2 paths start with the name
+        self.v2_files = OnceLock::new();
+        if let Key::MultiFile { files } = &mut self.key {
+            for part in files.iter_mut().flat_map(|file| file.path.iter_mut()) {
+                part.decode(encoding);
+            }
+        }
+    }
+
+    /// Where an updated version of the torrent is published (BEP 39),
+    /// e.g. when episodes are added to a season.
+    pub fn update_url(&self) -> Option<&str> {
+        match self.extra.get("update-url") {
+            Some(Value::Bytes(url)) => std::str::from_utf8(url).ok(),
+            _ => None,
+        }
+    }
+
+    /// The public key of whoever publishes the torrent's updates (BEP 39),
+    /// every version of the torrent carries the same one.
+    pub fn originator(&self) -> Option<&[u8]> {
+        match self.extra.get("originator") {
+            Some(Value::Bytes(key)) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// `meta version`, 2 for v2 and hybrid torrents (BEP 52).
+    pub fn meta_version(&self) -> i64 {
+        match self.extra.get("meta version") {
+            Some(Value::Int(version)) => *version,
+            _ => 1,
+        }
+    }
+
+    /// Whether the torrent also has v2 data. Torrents without v1 pieces
+    /// can't be loaded, so every v2 torrent here is a hybrid.
+    pub fn is_hybrid(&self) -> bool {
+        self.meta_version() == 2 && self.extra.contains_key("file tree")
+    }
+
+    /// The SHA-256 of the raw info dictionary, for hybrid torrents.
+    pub fn hash_v2(&self) -> Option<InfoHashV2> {
+        if !self.is_hybrid() {
+            return None;
+        }
+        Some(InfoHashV2::new(Sha256::digest(self.to_bytes()).into()))
+    }
+
+    /// Both hashes of a hybrid torrent, or just the v1 hash.
+    pub fn info_hashes(&self) -> InfoHashes {
+        InfoHashes {
+            v1: self.hash(),
+            v2: self.hash_v2(),
+        }
+    }
+
+    /// The files of the v2 `file tree`, in the same form as `file_paths`.
+    /// Empty unless the torrent is a hybrid.
+    pub fn v2_files(&self) -> &[V2File] {
+        self.v2_files.get_or_init(|| self.decode_v2_files())
+    }
+
+    fn decode_v2_files(&self) -> Vec<V2File> {
+        if !self.is_hybrid() {
+            return Vec::new();
+        }
+
+        let raw = self.to_bytes();
+        let Ok(info) = bencode::decode(&raw) else {
+            return Vec::new();
+        };
+        let Some(tree) = info.get(b"file tree") else {
+            return Vec::new();
+        };
+
+        // Multi-file torrents keep their files in a directory named after the torrent
+        let base = match &self.key {
+            Key::SingleFile { .. } => PathBuf::new(),
+            Key::MultiFile { .. } => PathBuf::from(self.name()),
+        };
+
+        let mut files = Vec::new();
+        collect_v2_files(tree, base, &mut files);
+        files
+    }
+
+    /// The attributes of each file, in the same order as `file_paths`.
+    pub fn file_attributes(&self) -> Vec<FileAttributes> {
+        match &self.key {
+            // Single file torrents keep `attr` in the info dictionary itself
+            Key::SingleFile { .. } => match self.extra.get("attr") {
+                Some(Value::Bytes(attr)) => {
+                    vec![FileAttributes::parse(&String::from_utf8_lossy(attr))]
+                }
+                _ => vec![FileAttributes::default()],
+            },
+            Key::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    let mut attributes = FileAttributes::parse(file.attr.as_deref().unwrap_or(""));
+                    // Pad files from before BEP 47 are only recognizable by name
+                    attributes.padding |= file
+                        .path
+                        .last()
+                        .is_some_and(|name| name.as_str().starts_with("_____padding_file_"));
+                    attributes
+                })
+                .collect(),
+        }
+    }
+
+    /// Where the symlink at `file_index` points, relative to the directory
+    /// it is in. `None` if the file is not a symlink.
+    pub fn symlink_target(&self, file_index: usize) -> Option<PathBuf> {
+        let Key::MultiFile { files } = &self.key else {
+            return None;
+        };
+        let file = files.get(file_index)?;
+        let target = file.symlink_path.as_ref()?;
+        if !self.file_attributes()[file_index].symlink {
+            return None;
+        }
+        // Never let a torrent point a link outside of itself
+        let escapes = target.iter().any(|part| {
+            matches!(part.as_str(), "" | "." | "..") || part.as_str().contains(['/', '\\'])
+        });
+        if escapes {
+            return None;
+        }
+
+        // `symlink path` is relative to the torrent's root, the link is
+        // `path.len() - 1` directories below it
+        let mut relative: PathBuf =
+            std::iter::repeat_n("..", file.path.len().saturating_sub(1)).collect();
+        relative.extend(target.iter().map(TorrentString::as_str));
+        Some(relative)
+    }
+
     pub fn private(&self) -> bool {
         match self.private {
             Some(num) => match num {
@@ -136,75 +755,238 @@ impl Info {
         self.piece_length
     }
 
-    pub fn hash(&self) -> sha1_smol::Digest {
-        let bencoded_info = serde_bencode::to_bytes(&self).expect("failed to bencode info");
-        let mut m = sha1_smol::Sha1::new();
-        m.update(&bencoded_info);
-
-        m.digest()
+    /// Whether this is a single file torrent, as opposed to a directory.
+    pub fn is_single_file(&self) -> bool {
+        matches!(self.key, Key::SingleFile { .. })
     }
-}
 
-impl<'de> Deserialize<'de> for Key {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_map(KeysVisitor)
+    /// Total length of the content in bytes.
+    pub fn total_length(&self) -> usize {
+        match &self.key {
+            Key::SingleFile { length } => *length,
+            Key::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
     }
-}
 
-struct KeysVisitor;
+    /// The length of each file in the order they are concatenated into pieces.
+    pub fn file_lengths(&self) -> Vec<usize> {
+        match &self.key {
+            Key::SingleFile { length } => vec![*length],
+            Key::MultiFile { files } => files.iter().map(|file| file.length).collect(),
+        }
+    }
 
-impl<'de> Visitor<'de> for KeysVisitor {
-    type Value = Key;
+    /// The path of each file relative to the download directory, in the
+    /// same order as `file_lengths`. For multi-file torrents this includes
+    /// the torrent's directory name.
+    ///
+    /// Files that would land on the same path as an earlier file, or on a
+    /// directory of another, are renamed, see `renamed_files`.
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.declared_file_paths();
+        for (index, _, renamed) in self.resolve_collisions(&paths) {
+            paths[index] = renamed;
+        }
+        paths
+    }
 
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a map with either a `length` or `files` field")
+    /// The files `file_paths` renames, with the path the torrent declared
+    /// and the one used instead, e.g. to warn about them when a torrent is
+    /// added.
+    ///
+    /// Paths are compared ignoring case, as they collide on Windows and
+    /// macOS, and renamed the same way on every platform so the data can be
+    /// moved between them. The first of two colliding files keeps its path,
+    /// the later one gets a ` (1)` suffix before its extension, ` (2)` if
+    /// that is taken too, and so on. Pad files are never written and left
+    /// out.
+    pub fn renamed_files(&self) -> Vec<(usize, PathBuf, PathBuf)> {
+        self.resolve_collisions(&self.declared_file_paths())
     }
 
-    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
-    where
-        A: MapAccess<'de>,
-    {
-        // Temporary storage for fields
-        let mut length: Option<usize> = None;
-        let mut files: Option<Vec<File>> = None;
-
-        while let Some(key) = map.next_key::<String>()? {
-            match key.as_str() {
-                "length" => {
-                    if length.is_some() {
-                        return Err(de::Error::duplicate_field("length"));
-                    }
-                    length = Some(map.next_value()?);
-                }
-                "files" => {
-                    if files.is_some() {
-                        return Err(de::Error::duplicate_field("files"));
+    /// The paths exactly as the torrent declares them, e.g. where a web
+    /// seed hosts the files, before `file_paths` renames any.
+    pub fn declared_file_paths(&self) -> Vec<PathBuf> {
+        match &self.key {
+            Key::SingleFile { .. } => vec![PathBuf::from(self.name())],
+            Key::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    let mut path = PathBuf::from(self.name());
+                    match &file.path_utf8 {
+                        Some(parts) => path.extend(parts.iter().map(TorrentString::as_str)),
+                        None => path.extend(file.path.iter().map(TorrentString::as_str)),
                     }
-                    files = Some(map.next_value()?);
-                }
-                _ => {
-                    return Err(de::Error::unknown_field(&key, &["length", "files"]));
-                }
+                    path
+                })
+                .collect(),
+        }
+    }
+
+    fn resolve_collisions(&self, paths: &[PathBuf]) -> Vec<(usize, PathBuf, PathBuf)> {
+        if paths.len() < 2 {
+            return Vec::new();
+        }
+        let attributes = self.file_attributes();
+        let stored = |index: &usize| !attributes[*index].padding;
+        let key = |path: &Path| path.to_string_lossy().to_lowercase();
+
+        // Directories every file needs, a file can't take one of their paths
+        let directories: HashSet<String> = (0..paths.len())
+            .filter(stored)
+            .flat_map(|index| paths[index].ancestors().skip(1).map(key))
+            .collect();
+
+        let mut taken = HashSet::new();
+        let mut renamed = Vec::new();
+        for index in (0..paths.len()).filter(stored) {
+            let path = &paths[index];
+            if !directories.contains(&key(path)) && taken.insert(key(path)) {
+                continue;
             }
+
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path.extension().map(|ext| ext.to_string_lossy());
+            let unique = (1..)
+                .map(|n| {
+                    let name = match &extension {
+                        Some(extension) => format!("{stem} ({n}).{extension}"),
+                        None => format!("{stem} ({n})"),
+                    };
+                    path.with_file_name(name)
+                })
+                .find(|candidate| {
+                    !directories.contains(&key(candidate))
+                        && !paths.iter().any(|other| key(other) == key(candidate))
+                        && taken.insert(key(candidate))
+                })
+                .expect("a free name");
+            renamed.push((index, path.clone(), unique));
+        }
+        renamed
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    /// The length of the piece at `index`, every piece is `piece_length`
+    /// except possibly the last one which may be truncated.
+    pub fn piece_len(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length
+            .min(self.total_length().saturating_sub(start))
+    }
+
+    /// Map the global byte range `offset..offset + len` onto the files it covers.
+    ///
+    /// Zero length files never appear in the result. Bytes past the end of
+    /// the last file are ignored.
+    pub fn spans(&self, offset: u64, len: u64) -> Vec<FileSpan> {
+        let end = offset + len;
+        let mut spans = Vec::new();
+        let mut file_start = 0u64;
+
+        for (file_index, file_length) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_start + file_length as u64;
+
+            if file_length > 0 && file_end > offset && file_start < end {
+                let span_start = offset.max(file_start);
+                let span_end = end.min(file_end);
+                spans.push(FileSpan {
+                    file_index,
+                    offset: span_start - file_start,
+                    len: span_end - span_start,
+                });
+            }
+
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+
+        spans
+    }
+
+    /// The file spans covered by the piece at `index`.
+    pub fn piece_spans(&self, index: usize) -> Vec<FileSpan> {
+        let offset = index as u64 * self.piece_length as u64;
+        self.spans(offset, self.piece_len(index) as u64)
+    }
+
+    /// The file spans covered by `len` bytes at `begin` in the piece at
+    /// `index`, e.g. a block, `None` if they don't fit in the piece.
+    pub fn block_spans(&self, index: usize, begin: u32, len: u32) -> Option<Vec<FileSpan>> {
+        if index >= self.piece_count() || begin as usize + len as usize > self.piece_len(index) {
+            return None;
+        }
+        let offset = index as u64 * self.piece_length as u64 + begin as u64;
+        Some(self.spans(offset, len as u64))
+    }
+
+    /// The bencoded info dictionary, exactly as received when available.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.raw {
+            Some(raw) => raw.0.clone(),
+            None => serde_bencode::to_bytes(&self).expect("failed to bencode info"),
+        }
+    }
+
+    /// The SHA-1 of the raw info dictionary. Only torrents built in memory
+    /// without raw bytes fall back to re-encoding.
+    pub fn hash(&self) -> InfoHash {
+        let mut m = sha1_smol::Sha1::new();
+        match &self.raw {
+            Some(raw) => m.update(&raw.0),
+            None => m.update(&serde_bencode::to_bytes(&self).expect("failed to bencode info")),
         }
 
-        // Determine the variant based on which field was present
-        if let Some(length) = length {
-            Ok(Key::SingleFile { length })
-        } else if let Some(files) = files {
-            Ok(Key::MultiFile { files })
+        m.digest().into()
+    }
+}
+
+/// A file in the v2 `file tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: PathBuf,
+    pub length: u64,
+    /// The root of the file's merkle tree, missing for empty files.
+    pub pieces_root: Option<merkle::Hash>,
+}
+
+/// Walk a `file tree` node. Files are dictionaries with a single empty key
+/// holding `length` and `pieces root`, everything else is a directory.
+fn collect_v2_files(node: &bencode::Node, path: PathBuf, files: &mut Vec<V2File>) {
+    let Some(entries) = node.as_dict() else {
+        return;
+    };
+
+    for (name, child) in entries {
+        if name.is_empty() {
+            files.push(V2File {
+                length: child
+                    .get(b"length")
+                    .and_then(|length| length.as_int())
+                    .unwrap_or(0) as u64,
+                pieces_root: child
+                    .get(b"pieces root")
+                    .and_then(|root| root.as_bytes())
+                    .and_then(|root| root.try_into().ok()),
+                path: path.clone(),
+            });
         } else {
-            Err(de::Error::missing_field("length or files"))
+            collect_v2_files(
+                child,
+                path.join(String::from_utf8_lossy(name).as_ref()),
+                files,
+            );
         }
     }
 }
 
 /// There is also a key length or a key files, but not both or neither.
-// NOTE: we did not use serde(untagged) for performance reasons
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
 pub enum Key {
     /// If length is present then the download represents a single file,
     /// otherwise it represents a set of files which go in a directory structure.
@@ -222,17 +1004,74 @@ pub enum Key {
     },
 }
 
+/// A contiguous range of bytes within a single file of a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Index into the torrent's file list.
+    pub file_index: usize,
+    /// Offset of the range within the file.
+    pub offset: u64,
+    pub len: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
     /// The length of the file, in bytes.
     length: usize,
     /// A list of UTF-8 encoded strings corresponding to subdirectory names,
     /// the last of which is the actual file name (a zero length list is an error case).
-    path: Vec<String>,
+    path: Vec<TorrentString>,
+    /// BitComet style `path.utf-8`, see `Info::name_utf8`.
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    path_utf8: Option<Vec<TorrentString>>,
+    /// BEP 47 attributes, see `FileAttributes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+    /// Where a symlink (`attr` `l`) points, relative to the torrent's root.
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    symlink_path: Option<Vec<TorrentString>>,
+    /// (optional) SHA-1 of the file's content (BEP 47).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha1: Option<ByteBuf>,
     // (optional) a 32-character hexadecimal string corresponding to the MD5 sum of the file. This is not used by BitTorrent at all, but it is included by some programs for greater compatibility.
     // md5sum: Option<String>,
 }
 
+// https://www.bittorrent.org/beps/bep_0047.html
+
+/// The attributes of a file, the `attr` string of the metainfo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// `p`, zeros that align the next file to a piece boundary. Never
+    /// written to disk.
+    pub padding: bool,
+    /// `x`
+    pub executable: bool,
+    /// `l`, the file is a symlink to its `symlink path` and has no data.
+    pub symlink: bool,
+    /// `h`
+    pub hidden: bool,
+}
+
+impl FileAttributes {
+    fn parse(attr: &str) -> Self {
+        Self {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            symlink: attr.contains('l'),
+            hidden: attr.contains('h'),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Hashes(pub Vec<[u8; 20]>);
 
@@ -250,7 +1089,7 @@ impl Visitor<'_> for HashesVisitor {
         E: de::Error,
     {
         let len = v.len();
-        if len % 20 != 0 {
+        if !len.is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", len)));
         }
 
@@ -292,3 +1131,104 @@ impl Serialize for Hashes {
         serializer.serialize_bytes(&output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single file torrent of `length` bytes, with `pieces` hashes.
+    fn single_file(length: usize, piece_length: usize, pieces: usize) -> Vec<u8> {
+        let mut bytes = format!(
+            "d8:announce0:4:infod6:lengthi{length}e4:name1:a12:piece lengthi{piece_length}e6:pieces{}:",
+            pieces * 20
+        )
+        .into_bytes();
+        bytes.extend(std::iter::repeat_n(b'h', pieces * 20));
+        bytes.extend(b"ee");
+        bytes
+    }
+
+    #[test]
+    fn a_hash_for_every_piece_is_accepted() {
+        let torrent = MetaInfo::from_bytes(&single_file(40, 16, 3)).unwrap();
+        assert_eq!(torrent.info().piece_count(), 3);
+        assert_eq!(torrent.info().piece_len(2), 8);
+        assert!(MetaInfo::from_bytes(&single_file(48, 16, 3)).is_ok());
+        assert!(MetaInfo::from_bytes(&single_file(0, 16, 0)).is_ok());
+    }
+
+    #[test]
+    fn a_zero_piece_length_is_refused() {
+        assert!(matches!(
+            MetaInfo::from_bytes(&single_file(40, 0, 3)),
+            Err(MetaInfoError::BencodeParseFailed)
+        ));
+    }
+
+    #[test]
+    fn pieces_not_matching_the_length_are_refused() {
+        for pieces in [0, 2, 4] {
+            assert!(MetaInfo::from_bytes(&single_file(40, 16, pieces)).is_err());
+        }
+        // Not a whole number of hashes
+        let mut bytes = single_file(40, 16, 3);
+        let at = bytes.windows(9).position(|w| w == b"pieces60:").unwrap();
+        bytes.splice(at..at + 9, b"pieces59:".iter().copied());
+        bytes.remove(bytes.len() - 3);
+        assert!(MetaInfo::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn the_hash_is_over_the_info_dictionary_as_written() {
+        // Keys out of order and one this crate doesn't know about
+        let info: &[u8] = b"d4:name1:a6:lengthi5e6:pieces20:hhhhhhhhhhhhhhhhhhhh\
+                            12:piece lengthi16e7:unknowni1ee";
+        let mut bytes = b"d8:announce0:4:info".to_vec();
+        bytes.extend(info);
+        bytes.push(b'e');
+
+        let torrent = MetaInfo::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info().to_bytes(), info);
+        assert_eq!(
+            torrent.info().hash(),
+            sha1_smol::Sha1::from(info).digest().into()
+        );
+    }
+
+    #[test]
+    fn malformed_bencode_is_refused() {
+        let mut bytes = single_file(40, 16, 3);
+        bytes.extend(b"trailing");
+        assert!(matches!(
+            MetaInfo::from_bytes(&bytes),
+            Err(MetaInfoError::BencodeParseFailed)
+        ));
+    }
+
+    #[test]
+    fn multi_file_lengths_add_up() {
+        let bytes = b"d8:announce0:4:infod5:filesld6:lengthi10e4:pathl1:aee\
+                      d6:lengthi0e4:pathl1:bee\
+                      d6:lengthi7e4:pathl1:ceee\
+                      4:name1:d12:piece lengthi8e6:pieces60:\
+                      hhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhhee";
+        let torrent = MetaInfo::from_bytes(bytes).unwrap();
+        assert_eq!(torrent.len(), 17);
+        assert_eq!(torrent.info().file_lengths(), [10, 0, 7]);
+        assert_eq!(
+            torrent.info().spans(8, 4),
+            [
+                FileSpan {
+                    file_index: 0,
+                    offset: 8,
+                    len: 2
+                },
+                FileSpan {
+                    file_index: 2,
+                    offset: 0,
+                    len: 2
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::upnp::{local_ip_towards, Protocol};
+
+// NAT-PMP (RFC 6886) and its successor PCP (RFC 6887), what routers without
+// UPnP tend to offer instead. Both are a single UDP request to the default
+// gateway. PCP is tried first, a NAT-PMP only gateway answers it with
+// "unsupported version" and is then spoken to in NAT-PMP.
+
+/// The port NAT-PMP and PCP gateways listen on.
+pub const PORT: u16 = 5351;
+
+/// How long to wait for the first answer, doubled on every retry (RFC 6886
+/// section 3.1).
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+/// Requests are sent this many times before the gateway is given up on, for
+/// about 4 seconds in total.
+const ATTEMPTS: u32 = 4;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+/// Set on the opcode of every response.
+const RESPONSE_BIT: u8 = 0x80;
+
+const NATPMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const PCP_OP_ANNOUNCE: u8 = 0;
+const PCP_OP_MAP: u8 = 1;
+const PCP_HEADER_LEN: usize = 24;
+
+/// The result code for a request in a version the gateway doesn't speak.
+const UNSUPPORTED_VERSION: u16 = 1;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum NatPmpError {
+    Io(io::Error),
+    /// The default gateway could not be determined.
+    NoGateway,
+    /// The gateway didn't answer, it likely speaks neither protocol.
+    NoResponse,
+    /// The gateway answered with something that isn't a valid response.
+    InvalidResponse,
+    /// The gateway refused the request, e.g. `2 NOT_AUTHORIZED`.
+    Refused(u16),
+}
+
+impl From<io::Error> for NatPmpError {
+    fn from(err: io::Error) -> Self {
+        NatPmpError::Io(err)
+    }
+}
+
+impl fmt::Display for NatPmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatPmpError::Io(err) => write!(f, "{err}"),
+            NatPmpError::NoGateway => write!(f, "no default gateway"),
+            NatPmpError::NoResponse => write!(f, "no NAT-PMP or PCP gateway found"),
+            NatPmpError::InvalidResponse => write!(f, "invalid response from the gateway"),
+            NatPmpError::Refused(code) => write!(f, "the gateway refused: result code {code}"),
+        }
+    }
+}
+
+/// Which protocol a gateway is spoken to in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    NatPmp,
+    Pcp,
+}
+
+/// A mapping the gateway made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// The port on the gateway, which may differ from the one asked for.
+    pub external_port: u16,
+    /// How long the gateway keeps the mapping.
+    pub lifetime: Duration,
+    /// `None` if the gateway would not tell.
+    pub external_ip: Option<IpAddr>,
+}
+
+/// A router that maps ports over NAT-PMP or PCP.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    addr: SocketAddr,
+    /// Our address on the gateway's network, where mapped ports point to.
+    local_ip: IpAddr,
+    version: Version,
+}
+
+impl Gateway {
+    /// The default gateway, if it answers NAT-PMP or PCP.
+    pub fn discover() -> Result<Self, NatPmpError> {
+        let ip = default_gateway().ok_or(NatPmpError::NoGateway)?;
+        Self::probe(SocketAddr::new(ip.into(), PORT))
+    }
+
+    /// Find out which protocol the gateway at `addr` speaks, with requests
+    /// that change nothing on it.
+    pub fn probe(addr: SocketAddr) -> Result<Self, NatPmpError> {
+        let local_ip = local_ip_towards(addr)?;
+        let mut gateway = Self {
+            addr,
+            local_ip,
+            version: Version::Pcp,
+        };
+
+        let response = gateway.request(&gateway.pcp_header(PCP_OP_ANNOUNCE, Duration::ZERO))?;
+        match gateway.pcp_response(&response, PCP_OP_ANNOUNCE, PCP_HEADER_LEN) {
+            Ok(_) => Ok(gateway),
+            Err(NatPmpError::Refused(UNSUPPORTED_VERSION)) => {
+                gateway.version = Version::NatPmp;
+                gateway.natpmp_external_ip()?;
+                Ok(gateway)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn local_ip(&self) -> IpAddr {
+        self.local_ip
+    }
+
+    /// Forward `port` on the gateway to the same port on this machine for
+    /// `lifetime`.
+    pub fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+    ) -> Result<Mapping, NatPmpError> {
+        match self.version {
+            Version::Pcp => self.pcp_map(protocol, port, lifetime),
+            Version::NatPmp => self.natpmp_map(protocol, port, lifetime),
+        }
+    }
+
+    /// Both protocols delete a mapping by asking for it with no lifetime.
+    pub fn remove_port_mapping(&self, protocol: Protocol, port: u16) -> Result<(), NatPmpError> {
+        self.add_port_mapping(protocol, port, Duration::ZERO)
+            .map(|_| ())
+    }
+
+    fn natpmp_external_ip(&self) -> Result<Ipv4Addr, NatPmpError> {
+        let response = self.request(&[NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDRESS])?;
+        let body = natpmp_response(&response, NATPMP_OP_EXTERNAL_ADDRESS, 12)?;
+        Ok(Ipv4Addr::new(body[0], body[1], body[2], body[3]))
+    }
+
+    fn natpmp_map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+    ) -> Result<Mapping, NatPmpError> {
+        let opcode = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        // Deleting a mapping asks for external port 0
+        let external = if lifetime.is_zero() { 0 } else { port };
+
+        let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&external.to_be_bytes());
+        request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+        let response = self.request(&request)?;
+        let body = natpmp_response(&response, opcode, 16)?;
+        let lifetime = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+        Ok(Mapping {
+            external_port: u16::from_be_bytes([body[2], body[3]]),
+            lifetime: Duration::from_secs(lifetime.into()),
+            // NAT-PMP has a separate request for it
+            external_ip: match lifetime {
+                0 => None,
+                _ => self.natpmp_external_ip().ok().map(IpAddr::V4),
+            },
+        })
+    }
+
+    fn pcp_map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+    ) -> Result<Mapping, NatPmpError> {
+        let nonce: [u8; 12] = rand::random();
+        let protocol = match protocol {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        };
+
+        let mut request = self.pcp_header(PCP_OP_MAP, lifetime);
+        request.extend_from_slice(&nonce);
+        request.extend_from_slice(&[protocol, 0, 0, 0]);
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        // Any external address, in the family of the local one
+        let any = match self.local_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        request.extend_from_slice(&mapped_ip(any).octets());
+
+        let response = self.request(&request)?;
+        let response = self.pcp_response(&response, PCP_OP_MAP, PCP_HEADER_LEN + 36)?;
+        if response[24..36] != nonce {
+            return Err(NatPmpError::InvalidResponse);
+        }
+
+        let mut external_ip = [0u8; 16];
+        external_ip.copy_from_slice(&response[44..60]);
+        let external_ip = Ipv6Addr::from(external_ip);
+        Ok(Mapping {
+            external_port: u16::from_be_bytes([response[42], response[43]]),
+            lifetime: Duration::from_secs(
+                u32::from_be_bytes([response[4], response[5], response[6], response[7]]).into(),
+            ),
+            external_ip: Some(
+                external_ip
+                    .to_ipv4_mapped()
+                    .map_or(IpAddr::V6(external_ip), IpAddr::V4),
+            ),
+        })
+    }
+
+    /// The common header of PCP requests.
+    fn pcp_header(&self, opcode: u8, lifetime: Duration) -> Vec<u8> {
+        let mut header = vec![PCP_VERSION, opcode, 0, 0];
+        header.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+        header.extend_from_slice(&mapped_ip(self.local_ip).octets());
+        header
+    }
+
+    /// Check a PCP response to `opcode` of at least `len` bytes.
+    fn pcp_response<'a>(
+        &self,
+        response: &'a [u8],
+        opcode: u8,
+        len: usize,
+    ) -> Result<&'a [u8], NatPmpError> {
+        // NAT-PMP gateways answer in their own version, with its 16 bit
+        // result code where PCP has a reserved byte and an 8 bit one
+        if response.len() < 4 {
+            return Err(NatPmpError::InvalidResponse);
+        }
+        if response[0] != PCP_VERSION {
+            return Err(match u16::from_be_bytes([response[2], response[3]]) {
+                UNSUPPORTED_VERSION => NatPmpError::Refused(UNSUPPORTED_VERSION),
+                _ => NatPmpError::InvalidResponse,
+            });
+        }
+        if response[1] != opcode | RESPONSE_BIT {
+            return Err(NatPmpError::InvalidResponse);
+        }
+        match response[3] {
+            0 if response.len() >= len => Ok(response),
+            0 => Err(NatPmpError::InvalidResponse),
+            code => Err(NatPmpError::Refused(code.into())),
+        }
+    }
+
+    /// Send `request` until the gateway answers, waiting twice as long
+    /// every time.
+    fn request(&self, request: &[u8]) -> Result<Vec<u8>, NatPmpError> {
+        let bind: SocketAddr = match self.addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind)?;
+        // Only the gateway's answers are received once connected
+        socket.connect(self.addr)?;
+
+        let mut timeout = INITIAL_TIMEOUT;
+        let mut buf = [0u8; 1100];
+        for _ in 0..ATTEMPTS {
+            socket.send(request)?;
+            socket.set_read_timeout(Some(timeout))?;
+            match socket.recv(&mut buf) {
+                Ok(len) => return Ok(buf[..len].to_vec()),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                // The gateway's port is closed
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    return Err(NatPmpError::NoResponse)
+                }
+                Err(err) => return Err(err.into()),
+            }
+            timeout *= 2;
+        }
+        Err(NatPmpError::NoResponse)
+    }
+}
+
+/// The body of a NAT-PMP response to `opcode`, after the version, opcode,
+/// result code and epoch. `len` is the length of the whole response.
+fn natpmp_response(response: &[u8], opcode: u8, len: usize) -> Result<&[u8], NatPmpError> {
+    if response.len() < 4 || response[0] != NATPMP_VERSION || response[1] != opcode | RESPONSE_BIT {
+        return Err(NatPmpError::InvalidResponse);
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => {}
+        code => return Err(NatPmpError::Refused(code)),
+    }
+    if response.len() < len {
+        return Err(NatPmpError::InvalidResponse);
+    }
+    Ok(&response[8..len])
+}
+
+/// PCP carries every address as IPv6, IPv4 ones mapped into it.
+fn mapped_ip(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// The router our traffic to the internet goes through.
+///
+/// Read from the routing table on Linux. Elsewhere the first address of the
+/// local /24 is guessed, which is what almost every home router uses.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/route") {
+        // Iface Destination Gateway ..., hex in host byte order
+        let gateway = routes.lines().skip(1).find_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let destination = fields.next()?;
+            let gateway = u32::from_str_radix(fields.next()?, 16).ok()?;
+            (destination == "00000000" && gateway != 0)
+                .then(|| Ipv4Addr::from(gateway.to_le_bytes()))
+        });
+        if gateway.is_some() {
+            return gateway;
+        }
+    }
+
+    // Connecting a UDP socket sends nothing, it only picks the route
+    match local_ip_towards((Ipv4Addr::new(192, 0, 2, 1), PORT).into()).ok()? {
+        IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_unspecified() => {
+            let [a, b, c, _] = ip.octets();
+            Some(Ipv4Addr::new(a, b, c, 1))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,289 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+// Checking and moving a torrent's data can take minutes for large torrents.
+// They run one at a time on the disk scheduler's thread, so they don't fight
+// over the disk nor over the hash pool, which a single check keeps busy.
+// Each gets an `Operation` handle to watch its progress and cancel it by.
+// Cancelling is cooperative: the task checks its handle between pieces or
+// chunks and stops at the next one, leaving the data as it was before the
+// task started where it can.
+//
+// Checks and rechecks can be held at their checkpoint as well, e.g. while
+// a laptop runs on battery, and carry on where they were once released.
+
+/// How often a held operation looks whether it was cancelled meanwhile.
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What a long-running disk operation does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperationKind {
+    /// Checking the data already on disk when a torrent is added.
+    Check,
+    /// Hashing every piece again, asked for by the user.
+    Recheck,
+    /// Moving the data to another directory.
+    Move,
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OperationKind::Check => "checking",
+            OperationKind::Recheck => "rechecking",
+            OperationKind::Move => "moving",
+        })
+    }
+}
+
+impl OperationKind {
+    /// Whether it hashes every piece, which keeps a core busy throughout.
+    pub fn is_hashing(self) -> bool {
+        matches!(self, OperationKind::Check | OperationKind::Recheck)
+    }
+}
+
+/// The operation was cancelled before it finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether hashing operations wait at their next checkpoint, shared by a
+/// scheduler and every operation it runs.
+#[derive(Debug, Default)]
+struct Hold {
+    held: Mutex<bool>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct Shared {
+    kind: OperationKind,
+    /// Units done, e.g. pieces checked or bytes moved.
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+    /// `None` for an operation run outside of a scheduler.
+    hold: Option<Arc<Hold>>,
+}
+
+/// A handle to a long-running disk operation. Cheap to clone, every clone
+/// sees the same progress and cancels the same operation.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    shared: Arc<Shared>,
+}
+
+impl Operation {
+    /// An operation of `total` units, e.g. the number of pieces to check.
+    pub fn new(kind: OperationKind, total: u64) -> Self {
+        Self::with_hold(kind, total, None)
+    }
+
+    fn with_hold(kind: OperationKind, total: u64, hold: Option<Arc<Hold>>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                kind,
+                done: AtomicU64::new(0),
+                total: AtomicU64::new(total),
+                cancelled: AtomicBool::new(false),
+                finished: AtomicBool::new(false),
+                hold,
+            }),
+        }
+    }
+
+    pub fn kind(&self) -> OperationKind {
+        self.shared.kind
+    }
+
+    /// Change the total once it is known, e.g. the bytes of a move.
+    pub fn set_total(&self, total: u64) {
+        self.shared.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Count `units` more as done.
+    pub fn advance(&self, units: u64) {
+        self.shared.done.fetch_add(units, Ordering::Relaxed);
+    }
+
+    /// `0.0..=1.0`, an operation of nothing is done right away.
+    pub fn fraction(&self) -> f64 {
+        let total = self.shared.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 1.0;
+        }
+        (self.shared.done.load(Ordering::Relaxed) as f64 / total as f64).min(1.0)
+    }
+
+    /// Where the operation is, for the list output and the TUI.
+    pub fn progress(&self) -> OperationProgress {
+        // Floored, so 100% only shows once it really is done
+        OperationProgress {
+            kind: self.kind(),
+            percent: (self.fraction() * 100.0).floor() as u8,
+        }
+    }
+
+    /// Ask the operation to stop at its next checkpoint.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Whether the task is over, finished, failed or cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+
+    /// Called by the task between units of work, it must stop on `Err`.
+    /// Blocks while its scheduler holds hashing operations.
+    pub fn checkpoint(&self) -> Result<(), Cancelled> {
+        if let Some(hold) = self
+            .shared
+            .hold
+            .as_ref()
+            .filter(|_| self.kind().is_hashing())
+        {
+            let mut held = hold.held.lock().unwrap();
+            while *held && !self.is_cancelled() {
+                held = hold
+                    .released
+                    .wait_timeout(held, HOLD_POLL_INTERVAL)
+                    .unwrap()
+                    .0;
+            }
+        }
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    fn finish(&self) {
+        self.shared.finished.store(true, Ordering::Release);
+    }
+}
+
+/// A snapshot of an operation's progress, e.g. `rechecking 42%`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OperationProgress {
+    pub kind: OperationKind,
+    pub percent: u8,
+}
+
+impl fmt::Display for OperationProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}%", self.kind, self.percent)
+    }
+}
+
+type Task = Box<dyn FnOnce(&Operation) + Send>;
+
+#[derive(Default)]
+struct Queue {
+    tasks: Mutex<VecDeque<(Operation, Task)>>,
+    ready: Condvar,
+}
+
+/// Runs long-running disk operations one at a time, in the order they were
+/// submitted, on a thread of its own. Cheap to clone.
+#[derive(Clone)]
+pub struct DiskScheduler {
+    queue: Arc<Queue>,
+    hold: Arc<Hold>,
+}
+
+impl fmt::Debug for DiskScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskScheduler")
+            .field("queued", &self.queue.tasks.lock().unwrap().len())
+            .field("hashing_paused", &self.is_hashing_paused())
+            .finish()
+    }
+}
+
+impl Default for DiskScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskScheduler {
+    /// Start the scheduler's thread, it runs for as long as the process.
+    pub fn new() -> Self {
+        let queue = Arc::new(Queue::default());
+        let worker = queue.clone();
+        thread::spawn(move || loop {
+            let (operation, task) = {
+                let mut tasks = worker.tasks.lock().unwrap();
+                loop {
+                    match tasks.pop_front() {
+                        Some(next) => break next,
+                        None => tasks = worker.ready.wait(tasks).unwrap(),
+                    }
+                }
+            };
+            // One cancelled while it waited never starts
+            if !operation.is_cancelled() {
+                task(&operation);
+            }
+            operation.finish();
+        });
+        Self {
+            queue,
+            hold: Arc::new(Hold::default()),
+        }
+    }
+
+    /// Hold checks and rechecks at their next piece until resumed, moves
+    /// carry on.
+    pub fn pause_hashing(&self, paused: bool) {
+        *self.hold.held.lock().unwrap() = paused;
+        self.hold.released.notify_all();
+    }
+
+    pub fn is_hashing_paused(&self) -> bool {
+        *self.hold.held.lock().unwrap()
+    }
+
+    /// Queue `task` behind the operations already submitted. It gets the
+    /// returned handle's twin to report progress to and must call its
+    /// `checkpoint` between units of work.
+    pub fn submit(
+        &self,
+        kind: OperationKind,
+        total: u64,
+        task: impl FnOnce(&Operation) + Send + 'static,
+    ) -> Operation {
+        let operation = Operation::with_hold(kind, total, Some(self.hold.clone()));
+        self.queue
+            .tasks
+            .lock()
+            .unwrap()
+            .push_back((operation.clone(), Box::new(task)));
+        self.queue.ready.notify_one();
+        operation
+    }
+}
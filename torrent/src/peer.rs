@@ -0,0 +1,370 @@
+use std::io::{self, Read, Write};
+
+pub mod block_size;
+pub mod connection;
+pub mod extension;
+pub mod have;
+pub mod holepunch;
+pub mod listener;
+pub mod mse;
+pub mod registry;
+pub mod unchoke;
+pub mod upload_queue;
+pub mod ut_metadata;
+pub mod validation;
+
+// https://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+// https://wiki.theory.org/BitTorrentSpecification#Peer_wire_protocol_.28TCP.29
+
+/// The protocol identifier sent as part of every handshake.
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of the handshake in bytes: pstrlen + pstr + reserved + info_hash + peer_id.
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// The longest message accepted from a peer, e.g. the bitfield of a torrent
+/// with 8 million pieces. Anything longer is refused before it is read, so
+/// a bogus length prefix can't make us allocate gigabytes.
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+/// The block size all current implementations use when requesting pieces (16 KiB).
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PeerError {
+    Io(io::Error),
+    /// The remote did not send `BitTorrent protocol` as the protocol string.
+    InvalidProtocol,
+    /// The message id is not one we know about.
+    UnknownMessage(u8),
+    /// The message length does not match what its id requires.
+    InvalidLength {
+        id: u8,
+        len: u32,
+    },
+    /// The length prefix is over `MAX_MESSAGE_LEN`.
+    MessageTooLong(u32),
+}
+
+impl From<io::Error> for PeerError {
+    fn from(err: io::Error) -> Self {
+        PeerError::Io(err)
+    }
+}
+
+/// The handshake is the first message transmitted by both sides of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// Eight reserved bytes, used to advertise extensions.
+    pub reserved: [u8; 8],
+    /// The 20 byte sha1 hash of the bencoded form of the info value from the metainfo file.
+    pub info_hash: [u8; 20],
+    /// The 20 byte peer id of the sender.
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// Set the reserved bit advertising support for the extension protocol (BEP 10).
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// Set the reserved bit advertising support for the fast extension (BEP 6).
+    pub fn with_fast_extension(mut self) -> Self {
+        self.reserved[7] |= 0x04;
+        self
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, PeerError> {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        reader.read_exact(&mut bytes)?;
+
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(PeerError::InvalidProtocol);
+        }
+
+        let mut handshake = Self::new([0; 20], [0; 20]);
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake.peer_id.copy_from_slice(&bytes[48..68]);
+        Ok(handshake)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+/// All of the remaining messages in the protocol take the form of
+/// `<length prefix><message ID><payload>`. The length prefix is a four byte
+/// big-endian value. The message ID is a single decimal byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A message with a zero length prefix and no id or payload.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// The zero-based index of a piece that has just been successfully downloaded and verified.
+    Have(u32),
+    /// Which pieces the sender has, the high bit of the first byte is piece index 0.
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The port the sender's DHT node is listening on.
+    Port(u16),
+    /// The sender has every piece, sent instead of a bitfield (BEP 6).
+    HaveAll,
+    /// The sender has no pieces, sent instead of a bitfield (BEP 6).
+    HaveNone,
+    /// A message for an extension negotiated through the extension protocol (BEP 10).
+    /// An `id` of 0 is the extension handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    pub const CHOKE: u8 = 0;
+    pub const UNCHOKE: u8 = 1;
+    pub const INTERESTED: u8 = 2;
+    pub const NOT_INTERESTED: u8 = 3;
+    pub const HAVE: u8 = 4;
+    pub const BITFIELD: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const PIECE: u8 = 7;
+    pub const CANCEL: u8 = 8;
+    pub const PORT: u8 = 9;
+    pub const HAVE_ALL: u8 = 0x0E;
+    pub const HAVE_NONE: u8 = 0x0F;
+    pub const EXTENDED: u8 = 20;
+
+    /// The message id, `None` for keep-alives which have no id.
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Message::KeepAlive => None,
+            Message::Choke => Some(Self::CHOKE),
+            Message::Unchoke => Some(Self::UNCHOKE),
+            Message::Interested => Some(Self::INTERESTED),
+            Message::NotInterested => Some(Self::NOT_INTERESTED),
+            Message::Have(_) => Some(Self::HAVE),
+            Message::Bitfield(_) => Some(Self::BITFIELD),
+            Message::Request { .. } => Some(Self::REQUEST),
+            Message::Piece { .. } => Some(Self::PIECE),
+            Message::Cancel { .. } => Some(Self::CANCEL),
+            Message::Port(_) => Some(Self::PORT),
+            Message::HaveAll => Some(Self::HAVE_ALL),
+            Message::HaveNone => Some(Self::HAVE_NONE),
+            Message::Extended { .. } => Some(Self::EXTENDED),
+        }
+    }
+
+    /// Encode the message including its length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have(index) => payload.extend_from_slice(&index.to_be_bytes()),
+            Message::Bitfield(bits) => payload.extend_from_slice(bits),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+            }
+            Message::Port(port) => payload.extend_from_slice(&port.to_be_bytes()),
+            Message::Extended { id, payload: data } => {
+                payload.push(*id);
+                payload.extend_from_slice(data);
+            }
+        }
+
+        let id = self.id().expect("only keep-alives have no id");
+        let mut bytes = Vec::with_capacity(4 + 1 + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        bytes.push(id);
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, PeerError> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+
+        if len == 0 {
+            return Ok(Message::KeepAlive);
+        }
+        if len > MAX_MESSAGE_LEN {
+            return Err(PeerError::MessageTooLong(len));
+        }
+
+        let mut body = vec![0u8; len as usize];
+        reader.read_exact(&mut body)?;
+
+        Self::from_body(&body)
+    }
+
+    /// Decode a message from its id and payload (everything after the length prefix).
+    pub fn from_body(body: &[u8]) -> Result<Self, PeerError> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+
+        let invalid_length = || PeerError::InvalidLength {
+            id,
+            len: body.len() as u32,
+        };
+
+        let u32_at = |offset: usize| -> u32 {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&payload[offset..offset + 4]);
+            u32::from_be_bytes(bytes)
+        };
+
+        let message = match id {
+            Self::CHOKE
+            | Self::UNCHOKE
+            | Self::INTERESTED
+            | Self::NOT_INTERESTED
+            | Self::HAVE_ALL
+            | Self::HAVE_NONE => {
+                if !payload.is_empty() {
+                    return Err(invalid_length());
+                }
+                match id {
+                    Self::CHOKE => Message::Choke,
+                    Self::UNCHOKE => Message::Unchoke,
+                    Self::INTERESTED => Message::Interested,
+                    Self::NOT_INTERESTED => Message::NotInterested,
+                    Self::HAVE_ALL => Message::HaveAll,
+                    _ => Message::HaveNone,
+                }
+            }
+            Self::HAVE => {
+                if payload.len() != 4 {
+                    return Err(invalid_length());
+                }
+                Message::Have(u32_at(0))
+            }
+            Self::BITFIELD => Message::Bitfield(payload.to_vec()),
+            Self::REQUEST | Self::CANCEL => {
+                if payload.len() != 12 {
+                    return Err(invalid_length());
+                }
+                let (index, begin, length) = (u32_at(0), u32_at(4), u32_at(8));
+                if id == Self::REQUEST {
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            Self::PIECE => {
+                if payload.len() < 8 {
+                    return Err(invalid_length());
+                }
+                Message::Piece {
+                    index: u32_at(0),
+                    begin: u32_at(4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            Self::PORT => {
+                if payload.len() != 2 {
+                    return Err(invalid_length());
+                }
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            Self::EXTENDED => {
+                let Some((&id, data)) = payload.split_first() else {
+                    return Err(invalid_length());
+                };
+                Message::Extended {
+                    id,
+                    payload: data.to_vec(),
+                }
+            }
+            other => return Err(PeerError::UnknownMessage(other)),
+        };
+
+        Ok(message)
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use super::BLOCK_SIZE;
+
+/// The block size requested from fast peers that accept it (32 KiB).
+pub const LARGE_BLOCK_SIZE: u32 = 1 << 15;
+
+/// Download rate from a peer, in bytes per second, above which larger blocks
+/// are worth trying. Below it the per-block overhead does not matter.
+pub const LARGE_BLOCK_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+/// How long the rate from a peer is measured before it is compared against
+/// `LARGE_BLOCK_THRESHOLD`.
+pub const MEASURE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A large request that is not answered within this long counts as rejected.
+/// Strict clients tend to drop requests over 16 KiB without a word.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlockSizeState {
+    /// Requesting 16 KiB blocks while measuring the peer's rate.
+    Measuring,
+    /// The peer is fast, a single large request was sent to see whether it
+    /// is answered.
+    Probing { sent: Instant },
+    /// The peer answered a large request, every request is large.
+    Large,
+    /// The peer rejected or ignored a large request, it only gets 16 KiB
+    /// requests for as long as it is connected.
+    Clamped,
+}
+
+/// Picks the size of the blocks requested from a single peer.
+///
+/// Every peer starts at 16 KiB, which all clients accept. Once a peer
+/// sustains more than `LARGE_BLOCK_THRESHOLD`, one 32 KiB request is sent as a
+/// probe. If it is answered the peer gets 32 KiB requests until its rate drops
+/// again. If it is rejected or ignored the peer stays at 16 KiB.
+#[derive(Debug, Clone)]
+pub struct BlockSizer {
+    state: BlockSizeState,
+    window_start: Instant,
+    window_bytes: u64,
+    /// Bytes per second over the last full window.
+    rate: u64,
+}
+
+impl BlockSizer {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: BlockSizeState::Measuring,
+            window_start: now,
+            window_bytes: 0,
+            rate: 0,
+        }
+    }
+
+    pub fn state(&self) -> BlockSizeState {
+        self.state
+    }
+
+    /// The download rate from the peer over the last measured window, in
+    /// bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// The length of the next block to request at `begin` in a piece of
+    /// `piece_length` bytes. May start a probe, so only call this when the
+    /// request is actually sent.
+    pub fn next_request(&mut self, piece_length: u32, begin: u32, now: Instant) -> u32 {
+        let remaining = piece_length.saturating_sub(begin);
+        let size = match self.state {
+            BlockSizeState::Large => LARGE_BLOCK_SIZE,
+            // The probe has to actually be a large block, not the tail of a piece
+            BlockSizeState::Measuring
+                if self.rate >= LARGE_BLOCK_THRESHOLD && remaining >= LARGE_BLOCK_SIZE =>
+            {
+                self.state = BlockSizeState::Probing { sent: now };
+                LARGE_BLOCK_SIZE
+            }
+            _ => BLOCK_SIZE,
+        };
+        size.min(remaining)
+    }
+
+    /// A block of `length` bytes arrived from the peer.
+    pub fn block_received(&mut self, length: u32, now: Instant) {
+        if length > BLOCK_SIZE && matches!(self.state, BlockSizeState::Probing { .. }) {
+            self.state = BlockSizeState::Large;
+        }
+
+        self.window_bytes += length as u64;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= MEASURE_WINDOW {
+            self.rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.window_start = now;
+            self.window_bytes = 0;
+
+            // Large blocks only pay off on fast links, a peer that slowed
+            // down goes back to what every client expects
+            if self.state == BlockSizeState::Large && self.rate < LARGE_BLOCK_THRESHOLD / 2 {
+                self.state = BlockSizeState::Measuring;
+            }
+        }
+    }
+
+    /// The peer rejected a request of `length` bytes (BEP 6 `reject_request`).
+    pub fn rejected(&mut self, length: u32) {
+        if length > BLOCK_SIZE {
+            self.state = BlockSizeState::Clamped;
+        }
+    }
+
+    /// Clamp the peer if a probe has gone unanswered for `PROBE_TIMEOUT`.
+    /// Returns whether it did, the probe should then be requested again as
+    /// 16 KiB blocks.
+    pub fn check_timeout(&mut self, now: Instant) -> bool {
+        match self.state {
+            BlockSizeState::Probing { sent } if now.duration_since(sent) >= PROBE_TIMEOUT => {
+                self.state = BlockSizeState::Clamped;
+                true
+            }
+            _ => false,
+        }
+    }
+}
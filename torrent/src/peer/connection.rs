@@ -0,0 +1,313 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    fmt, io,
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    interface::{address_family, outgoing},
+    proxy::{proxy_for, Traffic},
+    rate_limit::RateLimiter,
+};
+
+/// Options applied to every peer socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Differentiated services code point (0-63) to mark peer traffic with,
+    /// so routers can deprioritize it. `None` leaves the OS default.
+    pub dscp: Option<u8>,
+    /// Disable Nagle's algorithm so small protocol messages go out immediately.
+    pub nodelay: bool,
+    /// Size of the kernel send buffer in bytes, `None` leaves the OS default.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the kernel receive buffer in bytes, `None` leaves the OS default.
+    pub recv_buffer_size: Option<usize>,
+    /// Idle time before TCP keepalive probes are sent, `None` disables keepalive.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            dscp: None,
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Apply the options to `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+
+        socket.set_tcp_nodelay(self.nodelay)?;
+
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        match self.keepalive {
+            Some(time) => {
+                socket.set_keepalive(true)?;
+                socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+            }
+            None => socket.set_keepalive(false)?,
+        }
+
+        if let Some(dscp) = self.dscp {
+            // The DSCP occupies the upper six bits of the TOS / traffic class byte.
+            let tos = (dscp.min(63) as u32) << 2;
+            match stream.local_addr()? {
+                SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+                #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+                SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+                #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+                SocketAddr::V6(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits on outgoing connection attempts, so starting a torrent with a
+/// large peer list does not open hundreds of sockets at once. Some consumer
+/// routers fall over with that many SYNs outstanding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Maximum connection attempts started per second, `0` is unlimited.
+    pub per_second: u32,
+    /// Maximum connection attempts in progress at the same time across every
+    /// torrent, `0` is unlimited.
+    pub half_open: usize,
+    /// Maximum peers waiting in line for an attempt, more are refused right
+    /// away and can be tried again later, `0` is unlimited.
+    pub pending: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            per_second: 20,
+            half_open: 50,
+            pending: 500,
+        }
+    }
+}
+
+/// How many connection attempts are in progress and waiting, e.g. for
+/// `flud daemon status`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionUsage {
+    pub half_open: usize,
+    /// `0` is unlimited.
+    pub max_half_open: usize,
+    /// Peers waiting in line for an attempt.
+    pub pending: usize,
+}
+
+impl fmt::Display for ConnectionUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_half_open {
+            0 => write!(f, "{} connecting", self.half_open)?,
+            max => write!(f, "{} of {max} connecting", self.half_open)?,
+        }
+        write!(f, ", {} queued", self.pending)
+    }
+}
+
+#[derive(Debug)]
+struct PacerState {
+    half_open: usize,
+    window_start: Instant,
+    started_in_window: u32,
+    /// Attempts take a ticket and start in ticket order, so a peer queued
+    /// first is connected to first.
+    next_ticket: u64,
+    /// The ticket allowed to start next.
+    serving: u64,
+}
+
+impl PacerState {
+    fn pending(&self) -> usize {
+        (self.next_ticket - self.serving) as usize
+    }
+}
+
+#[derive(Debug)]
+struct Pacer {
+    limits: ConnectionLimits,
+    state: Mutex<PacerState>,
+    freed: Condvar,
+}
+
+impl Pacer {
+    fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(PacerState {
+                half_open: 0,
+                window_start: Instant::now(),
+                started_in_window: 0,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Block until a connection attempt may be started, behind the attempts
+    /// already waiting. Fails right away if too many are.
+    fn acquire(self: &Arc<Self>) -> io::Result<HalfOpen> {
+        let mut state = self.state.lock().unwrap();
+        if self.limits.pending > 0 && state.pending() >= self.limits.pending {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many peers waiting to be connected to",
+            ));
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+
+        loop {
+            let now = Instant::now();
+            if now.duration_since(state.window_start) >= Duration::from_secs(1) {
+                state.window_start = now;
+                state.started_in_window = 0;
+            }
+
+            let half_open_full =
+                self.limits.half_open > 0 && state.half_open >= self.limits.half_open;
+            let rate_exceeded =
+                self.limits.per_second > 0 && state.started_in_window >= self.limits.per_second;
+
+            if ticket == state.serving && !half_open_full && !rate_exceeded {
+                state.serving += 1;
+                state.half_open += 1;
+                state.started_in_window += 1;
+                // The next in line may be able to start as well
+                self.freed.notify_all();
+                return Ok(HalfOpen {
+                    pacer: self.clone(),
+                });
+            }
+
+            // Wake up when an attempt starts or finishes or the rate window
+            // rolls over.
+            let window_end = state.window_start + Duration::from_secs(1);
+            let wait = window_end.saturating_duration_since(now);
+            state = self.freed.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    fn usage(&self) -> ConnectionUsage {
+        let state = self.state.lock().unwrap();
+        ConnectionUsage {
+            half_open: state.half_open,
+            max_half_open: self.limits.half_open,
+            pending: state.pending(),
+        }
+    }
+}
+
+/// A connection attempt in progress, released when dropped.
+struct HalfOpen {
+    pacer: Arc<Pacer>,
+}
+
+impl Drop for HalfOpen {
+    fn drop(&mut self) {
+        self.pacer.state.lock().unwrap().half_open -= 1;
+        // Only the front of the line may start, wake everyone to find it
+        self.pacer.freed.notify_all();
+    }
+}
+
+/// Opens and accepts peer connections, making sure every socket gets the
+/// configured options and outgoing attempts are paced. Cheap to clone,
+/// every clone shares the same limits, rate limits included.
+#[derive(Debug, Clone)]
+pub struct ConnectionManager {
+    options: SocketOptions,
+    pacer: Arc<Pacer>,
+    rate_limiter: RateLimiter,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new(SocketOptions::default())
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(options: SocketOptions) -> Self {
+        Self {
+            options,
+            pacer: Arc::new(Pacer::new(ConnectionLimits::default())),
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.pacer = Arc::new(Pacer::new(limits));
+        self
+    }
+
+    /// Cap the data sent and received over every connection, together
+    /// with whatever else shares `rate_limiter`, e.g. web seeds.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Throttle the blocks sent and received with this.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.pacer.limits
+    }
+
+    /// The attempts in progress and waiting, across every clone.
+    pub fn usage(&self) -> ConnectionUsage {
+        self.pacer.usage()
+    }
+
+    /// Connect to a peer, waiting for the connection limits first. Peers of
+    /// an IP version that is turned off are refused, even through a proxy.
+    pub fn connect(&self, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let family = address_family();
+        if !family.allows(addr.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{addr} can't be reached ({family})"),
+            ));
+        }
+        let _attempt = self.pacer.acquire()?;
+        let stream = match proxy_for(Traffic::Peers) {
+            Some(proxy) => proxy.connect(addr, timeout)?,
+            None => outgoing().connect(addr, timeout)?,
+        };
+        self.options.apply(&stream)?;
+        Ok(stream)
+    }
+
+    /// Prepare a socket accepted from a listener.
+    pub fn accepted(&self, stream: TcpStream) -> io::Result<TcpStream> {
+        self.options.apply(&stream)?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr};
+
+use super::Message;
+use crate::identity::Identity;
+
+// https://www.bittorrent.org/beps/bep_0010.html
+
+/// The extended message id reserved for the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExtensionError {
+    /// The extension handshake could not be bdecoded.
+    InvalidHandshake,
+    /// A message arrived for an extension id we never advertised.
+    UnknownExtension(u8),
+    /// The registered extension rejected the payload.
+    InvalidMessage(&'static str),
+}
+
+/// The payload of the extension handshake, sent as extended message id 0
+/// right after the regular handshake when both sides set the extension bit.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Dictionary of supported extension messages which maps names of
+    /// extensions to an extended message ID for each extension message.
+    /// An ID of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Local TCP listen port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version (as a utf-8 string).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// A string containing the compact representation of the ip address this
+    /// peer sees you as.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "optional_bytes"
+    )]
+    pub yourip: Option<Vec<u8>>,
+    /// The number of outstanding request messages this client supports
+    /// without dropping any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// The size of the info dictionary in bytes, sent by peers supporting `ut_metadata`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        serde_bencode::from_bytes(bytes).map_err(|_| ExtensionError::InvalidHandshake)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("failed to bencode extension handshake")
+    }
+
+    /// The address the remote sees us as, if it told us.
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        match self.yourip.as_deref()? {
+            &[a, b, c, d] => Some(IpAddr::from([a, b, c, d])),
+            bytes => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        }
+    }
+}
+
+mod optional_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(value: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(bytes) => serializer.serialize_bytes(bytes),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<serde_bytes::ByteBuf>::deserialize(deserializer)?.map(|b| b.into_vec()))
+    }
+}
+
+/// An extension to the peer protocol negotiated through the extension handshake,
+/// such as `ut_metadata` or `ut_pex`.
+pub trait Extension: Send {
+    /// The name the extension is advertised under in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Add any extension specific keys to the handshake we send.
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// Called once the remote's extension handshake has been received.
+    /// `supported` is false if the remote does not support this extension.
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake, _supported: bool) {}
+
+    /// Handle a message addressed to this extension, returning any payloads
+    /// that should be sent back to the remote under this extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError>;
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
+}
+
+/// The extensions enabled for a single peer connection along with the
+/// message ids both sides assigned to them.
+pub struct ExtensionRegistry {
+    client: Option<String>,
+    reqq: Option<u32>,
+    /// Our extensions, the local message id is the index + 1.
+    extensions: Vec<Box<dyn Extension>>,
+    /// The message ids the remote assigned to each extension name.
+    remote: Option<ExtensionHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new(client: impl Into<String>) -> Self {
+        Self {
+            client: Some(client.into()),
+            reqq: None,
+            extensions: Vec::new(),
+            remote: None,
+        }
+    }
+
+    /// A registry telling the remote the client name of `identity`, if any.
+    pub fn for_identity(identity: &Identity) -> Self {
+        Self {
+            client: identity.client_name().map(str::to_owned),
+            reqq: None,
+            extensions: Vec::new(),
+            remote: None,
+        }
+    }
+
+    /// Advertise how many outstanding requests we queue without dropping.
+    pub fn with_reqq(mut self, reqq: u32) -> Self {
+        self.reqq = Some(reqq);
+        self
+    }
+
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    /// Look up a registered extension by its concrete type.
+    pub fn get_mut<T: Extension + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions
+            .iter_mut()
+            .find_map(|extension| extension.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// Build our extension handshake. `your_ip` is the address we see the remote as.
+    pub fn handshake(
+        &self,
+        listen_port: Option<u16>,
+        your_ip: Option<IpAddr>,
+    ) -> ExtensionHandshake {
+        let mut handshake = ExtensionHandshake {
+            p: listen_port,
+            v: self.client.clone(),
+            reqq: self.reqq,
+            yourip: your_ip.map(|ip| match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            }),
+            ..Default::default()
+        };
+
+        for (index, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_owned(), index as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+
+        handshake
+    }
+
+    pub fn handshake_message(&self, listen_port: Option<u16>, your_ip: Option<IpAddr>) -> Message {
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: self.handshake(listen_port, your_ip).to_bytes(),
+        }
+    }
+
+    /// The remote's extension handshake, once received.
+    pub fn remote(&self) -> Option<&ExtensionHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id the remote wants to receive messages for `name` under.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote
+            .as_ref()?
+            .m
+            .get(name)
+            .copied()
+            .filter(|&id| id != 0)
+    }
+
+    /// Wrap `payload` for the extension `name` so it can be sent to the remote.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        let id = self.remote_id(name)?;
+        Some(Message::Extended { id, payload })
+    }
+
+    /// Dispatch an incoming extended message, returning any replies.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, ExtensionError> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::from_bytes(payload)?;
+            for extension in &mut self.extensions {
+                let supported = handshake.m.get(extension.name()).is_some_and(|&id| id != 0);
+                extension.on_handshake(&handshake, supported);
+            }
+            self.remote = Some(handshake);
+            return Ok(Vec::new());
+        }
+
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Err(ExtensionError::UnknownExtension(id));
+        };
+
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+        Ok(replies
+            .into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect())
+    }
+}
@@ -0,0 +1,149 @@
+use super::Message;
+use crate::{disk::CommittedPiece, swarm::has_piece};
+
+/// How many `have` messages were sent to a peer and how many were not
+/// because the peer already had the piece.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HaveStats {
+    pub sent: u64,
+    pub suppressed: u64,
+}
+
+/// The pieces a remote peer has, from its bitfield and `have` messages.
+#[derive(Debug, Clone)]
+pub struct RemotePieces {
+    bitfield: Vec<u8>,
+    piece_count: usize,
+    count: usize,
+}
+
+impl RemotePieces {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            bitfield: vec![0; piece_count.div_ceil(8)],
+            piece_count,
+            count: 0,
+        }
+    }
+
+    /// The peer sent `bitfield`.
+    pub fn set_bitfield(&mut self, bitfield: &[u8]) {
+        self.bitfield = vec![0; self.piece_count.div_ceil(8)];
+        self.count = 0;
+        for index in 0..self.piece_count {
+            if has_piece(bitfield, index) {
+                self.insert(index);
+            }
+        }
+    }
+
+    /// The peer sent `have_all` (BEP 6).
+    pub fn set_all(&mut self) {
+        for index in 0..self.piece_count {
+            self.insert(index);
+        }
+    }
+
+    /// The peer sent `have`, returns false if it was already known.
+    pub fn insert(&mut self, index: usize) -> bool {
+        if index >= self.piece_count || self.has(index) {
+            return false;
+        }
+        self.bitfield[index / 8] |= 0x80 >> (index % 8);
+        self.count += 1;
+        true
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        has_piece(&self.bitfield, index)
+    }
+
+    pub fn bitfield(&self) -> &[u8] {
+        &self.bitfield
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.count == self.piece_count
+    }
+}
+
+/// Decides which `have` messages to send to one peer.
+///
+/// A peer that already has a piece (most importantly a seed, which has all
+/// of them) is never told we have it. While pieces are being checked,
+/// e.g. on resume, haves are collected and sent in one go when checking is
+/// done instead of trickling out one message per piece.
+#[derive(Debug, Clone)]
+pub struct HaveAnnouncer {
+    remote: RemotePieces,
+    pending: Vec<u32>,
+    batching: bool,
+    stats: HaveStats,
+}
+
+impl HaveAnnouncer {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            remote: RemotePieces::new(piece_count),
+            pending: Vec::new(),
+            batching: false,
+            stats: HaveStats::default(),
+        }
+    }
+
+    pub fn remote(&self) -> &RemotePieces {
+        &self.remote
+    }
+
+    pub fn remote_mut(&mut self) -> &mut RemotePieces {
+        &mut self.remote
+    }
+
+    pub fn stats(&self) -> HaveStats {
+        self.stats
+    }
+
+    /// Collect haves instead of sending them until `flush` is called.
+    pub fn start_batch(&mut self) {
+        self.batching = true;
+    }
+
+    /// We completed, verified and wrote `piece`, returns the `have` to send
+    /// to this peer if it should be told.
+    pub fn piece_completed(&mut self, piece: CommittedPiece) -> Option<Message> {
+        let index = piece.index();
+        if self.remote.has(index as usize) {
+            self.stats.suppressed += 1;
+            return None;
+        }
+
+        if self.batching {
+            self.pending.push(index);
+            return None;
+        }
+
+        self.stats.sent += 1;
+        Some(Message::Have(index))
+    }
+
+    /// Stop batching and return the collected haves, leaving out pieces the
+    /// peer got in the meantime.
+    pub fn flush(&mut self) -> Vec<Message> {
+        self.batching = false;
+
+        let mut messages = Vec::with_capacity(self.pending.len());
+        for index in std::mem::take(&mut self.pending) {
+            if self.remote.has(index as usize) {
+                self.stats.suppressed += 1;
+            } else {
+                self.stats.sent += 1;
+                messages.push(Message::Have(index));
+            }
+        }
+        messages
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    any::Any,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use super::{
+    extension::{Extension, ExtensionError, ExtensionHandshake},
+    registry::PeerRegistry,
+};
+use crate::info_hash::InfoHash;
+
+// https://www.bittorrent.org/beps/bep_0055.html
+//
+// Two peers behind NATs can't connect to each other directly, but a third
+// peer connected to both can introduce them. The initiator sends the relay a
+// rendezvous naming the target, the relay sends each of them a connect with
+// the other's address, and both connect at the same time so each NAT sees
+// the incoming packets as answers to outgoing ones.
+
+/// The name `ut_holepunch` is advertised under in the extension handshake.
+pub const NAME: &str = "ut_holepunch";
+
+const RENDEZVOUS: u8 = 0;
+const CONNECT: u8 = 1;
+const ERROR: u8 = 2;
+
+const IPV4: u8 = 0;
+const IPV6: u8 = 1;
+
+/// Why a relay could not introduce two peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The target endpoint is invalid.
+    NoSuchPeer,
+    /// The relay is not connected to the target.
+    NotConnected,
+    /// The target does not support the holepunch extension.
+    NoSupport,
+    /// The target is the relay itself.
+    NoSelf,
+}
+
+impl ErrorCode {
+    fn from_u32(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(ErrorCode::NoSuchPeer),
+            2 => Some(ErrorCode::NotConnected),
+            3 => Some(ErrorCode::NoSupport),
+            4 => Some(ErrorCode::NoSelf),
+            _ => None,
+        }
+    }
+
+    fn as_u32(self) -> u32 {
+        match self {
+            ErrorCode::NoSuchPeer => 1,
+            ErrorCode::NotConnected => 2,
+            ErrorCode::NoSupport => 3,
+            ErrorCode::NoSelf => 4,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::NoSuchPeer => write!(f, "no such peer"),
+            ErrorCode::NotConnected => write!(f, "not connected to the target"),
+            ErrorCode::NoSupport => write!(f, "the target does not support holepunching"),
+            ErrorCode::NoSelf => write!(f, "the target is the relay"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    /// Sent to a relay, asking it to introduce us to the peer at this address.
+    Rendezvous(SocketAddr),
+    /// Sent by a relay, connect to the peer at this address now.
+    Connect(SocketAddr),
+    /// Sent by a relay that could not introduce us to the peer at this address.
+    Error(SocketAddr, ErrorCode),
+}
+
+impl HolepunchMessage {
+    /// Decode `msg_type`, `addr_type`, `addr`, `port` and `err_code`, all
+    /// big endian.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ExtensionError> {
+        let invalid = || ExtensionError::InvalidMessage(NAME);
+        let (&[msg_type, addr_type], rest) = bytes.split_first_chunk().ok_or_else(invalid)?;
+
+        let (ip, rest) = match addr_type {
+            IPV4 => {
+                let (ip, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+                (IpAddr::from(*ip), rest)
+            }
+            IPV6 => {
+                let (ip, rest) = rest.split_first_chunk::<16>().ok_or_else(invalid)?;
+                (IpAddr::from(*ip), rest)
+            }
+            _ => return Err(invalid()),
+        };
+        let (port, rest) = rest.split_first_chunk::<2>().ok_or_else(invalid)?;
+        let (err_code, _) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let addr = SocketAddr::new(ip, u16::from_be_bytes(*port));
+
+        match msg_type {
+            RENDEZVOUS => Ok(HolepunchMessage::Rendezvous(addr)),
+            CONNECT => Ok(HolepunchMessage::Connect(addr)),
+            ERROR => {
+                let code =
+                    ErrorCode::from_u32(u32::from_be_bytes(*err_code)).ok_or_else(invalid)?;
+                Ok(HolepunchMessage::Error(addr, code))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, addr, err_code) = match *self {
+            HolepunchMessage::Rendezvous(addr) => (RENDEZVOUS, addr, 0),
+            HolepunchMessage::Connect(addr) => (CONNECT, addr, 0),
+            HolepunchMessage::Error(addr, code) => (ERROR, addr, code.as_u32()),
+        };
+
+        let mut bytes = vec![msg_type];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(IPV4);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(IPV6);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&addr.port().to_be_bytes());
+        bytes.extend_from_slice(&err_code.to_be_bytes());
+        bytes
+    }
+}
+
+/// Receives holepunch messages on a single peer connection. They concern
+/// other connections, so they are queued for the session to act on instead
+/// of being answered here.
+#[derive(Debug, Default)]
+pub struct Holepunch {
+    /// The remote advertised `ut_holepunch`, it can relay for us and be
+    /// introduced to others.
+    supported: bool,
+    received: Vec<HolepunchMessage>,
+}
+
+impl Holepunch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    /// The messages received since the last call, oldest first.
+    pub fn take_received(&mut self) -> Vec<HolepunchMessage> {
+        std::mem::take(&mut self.received)
+    }
+}
+
+impl Extension for Holepunch {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake, supported: bool) {
+        self.supported = supported;
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        self.received.push(HolepunchMessage::from_bytes(payload)?);
+        Ok(Vec::new())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Answer `initiator` asking us to introduce it to `target`, both peers of
+/// the torrent. Returns each message to send with the address of the
+/// connection it goes to.
+///
+/// `supports_holepunch` tells whether the connection to an address
+/// advertised `ut_holepunch`.
+pub fn relay(
+    registry: &PeerRegistry,
+    info_hash: InfoHash,
+    initiator: SocketAddr,
+    target: SocketAddr,
+    supports_holepunch: impl Fn(SocketAddr) -> bool,
+) -> Vec<(SocketAddr, HolepunchMessage)> {
+    let error = if target.ip().is_unspecified() || target.port() == 0 || target == initiator {
+        Some(ErrorCode::NoSuchPeer)
+    } else if registry.is_own(target) {
+        Some(ErrorCode::NoSelf)
+    } else if !registry.is_connected(info_hash, target) {
+        Some(ErrorCode::NotConnected)
+    } else if !supports_holepunch(target) {
+        Some(ErrorCode::NoSupport)
+    } else {
+        None
+    };
+
+    match error {
+        Some(code) => vec![(initiator, HolepunchMessage::Error(target, code))],
+        None => vec![
+            (target, HolepunchMessage::Connect(initiator)),
+            (initiator, HolepunchMessage::Connect(target)),
+        ],
+    }
+}
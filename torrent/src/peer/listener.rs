@@ -0,0 +1,263 @@
+use socket2::{Domain, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{
+    connection::ConnectionManager,
+    mse::{self, EncryptedStream, EncryptionMode, MseError},
+    Handshake, PeerError,
+};
+use crate::interface::{address_family, outgoing};
+
+/// The ports clients traditionally listen on, tried in order when the
+/// configured one is taken (BEP 3).
+pub const PORT_RANGE: RangeInclusive<u16> = 6881..=6889;
+
+/// Peers that don't finish the handshake within this long are dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the accept loop checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ListenError {
+    Mse(MseError),
+    Peer(PeerError),
+    /// The peer asked for a torrent we don't have loaded.
+    UnknownTorrent([u8; 20]),
+}
+
+impl From<MseError> for ListenError {
+    fn from(err: MseError) -> Self {
+        ListenError::Mse(err)
+    }
+}
+
+impl From<PeerError> for ListenError {
+    fn from(err: PeerError) -> Self {
+        ListenError::Peer(err)
+    }
+}
+
+impl From<io::Error> for ListenError {
+    fn from(err: io::Error) -> Self {
+        ListenError::Peer(PeerError::Io(err))
+    }
+}
+
+/// A peer that connected to us and sent its handshake for a loaded torrent.
+/// Our handshake is not sent yet, that is up to the torrent.
+#[derive(Debug)]
+pub struct IncomingPeer {
+    pub stream: EncryptedStream<TcpStream>,
+    pub addr: SocketAddr,
+    pub handshake: Handshake,
+}
+
+/// Accepts the connections peers open to us, over IPv4 and IPv6.
+#[derive(Debug)]
+pub struct PeerListener {
+    /// `None` when IPv4 is turned off.
+    v4: Option<TcpListener>,
+    /// `None` when the host has no IPv6 or it is turned off.
+    v6: Option<TcpListener>,
+    port: u16,
+    connections: ConnectionManager,
+    encryption: EncryptionMode,
+}
+
+impl PeerListener {
+    /// Listen on `port` on every IPv4 and IPv6 address. If it is taken and
+    /// one of `PORT_RANGE`, the next free port of the range is used instead,
+    /// `port` tells which. Failing to listen on IPv6 is only an error when
+    /// it is the one IP version in use, see `interface::address_family`.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let fallback = PORT_RANGE
+            .contains(&port)
+            .then_some(PORT_RANGE)
+            .into_iter()
+            .flatten()
+            .filter(|&other| other != port);
+        let family = address_family();
+        let first: IpAddr = match family.allows_ipv4() {
+            true => Ipv4Addr::UNSPECIFIED.into(),
+            false => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        let mut last_err = None;
+        for port in std::iter::once(port).chain(fallback) {
+            match bind_tcp((first, port).into()) {
+                Ok(listener) => {
+                    // Port 0 asks the OS for any free port
+                    let port = listener.local_addr()?.port();
+                    let (v4, v6) = match first.is_ipv4() {
+                        true => {
+                            let v6 = family
+                                .allows_ipv6()
+                                .then(|| bind_tcp((Ipv6Addr::UNSPECIFIED, port).into()).ok())
+                                .flatten();
+                            (Some(listener), v6)
+                        }
+                        false => (None, Some(listener)),
+                    };
+                    return Ok(Self {
+                        v4,
+                        v6,
+                        port,
+                        connections: ConnectionManager::default(),
+                        encryption: EncryptionMode::default(),
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => last_err = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::ErrorKind::AddrInUse.into()))
+    }
+
+    /// Apply the configured socket options to accepted connections.
+    pub fn with_connections(mut self, connections: ConnectionManager) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: EncryptionMode) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// The port actually listened on, to announce to trackers and the DHT.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether peers can connect over IPv6 as well.
+    pub fn has_ipv6(&self) -> bool {
+        self.v6.is_some()
+    }
+
+    /// Accept connections until `stop` is set.
+    ///
+    /// Every connection is handshaken on its own thread. Those for one of
+    /// the torrents `info_hashes` returns at the time are passed to
+    /// `hand_off`, the others are closed.
+    pub fn run<T, H>(&self, stop: &AtomicBool, info_hashes: T, hand_off: H) -> io::Result<()>
+    where
+        T: Fn() -> Vec<[u8; 20]>,
+        H: Fn(IncomingPeer) + Send + Sync + 'static,
+    {
+        let listeners: Vec<&TcpListener> = self.v4.iter().chain(&self.v6).collect();
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        let hand_off = Arc::new(hand_off);
+
+        while !stop.load(Ordering::Relaxed) {
+            let mut accepted = None;
+            for listener in &listeners {
+                match listener.accept() {
+                    Ok(connection) => {
+                        accepted = Some(connection);
+                        break;
+                    }
+                    // Nobody waiting, or the peer gave up before we got to it
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionAborted
+                        ) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            let Some((stream, addr)) = accepted else {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            };
+
+            let info_hashes = info_hashes();
+            let connections = self.connections.clone();
+            let encryption = self.encryption;
+            let hand_off = Arc::clone(&hand_off);
+            thread::spawn(move || {
+                match handshake(stream, addr, &connections, &info_hashes, encryption) {
+                    Ok(peer) => hand_off(peer),
+                    // Port scanners and peers that changed their mind
+                    Err(ListenError::Mse(MseError::Io(_)))
+                    | Err(ListenError::Peer(PeerError::Io(_))) => {}
+                    Err(err) => log::debug!("{addr}: {err:?}"),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// An IPv6 listener only takes IPv6, so an IPv4 one can listen on the same port.
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Like std, so a restart doesn't find the port taken by old connections
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    outgoing().bind(&socket, addr)?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Our IPv6 address on the internet, if the host has one. Link-local and
+/// unique local addresses are of no use to peers elsewhere.
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    // Connecting a UDP socket sends nothing, it only picks the route
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(("2001:4860:4860::8888", 80)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        std::net::IpAddr::V6(ip)
+            if !ip.is_loopback()
+                && !ip.is_unicast_link_local()
+                && !ip.is_unique_local()
+                && ip.to_ipv4_mapped().is_none() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+/// Read the handshake of a peer that connected to us, through encryption if
+/// it negotiates it, and check it is for one of `info_hashes`.
+pub fn handshake(
+    stream: TcpStream,
+    addr: SocketAddr,
+    connections: &ConnectionManager,
+    info_hashes: &[[u8; 20]],
+    encryption: EncryptionMode,
+) -> Result<IncomingPeer, ListenError> {
+    let stream = connections.accepted(stream)?;
+    // Accepted sockets inherit non-blocking from the listener on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let (mut stream, _) = mse::accept(stream, info_hashes, encryption)?;
+    let handshake = Handshake::read_from(&mut stream)?;
+    if !info_hashes.contains(&handshake.info_hash) {
+        return Err(ListenError::UnknownTorrent(handshake.info_hash));
+    }
+
+    Ok(IncomingPeer {
+        stream,
+        addr,
+        handshake,
+    })
+}
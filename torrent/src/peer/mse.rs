@@ -0,0 +1,560 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1_smol::Sha1;
+
+use super::PROTOCOL;
+
+// Message Stream Encryption, also known as Protocol Encryption
+// https://wiki.vuze.com/w/Message_Stream_Encryption
+
+/// The 768 bit prime the Diffie-Hellman exchange is done in.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+
+/// Length of a Diffie-Hellman public key in bytes.
+const KEY_LEN: usize = 96;
+/// The most random padding either side may send.
+const MAX_PAD: usize = 512;
+/// Eight zero bytes, used to verify both sides derived the same keys.
+const VC: [u8; 8] = [0; 8];
+/// RC4 keystream bytes thrown away before use, the start is weak.
+const DISCARD: usize = 1024;
+
+const PROVIDE_PLAINTEXT: u32 = 0x01;
+const PROVIDE_RC4: u32 = 0x02;
+
+/// Whether peer connections are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionMode {
+    /// Only speak the plain protocol, encrypted incoming connections are refused.
+    Disabled,
+    /// Encrypt outgoing connections and accept both plain and encrypted
+    /// incoming ones.
+    #[default]
+    Prefer,
+    /// Only speak to peers that encrypt.
+    Require,
+}
+
+/// How the payload of a connection is sent once the handshake is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoMethod {
+    Plaintext,
+    Rc4,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MseError {
+    Io(io::Error),
+    /// The peer sent a plain handshake but encryption is required.
+    EncryptionRequired,
+    /// The peer started an encrypted handshake but encryption is disabled.
+    EncryptionDisabled,
+    /// The marker that ends the peer's padding was not found, it does not
+    /// speak the protocol or derived different keys.
+    SyncNotFound,
+    /// The peer asked for a torrent we don't have.
+    UnknownInfoHash,
+    /// The verification constant did not decrypt to zeros.
+    InvalidVerification,
+    /// The peer sent a padding length over the maximum.
+    InvalidPadding(usize),
+    /// The peer offered or picked no method allowed by our mode.
+    NoSharedMethod,
+}
+
+impl From<io::Error> for MseError {
+    fn from(err: io::Error) -> Self {
+        MseError::Io(err)
+    }
+}
+
+/// The RC4 stream cipher, as the protocol requires.
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, byte) in state.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; DISCARD]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+/// A peer connection after the encryption handshake. Reads and writes go
+/// through the negotiated cipher, or straight to the stream for plain
+/// connections.
+#[derive(Debug)]
+pub struct EncryptedStream<S> {
+    inner: S,
+    /// Decrypted bytes received during the handshake, read before the stream.
+    buffered: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+}
+
+impl<S> EncryptedStream<S> {
+    fn plain(inner: S, buffered: Vec<u8>) -> Self {
+        Self {
+            inner,
+            buffered,
+            decrypt: None,
+            encrypt: None,
+        }
+    }
+
+    pub fn method(&self) -> CryptoMethod {
+        match self.encrypt {
+            Some(_) => CryptoMethod::Rc4,
+            None => CryptoMethod::Plaintext,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl EncryptedStream<TcpStream> {
+    /// Split into a half to read from and a half to write to, so one thread
+    /// can wait for messages while another sends. Each half only has the
+    /// cipher of its own direction, `method` is only right on the write half.
+    pub fn try_split(self) -> io::Result<(Self, Self)> {
+        let writer = Self {
+            inner: self.inner.try_clone()?,
+            buffered: Vec::new(),
+            decrypt: None,
+            encrypt: self.encrypt,
+        };
+        let reader = Self {
+            inner: self.inner,
+            buffered: self.buffered,
+            decrypt: self.decrypt,
+            encrypt: None,
+        };
+        Ok((reader, writer))
+    }
+}
+
+impl<S: Read> Read for EncryptedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffered.is_empty() {
+            let len = buf.len().min(self.buffered.len());
+            buf[..len].copy_from_slice(&self.buffered[..len]);
+            self.buffered.drain(..len);
+            return Ok(len);
+        }
+
+        let len = self.inner.read(buf)?;
+        if let Some(rc4) = &mut self.decrypt {
+            rc4.apply(&mut buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for EncryptedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encrypt {
+            Some(rc4) => {
+                // The keystream has moved on, so all of it has to go out
+                let mut data = buf.to_vec();
+                rc4.apply(&mut data);
+                self.inner.write_all(&data)?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Open an outgoing connection to a peer of the torrent `info_hash`.
+///
+/// `initial_payload`, usually our handshake, is sent along with the last
+/// handshake message to save a round trip. With `Disabled` it is written as is.
+///
+/// Peers that don't support encryption close the connection, with `Prefer`
+/// connect again with `Disabled` to talk to them.
+pub fn initiate<S: Read + Write>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    mode: EncryptionMode,
+    initial_payload: &[u8],
+) -> Result<EncryptedStream<S>, MseError> {
+    if mode == EncryptionMode::Disabled {
+        stream.write_all(initial_payload)?;
+        return Ok(EncryptedStream::plain(stream, Vec::new()));
+    }
+
+    let (private, public) = key_pair();
+    stream.write_all(&with_padding(&public))?;
+
+    let mut their_key = [0u8; KEY_LEN];
+    stream.read_exact(&mut their_key)?;
+    let secret = shared_secret(&private, &their_key);
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let provide = match mode {
+        EncryptionMode::Require => PROVIDE_RC4,
+        _ => PROVIDE_RC4 | PROVIDE_PLAINTEXT,
+    };
+    let pad = random_pad();
+    let mut message = Vec::new();
+    message.extend_from_slice(&hash(&[b"req1", &secret]));
+    message.extend_from_slice(&xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let encrypted_start = message.len();
+    message.extend_from_slice(&VC);
+    message.extend_from_slice(&provide.to_be_bytes());
+    message.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    message.extend_from_slice(&pad);
+    message.extend_from_slice(&(initial_payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(initial_payload);
+    encrypt.apply(&mut message[encrypted_start..]);
+    stream.write_all(&message)?;
+
+    // Their padding ends where the encrypted verification constant starts
+    let mut marker = VC;
+    decrypt.clone().apply(&mut marker);
+    sync(&mut stream, &marker, MAX_PAD + marker.len())?;
+    decrypt.apply(&mut [0; VC.len()]);
+
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select)?;
+    decrypt.apply(&mut select);
+    let method = u32::from_be_bytes([select[0], select[1], select[2], select[3]]);
+    let pad_len = u16::from_be_bytes([select[4], select[5]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::InvalidPadding(pad_len));
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad)?;
+    decrypt.apply(&mut pad);
+
+    match method {
+        PROVIDE_RC4 => Ok(EncryptedStream {
+            inner: stream,
+            buffered: Vec::new(),
+            decrypt: Some(decrypt),
+            encrypt: Some(encrypt),
+        }),
+        PROVIDE_PLAINTEXT if mode != EncryptionMode::Require => {
+            Ok(EncryptedStream::plain(stream, Vec::new()))
+        }
+        _ => Err(MseError::NoSharedMethod),
+    }
+}
+
+/// Accept an incoming connection for any of the torrents in `info_hashes`.
+///
+/// Both plain and encrypted connections are recognized, as far as `mode`
+/// allows. Returns the stream, with anything the peer already sent (e.g. its
+/// handshake) ready to be read, and the info hash it asked for if the
+/// connection is encrypted.
+pub fn accept<S: Read + Write>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    mode: EncryptionMode,
+) -> Result<(EncryptedStream<S>, Option<[u8; 20]>), MseError> {
+    // A plain handshake starts with the protocol string, a public key is random
+    let mut start = [0u8; 1 + PROTOCOL.len()];
+    stream.read_exact(&mut start)?;
+    if start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL {
+        if mode == EncryptionMode::Require {
+            return Err(MseError::EncryptionRequired);
+        }
+        return Ok((EncryptedStream::plain(stream, start.to_vec()), None));
+    }
+    if mode == EncryptionMode::Disabled {
+        return Err(MseError::EncryptionDisabled);
+    }
+
+    let mut their_key = [0u8; KEY_LEN];
+    their_key[..start.len()].copy_from_slice(&start);
+    stream.read_exact(&mut their_key[start.len()..])?;
+
+    let (private, public) = key_pair();
+    stream.write_all(&with_padding(&public))?;
+    let secret = shared_secret(&private, &their_key);
+
+    let req1 = hash(&[b"req1", &secret]);
+    sync(&mut stream, &req1, MAX_PAD + req1.len())?;
+
+    let mut skey = [0u8; 20];
+    stream.read_exact(&mut skey)?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(&hash(&[b"req2", info_hash.as_slice()]), &req3) == skey)
+        .ok_or(MseError::UnknownInfoHash)?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err(MseError::InvalidVerification);
+    }
+    let provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::InvalidPadding(pad_len));
+    }
+    // The padding, then the length of the initial payload
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad)?;
+    decrypt.apply(&mut pad);
+    let payload_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut payload = vec![0u8; payload_len];
+    stream.read_exact(&mut payload)?;
+    decrypt.apply(&mut payload);
+
+    let method = if provide & PROVIDE_RC4 != 0 {
+        PROVIDE_RC4
+    } else if provide & PROVIDE_PLAINTEXT != 0 && mode != EncryptionMode::Require {
+        PROVIDE_PLAINTEXT
+    } else {
+        return Err(MseError::NoSharedMethod);
+    };
+
+    let pad = random_pad();
+    let mut message = Vec::new();
+    message.extend_from_slice(&VC);
+    message.extend_from_slice(&method.to_be_bytes());
+    message.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    message.extend_from_slice(&pad);
+    encrypt.apply(&mut message);
+    stream.write_all(&message)?;
+
+    let stream = if method == PROVIDE_RC4 {
+        EncryptedStream {
+            inner: stream,
+            buffered: payload,
+            decrypt: Some(decrypt),
+            encrypt: Some(encrypt),
+        }
+    } else {
+        EncryptedStream::plain(stream, payload)
+    };
+    Ok((stream, Some(info_hash)))
+}
+
+/// A random 160 bit private key and the public key to send for it.
+fn key_pair() -> (BigUint, [u8; KEY_LEN]) {
+    let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+    let public = BigUint::from(GENERATOR).modpow(&private, &prime());
+    (private, to_key_bytes(&public))
+}
+
+fn shared_secret(private: &BigUint, their_key: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let their_key = BigUint::from_bytes_be(their_key);
+    to_key_bytes(&their_key.modpow(private, &prime()))
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("the prime is valid hex")
+}
+
+/// `value` as a big-endian number padded to the full key length.
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    for part in parts {
+        sha1.update(part);
+    }
+    sha1.digest().bytes()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|index| a[index] ^ b[index])
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD)];
+    rng.fill(pad.as_mut_slice());
+    pad
+}
+
+fn with_padding(key: &[u8; KEY_LEN]) -> Vec<u8> {
+    let mut message = key.to_vec();
+    message.extend_from_slice(&random_pad());
+    message
+}
+
+/// Read from `stream` until the bytes read end with `marker`, giving up
+/// after `max` bytes.
+fn sync<S: Read>(stream: &mut S, marker: &[u8], max: usize) -> Result<(), MseError> {
+    let mut read = Vec::with_capacity(max);
+    let mut byte = [0u8; 1];
+    while read.len() < max {
+        stream.read_exact(&mut byte)?;
+        read.push(byte[0]);
+        if read.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    Err(MseError::SyncNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    /// Sent along with the handshake, starting like a plain handshake.
+    fn payload() -> Vec<u8> {
+        [&[PROTOCOL.len() as u8][..], PROTOCOL, b"hello"].concat()
+    }
+
+    type Initiated = Result<EncryptedStream<TcpStream>, MseError>;
+    type Accepted = Result<(EncryptedStream<TcpStream>, Option<[u8; 20]>), MseError>;
+
+    /// Run `initiate` from another thread against `accept` on this one.
+    fn connect(
+        info_hash: [u8; 20],
+        outgoing: EncryptionMode,
+        incoming: EncryptionMode,
+    ) -> (Option<Initiated>, Accepted) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let initiator = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            initiate(stream, &info_hash, outgoing, &payload())
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let accepted = accept(stream, &[[1; 20], INFO_HASH], incoming);
+        // Left waiting for an answer when accepting failed
+        let initiated = accepted.is_ok().then(|| initiator.join().unwrap());
+        (initiated, accepted)
+    }
+
+    fn read_to(stream: &mut impl Read, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn both_sides_agree_on_rc4() {
+        for outgoing in [EncryptionMode::Prefer, EncryptionMode::Require] {
+            let (initiated, accepted) = connect(INFO_HASH, outgoing, EncryptionMode::Prefer);
+            let mut initiated = initiated.unwrap().unwrap();
+            let (mut accepted, info_hash) = accepted.unwrap();
+            assert_eq!(info_hash, Some(INFO_HASH));
+            assert_eq!(initiated.method(), CryptoMethod::Rc4);
+            assert_eq!(accepted.method(), CryptoMethod::Rc4);
+
+            // The handshake sent along comes first, then whatever follows
+            assert_eq!(read_to(&mut accepted, payload().len()), payload());
+            initiated.write_all(b"request").unwrap();
+            assert_eq!(read_to(&mut accepted, 7), b"request");
+            accepted.write_all(b"piece").unwrap();
+            assert_eq!(read_to(&mut initiated, 5), b"piece");
+        }
+    }
+
+    #[test]
+    fn plain_handshakes_are_replayed_unless_encryption_is_required() {
+        let (initiated, accepted) =
+            connect(INFO_HASH, EncryptionMode::Disabled, EncryptionMode::Prefer);
+        let (mut accepted, info_hash) = accepted.unwrap();
+        assert_eq!(info_hash, None);
+        assert_eq!(
+            initiated.unwrap().unwrap().method(),
+            CryptoMethod::Plaintext
+        );
+        assert_eq!(read_to(&mut accepted, payload().len()), payload());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.write_all(&[PROTOCOL.len() as u8]).unwrap();
+        stream.write_all(PROTOCOL).unwrap();
+        let (incoming, _) = listener.accept().unwrap();
+        assert!(matches!(
+            accept(incoming, &[INFO_HASH], EncryptionMode::Require),
+            Err(MseError::EncryptionRequired)
+        ));
+    }
+
+    #[test]
+    fn encrypted_handshakes_are_refused_when_disabled() {
+        let (_, accepted) = connect(INFO_HASH, EncryptionMode::Require, EncryptionMode::Disabled);
+        assert!(matches!(accepted, Err(MseError::EncryptionDisabled)));
+    }
+
+    #[test]
+    fn unknown_torrents_are_refused() {
+        let (_, accepted) = connect([9; 20], EncryptionMode::Require, EncryptionMode::Require);
+        assert!(matches!(accepted, Err(MseError::UnknownInfoHash)));
+    }
+
+    #[test]
+    fn keys_fill_the_full_length() {
+        assert_eq!(to_key_bytes(&BigUint::from(1u8))[KEY_LEN - 1], 1);
+        assert_eq!(
+            to_key_bytes(&BigUint::from(1u8))[..KEY_LEN - 1],
+            [0; KEY_LEN - 1]
+        );
+
+        let (a, a_public) = key_pair();
+        let (b, b_public) = key_pair();
+        assert_eq!(shared_secret(&a, &b_public), shared_secret(&b, &a_public));
+    }
+}
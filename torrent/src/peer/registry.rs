@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use crate::info_hash::InfoHash;
+
+// The same peer is usually found more than once, from the tracker, the DHT
+// and other peers' PEX messages. Every torrent's peers are kept in one table
+// for the whole session so each is connected to once, and connections to
+// ourselves are recognized by our peer id and not made again.
+
+/// Where a peer was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    /// Local service discovery (BEP 14).
+    Lsd,
+    /// A peer connected to both of us introduced it (BEP 55).
+    Holepunch,
+    /// The peer connected to us.
+    Incoming,
+}
+
+/// Every source a peer was found through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerSources {
+    pub tracker: bool,
+    pub dht: bool,
+    pub pex: bool,
+    pub lsd: bool,
+    pub holepunch: bool,
+    pub incoming: bool,
+}
+
+impl PeerSources {
+    pub fn insert(&mut self, source: PeerSource) {
+        match source {
+            PeerSource::Tracker => self.tracker = true,
+            PeerSource::Dht => self.dht = true,
+            PeerSource::Pex => self.pex = true,
+            PeerSource::Lsd => self.lsd = true,
+            PeerSource::Holepunch => self.holepunch = true,
+            PeerSource::Incoming => self.incoming = true,
+        }
+    }
+
+    pub fn contains(&self, source: PeerSource) -> bool {
+        match source {
+            PeerSource::Tracker => self.tracker,
+            PeerSource::Dht => self.dht,
+            PeerSource::Pex => self.pex,
+            PeerSource::Lsd => self.lsd,
+            PeerSource::Holepunch => self.holepunch,
+            PeerSource::Incoming => self.incoming,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Idle,
+    Connecting,
+    Connected,
+}
+
+/// A peer of one torrent, merged from every time it was found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Candidate {
+    pub sources: PeerSources,
+    /// Known once the peer sent its handshake.
+    pub peer_id: Option<[u8; 20]>,
+    pub state: ConnectionState,
+}
+
+/// Why a peer was not connected to, or its connection has to be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Rejected {
+    /// The address is ours, we would be talking to ourselves.
+    SelfConnection,
+    /// There is already a connection to or from this address.
+    AlreadyConnected,
+    /// Another address of the torrent is connected with the same peer id,
+    /// e.g. both ends connected to each other at the same time.
+    DuplicatePeerId,
+}
+
+/// Every torrent's peers, keyed by info hash and address.
+#[derive(Debug, Clone)]
+pub struct PeerRegistry {
+    peer_id: [u8; 20],
+    candidates: HashMap<(InfoHash, SocketAddr), Candidate>,
+    /// Addresses that turned out to be ourselves.
+    own_addrs: HashSet<SocketAddr>,
+}
+
+impl PeerRegistry {
+    /// A registry for the session using `peer_id` in its handshakes.
+    pub fn new(peer_id: [u8; 20]) -> Self {
+        Self {
+            peer_id,
+            candidates: HashMap::new(),
+            own_addrs: HashSet::new(),
+        }
+    }
+
+    /// `addr` was found through `source`. Returns whether it is new, known
+    /// peers only gain the source.
+    pub fn discovered(
+        &mut self,
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> bool {
+        if self.own_addrs.contains(&addr) {
+            return false;
+        }
+        let mut new = false;
+        let candidate = self.candidates.entry((info_hash, addr)).or_insert_with(|| {
+            new = true;
+            Candidate::default()
+        });
+        candidate.sources.insert(source);
+        new
+    }
+
+    /// Every peer of the torrent.
+    pub fn candidates(
+        &self,
+        info_hash: InfoHash,
+    ) -> impl Iterator<Item = (SocketAddr, &Candidate)> + '_ {
+        self.candidates
+            .iter()
+            .filter(move |((hash, _), _)| *hash == info_hash)
+            .map(|((_, addr), candidate)| (*addr, candidate))
+    }
+
+    /// Peers of the torrent found but not connected to.
+    pub fn idle(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        self.candidates(info_hash)
+            .filter(|(_, candidate)| candidate.state == ConnectionState::Idle)
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+
+    /// Whether there is an established connection to `addr` for the torrent.
+    pub fn is_connected(&self, info_hash: InfoHash, addr: SocketAddr) -> bool {
+        self.candidates
+            .get(&(info_hash, addr))
+            .is_some_and(|candidate| candidate.state == ConnectionState::Connected)
+    }
+
+    /// Whether `addr` turned out to be ourselves.
+    pub fn is_own(&self, addr: SocketAddr) -> bool {
+        self.own_addrs.contains(&addr)
+    }
+
+    /// We are about to connect to `addr`, or it connected to us.
+    pub fn connecting(
+        &mut self,
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> Result<(), Rejected> {
+        if self.own_addrs.contains(&addr) {
+            return Err(Rejected::SelfConnection);
+        }
+        let candidate = self.candidates.entry((info_hash, addr)).or_default();
+        candidate.sources.insert(source);
+        if candidate.state != ConnectionState::Idle {
+            return Err(Rejected::AlreadyConnected);
+        }
+        candidate.state = ConnectionState::Connecting;
+        Ok(())
+    }
+
+    /// `addr` sent its handshake with `peer_id`. On an error the connection
+    /// has to be closed, `disconnected` is called for it already.
+    pub fn handshake(
+        &mut self,
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        peer_id: [u8; 20],
+    ) -> Result<(), Rejected> {
+        if peer_id == self.peer_id {
+            self.candidates.remove(&(info_hash, addr));
+            self.own_addrs.insert(addr);
+            return Err(Rejected::SelfConnection);
+        }
+
+        let duplicate = self.candidates(info_hash).any(|(other, candidate)| {
+            other != addr
+                && candidate.state == ConnectionState::Connected
+                && candidate.peer_id == Some(peer_id)
+        });
+        if duplicate {
+            self.disconnected(info_hash, addr);
+            return Err(Rejected::DuplicatePeerId);
+        }
+
+        let candidate = self.candidates.entry((info_hash, addr)).or_default();
+        candidate.peer_id = Some(peer_id);
+        candidate.state = ConnectionState::Connected;
+        Ok(())
+    }
+
+    /// The connection to `addr` failed or closed, it may be tried again.
+    pub fn disconnected(&mut self, info_hash: InfoHash, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&(info_hash, addr)) {
+            candidate.state = ConnectionState::Idle;
+        }
+    }
+
+    /// Forget the torrent's peers, e.g. once it is removed.
+    pub fn remove_torrent(&mut self, info_hash: InfoHash) {
+        self.candidates.retain(|(hash, _), _| *hash != info_hash);
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
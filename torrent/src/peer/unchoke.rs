@@ -0,0 +1,361 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+// Uploading to every interested peer at once splits the upload so thin that
+// none of them gets far. Only so many are unchoked, each holding an upload
+// slot, and the rest wait in line for one to free up.
+//
+// A torrent's slots are drawn from the session's as well, like its rate
+// limiter, so a peer is only unchoked while both have one free.
+//
+// When flud is the main seed, peers that have a few pieces already keep
+// every slot busy and new ones wait with nothing to trade. A share of a
+// torrent's slots is reserved for peers with next to nothing, which only
+// they may take, on top of the optimistic unchokes peers give each other.
+
+/// Upload bandwidth each slot gets in auto mode.
+pub const AUTO_RATE_PER_SLOT: u64 = 32 * 1024;
+/// Auto mode never goes below or above these.
+pub const AUTO_MIN_SLOTS: usize = 2;
+pub const AUTO_MAX_SLOTS: usize = 50;
+/// Slots in auto mode while the upload bandwidth is not known.
+pub const AUTO_DEFAULT_SLOTS: usize = 8;
+/// The share of a torrent's slots reserved for new peers by default.
+pub const DEFAULT_RESERVED_SLOTS: f64 = 0.2;
+/// Peers with less than this share of the pieces count as new.
+pub const NEWCOMER_PROGRESS: f64 = 0.01;
+
+/// How many peers may be uploaded to at once, `auto` or a number in the
+/// config, `0` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UploadSlots {
+    /// One slot per `AUTO_RATE_PER_SLOT` of upload bandwidth.
+    #[default]
+    Auto,
+    Fixed(usize),
+}
+
+impl UploadSlots {
+    /// The number of slots, `None` for unlimited. `bandwidth` is the upload
+    /// cap, or the fastest upload seen when there is none, in bytes per
+    /// second, `None` if neither is known.
+    pub fn resolve(self, bandwidth: Option<u64>) -> Option<usize> {
+        match self {
+            UploadSlots::Fixed(0) => None,
+            UploadSlots::Fixed(slots) => Some(slots),
+            UploadSlots::Auto => Some(match bandwidth {
+                Some(rate) => {
+                    ((rate / AUTO_RATE_PER_SLOT) as usize).clamp(AUTO_MIN_SLOTS, AUTO_MAX_SLOTS)
+                }
+                None => AUTO_DEFAULT_SLOTS,
+            }),
+        }
+    }
+}
+
+impl FromStr for UploadSlots {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(UploadSlots::Auto),
+            _ => s
+                .parse()
+                .map(UploadSlots::Fixed)
+                .map_err(|_| format!("unknown upload slots {s}, expected auto or a number")),
+        }
+    }
+}
+
+impl fmt::Display for UploadSlots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadSlots::Auto => f.write_str("auto"),
+            UploadSlots::Fixed(0) => f.write_str("unlimited"),
+            UploadSlots::Fixed(slots) => write!(f, "{slots}"),
+        }
+    }
+}
+
+impl Serialize for UploadSlots {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            UploadSlots::Auto => serializer.serialize_str("auto"),
+            UploadSlots::Fixed(slots) => serializer.serialize_u64(*slots as u64),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UploadSlots {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = UploadSlots;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("\"auto\" or a number of slots")
+            }
+
+            fn visit_u64<E: de::Error>(self, slots: u64) -> Result<Self::Value, E> {
+                Ok(UploadSlots::Fixed(slots as usize))
+            }
+
+            fn visit_i64<E: de::Error>(self, slots: i64) -> Result<Self::Value, E> {
+                usize::try_from(slots)
+                    .map(UploadSlots::Fixed)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(slots), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// How many upload slots are taken, e.g. `3 of 8, 2 for new peers`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlotUsage {
+    pub used: usize,
+    /// `None` for unlimited.
+    pub slots: Option<usize>,
+    /// Of the slots, those only new peers may take.
+    pub reserved: usize,
+    /// Interested peers waiting for a slot.
+    pub waiting: usize,
+}
+
+impl fmt::Display for SlotUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.slots {
+            Some(slots) => write!(f, "{} of {slots}", self.used)?,
+            None => write!(f, "{} of unlimited", self.used)?,
+        }
+        if self.reserved > 0 {
+            write!(f, ", {} for new peers", self.reserved)?;
+        }
+        if self.waiting > 0 {
+            write!(f, ", {} waiting", self.waiting)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    slots: Option<usize>,
+    used: usize,
+    /// The share of the slots reserved for new peers, only for a torrent.
+    reserved: f64,
+    /// The peers holding a slot, only kept for a torrent.
+    unchoked: HashSet<SocketAddr>,
+    /// Interested peers in the order they asked, only kept for a torrent.
+    waiting: VecDeque<SocketAddr>,
+    /// Peers past `NEWCOMER_PROGRESS`, every other one is new.
+    established: HashSet<SocketAddr>,
+}
+
+impl State {
+    fn reserved_slots(&self) -> usize {
+        self.slots.map_or(0, |slots| {
+            ((slots as f64 * self.reserved).ceil() as usize).min(slots)
+        })
+    }
+
+    fn is_new(&self, addr: SocketAddr) -> bool {
+        !self.established.contains(&addr)
+    }
+
+    /// Whether a slot is free for `addr`, one of the reserved ones only if
+    /// it is new.
+    fn has_room_for(&self, addr: SocketAddr) -> bool {
+        if !self.has_room() {
+            return false;
+        }
+        let Some(slots) = self.slots else {
+            return true;
+        };
+        if self.is_new(addr) {
+            return true;
+        }
+        let established = self
+            .unchoked
+            .iter()
+            .filter(|&&unchoked| !self.is_new(unchoked))
+            .count();
+        established + self.reserved_slots() < slots
+    }
+
+    fn has_room(&self) -> bool {
+        self.slots.is_none_or(|slots| self.used < slots)
+    }
+}
+
+/// Hands out upload slots to interested peers. Cheap to clone, every clone
+/// shares the same slots.
+#[derive(Debug, Clone)]
+pub struct Unchoker {
+    state: Arc<Mutex<State>>,
+    /// The session's slots, for the unchoker of a torrent.
+    session: Option<Box<Unchoker>>,
+}
+
+impl Default for Unchoker {
+    fn default() -> Self {
+        Self::new(UploadSlots::default().resolve(None))
+    }
+}
+
+impl Unchoker {
+    /// `slots` across the session, `None` for unlimited.
+    pub fn new(slots: Option<usize>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                slots,
+                ..State::default()
+            })),
+            session: None,
+        }
+    }
+
+    /// An unchoker for one torrent with `slots` of its own, whose peers
+    /// take a slot of this unchoker as well.
+    pub fn for_torrent(&self, slots: Option<usize>) -> Self {
+        Self {
+            session: Some(Box::new(self.clone())),
+            ..Self::new(slots)
+        }
+    }
+
+    /// Reserve `share` of the slots, `0.0..=1.0`, for peers with next to
+    /// nothing, see `progress`.
+    pub fn with_reserved(self, share: f64) -> Self {
+        self.state.lock().unwrap().reserved = share.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Change the number of slots. Fewer slots take effect as unchoked
+    /// peers leave, more right away for the peers `fill` returns.
+    pub fn set_slots(&self, slots: Option<usize>) {
+        self.state.lock().unwrap().slots = slots;
+    }
+
+    pub fn usage(&self) -> SlotUsage {
+        let state = self.state.lock().unwrap();
+        SlotUsage {
+            used: state.used,
+            slots: state.slots,
+            reserved: state.reserved_slots(),
+            waiting: state.waiting.len(),
+        }
+    }
+
+    pub fn is_unchoked(&self, addr: SocketAddr) -> bool {
+        self.state.lock().unwrap().unchoked.contains(&addr)
+    }
+
+    /// `addr` has `have` of the torrent's `pieces`. Until it is told, and
+    /// again once it left, a peer is new, as one without any pieces sends
+    /// no bitfield. A new peer
+    /// that gets further keeps its slot, the reserved ones free up for new
+    /// peers once established peers leave.
+    pub fn progress(&self, addr: SocketAddr, have: usize, pieces: usize) {
+        let mut state = self.state.lock().unwrap();
+        if pieces > 0 && have as f64 / pieces as f64 >= NEWCOMER_PROGRESS {
+            state.established.insert(addr);
+        } else {
+            state.established.remove(&addr);
+        }
+    }
+
+    /// `addr` is interested, returns whether to unchoke it now. Otherwise
+    /// it waits for a slot and is returned by `leave` or `fill` once it has
+    /// one.
+    pub fn interested(&self, addr: SocketAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.unchoked.contains(&addr) {
+            return true;
+        }
+        // Only peers that may take the same slots are ahead of it
+        let new = state.is_new(addr);
+        let ahead = state
+            .waiting
+            .iter()
+            .any(|&waiting| state.is_new(waiting) == new);
+        if !ahead && self.take(&mut state, addr) {
+            return true;
+        }
+        if !state.waiting.contains(&addr) {
+            state.waiting.push_back(addr);
+        }
+        false
+    }
+
+    /// `addr` lost interest or disconnected, returns the waiting peers to
+    /// unchoke with the slot it held.
+    pub fn leave(&self, addr: SocketAddr) -> Vec<SocketAddr> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.waiting.retain(|&waiting| waiting != addr);
+            state.established.remove(&addr);
+            if state.unchoked.remove(&addr) {
+                state.used -= 1;
+                if let Some(session) = &self.session {
+                    session.release();
+                }
+            }
+        }
+        self.fill()
+    }
+
+    /// Give free slots to waiting peers, returning those to unchoke, e.g.
+    /// after `set_slots` or once a peer of another torrent left.
+    pub fn fill(&self) -> Vec<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        let mut unchoked = Vec::new();
+        // A new peer further back may take a reserved slot an established
+        // one ahead of it may not
+        let mut position = 0;
+        while position < state.waiting.len() && state.has_room() {
+            let next = state.waiting[position];
+            if self.take(&mut state, next) {
+                state.waiting.remove(position);
+                unchoked.push(next);
+            } else {
+                position += 1;
+            }
+        }
+        unchoked
+    }
+
+    /// Give `addr` a slot, if this and the session have one free.
+    fn take(&self, state: &mut State, addr: SocketAddr) -> bool {
+        if !state.has_room_for(addr) {
+            return false;
+        }
+        if let Some(session) = &self.session {
+            let mut session = session.state.lock().unwrap();
+            if !session.has_room() {
+                return false;
+            }
+            session.used += 1;
+        }
+        state.used += 1;
+        state.unchoked.insert(addr);
+        true
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.used = state.used.saturating_sub(1);
+    }
+}
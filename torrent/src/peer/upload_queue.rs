@@ -0,0 +1,123 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+// Serving requests in the order they arrive lets a peer that pipelines
+// hundreds of requests take every disk read and all of the upload while the
+// others wait. Peers take turns instead, one block each, and each may only
+// have so many requests queued.
+
+/// How many requests a peer may have queued before more are dropped.
+pub const DEFAULT_MAX_PER_PEER: usize = 128;
+
+/// A block a peer asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// The requests of every peer we upload to, served round-robin.
+#[derive(Debug, Clone)]
+pub struct UploadQueue {
+    max_per_peer: usize,
+    queues: HashMap<SocketAddr, VecDeque<BlockRequest>>,
+    /// Peers with queued requests, the front one is served next.
+    turns: VecDeque<SocketAddr>,
+    len: usize,
+}
+
+impl Default for UploadQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PER_PEER)
+    }
+}
+
+impl UploadQueue {
+    pub fn new(max_per_peer: usize) -> Self {
+        Self {
+            max_per_peer,
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            len: 0,
+        }
+    }
+
+    /// Queue `request` from `peer`. Returns false if it was dropped, because
+    /// the peer already has `max_per_peer` requests queued or asked for the
+    /// same block before.
+    pub fn push(&mut self, peer: SocketAddr, request: BlockRequest) -> bool {
+        let queue = self.queues.entry(peer).or_default();
+        if queue.len() >= self.max_per_peer || queue.contains(&request) {
+            return false;
+        }
+        if queue.is_empty() {
+            self.turns.push_back(peer);
+        }
+        queue.push_back(request);
+        self.len += 1;
+        true
+    }
+
+    /// The next request to serve, the oldest one of the peer whose turn it is.
+    pub fn pop(&mut self) -> Option<(SocketAddr, BlockRequest)> {
+        let peer = self.turns.pop_front()?;
+        let queue = self.queues.get_mut(&peer)?;
+        let request = queue.pop_front()?;
+        self.len -= 1;
+
+        if queue.is_empty() {
+            self.queues.remove(&peer);
+        } else {
+            self.turns.push_back(peer);
+        }
+        Some((peer, request))
+    }
+
+    /// The request `pop` returns next, without taking it, e.g. to read its
+    /// piece ahead.
+    pub fn peek(&self) -> Option<BlockRequest> {
+        let peer = self.turns.front()?;
+        self.queues.get(peer)?.front().copied()
+    }
+
+    /// The peer cancelled `request`. Returns whether it was still queued.
+    pub fn cancel(&mut self, peer: SocketAddr, request: BlockRequest) -> bool {
+        let Some(queue) = self.queues.get_mut(&peer) else {
+            return false;
+        };
+        let Some(position) = queue.iter().position(|queued| *queued == request) else {
+            return false;
+        };
+        queue.remove(position);
+        self.len -= 1;
+
+        if queue.is_empty() {
+            self.remove_peer(peer);
+        }
+        true
+    }
+
+    /// Drop every request of `peer`, e.g. once it is choked or disconnected.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(queue) = self.queues.remove(&peer) {
+            self.len -= queue.len();
+        }
+        self.turns.retain(|&turn| turn != peer);
+    }
+
+    /// How many requests `peer` has queued.
+    pub fn queued(&self, peer: SocketAddr) -> usize {
+        self.queues.get(&peer).map_or(0, VecDeque::len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::{any::Any, io, net::SocketAddr, time::Duration};
+
+use super::{
+    connection::ConnectionManager,
+    extension::{Extension, ExtensionError, ExtensionHandshake, ExtensionRegistry},
+    Handshake, Message, PeerError,
+};
+use crate::{bencode, identity::Identity};
+
+// https://www.bittorrent.org/beps/bep_0009.html
+
+/// The name `ut_metadata` is advertised under in the extension handshake.
+pub const NAME: &str = "ut_metadata";
+
+/// The metadata is handled in blocks of 16KiB.
+pub const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// Refuse metadata larger than this, nothing legitimate comes close.
+pub const MAX_METADATA_SIZE: usize = 64 * 1024 * 1024;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Downloads (and serves) the info dictionary of a torrent using `ut_metadata`.
+pub struct MetadataExchange {
+    info_hash: [u8; 20],
+    /// The full metadata, once we have it we can also serve it to others.
+    metadata: Option<Vec<u8>>,
+    /// Pieces received so far while downloading.
+    pieces: Vec<Option<Vec<u8>>>,
+    metadata_size: Option<usize>,
+    /// The remote rejected one of our requests, it doesn't have the metadata either.
+    rejected: bool,
+}
+
+impl MetadataExchange {
+    /// Download the metadata for `info_hash` from peers.
+    pub fn new(info_hash: [u8; 20]) -> Self {
+        Self {
+            info_hash,
+            metadata: None,
+            pieces: Vec::new(),
+            metadata_size: None,
+            rejected: false,
+        }
+    }
+
+    /// Serve metadata we already have to peers that ask for it.
+    pub fn with_metadata(info_hash: [u8; 20], metadata: Vec<u8>) -> Self {
+        Self {
+            metadata_size: Some(metadata.len()),
+            metadata: Some(metadata),
+            ..Self::new(info_hash)
+        }
+    }
+
+    /// The verified metadata (the bencoded info dictionary), once complete.
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.rejected
+    }
+
+    /// Request payloads for every piece we are still missing.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        if self.metadata.is_some() {
+            return Vec::new();
+        }
+
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(piece, _)| {
+                serde_bencode::to_bytes(&MetadataMessage {
+                    msg_type: REQUEST,
+                    piece,
+                    total_size: None,
+                })
+                .expect("failed to bencode metadata request")
+            })
+            .collect()
+    }
+
+    fn piece(&self, piece: usize) -> Option<&[u8]> {
+        let metadata = self.metadata.as_ref()?;
+        let start = piece * METADATA_PIECE_SIZE;
+        let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+        metadata.get(start..end)
+    }
+
+    fn store(&mut self, piece: usize, data: &[u8]) {
+        let Some(slot) = self.pieces.get_mut(piece) else {
+            return;
+        };
+        *slot = Some(data.to_vec());
+
+        if self.pieces.iter().any(Option::is_none) {
+            return;
+        }
+
+        let metadata: Vec<u8> = self.pieces.iter().flatten().flatten().copied().collect();
+        if sha1_smol::Sha1::from(&metadata).digest().bytes() == self.info_hash {
+            self.metadata = Some(metadata);
+        } else {
+            // Someone sent us bad data, start over.
+            self.pieces.iter_mut().for_each(|piece| *piece = None);
+        }
+    }
+}
+
+impl Extension for MetadataExchange {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+        if let Some(metadata) = &self.metadata {
+            handshake.metadata_size = Some(metadata.len());
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake, supported: bool) {
+        if !supported || self.metadata.is_some() {
+            return;
+        }
+
+        if let Some(size) = handshake
+            .metadata_size
+            .filter(|&s| s > 0 && s <= MAX_METADATA_SIZE)
+        {
+            self.metadata_size = Some(size);
+            self.pieces = vec![None; size.div_ceil(METADATA_PIECE_SIZE)];
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let (_, end) =
+            bencode::decode_prefix(payload).map_err(|_| ExtensionError::InvalidMessage(NAME))?;
+        let message: MetadataMessage = serde_bencode::from_bytes(&payload[..end])
+            .map_err(|_| ExtensionError::InvalidMessage(NAME))?;
+
+        match message.msg_type {
+            REQUEST => {
+                let reply = match self.piece(message.piece) {
+                    Some(data) => {
+                        let mut reply = serde_bencode::to_bytes(&MetadataMessage {
+                            msg_type: DATA,
+                            piece: message.piece,
+                            total_size: self.metadata_size,
+                        })
+                        .expect("failed to bencode metadata data");
+                        reply.extend_from_slice(data);
+                        reply
+                    }
+                    None => serde_bencode::to_bytes(&MetadataMessage {
+                        msg_type: REJECT,
+                        piece: message.piece,
+                        total_size: None,
+                    })
+                    .expect("failed to bencode metadata reject"),
+                };
+                Ok(vec![reply])
+            }
+            DATA => {
+                if self.metadata.is_none() {
+                    self.store(message.piece, &payload[end..]);
+                }
+                Ok(Vec::new())
+            }
+            REJECT => {
+                self.rejected = true;
+                Ok(Vec::new())
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FetchMetadataError {
+    Peer(PeerError),
+    Extension(ExtensionError),
+    /// The peer does not support the extension protocol or `ut_metadata`.
+    Unsupported,
+    /// The peer rejected our requests, it does not have the metadata.
+    Rejected,
+}
+
+impl From<PeerError> for FetchMetadataError {
+    fn from(err: PeerError) -> Self {
+        FetchMetadataError::Peer(err)
+    }
+}
+
+impl From<io::Error> for FetchMetadataError {
+    fn from(err: io::Error) -> Self {
+        FetchMetadataError::Peer(PeerError::Io(err))
+    }
+}
+
+impl From<ExtensionError> for FetchMetadataError {
+    fn from(err: ExtensionError) -> Self {
+        FetchMetadataError::Extension(err)
+    }
+}
+
+/// Connect to `addr` and download the info dictionary for `info_hash`.
+///
+/// This is how a torrent added from a magnet link obtains its metainfo.
+/// The returned bytes have been verified against `info_hash`.
+pub fn fetch_metadata(
+    connections: &ConnectionManager,
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    identity: &Identity,
+    timeout: Duration,
+) -> Result<Vec<u8>, FetchMetadataError> {
+    let mut stream = connections.connect(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    Handshake::new(info_hash, identity.peer_id())
+        .with_extension_protocol()
+        .write_to(&mut stream)?;
+
+    let handshake = Handshake::read_from(&mut stream)?;
+    if handshake.info_hash != info_hash || !handshake.supports_extension_protocol() {
+        return Err(FetchMetadataError::Unsupported);
+    }
+
+    let mut registry = ExtensionRegistry::for_identity(identity);
+    registry.register(Box::new(MetadataExchange::new(info_hash)));
+    registry
+        .handshake_message(None, Some(addr.ip()))
+        .write_to(&mut stream)?;
+
+    loop {
+        let Message::Extended { id, payload } = Message::read_from(&mut stream)? else {
+            continue;
+        };
+
+        let is_handshake = id == super::extension::HANDSHAKE_ID;
+        for reply in registry.handle(id, &payload)? {
+            reply.write_to(&mut stream)?;
+        }
+
+        let exchange = registry
+            .get_mut::<MetadataExchange>()
+            .expect("ut_metadata is registered");
+
+        if let Some(metadata) = exchange.metadata() {
+            return Ok(metadata.to_vec());
+        }
+
+        if exchange.is_rejected() {
+            return Err(FetchMetadataError::Rejected);
+        }
+
+        if is_handshake {
+            let requests = exchange.requests();
+            if requests.is_empty() {
+                return Err(FetchMetadataError::Unsupported);
+            }
+            for request in requests {
+                if let Some(message) = registry.message(NAME, request) {
+                    message.write_to(&mut stream)?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::net::SocketAddr;
+
+use super::Message;
+
+/// The largest block a peer may request or send. The spec notes that
+/// requests larger than 2^17 are commonly dropped, so we treat them as a violation.
+pub const MAX_BLOCK_LENGTH: u32 = 1 << 17;
+
+/// How to treat peers that send messages violating the protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Disconnect on the first violation.
+    #[default]
+    Strict,
+    /// Log and drop the offending message but keep the connection open.
+    /// Useful when debugging broken clients.
+    Permissive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A `have`, `request`, `piece` or `cancel` for a piece that does not exist.
+    PieceIndexOutOfBounds { index: u32 },
+    /// A request or block larger than `MAX_BLOCK_LENGTH`, or of zero length.
+    InvalidBlockLength { length: u32 },
+    /// A block that would extend past the end of its piece.
+    BlockOutOfPieceBounds { index: u32, begin: u32, length: u32 },
+    /// A request received while we are choking the peer.
+    RequestWhileChoked,
+    /// A bitfield that is not exactly ceil(pieces / 8) bytes long.
+    InvalidBitfieldLength { expected: usize, actual: usize },
+    /// A bitfield with any of the trailing spare bits set.
+    BitfieldSpareBitsSet,
+    /// A bitfield, `have_all` or `have_none` sent after any other message.
+    LateBitfield,
+}
+
+/// What the connection should do with a message after validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Verdict {
+    Accept,
+    /// Drop the message but keep the connection.
+    Ignore(Violation),
+    /// Close the connection.
+    Disconnect(Violation),
+}
+
+/// Number of violations seen from a single peer, by kind.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ViolationCounters {
+    pub piece_index_out_of_bounds: usize,
+    pub invalid_block_length: usize,
+    pub block_out_of_piece_bounds: usize,
+    pub request_while_choked: usize,
+    pub invalid_bitfield: usize,
+    pub late_bitfield: usize,
+}
+
+impl ViolationCounters {
+    fn record(&mut self, violation: Violation) {
+        let counter = match violation {
+            Violation::PieceIndexOutOfBounds { .. } => &mut self.piece_index_out_of_bounds,
+            Violation::InvalidBlockLength { .. } => &mut self.invalid_block_length,
+            Violation::BlockOutOfPieceBounds { .. } => &mut self.block_out_of_piece_bounds,
+            Violation::RequestWhileChoked => &mut self.request_while_choked,
+            Violation::InvalidBitfieldLength { .. } | Violation::BitfieldSpareBitsSet => {
+                &mut self.invalid_bitfield
+            }
+            Violation::LateBitfield => &mut self.late_bitfield,
+        };
+        *counter += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.piece_index_out_of_bounds
+            + self.invalid_block_length
+            + self.block_out_of_piece_bounds
+            + self.request_while_choked
+            + self.invalid_bitfield
+            + self.late_bitfield
+    }
+}
+
+/// Validates every inbound message of one peer connection against the torrent's layout.
+#[derive(Debug)]
+pub struct MessageValidator {
+    mode: ValidationMode,
+    addr: Option<SocketAddr>,
+    piece_count: u32,
+    piece_length: u64,
+    total_length: u64,
+    /// Whether we are currently choking the peer.
+    choking: bool,
+    /// Whether any message other than the bitfield has been received yet.
+    seen_message: bool,
+    counters: ViolationCounters,
+}
+
+impl MessageValidator {
+    pub fn new(
+        mode: ValidationMode,
+        piece_count: u32,
+        piece_length: u64,
+        total_length: u64,
+    ) -> Self {
+        Self {
+            mode,
+            addr: None,
+            piece_count,
+            piece_length,
+            total_length,
+            choking: true,
+            seen_message: false,
+            counters: ViolationCounters::default(),
+        }
+    }
+
+    /// The address of the peer, included when violations are logged.
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Keep track of whether we are choking the peer, requests are only valid while unchoked.
+    pub fn set_choking(&mut self, choking: bool) {
+        self.choking = choking;
+    }
+
+    pub fn counters(&self) -> &ViolationCounters {
+        &self.counters
+    }
+
+    pub fn mode(&self) -> ValidationMode {
+        self.mode
+    }
+
+    /// The length of the piece at `index`, the last piece may be shorter.
+    fn piece_len(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start))
+    }
+
+    fn check_block(&self, index: u32, begin: u32, length: u32) -> Result<(), Violation> {
+        if index >= self.piece_count {
+            return Err(Violation::PieceIndexOutOfBounds { index });
+        }
+        if length == 0 || length > MAX_BLOCK_LENGTH {
+            return Err(Violation::InvalidBlockLength { length });
+        }
+        if begin as u64 + length as u64 > self.piece_len(index) {
+            return Err(Violation::BlockOutOfPieceBounds {
+                index,
+                begin,
+                length,
+            });
+        }
+        Ok(())
+    }
+
+    fn check(&self, message: &Message) -> Result<(), Violation> {
+        match message {
+            Message::Have(index) if *index >= self.piece_count => {
+                Err(Violation::PieceIndexOutOfBounds { index: *index })
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone if self.seen_message => {
+                Err(Violation::LateBitfield)
+            }
+            Message::Bitfield(bits) => {
+                let expected = (self.piece_count as usize).div_ceil(8);
+                if bits.len() != expected {
+                    return Err(Violation::InvalidBitfieldLength {
+                        expected,
+                        actual: bits.len(),
+                    });
+                }
+                let spare = expected * 8 - self.piece_count as usize;
+                let mask = ((1u16 << spare) - 1) as u8;
+                if spare > 0 && bits[expected - 1] & mask != 0 {
+                    return Err(Violation::BitfieldSpareBitsSet);
+                }
+                Ok(())
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                self.check_block(*index, *begin, *length)?;
+                if self.choking {
+                    return Err(Violation::RequestWhileChoked);
+                }
+                Ok(())
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => self.check_block(*index, *begin, *length),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => self.check_block(*index, *begin, block.len() as u32),
+            _ => Ok(()),
+        }
+    }
+
+    /// Validate an inbound message, counting and logging any violation.
+    pub fn validate(&mut self, message: &Message) -> Verdict {
+        let result = self.check(message);
+        if !matches!(message, Message::KeepAlive) {
+            self.seen_message = true;
+        }
+
+        let Err(violation) = result else {
+            return Verdict::Accept;
+        };
+
+        self.counters.record(violation);
+
+        match self.mode {
+            ValidationMode::Strict => Verdict::Disconnect(violation),
+            ValidationMode::Permissive => {
+                log::warn!("peer {:?} sent invalid message: {:?}", self.addr, violation);
+                Verdict::Ignore(violation)
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use reqwest::Url;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use crate::interface::outgoing;
+
+// SOCKS5 (RFC 1928) with username/password authentication (RFC 1929).
+//
+// Peer connections are opened through the proxy here, HTTP trackers go
+// through reqwest's own SOCKS support. UDP isn't proxied: UDP trackers are
+// skipped while trackers are proxied, and the DHT has to be turned off to
+// keep it from talking to nodes directly.
+//
+// An HTTP(S) proxy can be set instead for the HTTP requests alone, that is
+// trackers, .torrent downloads and web seeds. Where both cover a request
+// the SOCKS proxy is used.
+
+/// The port SOCKS proxies usually listen on.
+pub const DEFAULT_PORT: u16 = 1080;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const AUTH_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProxyError {
+    Io(io::Error),
+    /// The proxy doesn't speak SOCKS5.
+    UnsupportedVersion(u8),
+    /// The proxy wants a way of authenticating we don't offer, e.g. a
+    /// username and password when none are configured.
+    NoAcceptableAuth,
+    AuthFailed,
+    /// The proxy couldn't connect to the target, with its reply code.
+    Refused(u8),
+    InvalidResponse,
+    /// An HTTP proxy url that isn't `http://` or `https://`.
+    InvalidUrl(String),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Io(err) => write!(f, "unable to reach the proxy: {err}"),
+            ProxyError::UnsupportedVersion(version) => {
+                write!(f, "the proxy speaks SOCKS{version}, not SOCKS5")
+            }
+            ProxyError::NoAcceptableAuth => {
+                write!(f, "the proxy accepts none of our authentication methods")
+            }
+            ProxyError::AuthFailed => write!(f, "the proxy rejected the username or password"),
+            ProxyError::Refused(code) => {
+                let reason = match code {
+                    1 => "general failure",
+                    2 => "not allowed by ruleset",
+                    3 => "network unreachable",
+                    4 => "host unreachable",
+                    5 => "connection refused",
+                    6 => "TTL expired",
+                    7 => "command not supported",
+                    8 => "address type not supported",
+                    _ => "unknown error",
+                };
+                write!(f, "the proxy could not connect: {reason}")
+            }
+            ProxyError::InvalidResponse => write!(f, "invalid response from the proxy"),
+            ProxyError::InvalidUrl(url) => write!(
+                f,
+                "invalid proxy url {url:?}, expected e.g. http://proxy.example.com:3128"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::Io(err)
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+/// A SOCKS5 proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    remote_dns: bool,
+}
+
+impl Socks5Proxy {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            credentials: None,
+            remote_dns: true,
+        }
+    }
+
+    pub fn with_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    /// Whether the proxy resolves the host names of trackers, so the
+    /// lookups don't leak outside of it. On by default.
+    pub fn with_remote_dns(mut self, remote_dns: bool) -> Self {
+        self.remote_dns = remote_dns;
+        self
+    }
+
+    /// Connect to `addr` through the proxy.
+    pub fn connect(&self, addr: SocketAddr, timeout: Duration) -> Result<TcpStream, ProxyError> {
+        let proxy = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let mut stream = outgoing().connect(proxy, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        self.negotiate(&mut stream)?;
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                request.push(IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&addr.port().to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        let [version, code, _, address_type] = reply;
+        if version != VERSION {
+            return Err(ProxyError::InvalidResponse);
+        }
+        if code != 0 {
+            return Err(ProxyError::Refused(code));
+        }
+        // The address the proxy connects from, of no use to us
+        let bound_len = match address_type {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN => {
+                let mut len = [0];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(ProxyError::InvalidResponse),
+        };
+        io::copy(
+            &mut (&mut stream).take(bound_len as u64 + 2),
+            &mut io::sink(),
+        )?;
+
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Agree on an authentication method and authenticate.
+    fn negotiate(&self, stream: &mut TcpStream) -> Result<(), ProxyError> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD],
+            None => &[VERSION, 1, NO_AUTH],
+        };
+        stream.write_all(greeting)?;
+
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice)?;
+        match choice {
+            [VERSION, NO_AUTH] => Ok(()),
+            [VERSION, USERNAME_PASSWORD] => {
+                let Some((username, password)) = &self.credentials else {
+                    return Err(ProxyError::NoAcceptableAuth);
+                };
+                let field = |value: &str| {
+                    u8::try_from(value.len())
+                        .map(|len| [&[len], value.as_bytes()].concat())
+                        .map_err(|_| ProxyError::AuthFailed)
+                };
+                let request = [vec![AUTH_VERSION], field(username)?, field(password)?].concat();
+                stream.write_all(&request)?;
+
+                let mut status = [0; 2];
+                stream.read_exact(&mut status)?;
+                match status {
+                    [_, 0] => Ok(()),
+                    _ => Err(ProxyError::AuthFailed),
+                }
+            }
+            [VERSION, NO_ACCEPTABLE_METHOD] => Err(ProxyError::NoAcceptableAuth),
+            [VERSION, _] => Err(ProxyError::InvalidResponse),
+            [version, _] => Err(ProxyError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// The proxy for reqwest, `socks5h://` when it resolves host names.
+    /// `None` if the host can't be part of a url.
+    fn reqwest_proxy(&self) -> Option<reqwest::Proxy> {
+        let scheme = if self.remote_dns { "socks5h" } else { "socks5" };
+        let host = match self.host.parse::<std::net::Ipv6Addr>() {
+            Ok(ip) => format!("[{ip}]"),
+            Err(_) => self.host.clone(),
+        };
+        let mut url = Url::parse(&format!("{scheme}://{host}:{}", self.port)).ok()?;
+        if let Some((username, password)) = &self.credentials {
+            url.set_username(username).ok()?;
+            url.set_password(Some(password)).ok()?;
+        }
+        reqwest::Proxy::all(url).ok()
+    }
+}
+
+/// An HTTP or HTTPS proxy for tracker and web seed requests, e.g.
+/// `http://proxy.example.com:3128`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpProxy {
+    url: Url,
+    credentials: Option<(String, String)>,
+}
+
+impl HttpProxy {
+    /// Authenticate with HTTP basic auth.
+    pub fn with_credentials(mut self, username: String, password: String) -> Self {
+        self.credentials = Some((username, password));
+        self
+    }
+
+    fn reqwest_proxy(&self) -> Option<reqwest::Proxy> {
+        let proxy = reqwest::Proxy::all(self.url.clone()).ok()?;
+        Some(match &self.credentials {
+            Some((username, password)) => proxy.basic_auth(username, password),
+            None => proxy,
+        })
+    }
+}
+
+impl FromStr for HttpProxy {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim())
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+            .ok_or_else(|| ProxyError::InvalidUrl(s.to_owned()))?;
+        Ok(Self {
+            url,
+            credentials: None,
+        })
+    }
+}
+
+impl fmt::Display for HttpProxy {
+    /// The url, without any credentials in it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut url = self.url.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        write!(f, "{url}")
+    }
+}
+
+/// Which traffic goes through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedTraffic {
+    /// Connections to peers and web seeds.
+    pub peers: bool,
+    /// Announces, scrapes and .torrent downloads.
+    pub trackers: bool,
+}
+
+impl Default for ProxiedTraffic {
+    fn default() -> Self {
+        Self {
+            peers: true,
+            trackers: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    Peers,
+    Trackers,
+}
+
+static PROXY: OnceLock<Option<(Socks5Proxy, ProxiedTraffic)>> = OnceLock::new();
+
+/// Send `traffic` through `proxy` for the rest of the process. Has to be
+/// called before the first connection, which otherwise settles on no proxy.
+pub fn set_proxy(proxy: Socks5Proxy, traffic: ProxiedTraffic) -> io::Result<()> {
+    if proxy.reqwest_proxy().is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid proxy host {}", proxy.host),
+        ));
+    }
+    let proxy = Some((proxy, traffic));
+    if *PROXY.get_or_init(|| proxy.clone()) != proxy {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the proxy is already set",
+        ));
+    }
+    Ok(())
+}
+
+static HTTP_PROXY: OnceLock<Option<HttpProxy>> = OnceLock::new();
+
+/// Send the HTTP requests to trackers and web seeds through `proxy` for the
+/// rest of the process, unless the SOCKS proxy covers them. Has to be called
+/// before the first request, which otherwise settles on no proxy.
+pub fn set_http_proxy(proxy: HttpProxy) -> io::Result<()> {
+    if proxy.reqwest_proxy().is_none() {
+        return Err(ProxyError::InvalidUrl(proxy.to_string()).into());
+    }
+    let proxy = Some(proxy);
+    if *HTTP_PROXY.get_or_init(|| proxy.clone()) != proxy {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the HTTP proxy is already set",
+        ));
+    }
+    Ok(())
+}
+
+/// The HTTP proxy, if any.
+pub fn http_proxy() -> Option<&'static HttpProxy> {
+    HTTP_PROXY.get_or_init(|| None).as_ref()
+}
+
+/// The proxy `traffic` goes through, if any.
+pub fn proxy_for(traffic: Traffic) -> Option<&'static Socks5Proxy> {
+    let (proxy, proxied) = PROXY.get_or_init(|| None).as_ref()?;
+    let is_proxied = match traffic {
+        Traffic::Peers => proxied.peers,
+        Traffic::Trackers => proxied.trackers,
+    };
+    is_proxied.then_some(proxy)
+}
+
+/// The proxy HTTP requests for `traffic` go through: the SOCKS proxy if
+/// it covers them, the HTTP proxy otherwise.
+fn reqwest_proxy_for(traffic: Traffic) -> Option<reqwest::Proxy> {
+    let proxy = match proxy_for(traffic) {
+        Some(proxy) => proxy.reqwest_proxy().expect("checked by set_proxy"),
+        None => http_proxy()?
+            .reqwest_proxy()
+            .expect("checked by set_http_proxy"),
+    };
+    Some(proxy)
+}
+
+/// An HTTP client builder for `traffic`, going through the proxy if it is
+/// proxied, and from the outgoing interface.
+pub(crate) fn http_client_builder(traffic: Traffic) -> reqwest::ClientBuilder {
+    let builder = outgoing().http(reqwest::Client::builder());
+    match reqwest_proxy_for(traffic) {
+        Some(proxy) => builder.proxy(proxy),
+        None => builder,
+    }
+}
+
+/// `http_client_builder` for blocking clients.
+pub(crate) fn blocking_http_client_builder(traffic: Traffic) -> reqwest::blocking::ClientBuilder {
+    let builder = outgoing().blocking_http(reqwest::blocking::Client::builder());
+    match reqwest_proxy_for(traffic) {
+        Some(proxy) => builder.proxy(proxy),
+        None => builder,
+    }
+}
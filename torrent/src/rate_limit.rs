@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// A token bucket per direction, shared by every peer connection and web
+// seed of the session. A transfer takes its bytes out of the bucket up
+// front and, if that overdraws it, waits until the refill has paid the debt
+// back. Whole blocks and pieces go through at once while the average rate
+// stays at the cap.
+//
+// A torrent with caps of its own gets a limiter of its own, which draws from
+// its buckets and the session's alike and waits for the slower of the two.
+
+/// How many seconds' worth of bytes a bucket holds, the largest burst after
+/// being idle.
+const BURST_SECS: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// Caps in bytes per second, `None` for unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+impl RateLimits {
+    /// The lower of each cap, e.g. low-power mode's on top of the global
+    /// ones.
+    pub fn min(self, other: Self) -> Self {
+        let lower = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (cap, None) | (None, cap) => cap,
+        };
+        Self {
+            download: lower(self.download, other.download),
+            upload: lower(self.upload, other.upload),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Option<u64>,
+    /// Negative while transfers wait for the refill.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        // A rate of 0 would wait forever, it means no limit like elsewhere
+        let rate = rate.filter(|rate| *rate > 0);
+        Self {
+            rate,
+            tokens: rate.map_or(0.0, |rate| rate as f64 * BURST_SECS),
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64 * BURST_SECS);
+        }
+        self.refilled = now;
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        let rate = rate.filter(|rate| *rate > 0);
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = match rate {
+            Some(rate) => self.tokens.min(rate as f64 * BURST_SECS),
+            None => 0.0,
+        };
+    }
+
+    /// Take `bytes`, returning how long to wait before transferring them.
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+/// The session's global download and upload caps. Cheap to clone, every
+/// clone draws from the same buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<(TokenBucket, TokenBucket)>>,
+    /// The session's limiter, for the limiter of a torrent.
+    session: Option<Box<RateLimiter>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            buckets: Arc::new(Mutex::new((
+                TokenBucket::new(limits.download, now),
+                TokenBucket::new(limits.upload, now),
+            ))),
+            session: None,
+        }
+    }
+
+    /// A limiter for one torrent with its own `limits`, whose transfers
+    /// count towards this limiter's caps as well.
+    pub fn for_torrent(&self, limits: RateLimits) -> Self {
+        Self {
+            session: Some(Box::new(self.clone())),
+            ..Self::new(limits)
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        let buckets = self.buckets.lock().unwrap();
+        RateLimits {
+            download: buckets.0.rate,
+            upload: buckets.1.rate,
+        }
+    }
+
+    /// Change the caps, taking effect for the next transfer. Those of the
+    /// session stay as they are for a torrent's limiter.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.0.set_rate(limits.download, now);
+        buckets.1.set_rate(limits.upload, now);
+    }
+
+    /// Take `bytes` about to be transferred in `direction`, returning how
+    /// long to wait first.
+    pub fn reserve(&self, direction: Direction, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = match direction {
+                Direction::Download => &mut buckets.0,
+                Direction::Upload => &mut buckets.1,
+            };
+            bucket.take(bytes, now)
+        };
+        match &self.session {
+            Some(session) => wait.max(session.reserve(direction, bytes)),
+            None => wait,
+        }
+    }
+
+    /// Block until `bytes` may be transferred in `direction`.
+    pub fn throttle(&self, direction: Direction, bytes: usize) {
+        let wait = self.reserve(direction, bytes);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// `throttle` for async tasks.
+    pub async fn throttle_async(&self, direction: Direction, bytes: usize) {
+        let wait = self.reserve(direction, bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn a_full_bucket_lets_a_burst_through_then_waits() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), start);
+        assert_eq!(bucket.take(1000, start), Duration::ZERO);
+        // Overdrawn by half a second's worth
+        assert_eq!(bucket.take(500, start), SEC / 2);
+        // Paid back as it refills
+        assert_eq!(bucket.take(0, start + SEC / 2), Duration::ZERO);
+        assert_eq!(bucket.take(250, start + SEC / 2), SEC / 4);
+    }
+
+    #[test]
+    fn idle_time_only_fills_the_bucket_once() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), start);
+        bucket.take(1000, start);
+        let later = start + 60 * SEC;
+        assert_eq!(bucket.take(1000, later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, later), SEC);
+    }
+
+    #[test]
+    fn no_rate_or_a_rate_of_zero_is_unlimited() {
+        let now = Instant::now();
+        for rate in [None, Some(0)] {
+            let mut bucket = TokenBucket::new(rate, now);
+            assert_eq!(bucket.rate, None);
+            assert_eq!(bucket.take(usize::MAX, now), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn lowering_the_rate_caps_the_saved_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), now);
+        bucket.set_rate(Some(100), now);
+        assert_eq!(bucket.take(200, now), SEC);
+
+        bucket.set_rate(None, now);
+        assert_eq!(bucket.take(200, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn each_direction_has_its_own_cap() {
+        let limiter = RateLimiter::new(RateLimits {
+            download: Some(1000),
+            upload: None,
+        });
+        assert_eq!(limiter.reserve(Direction::Upload, 1 << 20), Duration::ZERO);
+        assert_eq!(limiter.reserve(Direction::Download, 1000), Duration::ZERO);
+        let wait = limiter.reserve(Direction::Download, 1000);
+        assert!(wait > SEC * 9 / 10 && wait <= SEC, "{wait:?}");
+
+        // Clones draw from the same buckets
+        let wait = limiter.clone().reserve(Direction::Download, 1000);
+        assert!(wait > SEC * 19 / 10, "{wait:?}");
+
+        limiter.set_limits(RateLimits::default());
+        assert_eq!(limiter.limits(), RateLimits::default());
+        assert_eq!(limiter.reserve(Direction::Download, 1000), Duration::ZERO);
+    }
+
+    #[test]
+    fn torrents_wait_for_the_slower_of_their_cap_and_the_sessions() {
+        let session = RateLimiter::new(RateLimits {
+            download: Some(1000),
+            upload: Some(1000),
+        });
+        let torrent = session.for_torrent(RateLimits {
+            download: Some(100),
+            upload: None,
+        });
+        let wait = torrent.reserve(Direction::Download, 200);
+        assert!(wait > SEC * 9 / 10 && wait <= SEC, "{wait:?}");
+
+        // Its uploads count towards the session's cap alone
+        assert_eq!(torrent.reserve(Direction::Upload, 1000), Duration::ZERO);
+        let wait = session.reserve(Direction::Upload, 1000);
+        assert!(wait > SEC * 9 / 10, "{wait:?}");
+    }
+
+    #[test]
+    fn the_lower_of_each_cap_applies() {
+        let global = RateLimits {
+            download: Some(1000),
+            upload: None,
+        };
+        let low_power = RateLimits {
+            download: Some(5000),
+            upload: Some(100),
+        };
+        let lower = RateLimits {
+            download: Some(1000),
+            upload: Some(100),
+        };
+        assert_eq!(global.min(low_power), lower);
+        assert_eq!(low_power.min(global), lower);
+        assert_eq!(global.min(RateLimits::default()), global);
+    }
+}
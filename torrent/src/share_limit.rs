@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::stats::TransferStats;
+
+/// How long the user is warned before a torrent is removed for reaching
+/// its share limit.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// What happens to a torrent once it reaches its share limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShareLimitAction {
+    /// Stop transferring but keep it with the active torrents, resuming
+    /// ignores the limit.
+    #[default]
+    Pause,
+    /// Stop and move it to the completed torrents.
+    Stop,
+    /// Forget the torrent, the downloaded data stays.
+    Remove,
+    /// Forget the torrent and delete the downloaded data.
+    RemoveWithData,
+}
+
+impl ShareLimitAction {
+    /// Whether the action can't be undone, these are announced first.
+    pub fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            ShareLimitAction::Remove | ShareLimitAction::RemoveWithData
+        )
+    }
+}
+
+impl FromStr for ShareLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(ShareLimitAction::Pause),
+            "stop" => Ok(ShareLimitAction::Stop),
+            "remove" => Ok(ShareLimitAction::Remove),
+            "remove-with-data" => Ok(ShareLimitAction::RemoveWithData),
+            _ => Err(format!(
+                "unknown action {s}, expected pause, stop, remove or remove-with-data"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ShareLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ShareLimitAction::Pause => "pause",
+            ShareLimitAction::Stop => "stop",
+            ShareLimitAction::Remove => "remove",
+            ShareLimitAction::RemoveWithData => "remove-with-data",
+        })
+    }
+}
+
+/// Seeding limits of a torrent. Unset fields fall back to the torrent's
+/// label, then to the global limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ShareLimits {
+    /// Upload ratio to seed to.
+    pub ratio: Option<f64>,
+    /// Minutes to seed for.
+    pub seed_time_mins: Option<u64>,
+    pub action: Option<ShareLimitAction>,
+}
+
+impl ShareLimits {
+    /// Fill in every field not set here from `fallback`.
+    pub fn or(self, fallback: ShareLimits) -> Self {
+        Self {
+            ratio: self.ratio.or(fallback.ratio),
+            seed_time_mins: self.seed_time_mins.or(fallback.seed_time_mins),
+            action: self.action.or(fallback.action),
+        }
+    }
+
+    pub fn action(&self) -> ShareLimitAction {
+        self.action.unwrap_or_default()
+    }
+
+    /// Whether either limit has been reached. Without any limit a torrent
+    /// seeds forever.
+    pub fn reached(&self, stats: &TransferStats, seed_time: Duration) -> bool {
+        let ratio = self.ratio.is_some_and(|ratio| stats.ratio() >= ratio);
+        let seed_time = self
+            .seed_time_mins
+            .is_some_and(|mins| seed_time >= Duration::from_secs(mins * 60));
+        ratio || seed_time
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ShareLimitEvent {
+    /// The limit was reached and `action` will be taken once the grace
+    /// period is over, unless the user steps in.
+    Warning {
+        action: ShareLimitAction,
+        remaining: Duration,
+    },
+    /// Take `action` now.
+    Apply(ShareLimitAction),
+}
+
+/// Watches one seeding torrent for its share limit.
+#[derive(Debug, Clone)]
+pub struct ShareLimitWatch {
+    limits: ShareLimits,
+    grace: Duration,
+    warned_at: Option<Instant>,
+    done: bool,
+}
+
+impl ShareLimitWatch {
+    pub fn new(limits: ShareLimits) -> Self {
+        Self {
+            limits,
+            grace: DEFAULT_GRACE_PERIOD,
+            warned_at: None,
+            done: false,
+        }
+    }
+
+    pub fn with_grace_period(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    pub fn limits(&self) -> ShareLimits {
+        self.limits
+    }
+
+    /// Check the limit, returning at most one `Warning` and one `Apply`
+    /// over the life of the watch.
+    pub fn check(
+        &mut self,
+        stats: &TransferStats,
+        seed_time: Duration,
+        now: Instant,
+    ) -> Option<ShareLimitEvent> {
+        if self.done || !self.limits.reached(stats, seed_time) {
+            return None;
+        }
+
+        let action = self.limits.action();
+        if !action.is_destructive() || self.grace.is_zero() {
+            self.done = true;
+            return Some(ShareLimitEvent::Apply(action));
+        }
+
+        match self.warned_at {
+            None => {
+                self.warned_at = Some(now);
+                Some(ShareLimitEvent::Warning {
+                    action,
+                    remaining: self.grace,
+                })
+            }
+            Some(warned_at) if now.duration_since(warned_at) >= self.grace => {
+                self.done = true;
+                Some(ShareLimitEvent::Apply(action))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// The user chose to keep seeding, e.g. after the warning or by
+    /// resuming a paused torrent. The limit is not applied again.
+    pub fn dismiss(&mut self) {
+        self.done = true;
+        self.warned_at = None;
+    }
+}
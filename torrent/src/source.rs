@@ -0,0 +1,235 @@
+use std::{io::Read, path::PathBuf, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{
+    dns::http_client,
+    info_hash::InfoHash,
+    magnet::{MagnetLink, MagnetLinkError},
+    meta_info::{MetaInfo, MetaInfoError},
+};
+
+/// Everything a torrent can be added from.
+#[derive(Debug, Clone)]
+pub enum TorrentSource {
+    /// A .torrent file on disk.
+    File(PathBuf),
+    /// The contents of a .torrent file, e.g. uploaded over RPC.
+    Bytes(Vec<u8>),
+    Magnet(MagnetLink),
+    /// A bare info hash, the metadata has to come from the swarm.
+    InfoHash(InfoHash),
+    /// A .torrent file to download, e.g. from an RSS feed.
+    Url {
+        url: Url,
+        /// The info hash announced alongside the URL (e.g. by the feed),
+        /// the download is rejected if it doesn't match.
+        info_hash: Option<InfoHash>,
+    },
+}
+
+impl TorrentSource {
+    /// Expect the .torrent downloaded from a URL to have `info_hash`.
+    /// Other sources already are or name exactly one torrent.
+    pub fn with_info_hash(self, expected: InfoHash) -> Self {
+        match self {
+            TorrentSource::Url { url, .. } => TorrentSource::Url {
+                url,
+                info_hash: Some(expected),
+            },
+            source => source,
+        }
+    }
+}
+
+/// The largest .torrent file downloaded by default, 10 MiB is plenty for
+/// torrents with hundreds of thousands of pieces.
+pub const DEFAULT_MAX_TORRENT_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SourceError {
+    Magnet(MagnetLinkError),
+    MetaInfo(MetaInfoError),
+    /// The .torrent file could not be downloaded.
+    DownloadFailed,
+    /// The .torrent file is larger than the configured maximum.
+    TooLarge,
+    /// The downloaded .torrent file is not the torrent that was announced.
+    InfoHashMismatch {
+        expected: InfoHash,
+        actual: InfoHash,
+    },
+}
+
+impl From<MagnetLinkError> for SourceError {
+    fn from(err: MagnetLinkError) -> Self {
+        SourceError::Magnet(err)
+    }
+}
+
+impl From<MetaInfoError> for SourceError {
+    fn from(err: MetaInfoError) -> Self {
+        SourceError::MetaInfo(err)
+    }
+}
+
+impl FromStr for TorrentSource {
+    type Err = SourceError;
+
+    /// Guess what the user gave us:
+    ///
+    /// - `magnet:?...` is a magnet link
+    /// - `http://` or `https://` is a .torrent file to download
+    /// - 40 hex or 32 base32 characters that are not an existing file are an info hash
+    /// - everything else is a path to a .torrent file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("magnet:") {
+            return Ok(Self::Magnet(s.parse()?));
+        }
+
+        if s.starts_with("http://") || s.starts_with("https://") {
+            if let Ok(url) = Url::parse(s) {
+                return Ok(Self::Url {
+                    url,
+                    info_hash: None,
+                });
+            }
+        }
+
+        let path = PathBuf::from(s);
+        if !path.exists() {
+            if let Ok(info_hash) = s.parse::<InfoHash>() {
+                return Ok(Self::InfoHash(info_hash));
+            }
+        }
+
+        Ok(Self::File(path))
+    }
+}
+
+/// A source after resolving it as far as possible without talking to peers.
+#[derive(Debug)]
+pub enum ResolvedSource {
+    /// We have the full metainfo.
+    MetaInfo(Box<MetaInfo>),
+    /// Only the info hash (and maybe trackers/peers) is known, the info
+    /// dictionary has to be fetched from peers (BEP 9) first.
+    Magnet(MagnetLink),
+}
+
+impl ResolvedSource {
+    pub fn info_hash(&self) -> InfoHash {
+        match self {
+            ResolvedSource::MetaInfo(meta_info) => meta_info.info().hash(),
+            ResolvedSource::Magnet(magnet) => magnet.info_hash,
+        }
+    }
+}
+
+/// Turns any `TorrentSource` into a `ResolvedSource`, so every way of
+/// adding a torrent goes through the same code.
+#[derive(Debug)]
+pub struct SourceResolver {
+    /// Trackers to use for bare info hashes, which come without any.
+    default_trackers: Vec<String>,
+    /// The largest .torrent file to download, in bytes.
+    max_torrent_size: u64,
+}
+
+impl Default for SourceResolver {
+    fn default() -> Self {
+        Self {
+            default_trackers: Vec::new(),
+            max_torrent_size: DEFAULT_MAX_TORRENT_SIZE,
+        }
+    }
+}
+
+impl SourceResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_torrent_size(mut self, bytes: u64) -> Self {
+        self.max_torrent_size = bytes;
+        self
+    }
+
+    pub fn with_default_trackers(mut self, trackers: Vec<String>) -> Self {
+        self.default_trackers = trackers;
+        self
+    }
+
+    /// Parse `input` as a `TorrentSource` and resolve it.
+    pub fn resolve_str(&self, input: &str) -> Result<ResolvedSource, SourceError> {
+        self.resolve(input.parse()?)
+    }
+
+    pub fn resolve(&self, source: TorrentSource) -> Result<ResolvedSource, SourceError> {
+        match source {
+            TorrentSource::File(path) => Ok(ResolvedSource::MetaInfo(Box::new(
+                MetaInfo::try_from(path)?,
+            ))),
+            TorrentSource::Bytes(bytes) => Ok(ResolvedSource::MetaInfo(Box::new(
+                MetaInfo::from_bytes(&bytes)?,
+            ))),
+            TorrentSource::Magnet(magnet) => Ok(ResolvedSource::Magnet(magnet)),
+            TorrentSource::InfoHash(info_hash) => Ok(ResolvedSource::Magnet(MagnetLink {
+                info_hash,
+                info_hash_v2: None,
+                display_name: None,
+                trackers: self.default_trackers.clone(),
+                peers: Vec::new(),
+                web_seeds: Vec::new(),
+            })),
+            TorrentSource::Url { url, info_hash } => {
+                let body = self.download(url)?;
+                let resolved = self.resolve(TorrentSource::Bytes(body))?;
+
+                match info_hash {
+                    Some(expected) if expected != resolved.info_hash() => {
+                        Err(SourceError::InfoHashMismatch {
+                            expected,
+                            actual: resolved.info_hash(),
+                        })
+                    }
+                    _ => Ok(resolved),
+                }
+            }
+        }
+    }
+
+    /// Download a .torrent file, giving up as soon as it is known to be
+    /// larger than `max_torrent_size`.
+    pub fn download(&self, url: Url) -> Result<Vec<u8>, SourceError> {
+        let Ok(response) = http_client()
+            .get(url)
+            .send()
+            .and_then(|r| r.error_for_status())
+        else {
+            return Err(SourceError::DownloadFailed);
+        };
+
+        if response
+            .content_length()
+            .is_some_and(|len| len > self.max_torrent_size)
+        {
+            return Err(SourceError::TooLarge);
+        }
+
+        // The length may be missing or wrong, stop reading past the limit
+        let mut body = Vec::new();
+        if response
+            .take(self.max_torrent_size + 1)
+            .read_to_end(&mut body)
+            .is_err()
+        {
+            return Err(SourceError::DownloadFailed);
+        }
+        if body.len() as u64 > self.max_torrent_size {
+            return Err(SourceError::TooLarge);
+        }
+        Ok(body)
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::disk::CommittedPiece;
+
+/// Bars of a sparkline, lowest first.
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Transfer counters for a single torrent.
+///
+/// Received data only counts towards progress once the piece it belongs to
+/// has been verified against its hash, so a torrent never shows as done on
+/// data that later turns out to be bad.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Every payload byte received from peers, including data that failed
+    /// verification or was received twice.
+    pub downloaded: u64,
+    /// Every payload byte sent to peers.
+    pub uploaded: u64,
+    /// Bytes of pieces we have and whose hash matched.
+    pub verified: u64,
+    /// Bytes of pieces that failed verification and were thrown away.
+    pub failed: u64,
+}
+
+impl TransferStats {
+    /// Start from what is already on disk, e.g. after a recheck on resume.
+    pub fn with_verified(verified: u64) -> Self {
+        Self {
+            verified,
+            ..Self::default()
+        }
+    }
+
+    pub fn block_received(&mut self, len: u64) {
+        self.downloaded += len;
+    }
+
+    pub fn block_sent(&mut self, len: u64) {
+        self.uploaded += len;
+    }
+
+    /// Count a piece as done once it is on disk, not as soon as it verified.
+    pub fn piece_verified(&mut self, piece: &CommittedPiece) {
+        self.verified += piece.len();
+    }
+
+    pub fn piece_failed(&mut self, len: u64) {
+        self.failed += len;
+    }
+
+    /// How many bytes are still missing, as sent to trackers as `left`.
+    pub fn left(&self, total_length: u64) -> u64 {
+        total_length.saturating_sub(self.verified)
+    }
+
+    /// Verified progress in `0.0..=1.0`.
+    pub fn progress(&self, total_length: u64) -> f64 {
+        if total_length == 0 {
+            return 1.0;
+        }
+        (self.verified.min(total_length) as f64) / total_length as f64
+    }
+
+    /// Verified progress as a whole percentage, rounded down so only a fully
+    /// verified torrent reaches 100.
+    pub fn percent_done(&self, total_length: u64) -> u8 {
+        if total_length == 0 {
+            return 100;
+        }
+        (self.verified.min(total_length) * 100 / total_length) as u8
+    }
+
+    pub fn is_complete(&self, total_length: u64) -> bool {
+        self.verified >= total_length
+    }
+
+    /// Upload ratio against the data we kept.
+    pub fn ratio(&self) -> f64 {
+        if self.verified == 0 {
+            return 0.0;
+        }
+        self.uploaded as f64 / self.verified as f64
+    }
+}
+
+/// The session's transfer rates at one moment, in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateSample {
+    /// Seconds since the unix epoch.
+    pub at: u64,
+    pub download: u64,
+    pub upload: u64,
+}
+
+/// Draw `values` as a sparkline at most `width` characters wide, averaging
+/// neighbouring values when there are more than that. The highest value
+/// is a full bar.
+pub fn sparkline(values: &[u64], width: usize) -> String {
+    if values.is_empty() || width == 0 {
+        return String::new();
+    }
+
+    let bucket = values.len().div_ceil(width);
+    let averages: Vec<u64> = values
+        .chunks(bucket)
+        .map(|chunk| chunk.iter().sum::<u64>() / chunk.len() as u64)
+        .collect();
+    let max = averages.iter().copied().max().unwrap_or(0);
+
+    averages
+        .into_iter()
+        .map(|value| match max {
+            0 => SPARKS[0],
+            _ => SPARKS[(value * (SPARKS.len() as u64 - 1)).div_ceil(max) as usize],
+        })
+        .collect()
+}
@@ -0,0 +1,569 @@
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{
+    meta_info::Info,
+    operation::{Cancelled, Operation},
+};
+
+// Where the data of a torrent's files is kept. `PieceWriter` splits pieces
+// and blocks into the ranges of the files they cover and skips pad files,
+// so a storage only ever sees reads and writes within one real file.
+
+/// How much is written at once when allocating a file in full.
+const ALLOCATION_CHUNK: usize = 1024 * 1024;
+
+/// How long the file made to find out whether a file system keeps files
+/// sparse is.
+const SPARSE_PROBE_LEN: u64 = 16 * 1024 * 1024;
+static SPARSE_PROBE_NAME: &str = ".flud-sparse-probe";
+
+/// How room for a torrent's files is reserved before any data arrives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Allocation {
+    /// Files are extended to their length without writing anything, most
+    /// file systems only take up room for the parts written since.
+    #[default]
+    Sparse,
+    /// Files are filled with zeros up front, so the disk can't run out
+    /// halfway and the data is less fragmented. Slow for large torrents.
+    Full,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Allocation::Sparse => "sparse",
+            Allocation::Full => "full",
+        })
+    }
+}
+
+/// A read of `buf.len()` bytes at `offset` in `file`, one of a batch.
+#[derive(Debug)]
+pub struct BlockRead<'a> {
+    pub file: usize,
+    pub offset: u64,
+    pub buf: &'a mut [u8],
+}
+
+/// A write of `data` at `offset` in `file`, one of a batch.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockWrite<'a> {
+    pub file: usize,
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+/// A backend for the data of a torrent's files, addressed by the index of
+/// the file in the torrent and the offset within it.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Fill `buf` with the data at `offset` in `file`, failing with
+    /// `UnexpectedEof` if it was never written.
+    fn read_block(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write `data` at `offset` in `file`, creating it if needed.
+    fn write_block(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Fill every read of the batch, e.g. the spans of a piece. One after
+    /// the other by default, a storage that can have them in flight at
+    /// once overrides it.
+    fn read_blocks(&self, reads: &mut [BlockRead<'_>]) -> io::Result<()> {
+        reads
+            .iter_mut()
+            .try_for_each(|read| self.read_block(read.file, read.offset, read.buf))
+    }
+
+    /// Carry out every write of the batch, like `read_blocks`. On failure
+    /// any of them may have been written.
+    fn write_blocks(&self, writes: &[BlockWrite<'_>]) -> io::Result<()> {
+        writes
+            .iter()
+            .try_for_each(|write| self.write_block(write.file, write.offset, write.data))
+    }
+
+    /// Make everything written to `file` so far survive a crash or power loss.
+    fn flush(&self, file: usize) -> io::Result<()>;
+
+    /// Hand what is buffered for `file` to the OS, without waiting for it
+    /// to reach the disk. Nothing by default, for storages that don't
+    /// buffer.
+    fn write_back(&self, _file: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reserve room for `len` bytes in `file` before any data arrives.
+    fn preallocate(&self, file: usize, len: u64) -> io::Result<()>;
+
+    /// How many bytes `file` takes up on disk, less than its length while
+    /// it is sparse. `0` if it doesn't exist yet.
+    fn allocated(&self, file: usize) -> io::Result<u64>;
+
+    /// Once every piece is written, e.g. to apply the file attributes of
+    /// BEP 47. Nothing by default.
+    fn finish(&self, _info: &Info) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The default storage: the torrent's files in a directory.
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+    allocation: Allocation,
+    /// Whether the file system under `root` keeps files sparse, found out
+    /// on the first sparse preallocation.
+    sparse: OnceLock<bool>,
+    /// Files created since their directory was last synced.
+    created: Mutex<HashSet<usize>>,
+}
+
+impl FileStorage {
+    pub fn new(info: &Info, root: PathBuf) -> Self {
+        for (_, declared, renamed) in info.renamed_files() {
+            log::warn!(
+                "{} collides with another file of {}, saving it as {}",
+                declared.display(),
+                info.name(),
+                renamed.display()
+            );
+        }
+        Self {
+            paths: info.file_paths(),
+            root,
+            allocation: Allocation::default(),
+            sparse: OnceLock::new(),
+            created: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Reserve room for files with `allocation` rather than sparsely.
+    pub fn with_allocation(mut self, allocation: Allocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn path(&self, file: usize) -> io::Result<PathBuf> {
+        let path = self.paths.get(file).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no such file in the torrent")
+        })?;
+        Ok(self.root.join(path))
+    }
+
+    /// The allocation preallocating actually uses: full where the file
+    /// system can't keep files sparse, e.g. FAT, as extending a file there
+    /// writes the zeros anyway, only all at once and without progress.
+    pub fn allocation(&self) -> Allocation {
+        match self.allocation {
+            Allocation::Full => Allocation::Full,
+            Allocation::Sparse => {
+                let sparse = *self.sparse.get_or_init(|| {
+                    let supported = std::fs::create_dir_all(&self.root)
+                        .and_then(|()| supports_sparse(&self.root));
+                    match supported {
+                        Ok(true) => true,
+                        Ok(false) => {
+                            log::warn!(
+                                "{} can't keep files sparse, allocating them in full",
+                                self.root.display()
+                            );
+                            false
+                        }
+                        Err(err) => {
+                            log::warn!(
+                                "unable to tell whether {} keeps files sparse: {err}",
+                                self.root.display()
+                            );
+                            true
+                        }
+                    }
+                });
+                match sparse {
+                    true => Allocation::Sparse,
+                    false => Allocation::Full,
+                }
+            }
+        }
+    }
+
+    /// Open `file` for writing, creating it and its directories if needed.
+    pub(crate) fn open_for_write(&self, file: usize) -> io::Result<File> {
+        let path = self.path(file)?;
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            self.created.lock().unwrap().insert(file);
+        }
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = File::open(self.path(file)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn write_block(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = self.open_for_write(file)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn flush(&self, file: usize) -> io::Result<()> {
+        let path = self.path(file)?;
+        File::open(&path)?.sync_data()?;
+        // A new file's directory entry has to be synced as well, or the
+        // file may be missing entirely after a power loss.
+        if self.created.lock().unwrap().remove(&file) {
+            sync_dir(path.parent().unwrap_or(&self.root))?;
+        }
+        Ok(())
+    }
+
+    /// Extends the file to `len`, sparse or filled with zeros depending on
+    /// the allocation. Data already in the file is left alone.
+    fn preallocate(&self, file: usize, len: u64) -> io::Result<()> {
+        let mut file = self.open_for_write(file)?;
+        let current = file.metadata()?.len();
+        if current >= len {
+            return Ok(());
+        }
+
+        match self.allocation() {
+            Allocation::Sparse => file.set_len(len),
+            Allocation::Full => {
+                file.seek(SeekFrom::Start(current))?;
+                let zeros = vec![0; ALLOCATION_CHUNK];
+                let mut left = len - current;
+                while left > 0 {
+                    let chunk = left.min(ALLOCATION_CHUNK as u64) as usize;
+                    file.write_all(&zeros[..chunk])?;
+                    left -= chunk as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn allocated(&self, file: usize) -> io::Result<u64> {
+        match std::fs::symlink_metadata(self.path(file)?) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            metadata => Ok(allocated_size(&metadata?)),
+        }
+    }
+
+    /// Create symlinks and mark executables as such (BEP 47).
+    fn finish(&self, info: &Info) -> io::Result<()> {
+        for (index, attributes) in info.file_attributes().into_iter().enumerate() {
+            let path = self.path(index)?;
+
+            if attributes.symlink {
+                let Some(target) = info.symlink_target(index) else {
+                    continue;
+                };
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                match std::fs::remove_file(&path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+                symlink(&target, &path)?;
+            } else if attributes.executable {
+                set_executable(&path)?;
+            }
+            // Hidden files are hidden by their leading dot everywhere but
+            // Windows, where the attribute is not set yet.
+        }
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory, e.g. for tests or to stream a torrent
+/// without touching the disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The data of `file` written so far.
+    pub fn file(&self, file: usize) -> Vec<u8> {
+        let files = self.files.lock().unwrap();
+        files.get(file).cloned().unwrap_or_default()
+    }
+
+    fn with_file<T>(&self, file: usize, f: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+        let mut files = self.files.lock().unwrap();
+        if files.len() <= file {
+            files.resize_with(file + 1, Vec::new);
+        }
+        f(&mut files[file])
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let files = self.files.lock().unwrap();
+        let data = files
+            .get(file)
+            .and_then(|data| data.get(offset as usize..offset as usize + buf.len()))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_block(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.with_file(file, |file| {
+            let end = offset as usize + data.len();
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[offset as usize..end].copy_from_slice(data);
+        });
+        Ok(())
+    }
+
+    fn flush(&self, _file: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn preallocate(&self, file: usize, len: u64) -> io::Result<()> {
+        self.with_file(file, |file| {
+            if (file.len() as u64) < len {
+                file.resize(len as usize, 0);
+            }
+        });
+        Ok(())
+    }
+
+    fn allocated(&self, file: usize) -> io::Result<u64> {
+        let files = self.files.lock().unwrap();
+        Ok(files.get(file).map_or(0, |data| data.len() as u64))
+    }
+}
+
+/// Whether the file system `dir` is on keeps files sparse, by extending a
+/// file there and looking at how much of it takes up room.
+pub fn supports_sparse(dir: &Path) -> io::Result<bool> {
+    let probe = dir.join(SPARSE_PROBE_NAME);
+    let result = (|| {
+        let file = File::create(&probe)?;
+        file.set_len(SPARSE_PROBE_LEN)?;
+        Ok(allocated_size(&file.metadata()?) < SPARSE_PROBE_LEN)
+    })();
+    let _ = std::fs::remove_file(&probe);
+    result
+}
+
+/// How much is copied between checks for cancellation when moving across
+/// file systems.
+const MOVE_CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MoveError {
+    /// Everything moved so far was put back.
+    Cancelled,
+    Io(io::Error),
+}
+
+impl From<io::Error> for MoveError {
+    fn from(err: io::Error) -> Self {
+        MoveError::Io(err)
+    }
+}
+
+impl From<Cancelled> for MoveError {
+    fn from(_: Cancelled) -> Self {
+        MoveError::Cancelled
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::Cancelled => f.write_str("the move was cancelled"),
+            MoveError::Io(err) => write!(f, "unable to move the data: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+/// How a file got to its new place, to undo it.
+enum Moved {
+    Renamed,
+    Copied,
+}
+
+/// Move the torrent's files from under `from` to under `to`, reporting the
+/// bytes moved to `operation`.
+///
+/// Files are renamed where possible and copied in chunks otherwise, the
+/// originals of copies are only removed once every file is in place. If the
+/// move is cancelled or fails, the files moved so far are put back.
+pub fn move_files(
+    info: &Info,
+    from: &Path,
+    to: &Path,
+    operation: &Operation,
+) -> Result<(), MoveError> {
+    let attributes = info.file_attributes();
+    let files: Vec<(PathBuf, u64)> = info
+        .file_paths()
+        .into_iter()
+        .zip(info.file_lengths())
+        .zip(&attributes)
+        .filter(|(_, attributes)| !attributes.padding)
+        .map(|((path, len), _)| (path, len as u64))
+        .filter(|(path, _)| from.join(path).exists())
+        .collect();
+    operation.set_total(files.iter().map(|(_, len)| len).sum());
+
+    let mut moved = Vec::with_capacity(files.len());
+    for (path, len) in &files {
+        let result = operation
+            .checkpoint()
+            .map_err(MoveError::from)
+            .and_then(|()| move_file(&from.join(path), &to.join(path), *len, operation));
+        match result {
+            Ok(how) => moved.push((path, how)),
+            Err(err) => {
+                for (path, how) in moved.into_iter().rev() {
+                    let (source, target) = (from.join(path), to.join(path));
+                    let undone = match how {
+                        Moved::Renamed => std::fs::rename(&target, &source),
+                        Moved::Copied => std::fs::remove_file(&target),
+                    };
+                    if let Err(undo_err) = undone {
+                        log::warn!("unable to put {} back: {undo_err}", source.display());
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    for (path, how) in moved {
+        if let Moved::Copied = how {
+            std::fs::remove_file(from.join(path))?;
+        }
+    }
+    Ok(())
+}
+
+fn move_file(
+    source: &Path,
+    target: &Path,
+    len: u64,
+    operation: &Operation,
+) -> Result<Moved, MoveError> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(source, target).is_ok() {
+        operation.advance(len);
+        return Ok(Moved::Renamed);
+    }
+
+    // Another file system, copy it a chunk at a time
+    let copied = (|| -> Result<(), MoveError> {
+        let mut reader = File::open(source)?;
+        let mut writer = File::create(target)?;
+        let mut buf = vec![0; MOVE_CHUNK];
+        loop {
+            operation.checkpoint()?;
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buf[..read])?;
+            operation.advance(read as u64);
+        }
+        writer.sync_data()?;
+        Ok(())
+    })();
+    if copied.is_err() {
+        let _ = std::fs::remove_file(target);
+    }
+    copied.map(|()| Moved::Copied)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    // Executable for whoever may read it
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn allocated_size(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    // Counted in 512 byte units whatever the block size
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_size(metadata: &std::fs::Metadata) -> u64 {
+    // Not known without platform APIs, sparse files count in full
+    metadata.len()
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directories can't be opened as files here, NTFS journals the entry.
+    Ok(())
+}
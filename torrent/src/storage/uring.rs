@@ -0,0 +1,260 @@
+use io_uring::{opcode, squeue, types, IoUring};
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    fs::File,
+    io,
+    os::fd::{AsRawFd, RawFd},
+    sync::Mutex,
+};
+
+use super::{BlockRead, BlockWrite, FileStorage, Storage};
+use crate::meta_info::Info;
+
+// `FileStorage` makes one blocking call per block on the calling thread, so
+// a disk only has as many requests queued as there are threads waiting on
+// it. Through io_uring every read and write of a batch, the spans of a piece
+// or the runs the write cache hands back, is submitted at once and the
+// kernel works through them together, which keeps an NVMe drive busy from a
+// single thread. Everything but reads and writes is left to the
+// `FileStorage` underneath.
+//
+// The kernel fills and reads the buffers of a batch after it was submitted.
+// The ring works on buffers of its own and copies from and to the caller's
+// once the batch completed, so one cut short by an error never leaves the
+// kernel holding memory that was handed back.
+
+/// Entries submitted at once, larger batches are submitted in turns.
+pub const RING_ENTRIES: u32 = 64;
+/// The most one entry reads or writes, the rest of a longer block follows
+/// in another.
+const MAX_ENTRY_LEN: usize = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Read,
+    Write,
+}
+
+/// A read or write of a batch, with the buffer the kernel works on.
+#[derive(Debug)]
+struct Op {
+    kind: Kind,
+    fd: RawFd,
+    offset: u64,
+    buf: Vec<u8>,
+    /// How much of `buf` was read or written so far.
+    done: usize,
+}
+
+impl Op {
+    /// The entry for the rest of the op, tagged with its index in the batch.
+    fn entry(&mut self, index: usize) -> squeue::Entry {
+        let len = (self.buf.len() - self.done).min(MAX_ENTRY_LEN) as u32;
+        let offset = self.offset + self.done as u64;
+        let buf = &mut self.buf[self.done..];
+        let entry = match self.kind {
+            Kind::Read => opcode::Read::new(types::Fd(self.fd), buf.as_mut_ptr(), len)
+                .offset(offset)
+                .build(),
+            Kind::Write => opcode::Write::new(types::Fd(self.fd), buf.as_ptr(), len)
+                .offset(offset)
+                .build(),
+        };
+        entry.user_data(index as u64)
+    }
+}
+
+/// The torrent's files in a directory like `FileStorage`, read and written
+/// in batches through io_uring where the kernel offers it.
+pub struct UringStorage {
+    files: FileStorage,
+    /// `None` once the ring failed, every batch goes to `files` then.
+    ring: Mutex<Option<IoUring>>,
+}
+
+impl fmt::Debug for UringStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringStorage")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UringStorage {
+    /// Reads and writes go to `files` one by one if the kernel doesn't
+    /// offer io_uring, e.g. before 5.1 or when a seccomp profile forbids it.
+    pub fn new(files: FileStorage) -> Self {
+        let ring = IoUring::new(RING_ENTRIES)
+            .inspect_err(|err| {
+                log::warn!("io_uring is not available, reading and writing files one by one: {err}")
+            })
+            .ok();
+        Self {
+            files,
+            ring: Mutex::new(ring),
+        }
+    }
+
+    /// Carry out every op of the batch, returning them with their buffers
+    /// filled. `None` if the ring is gone.
+    fn run(&self, mut ops: Vec<Op>) -> Option<io::Result<Vec<Op>>> {
+        let mut guard = self.ring.lock().unwrap();
+        let ring = guard.as_mut()?;
+
+        let mut queued: VecDeque<usize> = (0..ops.len())
+            .filter(|&index| !ops[index].buf.is_empty())
+            .collect();
+        let mut in_flight = 0;
+        let mut error = None;
+        loop {
+            // After an error only what is in flight is waited for
+            while error.is_none() && in_flight < RING_ENTRIES as usize {
+                let Some(index) = queued.pop_front() else {
+                    break;
+                };
+                let entry = ops[index].entry(index);
+                // Safety: the buffer and the file of the op outlive the
+                // entry, they are only dropped once it completed or leaked
+                // along with the ring below.
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    queued.push_front(index);
+                    break;
+                }
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // Entries may still be in flight, their buffers have to
+                    // stay where they are
+                    log::warn!("io_uring failed, reading and writing files one by one: {err}");
+                    std::mem::forget(ops);
+                    *guard = None;
+                    return Some(Err(err));
+                }
+            }
+
+            for completion in ring.completion() {
+                in_flight -= 1;
+                let index = completion.user_data() as usize;
+                let op = &mut ops[index];
+                let result = completion.result();
+                if result < 0 {
+                    let err = io::Error::from_raw_os_error(-result);
+                    match err.kind() {
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => {
+                            queued.push_back(index)
+                        }
+                        _ => {
+                            error.get_or_insert(err);
+                        }
+                    }
+                } else if result == 0 {
+                    error.get_or_insert(match op.kind {
+                        Kind::Read => io::ErrorKind::UnexpectedEof.into(),
+                        Kind::Write => io::ErrorKind::WriteZero.into(),
+                    });
+                } else {
+                    op.done += result as usize;
+                    if op.done < op.buf.len() {
+                        queued.push_back(index);
+                    }
+                }
+            }
+        }
+
+        Some(match error {
+            Some(err) => Err(err),
+            None => Ok(ops),
+        })
+    }
+}
+
+/// The descriptor of `file`, opened with `open` the first time.
+fn descriptor(
+    files: &mut HashMap<usize, File>,
+    file: usize,
+    open: impl FnOnce() -> io::Result<File>,
+) -> io::Result<RawFd> {
+    Ok(match files.entry(file) {
+        Entry::Occupied(entry) => entry.get().as_raw_fd(),
+        Entry::Vacant(entry) => entry.insert(open()?).as_raw_fd(),
+    })
+}
+
+impl Storage for UringStorage {
+    fn read_block(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_blocks(&mut [BlockRead { file, offset, buf }])
+    }
+
+    fn write_block(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.write_blocks(&[BlockWrite { file, offset, data }])
+    }
+
+    fn read_blocks(&self, reads: &mut [BlockRead<'_>]) -> io::Result<()> {
+        let mut files = HashMap::new();
+        let mut ops = Vec::with_capacity(reads.len());
+        for read in reads.iter() {
+            ops.push(Op {
+                kind: Kind::Read,
+                fd: descriptor(&mut files, read.file, || {
+                    File::open(self.files.path(read.file)?)
+                })?,
+                offset: read.offset,
+                buf: vec![0; read.buf.len()],
+                done: 0,
+            });
+        }
+
+        let Some(ops) = self.run(ops) else {
+            return self.files.read_blocks(reads);
+        };
+        for (read, op) in reads.iter_mut().zip(ops?) {
+            read.buf.copy_from_slice(&op.buf);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, writes: &[BlockWrite<'_>]) -> io::Result<()> {
+        let mut files = HashMap::new();
+        let mut ops = Vec::with_capacity(writes.len());
+        for write in writes {
+            ops.push(Op {
+                kind: Kind::Write,
+                fd: descriptor(&mut files, write.file, || {
+                    self.files.open_for_write(write.file)
+                })?,
+                offset: write.offset,
+                buf: write.data.to_vec(),
+                done: 0,
+            });
+        }
+
+        match self.run(ops) {
+            Some(result) => result.map(|_| ()),
+            None => self.files.write_blocks(writes),
+        }
+    }
+
+    fn flush(&self, file: usize) -> io::Result<()> {
+        self.files.flush(file)
+    }
+
+    fn preallocate(&self, file: usize, len: u64) -> io::Result<()> {
+        self.files.preallocate(file, len)
+    }
+
+    fn allocated(&self, file: usize) -> io::Result<u64> {
+        self.files.allocated(file)
+    }
+
+    fn finish(&self, info: &Info) -> io::Result<()> {
+        self.files.finish(info)
+    }
+}
@@ -0,0 +1,302 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A torrent is considered dead when no complete copy has been seen for this long.
+pub const DEAD_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long pieces have to be missing from the swarm before the torrent
+/// counts as stalled, so peers that are still sending their bitfields or a
+/// seed that briefly drops out don't trigger it.
+pub const STALLED_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// How many connected peers have each piece.
+#[derive(Debug, Clone)]
+pub struct Availability {
+    counts: Vec<u32>,
+    /// Connected peers that have every piece.
+    seeds: usize,
+}
+
+impl Availability {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            counts: vec![0; piece_count],
+            seeds: 0,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Whether `bitfield` has every one of `piece_count` pieces.
+    pub fn is_complete(bitfield: &[u8], piece_count: usize) -> bool {
+        (0..piece_count).all(|index| has_piece(bitfield, index))
+    }
+
+    /// A peer sent its bitfield.
+    pub fn add_bitfield(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            if has_piece(bitfield, index) {
+                *count += 1;
+            }
+        }
+        if Self::is_complete(bitfield, self.counts.len()) {
+            self.seeds += 1;
+        }
+    }
+
+    /// A peer that sent `bitfield` disconnected.
+    pub fn remove_bitfield(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            if has_piece(bitfield, index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        if Self::is_complete(bitfield, self.counts.len()) {
+            self.seeds = self.seeds.saturating_sub(1);
+        }
+    }
+
+    /// A peer sent `have_all` (BEP 6), it is a seed.
+    pub fn add_seed(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count += 1);
+        self.seeds += 1;
+    }
+
+    pub fn remove_seed(&mut self) {
+        self.counts
+            .iter_mut()
+            .for_each(|count| *count = count.saturating_sub(1));
+        self.seeds = self.seeds.saturating_sub(1);
+    }
+
+    /// A peer announced it has `index`. `completed` is true if that gave the
+    /// peer every piece, making it a seed.
+    pub fn add_have(&mut self, index: usize, completed: bool) {
+        if let Some(count) = self.counts.get_mut(index) {
+            *count += 1;
+        }
+        if completed {
+            self.seeds += 1;
+        }
+    }
+
+    pub fn count(&self, index: usize) -> u32 {
+        self.counts.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn seeds(&self) -> usize {
+        self.seeds
+    }
+
+    /// Whether every piece is available from at least one connected peer,
+    /// even if no single peer is a seed.
+    pub fn has_complete_copy(&self) -> bool {
+        self.counts.iter().all(|&count| count > 0)
+    }
+
+    /// The pieces we don't have (`have`) that no connected peer has either,
+    /// `None` if every piece can still be downloaded.
+    pub fn stall(&self, have: &[u8]) -> Option<Stall> {
+        let missing = (0..self.counts.len())
+            .filter(|&index| !has_piece(have, index) && self.counts[index] == 0)
+            .count();
+        (missing > 0).then_some(Stall {
+            missing,
+            pieces: self.counts.len(),
+        })
+    }
+
+    /// Rarest first: the piece we don't have (`have`) and that isn't being
+    /// downloaded (`in_flight`) with the fewest copies among `source`'s pieces,
+    /// lower indices winning ties.
+    ///
+    /// Web seeds have every piece and count towards every piece like a
+    /// seed, so peers and web seeds are picked for from the same counts.
+    pub fn pick(&self, have: &[u8], source: &[u8], in_flight: &[usize]) -> Option<usize> {
+        (0..self.counts.len())
+            .filter(|&index| !has_piece(have, index) && has_piece(source, index))
+            .filter(|index| !in_flight.contains(index))
+            .min_by_key(|&index| self.counts[index])
+    }
+}
+
+/// A bitfield with all of `piece_count` pieces set, e.g. what a web seed has.
+pub fn full_bitfield(piece_count: usize) -> Vec<u8> {
+    let mut bitfield = vec![0xff; piece_count.div_ceil(8)];
+    if !piece_count.is_multiple_of(8) {
+        if let Some(last) = bitfield.last_mut() {
+            *last = 0xff << (8 - piece_count % 8);
+        }
+    }
+    bitfield
+}
+
+/// Whether the piece at `index` is set in `bitfield`, the high bit of the
+/// first byte is piece 0.
+pub fn has_piece(bitfield: &[u8], index: usize) -> bool {
+    bitfield
+        .get(index / 8)
+        .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+/// When a complete copy of the torrent was last observed in the swarm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeedPresence {
+    /// Seconds since the unix epoch, `None` if never seen.
+    pub last_seen_complete: Option<u64>,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl SeedPresence {
+    pub fn new(last_seen_complete: Option<u64>) -> Self {
+        Self { last_seen_complete }
+    }
+
+    /// Record the current availability, returns true if `last_seen_complete` changed.
+    pub fn observe(&mut self, availability: &Availability, now: SystemTime) -> bool {
+        if availability.seeds() == 0 && !availability.has_complete_copy() {
+            return false;
+        }
+        self.last_seen_complete = Some(unix_seconds(now));
+        true
+    }
+
+    /// How long ago a complete copy was seen.
+    pub fn since(&self, now: SystemTime) -> Option<Duration> {
+        let seen = self.last_seen_complete?;
+        Some(Duration::from_secs(unix_seconds(now).saturating_sub(seen)))
+    }
+
+    /// Dead torrent heuristic: no complete copy is currently available and
+    /// none has been seen in `DEAD_AFTER`. `added` is when the torrent was
+    /// added, so torrents that were never seen complete get a grace period.
+    pub fn is_dead(&self, availability: &Availability, added: SystemTime, now: SystemTime) -> bool {
+        if availability.seeds() > 0 || availability.has_complete_copy() {
+            return false;
+        }
+
+        match self.since(now) {
+            Some(since) => since > DEAD_AFTER,
+            None => now.duration_since(added).unwrap_or_default() > DEAD_AFTER,
+        }
+    }
+
+    /// e.g. `last seen complete: 3d ago`
+    pub fn describe(&self, now: SystemTime) -> String {
+        match self.since(now) {
+            Some(since) => format!("last seen complete: {} ago", format_duration(since)),
+            None => "last seen complete: never".to_string(),
+        }
+    }
+}
+
+/// Pieces of a torrent that neither we nor any connected peer have, so the
+/// download can't finish until someone with them joins the swarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub missing: usize,
+    pub pieces: usize,
+}
+
+impl Stall {
+    /// How much of the torrent we have or can get, `0.0..=1.0`.
+    pub fn available(&self) -> f64 {
+        if self.pieces == 0 {
+            return 1.0;
+        }
+        (self.pieces - self.missing) as f64 / self.pieces as f64
+    }
+}
+
+impl fmt::Display for Stall {
+    /// e.g. `stalled: missing pieces (97.3% available)`, never rounding up
+    /// to 100%.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = (self.available() * 1000.0).floor() / 10.0;
+        write!(f, "stalled: missing pieces ({percent:.1}% available)")
+    }
+}
+
+/// What happens to a torrent that stays stalled for `STALLED_AFTER`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StallAction {
+    /// Keep connecting to peers in case one with the missing pieces shows up.
+    #[default]
+    KeepTrying,
+    /// Pause the torrent and fire the `stalled` hooks, e.g. to send a
+    /// notification.
+    Pause,
+}
+
+impl FromStr for StallAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-trying" => Ok(StallAction::KeepTrying),
+            "pause" => Ok(StallAction::Pause),
+            _ => Err(format!("unknown action {s}, expected keep-trying or pause")),
+        }
+    }
+}
+
+impl fmt::Display for StallAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StallAction::KeepTrying => "keep-trying",
+            StallAction::Pause => "pause",
+        })
+    }
+}
+
+/// Tracks how long a torrent has been stalled, to act on it once.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StallWatch {
+    since: Option<Instant>,
+    acted: bool,
+}
+
+impl StallWatch {
+    /// Record whether the torrent is stalled now, returns true the first
+    /// time it has been for `STALLED_AFTER` in a row.
+    pub fn observe(&mut self, stall: Option<&Stall>, now: Instant) -> bool {
+        if stall.is_none() {
+            *self = Self::default();
+            return false;
+        }
+        let since = *self.since.get_or_insert(now);
+        if self.acted || now.saturating_duration_since(since) < STALLED_AFTER {
+            return false;
+        }
+        self.acted = true;
+        true
+    }
+
+    /// Since when the torrent has been stalled, `None` if it isn't.
+    pub fn since(&self) -> Option<Instant> {
+        self.since
+    }
+}
+
+/// Format a duration using its largest unit, e.g. `3d`, `5h`, `12m`, `40s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
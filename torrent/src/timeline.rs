@@ -0,0 +1,126 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How many events a timeline keeps, older ones are dropped first.
+pub const MAX_EVENTS: usize = 100;
+
+/// Something notable that happened to a torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum TimelineEvent {
+    Added,
+    /// The info dictionary of a magnet link arrived from a peer.
+    MetadataReceived,
+    /// The first peer connection was made.
+    FirstPeer {
+        addr: String,
+    },
+    /// Checking the data on disk finished, e.g. on resume.
+    CheckingFinished {
+        verified_pieces: u32,
+        pieces: u32,
+    },
+    /// Every selected file has been downloaded and verified.
+    Completed,
+    TrackerError {
+        tracker: String,
+        message: String,
+    },
+    /// Pieces were missing from the swarm for `STALLED_AFTER`.
+    Stalled {
+        missing: u32,
+        pieces: u32,
+    },
+    /// Added in place of an older version from its `update-url` (BEP 39).
+    Updated {
+        from: String,
+    },
+}
+
+impl fmt::Display for TimelineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimelineEvent::Added => write!(f, "added"),
+            TimelineEvent::MetadataReceived => write!(f, "metadata received"),
+            TimelineEvent::FirstPeer { addr } => write!(f, "first peer {addr}"),
+            TimelineEvent::CheckingFinished {
+                verified_pieces,
+                pieces,
+            } => write!(f, "checking finished, {verified_pieces}/{pieces} pieces"),
+            TimelineEvent::Completed => write!(f, "completed"),
+            TimelineEvent::TrackerError { tracker, message } => {
+                write!(f, "tracker error from {tracker}: {message}")
+            }
+            TimelineEvent::Stalled { missing, pieces } => {
+                write!(
+                    f,
+                    "stalled, {missing}/{pieces} pieces missing from the swarm"
+                )
+            }
+            TimelineEvent::Updated { from } => write!(f, "updated from {from}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimelineEntry {
+    /// Seconds since the unix epoch.
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: TimelineEvent,
+}
+
+/// The last `MAX_EVENTS` notable events of a torrent, oldest first, to
+/// answer what happened to it while nobody was looking.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Timeline {
+    entries: VecDeque<TimelineEntry>,
+    /// Whether `FirstPeer` was recorded, it may since have been dropped.
+    had_peer: bool,
+}
+
+impl Timeline {
+    /// Record `event` as happening now.
+    pub fn record(&mut self, event: TimelineEvent) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.record_at(now, event);
+    }
+
+    /// Record `event` as happening at `at`, in seconds since the unix epoch.
+    /// Only the first `FirstPeer` is kept.
+    pub fn record_at(&mut self, at: u64, event: TimelineEvent) {
+        if let TimelineEvent::FirstPeer { .. } = event {
+            if self.had_peer {
+                return;
+            }
+            self.had_peer = true;
+        }
+
+        if self.entries.len() == MAX_EVENTS {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TimelineEntry { at, event });
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &TimelineEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,4 +1,8 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Mutex, OnceLock},
+};
 
 use rand::Rng;
 use serde::{
@@ -7,48 +11,230 @@ use serde::{
 };
 use std::fmt;
 
-use crate::{bool_from_int, meta_info::MetaInfo};
+use crate::{
+    bool_from_int, bool_to_int, dns::tracker_http_client, identity::Identity, info_hash::InfoHash,
+    interface::address_family, meta_info::MetaInfo, stats::TransferStats,
+    tracker::client::TrackerError,
+};
 
-// [u8; 20]
+pub mod client;
+pub mod encoding;
+pub mod filter;
+pub mod schedule;
+pub mod scrape;
+pub mod stats;
+pub mod tiers;
+pub mod udp;
+
+/// A new peer id in the Azureus style, `-FL0100-` followed by 12 random
+/// alphanumerics, so it is valid as both bytes and a string.
 pub fn random_peer_id() -> String {
-    let mut rng = rand::thread_rng();
-    let random_bytes: [u8; 20] = rng.gen();
-    let s = std::str::from_utf8(&random_bytes).expect("msg");
-    s.to_owned()
+    let version: String = env!("CARGO_PKG_VERSION")
+        .split('.')
+        .map(|part| part.chars().next().unwrap_or('0'))
+        .chain(std::iter::repeat('0'))
+        .take(4)
+        .collect();
+    let random: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    format!("-FL{version}-{random}")
 }
 
-pub struct Tracker;
+/// Percent-encode raw bytes for use in a query string, leaving only the
+/// unreserved characters `0-9a-zA-Z.-_~` as-is.
+pub(crate) fn url_encode_bytes(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
 
-impl Tracker {
-    pub fn request(torrent: &MetaInfo) -> Result<TrackerResponse, ()> {
-        let request = TrackerRequest::new_compact(torrent);
+/// How many redirects an HTTP announce follows before the tracker counts
+/// as failed.
+pub const MAX_REDIRECTS: usize = 5;
 
-        let query_params =
-            serde_urlencoded::to_string(request).expect("failed to urlencode TrackerRequest");
+/// Trackers that refused `compact=1`, announced to with `compact=0` from
+/// then on. Shared by every announce of the session.
+fn non_compact_trackers() -> &'static Mutex<HashSet<String>> {
+    static TRACKERS: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    TRACKERS.get_or_init(Default::default)
+}
 
-        let tracker_url = torrent.tracker_url();
+/// Whether `tracker_url` is known to only answer `compact=0`.
+pub(crate) fn prefers_non_compact(tracker_url: &str) -> bool {
+    non_compact_trackers()
+        .lock()
+        .is_ok_and(|trackers| trackers.contains(tracker_url))
+}
 
-        let Ok(mut url) = reqwest::Url::parse(&tracker_url) else {
-            return Err(());
-        };
+pub(crate) fn remember_non_compact(tracker_url: &str) {
+    if let Ok(mut trackers) = non_compact_trackers().lock() {
+        trackers.insert(tracker_url.to_owned());
+    }
+}
 
-        url.set_query(Some(&query_params));
+/// Whether a `failure reason` is the tracker refusing the compact peer
+/// list. There is no error code for it, trackers mention it in the text.
+pub(crate) fn rejects_compact(failure_reason: &str) -> bool {
+    failure_reason.to_ascii_lowercase().contains("compact")
+}
 
-        let Ok(response) = reqwest::blocking::get(url) else {
-            return Err(());
-        };
+/// Where a tracker redirected an announce at `current` to, `None` if it is
+/// not an HTTP(S) url. Trackers that moved sometimes redirect to their new
+/// announce url without the query, it is added back then.
+pub(crate) fn redirect_target(
+    request: &TrackerRequest,
+    current: &reqwest::Url,
+    location: &str,
+) -> Option<reqwest::Url> {
+    let target = current.join(location).ok()?;
+    if !matches!(target.scheme(), "http" | "https") {
+        return None;
+    }
+    match target.query() {
+        Some(query) if query.contains("info_hash=") => Some(target),
+        _ => request.url(target.as_str()),
+    }
+}
 
-        let Ok(body) = response.bytes() else {
-            return Err(());
-        };
+pub struct Tracker;
+
+impl Tracker {
+    /// Announce to the torrent's trackers `filter` allows, following BEP 12 tier order.
+    ///
+    /// Meant for a single announce, a torrent that announces again should
+    /// keep its `TrackerTiers` and go through `announce_tiers`, so the
+    /// trackers that answered are asked first.
+    pub fn request(
+        torrent: &MetaInfo,
+        identity: &Identity,
+        filter: &filter::TrackerFilter,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut tiers = tiers::TrackerTiers::from(torrent);
+        tiers.retain(filter);
+        let request =
+            TrackerRequest::new_compact(torrent, identity).with_event(Some(Event::Started));
+        Self::announce_tiers(&request, &mut tiers).map(|(_, response)| response)
+    }
+
+    /// Announce under every info hash of the torrent, both swarms of a
+    /// hybrid torrent, returning the peers of all of them without duplicates.
+    pub fn request_swarms(
+        torrent: &MetaInfo,
+        identity: &Identity,
+        filter: &filter::TrackerFilter,
+    ) -> Result<Vec<TrackerPeer>, TrackerError> {
+        let mut last_error = TrackerError::InvalidUrl;
+        let mut peers = Vec::new();
+        let mut answered = false;
+
+        for info_hash in torrent.info().info_hashes().swarms() {
+            let mut tiers = tiers::TrackerTiers::from(torrent);
+            tiers.retain(filter);
+            let request = TrackerRequest::new_compact(torrent, identity)
+                .with_info_hash(info_hash)
+                .with_event(Some(Event::Started));
+
+            match Self::announce_tiers(&request, &mut tiers) {
+                Ok((_, TrackerResponse::Success(response))) => {
+                    answered = true;
+                    peers.extend(response.peers().copied());
+                }
+                Ok((_, TrackerResponse::Failure(failure))) => {
+                    last_error = TrackerError::Failure(failure.failure_reason);
+                }
+                Err(err) => last_error = err,
+            }
+        }
+
+        answered.then(|| dedup_peers(peers)).ok_or(last_error)
+    }
+
+    /// Announce to `tracker_url`, which may differ from the torrent's own
+    /// trackers when the user edited them.
+    pub fn announce(
+        request: &TrackerRequest,
+        tracker_url: &str,
+    ) -> Result<TrackerResponse, TrackerError> {
+        if tracker_url.starts_with("udp://") {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|_| TrackerError::Connect)?;
+            return match runtime.block_on(udp::announce(request, tracker_url, udp::DEFAULT_TIMEOUT))
+            {
+                Ok(response) => Ok(TrackerResponse::Success(response)),
+                Err(TrackerError::Failure(failure_reason)) => {
+                    Ok(TrackerResponse::Failure(TrackerFailureResponse {
+                        failure_reason,
+                    }))
+                }
+                Err(err) => Err(err),
+            };
+        }
 
-        let res: TrackerResponse = serde_bencode::from_bytes(&body).expect("msg");
+        if prefers_non_compact(tracker_url) {
+            return Self::announce_http(&request.clone().with_compact(false), tracker_url);
+        }
+        match Self::announce_http(request, tracker_url)? {
+            TrackerResponse::Failure(failure) if rejects_compact(&failure.failure_reason) => {
+                let response =
+                    Self::announce_http(&request.clone().with_compact(false), tracker_url);
+                // Only remember it if the tracker actually takes compact=0
+                if let Ok(TrackerResponse::Success(_)) = response {
+                    remember_non_compact(tracker_url);
+                }
+                response
+            }
+            response => Ok(response),
+        }
+    }
 
-        Ok(res)
+    /// Announce to an HTTP(S) tracker, following up to `MAX_REDIRECTS` redirects.
+    fn announce_http(
+        request: &TrackerRequest,
+        tracker_url: &str,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut url = request.url(tracker_url).ok_or(TrackerError::InvalidUrl)?;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let response = tracker_http_client()
+                .get(url.clone())
+                .header(reqwest::header::ACCEPT_ENCODING, encoding::ACCEPT_ENCODING)
+                .send()?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(TrackerError::Redirect)?;
+                url = redirect_target(request, &url, location).ok_or(TrackerError::Redirect)?;
+                continue;
+            }
+
+            let headers = response.headers().clone();
+            let body = encoding::read_body(response).map_err(|err| match err {
+                encoding::DecodeError::Read(_) => TrackerError::Connect,
+                _ => TrackerError::InvalidResponse,
+            })?;
+            // A tracker answering garbage is just a failed tracker, try the next one
+            let body = encoding::decode_body(&headers, &body)
+                .map_err(|_| TrackerError::InvalidResponse)?;
+            return serde_bencode::from_bytes(&body).map_err(|_| TrackerError::InvalidResponse);
+        }
+        Err(TrackerError::Redirect)
     }
 }
 
-#[derive(serde::Deserialize, Serialize)]
+#[derive(Clone, serde::Deserialize, Serialize)]
 pub struct TrackerRequest {
     /// The 20 byte sha1 hash of the bencoded form of the info value from the
     /// metainfo file. This value will almost certainly have to be escaped.
@@ -62,7 +248,10 @@ pub struct TrackerRequest {
     /// Conversely that means clients must either reject invalid metainfo files
     /// or extract the substring directly. They must not perform a
     /// decode-encode roundtrip on invalid data.
-    info_hash: String,
+    ///
+    /// Encoded by hand in `url`, form encoding would mangle the raw bytes.
+    #[serde(skip)]
+    info_hash: InfoHash,
     /// A string of length 20 which this downloader uses as its id.
     /// Each downloader generates its own id at random at the start of a
     /// new download. This value will also almost certainly have to be escaped. [u8; 20]
@@ -70,53 +259,140 @@ pub struct TrackerRequest {
     /// An optional parameter giving the IP (or dns name) which this peer is at.
     /// Generally used for the origin if it's on the same machine as the tracker.
     ip: Option<String>,
+    /// Our IPv6 address, so a tracker reached over IPv4 can hand it out to
+    /// IPv6 peers too (BEP 7).
+    ipv6: Option<String>,
     /// The port number this peer is listening on.
     ///
     /// Common behavior is for a downloader to try to listen on port 6881 and if
     /// that port is taken try 6882, then 6883, etc. and give up after 6889.
     port: u16,
     /// The total amount uploaded so far, encoded in base ten ascii.
-    uploaded: usize,
+    uploaded: u64,
     /// The total amount downloaded so far, encoded in base ten ascii.
-    downloaded: usize,
+    downloaded: u64,
     /// The number of bytes this peer still has to download,
     /// encoded in base ten ascii. Note that this can't be computed from
     /// downloaded and the file length since it might be a resume,
     /// and there's a chance that some of the downloaded data failed an integrity
     /// check and had to be re-downloaded.
-    left: usize,
+    left: u64,
     /// https://www.bittorrent.org/beps/bep_0023.html
     /// default=1
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     compact: bool,
-    // This is an optional key which maps to started, completed, or stopped
-    // (or empty, which is the same as not being present).
-    // If not present, this is one of the announcements done at regular intervals.
-    // An announcement using started is sent when a download first begins,
-    // and one using completed is sent when the download is complete.
-    // No completed is sent if the file was complete when started.
-    // Downloaders send an announcement using stopped when they cease downloading.
-    // event: String,
+    /// This is an optional key which maps to started, completed, or stopped
+    /// (or empty, which is the same as not being present).
+    /// If not present, this is one of the announcements done at regular intervals.
+    event: Option<Event>,
+    /// Leave out `ip` and `ipv6`, see `Identity::anonymous`.
+    #[serde(skip)]
+    anonymous: bool,
 }
 
-impl TrackerRequest {
-    pub fn new_compact(meta_info: &MetaInfo) -> Self {
-        let b = meta_info.info().hash().bytes();
-        let b: &[u8] = &b;
-
-        let info_hash =
-            serde_urlencoded::from_bytes(b).expect("failed to urlencode info_hash bytes");
+/// An announcement using started is sent when a download first begins,
+/// and one using completed is sent when the download is complete.
+/// No completed is sent if the file was complete when started.
+/// Downloaders send an announcement using stopped when they cease downloading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
 
+impl TrackerRequest {
+    /// Announce `meta_info` as `identity`, so every request carries the
+    /// session's own peer id and anonymous mode covers all of them.
+    pub fn new_compact(meta_info: &MetaInfo, identity: &Identity) -> Self {
         Self {
-            info_hash,
-            peer_id: String::from("20129487650173049587"),
+            info_hash: meta_info.info().hash(),
+            peer_id: String::new(),
             port: 6881,
             ip: None,
+            ipv6: None,
             uploaded: 0,
             downloaded: 0,
-            left: meta_info.len(),
+            left: meta_info.len() as u64,
             compact: true,
+            event: None,
+            anonymous: false,
+        }
+        .with_identity(identity)
+    }
+
+    /// The announce url for `tracker_url` with this request as its query string.
+    pub fn url(&self, tracker_url: &str) -> Option<reqwest::Url> {
+        let query_params =
+            serde_urlencoded::to_string(self).expect("failed to urlencode TrackerRequest");
+
+        let mut url = reqwest::Url::parse(tracker_url).ok()?;
+        // Some trackers already carry a query, e.g. a passkey
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!(
+                "{existing}&info_hash={}&{query_params}",
+                self.info_hash.url_encoded()
+            ),
+            _ => format!("info_hash={}&{query_params}", self.info_hash.url_encoded()),
+        };
+        url.set_query(Some(&query));
+        Some(url)
+    }
+
+    /// Announce under `info_hash`, e.g. the truncated v2 hash of a hybrid torrent.
+    pub fn with_info_hash(mut self, info_hash: InfoHash) -> Self {
+        self.info_hash = info_hash;
+        self
+    }
+
+    /// The port peers should connect to, instead of the default 6881.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Also listening on `ip`, see `peer::listener::global_ipv6`.
+    pub fn with_ipv6(mut self, ip: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ip.filter(|_| !self.anonymous).map(|ip| ip.to_string());
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: String) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// Announce with the identity's peer id. Anonymous ones don't tell the
+    /// tracker any other address we are at.
+    pub fn with_identity(mut self, identity: &Identity) -> Self {
+        self.peer_id = identity.peer_id_str().to_owned();
+        self.anonymous = identity.is_anonymous();
+        if self.anonymous {
+            self.ip = None;
+            self.ipv6 = None;
         }
+        self
+    }
+
+    /// Ask for the dictionary peer list instead of the compact one (BEP 23),
+    /// for trackers that refuse `compact=1`.
+    pub fn with_compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    pub fn with_event(mut self, event: Option<Event>) -> Self {
+        self.event = event;
+        self
+    }
+
+    /// Report the real transfer counters instead of a fresh download.
+    pub fn with_stats(mut self, stats: &TransferStats, total_length: u64) -> Self {
+        self.uploaded = stats.uploaded;
+        self.downloaded = stats.downloaded;
+        self.left = stats.left(total_length);
+        self
     }
 }
 
@@ -154,8 +430,16 @@ pub struct TrackerFailureResponse {
 pub struct TrackerPeerResponse {
     /// The number of seconds the downloader should wait between regular rerequests
     interval: usize,
+    /// (optional) Clients must not reannounce more frequently than this.
+    #[serde(rename = "min interval")]
+    min_interval: Option<usize>,
     /// list of dictionaries corresponding to peers
+    #[serde(default)]
     peers: Peers,
+    /// https://www.bittorrent.org/beps/bep_0007.html
+    /// (optional) IPv6 peers in the compact form, 18 bytes per peer.
+    #[serde(default)]
+    peers6: Peers6,
     // More commonly is that trackers return a compact representation of the peer list, see BEP 23.
 
     // If you want to make any extensions to metainfo files or tracker queries,
@@ -165,35 +449,168 @@ pub struct TrackerPeerResponse {
 }
 
 impl TrackerPeerResponse {
-    pub fn peers(&self) -> &Vec<SocketAddrV4> {
-        &self.peers.0
+    pub fn interval(&self) -> usize {
+        self.interval
     }
+
+    pub fn min_interval(&self) -> Option<usize> {
+        self.min_interval
+    }
+
+    /// Every peer of the IP versions in use, IPv4 from `peers` followed by
+    /// IPv6 from `peers6`.
+    pub fn peers(&self) -> impl Iterator<Item = &TrackerPeer> {
+        let family = address_family();
+        self.peers
+            .0
+            .iter()
+            .chain(&self.peers6.0)
+            .filter(move |peer| family.allows(peer.addr.ip()))
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers().map(|peer| peer.addr)
+    }
+
+    /// Add the peers of another response from the same tracker, e.g. over
+    /// the other address family, keeping the longer interval.
+    pub(crate) fn merge(&mut self, other: TrackerPeerResponse) {
+        self.interval = self.interval.max(other.interval);
+        self.min_interval = self.min_interval.max(other.min_interval);
+        for peer in other.peers.0 {
+            if !self.peers.0.iter().any(|known| known.addr == peer.addr) {
+                self.peers.0.push(peer);
+            }
+        }
+        for peer in other.peers6.0 {
+            if !self.peers6.0.iter().any(|known| known.addr == peer.addr) {
+                self.peers6.0.push(peer);
+            }
+        }
+    }
+}
+
+/// Drop peers seen more than once, e.g. in both swarms of a hybrid
+/// torrent, by address or, when known, by peer id.
+pub fn dedup_peers(peers: impl IntoIterator<Item = TrackerPeer>) -> Vec<TrackerPeer> {
+    let mut unique: Vec<TrackerPeer> = Vec::new();
+    for peer in peers {
+        let seen = unique.iter().any(|other| {
+            other.addr == peer.addr || (peer.peer_id.is_some() && other.peer_id == peer.peer_id)
+        });
+        if !seen {
+            unique.push(peer);
+        }
+    }
+    unique
+}
+
+/// A peer returned by a tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackerPeer {
+    pub addr: SocketAddr,
+    /// Only known when the tracker returned the non-compact (dictionary) form.
+    pub peer_id: Option<[u8; 20]>,
 }
 
-pub struct Peers(pub Vec<SocketAddrV4>);
-pub struct PeersVisitor;
+/// The `peers` key, either the compact string of 6 bytes per peer (BEP 23)
+/// or the original list of dictionaries with `peer id`, `ip` and `port`.
+#[derive(Default)]
+pub struct Peers(pub Vec<TrackerPeer>);
+pub(crate) struct PeersVisitor;
+
+/// The `peers6` key, 16 bytes of IPv6 address and 2 bytes of port per peer.
+#[derive(Default)]
+pub struct Peers6(pub Vec<TrackerPeer>);
+
+impl<'de> Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        if bytes.len() % 18 != 0 {
+            return Err(de::Error::custom(format!(
+                "invalid length: {}",
+                bytes.len()
+            )));
+        }
+
+        let peers = bytes
+            .chunks_exact(18)
+            .map(|chunk| {
+                let ip = <[u8; 16]>::try_from(&chunk[..16]).expect("chunk is 18 bytes");
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                TrackerPeer {
+                    addr: SocketAddr::new(IpAddr::from(ip), port),
+                    peer_id: None,
+                }
+            })
+            .collect();
+
+        Ok(Peers6(peers))
+    }
+}
+
+/// A peer in the non-compact form.
+#[derive(Deserialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    peer_id: Option<serde_bytes::ByteBuf>,
+    /// IPv4, IPv6 or a dns name.
+    ip: String,
+    port: u16,
+}
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("6 bytes per peer: 4 bytes for IPv4 address and 2 bytes for port")
+        formatter.write_str(
+            "6 bytes per peer: 4 bytes for IPv4 address and 2 bytes for port, or a list of peer dictionaries",
+        )
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if v.len() % 6 != 0 {
+        if !v.len().is_multiple_of(6) {
             return Err(E::custom(format!("invalid length: {}", v.len())));
         }
 
         // Preallocate Vec with the expected capacity
         let mut peers = Vec::with_capacity(v.len() / 6);
         for chunk in v.chunks_exact(6) {
-            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let ip = IpAddr::from([chunk[0], chunk[1], chunk[2], chunk[3]]);
             let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-            peers.push(SocketAddrV4::new(ip, port));
+            peers.push(TrackerPeer {
+                addr: SocketAddr::new(ip, port),
+                peer_id: None,
+            });
+        }
+
+        Ok(Peers(peers))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(peer) = seq.next_element::<PeerDict>()? {
+            // Peers given by dns name are skipped rather than resolving
+            // them while parsing the response.
+            let Ok(ip) = peer.ip.parse::<IpAddr>() else {
+                continue;
+            };
+
+            peers.push(TrackerPeer {
+                addr: SocketAddr::new(ip, peer.port),
+                peer_id: peer
+                    .peer_id
+                    .and_then(|id| <[u8; 20]>::try_from(id.as_slice()).ok()),
+            });
         }
 
         Ok(Peers(peers))
@@ -205,7 +622,7 @@ impl<'de> Deserialize<'de> for Peers {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_any(PeersVisitor)
     }
 }
 
@@ -216,10 +633,137 @@ impl Serialize for Peers {
     {
         // Preallocate Vec with the exact number of bytes needed
         let mut slice = Vec::with_capacity(6 * self.0.len());
+        // The compact form only has room for IPv4 peers
         for peer in &self.0 {
-            slice.extend_from_slice(&peer.ip().octets());
-            slice.extend_from_slice(&peer.port().to_be_bytes());
+            if let SocketAddr::V4(addr) = peer.addr {
+                slice.extend_from_slice(&addr.ip().octets());
+                slice.extend_from_slice(&addr.port().to_be_bytes());
+            }
         }
         serializer.serialize_bytes(&slice)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent() -> MetaInfo {
+        MetaInfo::from_bytes(
+            b"d8:announce28:http://tracker.test/announce4:infod6:lengthi5e4:name1:a\
+              12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap()
+    }
+
+    fn query(request: &TrackerRequest) -> Vec<(String, String)> {
+        let url = request
+            .url("http://tracker.test/announce?passkey=abc")
+            .unwrap();
+        url.query_pairs().into_owned().collect()
+    }
+
+    #[test]
+    fn requests_carry_the_identity_peer_id() {
+        let torrent = torrent();
+        let identity = Identity::new();
+        let request = TrackerRequest::new_compact(&torrent, &identity);
+        let query = query(&request);
+
+        let peer_id = query.iter().find(|(key, _)| key == "peer_id").unwrap();
+        assert_eq!(peer_id.1, identity.peer_id_str());
+        assert!(peer_id.1.starts_with("-FL"));
+        // Kept in front of what we add
+        assert_eq!(query[0], ("passkey".to_owned(), "abc".to_owned()));
+
+        let other = TrackerRequest::new_compact(&torrent, &Identity::new());
+        assert_ne!(other.peer_id, request.peer_id);
+    }
+
+    #[test]
+    fn anonymous_requests_leave_out_other_addresses() {
+        let torrent = torrent();
+        let identity = Identity::anonymous();
+        let request = TrackerRequest::new_compact(&torrent, &identity)
+            .with_ipv6(Some("2001:db8::1".parse().unwrap()));
+
+        assert!(!request.peer_id.starts_with("-FL"));
+        assert!(!query(&request).iter().any(|(key, _)| key == "ipv6"));
+
+        let request = TrackerRequest::new_compact(&torrent, &Identity::new())
+            .with_ipv6(Some("2001:db8::1".parse().unwrap()));
+        assert!(query(&request).contains(&("ipv6".to_owned(), "2001:db8::1".to_owned())));
+    }
+
+    fn success(body: &[u8]) -> TrackerPeerResponse {
+        match serde_bencode::from_bytes(body) {
+            Ok(TrackerResponse::Success(response)) => response,
+            Ok(TrackerResponse::Failure(failure)) => panic!("{}", failure.failure_reason),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    #[test]
+    fn compact_peers_are_six_bytes_each() {
+        let response =
+            success(b"d8:intervali900e5:peers12:\x7f\0\0\x01\x1a\xe1\x0a\0\0\x02\0\x50e");
+        assert_eq!(response.interval(), 900);
+        assert_eq!(response.min_interval(), None);
+        let addrs: Vec<_> = response.peers.0.iter().map(|peer| peer.addr).collect();
+        assert_eq!(
+            addrs,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+        assert!(response.peers.0.iter().all(|peer| peer.peer_id.is_none()));
+
+        let truncated = b"d8:intervali900e5:peers5:\x7f\0\0\x01\x1ae";
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(truncated).is_err());
+    }
+
+    #[test]
+    fn peer_dictionaries_keep_their_peer_id() {
+        let response = success(
+            b"d8:intervali900e12:min intervali60e5:peersl\
+              d2:ip9:127.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+              d2:ip3:::14:porti80ee\
+              d2:ip12:tracker.test4:porti80ee\
+              ee",
+        );
+        assert_eq!(response.min_interval(), Some(60));
+        // The dns name is skipped, the address without a peer id is kept
+        assert_eq!(response.peers.0.len(), 2);
+        assert_eq!(response.peers.0[0].addr, "127.0.0.1:6881".parse().unwrap());
+        assert_eq!(response.peers.0[0].peer_id, Some(*b"-XX0001-0123456789ab"));
+        assert_eq!(response.peers.0[1].peer_id, None);
+    }
+
+    #[test]
+    fn failures_carry_their_reason() {
+        let body = b"d14:failure reason12:unregisterede";
+        match serde_bencode::from_bytes(body) {
+            Ok(TrackerResponse::Failure(failure)) => {
+                assert_eq!(failure.failure_reason, "unregistered")
+            }
+            _ => panic!("not a failure"),
+        }
+    }
+
+    #[test]
+    fn ipv6_peers_are_eighteen_bytes_each() {
+        let mut body = b"d8:intervali900e6:peers618:".to_vec();
+        body.extend(Ipv6Addr::LOCALHOST.octets());
+        body.extend(6881u16.to_be_bytes());
+        body.push(b'e');
+        let response = success(&body);
+        assert!(response.peers.0.is_empty());
+        assert_eq!(response.peers6.0[0].addr, "[::1]:6881".parse().unwrap());
+
+        let mut truncated = b"d8:intervali900e6:peers617:".to_vec();
+        truncated.extend([0; 17]);
+        truncated.push(b'e');
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(&truncated).is_err());
+    }
+}
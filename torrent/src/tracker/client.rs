@@ -0,0 +1,261 @@
+use std::{sync::Arc, time::Duration};
+
+use super::{
+    dedup_peers, encoding, prefers_non_compact, redirect_target, rejects_compact,
+    remember_non_compact, tiers::TrackerTiers, udp, TrackerPeer, TrackerPeerResponse,
+    TrackerRequest, TrackerResponse, MAX_REDIRECTS,
+};
+use crate::{
+    dns::DnsCache,
+    proxy::{http_client_builder, Traffic},
+};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Wait before the first retry, doubled for every retry after it.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TrackerError {
+    InvalidUrl,
+    /// Neither an HTTP(S) nor a UDP tracker.
+    UnsupportedProtocol,
+    Timeout,
+    /// The tracker could not be reached.
+    Connect,
+    /// The tracker answered with a non-success HTTP status.
+    Status(u16),
+    /// The response is not a valid tracker response.
+    InvalidResponse,
+    /// The tracker redirected more than `MAX_REDIRECTS` times, or somewhere
+    /// other than an HTTP(S) url.
+    Redirect,
+    /// The tracker answered with a `failure reason`.
+    Failure(String),
+    /// A UDP tracker while trackers go through the proxy, which only
+    /// carries TCP.
+    Proxied,
+}
+
+impl TrackerError {
+    /// Whether trying the same tracker again may succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            TrackerError::Timeout | TrackerError::Connect => true,
+            TrackerError::Status(status) => *status >= 500 || *status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            TrackerError::Timeout
+        } else if let Some(status) = err.status() {
+            TrackerError::Status(status.as_u16())
+        } else if err.is_connect() || err.is_request() {
+            TrackerError::Connect
+        } else {
+            TrackerError::InvalidResponse
+        }
+    }
+}
+
+/// An async tracker client, so the daemon can announce to many trackers
+/// concurrently without tying up a thread per request.
+///
+/// Cheap to clone, every clone shares the same connection pool.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: reqwest::Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT)
+    }
+}
+
+impl TrackerClient {
+    pub fn new(connect_timeout: Duration, read_timeout: Duration) -> Self {
+        let http = http_client_builder(Traffic::Trackers)
+            .connect_timeout(connect_timeout)
+            .read_timeout(read_timeout)
+            .dns_resolver(Arc::new(DnsCache::default()))
+            // Followed by hand, to keep the announce query and a hop limit
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build http client");
+
+        Self {
+            http,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+        }
+    }
+
+    /// Retry transient failures (timeouts, unreachable tracker, server
+    /// errors) up to `retries` times, waiting `backoff` doubled every time.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Announce to `tracker_url`, retrying transient failures.
+    pub async fn announce(
+        &self,
+        request: &TrackerRequest,
+        tracker_url: &str,
+    ) -> Result<TrackerPeerResponse, TrackerError> {
+        let mut attempt = 0;
+        loop {
+            match self.announce_once(request, tracker_url).await {
+                Err(err) if err.is_transient() && attempt < self.retries => {
+                    tokio::time::sleep(self.backoff.saturating_mul(1 << attempt.min(16))).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn announce_once(
+        &self,
+        request: &TrackerRequest,
+        tracker_url: &str,
+    ) -> Result<TrackerPeerResponse, TrackerError> {
+        if tracker_url.starts_with("udp://") {
+            return udp::announce(request, tracker_url, udp::DEFAULT_TIMEOUT).await;
+        }
+        if !tracker_url.starts_with("http://") && !tracker_url.starts_with("https://") {
+            return Err(TrackerError::UnsupportedProtocol);
+        }
+
+        if prefers_non_compact(tracker_url) {
+            let request = request.clone().with_compact(false);
+            return self.announce_http(&request, tracker_url).await;
+        }
+        match self.announce_http(request, tracker_url).await {
+            Err(TrackerError::Failure(reason)) if rejects_compact(&reason) => {
+                let request = request.clone().with_compact(false);
+                let response = self.announce_http(&request, tracker_url).await;
+                // Only remember it if the tracker actually takes compact=0
+                if response.is_ok() {
+                    remember_non_compact(tracker_url);
+                }
+                response
+            }
+            result => result,
+        }
+    }
+
+    /// Announce to an HTTP(S) tracker, following up to `MAX_REDIRECTS` redirects.
+    async fn announce_http(
+        &self,
+        request: &TrackerRequest,
+        tracker_url: &str,
+    ) -> Result<TrackerPeerResponse, TrackerError> {
+        let mut url = request.url(tracker_url).ok_or(TrackerError::InvalidUrl)?;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self
+                .http
+                .get(url.clone())
+                .header(reqwest::header::ACCEPT_ENCODING, encoding::ACCEPT_ENCODING)
+                .send()
+                .await?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(TrackerError::Redirect)?;
+                url = redirect_target(request, &url, location).ok_or(TrackerError::Redirect)?;
+                continue;
+            }
+
+            let response = response.error_for_status()?;
+            let headers = response.headers().clone();
+            let body = encoding::read_body_async(response)
+                .await
+                .map_err(|err| match err {
+                    encoding::DecodeError::Read(_) => TrackerError::Connect,
+                    _ => TrackerError::InvalidResponse,
+                })?;
+            let body = encoding::decode_body(&headers, &body)
+                .map_err(|_| TrackerError::InvalidResponse)?;
+            return match serde_bencode::from_bytes(&body) {
+                Ok(TrackerResponse::Success(response)) => Ok(response),
+                Ok(TrackerResponse::Failure(failure)) => {
+                    Err(TrackerError::Failure(failure.failure_reason))
+                }
+                Err(_) => Err(TrackerError::InvalidResponse),
+            };
+        }
+        Err(TrackerError::Redirect)
+    }
+
+    /// Announce to the first tracker in `tiers` that answers (BEP 12),
+    /// returning its url along with the response, or the last error.
+    pub async fn announce_tiers(
+        &self,
+        request: &TrackerRequest,
+        tiers: &mut TrackerTiers,
+    ) -> Result<(String, TrackerPeerResponse), TrackerError> {
+        let mut last_error = TrackerError::InvalidUrl;
+        let urls: Vec<String> = tiers.iter().cloned().collect();
+
+        for url in urls {
+            match self.announce(request, &url).await {
+                Ok(response) => {
+                    tiers.succeeded(&url);
+                    return Ok((url, response));
+                }
+                Err(err) => last_error = err,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Announce to the first HTTP tracker and the first UDP tracker in
+    /// `tiers` that answer, returning the peers of both without duplicates.
+    pub async fn announce_merged(
+        &self,
+        request: &TrackerRequest,
+        tiers: &mut TrackerTiers,
+    ) -> Result<Vec<TrackerPeer>, TrackerError> {
+        let mut last_error = TrackerError::InvalidUrl;
+        let mut peers = Vec::new();
+        let mut answered = false;
+        let urls: Vec<String> = tiers.iter().cloned().collect();
+
+        for is_udp in [false, true] {
+            for url in urls
+                .iter()
+                .filter(|url| url.starts_with("udp://") == is_udp)
+            {
+                match self.announce(request, url).await {
+                    Ok(response) => {
+                        tiers.succeeded(url);
+                        peers.extend(response.peers().copied());
+                        answered = true;
+                        break;
+                    }
+                    Err(err) => last_error = err,
+                }
+            }
+        }
+
+        match answered {
+            true => Ok(dedup_peers(peers)),
+            false => Err(last_error),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use std::{fmt, io::Read};
+
+// Some trackers compress their responses, which adds up for the peer lists
+// of large swarms. We ask for gzip and deflate and undo either before the
+// body is bdecoded, capped so a small compressed body can't unpack into
+// gigabytes. A gzip body without a `Content-Encoding`, which some trackers
+// behind misconfigured proxies send, is recognised by its magic bytes, a
+// bencoded response never starts with them.
+
+/// Sent as `Accept-Encoding` with every announce and scrape.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate";
+/// The largest response body taken, before and after decompression.
+pub const MAX_RESPONSE_SIZE: u64 = 8 * 1024 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DecodeError {
+    /// A `Content-Encoding` other than gzip, deflate or identity.
+    Unsupported(String),
+    /// Larger than `MAX_RESPONSE_SIZE`, compressed or not.
+    TooLarge,
+    /// Not valid gzip or deflate data.
+    Corrupt(std::io::Error),
+    /// The connection failed while the body was read.
+    Read(std::io::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Unsupported(encoding) => write!(f, "unsupported encoding {encoding}"),
+            DecodeError::TooLarge => write!(f, "response larger than {MAX_RESPONSE_SIZE} bytes"),
+            DecodeError::Corrupt(err) => write!(f, "unable to decompress response: {err}"),
+            DecodeError::Read(err) => write!(f, "unable to read response: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Read a response body of at most `MAX_RESPONSE_SIZE`, failing as soon as
+/// there is more instead of buffering whatever the tracker sends.
+pub fn read_body(body: impl Read) -> Result<Vec<u8>, DecodeError> {
+    let mut data = Vec::new();
+    body.take(MAX_RESPONSE_SIZE + 1)
+        .read_to_end(&mut data)
+        .map_err(DecodeError::Read)?;
+    if data.len() as u64 > MAX_RESPONSE_SIZE {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(data)
+}
+
+/// Like `read_body`, for the async client's responses.
+pub async fn read_body_async(mut response: reqwest::Response) -> Result<Vec<u8>, DecodeError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| DecodeError::Read(std::io::Error::other(err)))?
+    {
+        if (data.len() + chunk.len()) as u64 > MAX_RESPONSE_SIZE {
+            return Err(DecodeError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// The body of a response with `headers`, decompressed as its
+/// `Content-Encoding` says.
+pub fn decode_body(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    if body.len() as u64 > MAX_RESPONSE_SIZE {
+        return Err(DecodeError::TooLarge);
+    }
+
+    let encodings: Vec<String> = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+    if encodings.is_empty() {
+        return match body.starts_with(&GZIP_MAGIC) {
+            true => decompress(GzDecoder::new(body)),
+            false => Ok(body.to_vec()),
+        };
+    }
+
+    // Listed in the order they were applied
+    let mut body = body.to_vec();
+    for encoding in encodings.iter().rev() {
+        body = match encoding.as_str() {
+            "gzip" | "x-gzip" => decompress(GzDecoder::new(&body[..]))?,
+            // Meant to be zlib wrapped, some servers send it raw
+            "deflate" if is_zlib(&body) => decompress(ZlibDecoder::new(&body[..]))?,
+            "deflate" => decompress(DeflateDecoder::new(&body[..]))?,
+            _ => return Err(DecodeError::Unsupported(encoding.clone())),
+        };
+    }
+    Ok(body)
+}
+
+/// Whether `data` starts with a zlib header, see RFC 1950.
+fn is_zlib(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+fn decompress(decoder: impl Read) -> Result<Vec<u8>, DecodeError> {
+    let mut body = Vec::new();
+    decoder
+        .take(MAX_RESPONSE_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(DecodeError::Corrupt)?;
+    if body.len() as u64 > MAX_RESPONSE_SIZE {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use reqwest::header::HeaderValue;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn read_body_stops_past_the_limit() {
+        // Never ends, only the limit stops reading it
+        let endless = std::io::repeat(b'd');
+        assert!(matches!(read_body(endless), Err(DecodeError::TooLarge)));
+
+        let body = vec![b'd'; MAX_RESPONSE_SIZE as usize];
+        assert_eq!(read_body(&body[..]).unwrap().len(), body.len());
+    }
+
+    #[test]
+    fn gzip_is_undone_with_or_without_content_encoding() {
+        let body = b"d8:intervali1800ee";
+        let compressed = gzip(body);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(decode_body(&headers, &compressed).unwrap(), body);
+        assert_eq!(decode_body(&HeaderMap::new(), &compressed).unwrap(), body);
+        assert_eq!(decode_body(&HeaderMap::new(), body).unwrap(), body);
+    }
+
+    #[test]
+    fn decompressed_bodies_are_capped() {
+        let bomb = gzip(&vec![0; MAX_RESPONSE_SIZE as usize + 1]);
+        assert!(matches!(
+            decode_body(&HeaderMap::new(), &bomb),
+            Err(DecodeError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn unknown_encodings_are_refused() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(matches!(
+            decode_body(&headers, b"d8:intervali1800ee"),
+            Err(DecodeError::Unsupported(encoding)) if encoding == "br"
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use reqwest::Url;
+
+/// Rules deciding which trackers may be announced to, e.g. to only use an
+/// internal tracker on a corporate network or to ignore dead public trackers
+/// commonly embedded in magnet links.
+///
+/// Rules are host names, `*.example.com` matches every subdomain of
+/// `example.com`. When `allow` is not empty only matching hosts are used,
+/// `deny` always wins.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+/// Why a tracker was not allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocked<'a> {
+    /// The host matched this deny rule.
+    Denied(&'a str),
+    /// There are allow rules and none matched.
+    NotAllowed,
+    /// The tracker url has no host to match against.
+    NoHost,
+}
+
+impl TrackerFilter {
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        let lowercase = |rules: Vec<String>| rules.into_iter().map(|r| r.to_lowercase()).collect();
+        Self {
+            allow: lowercase(allow),
+            deny: lowercase(deny),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check `tracker` against the rules.
+    pub fn check(&self, tracker: &str) -> Result<(), Blocked<'_>> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let Some(host) = host(tracker) else {
+            return Err(Blocked::NoHost);
+        };
+
+        if let Some(rule) = self.deny.iter().find(|rule| matches(rule, &host)) {
+            return Err(Blocked::Denied(rule));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| matches(rule, &host)) {
+            return Err(Blocked::NotAllowed);
+        }
+
+        Ok(())
+    }
+
+    /// Whether `tracker` may be used, logging the rule if it may not.
+    pub fn allows(&self, tracker: &str) -> bool {
+        match self.check(tracker) {
+            Ok(()) => true,
+            Err(Blocked::Denied(rule)) => {
+                log::info!("tracker {tracker} blocked by deny rule {rule}");
+                false
+            }
+            Err(Blocked::NotAllowed) => {
+                log::info!("tracker {tracker} blocked, not in the allow list");
+                false
+            }
+            Err(Blocked::NoHost) => {
+                log::info!("tracker {tracker} blocked, unable to find its host");
+                false
+            }
+        }
+    }
+}
+
+/// The lowercased host of the `tracker` url, e.g. `tracker.example.com`.
+pub fn host(tracker: &str) -> Option<String> {
+    Url::parse(tracker)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+}
+
+fn matches(rule: &str, host: &str) -> bool {
+    match rule.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => rule == host,
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{Event, TrackerPeerResponse};
+
+/// Used until a tracker tells us its interval.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The shortest interval taken from a tracker, one asking for `0` would
+/// have us announce in a loop.
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait before retrying after the first failed announce, doubled
+/// on every consecutive failure up to the announce interval.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+struct AnnounceTimer {
+    next_announce: Instant,
+    last_announce: Option<Instant>,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    failures: u32,
+    /// Whether `started` has been sent successfully.
+    started: bool,
+    /// Whether `completed` was sent, or the torrent was already complete
+    /// when it was added, in which case it is never sent.
+    completed: bool,
+}
+
+impl AnnounceTimer {
+    /// When the tracker's `min interval` since the last announce is over,
+    /// `None` if there is nothing to wait for.
+    fn reannounce_allowed(&self, ignore_min_interval: bool) -> Option<Instant> {
+        match (self.last_announce, self.min_interval) {
+            (Some(last), Some(min_interval)) if !ignore_min_interval => Some(last + min_interval),
+            _ => None,
+        }
+    }
+}
+
+/// Decides when each torrent re-announces to its trackers: at the interval
+/// the tracker returned, never sooner than its `min interval`, even when
+/// the user forces a re-announce.
+#[derive(Default)]
+pub struct AnnounceScheduler {
+    torrents: HashMap<[u8; 20], AnnounceTimer>,
+    ignore_min_interval: bool,
+}
+
+impl AnnounceScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let forced re-announces through regardless of the tracker's
+    /// `min interval`, for trackers run locally or for testing.
+    pub fn with_ignore_min_interval(mut self, ignore: bool) -> Self {
+        self.ignore_min_interval = ignore;
+        self
+    }
+
+    /// Start announcing `info_hash`, the first announce is due immediately.
+    /// `complete` is whether all data was already there.
+    pub fn add(&mut self, info_hash: [u8; 20], complete: bool, now: Instant) {
+        self.torrents.entry(info_hash).or_insert(AnnounceTimer {
+            next_announce: now,
+            last_announce: None,
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            min_interval: None,
+            failures: 0,
+            started: false,
+            completed: complete,
+        });
+    }
+
+    /// Stop announcing `info_hash`. Returns `Event::Stopped` if the trackers
+    /// know about the torrent and should be told it is gone.
+    pub fn remove(&mut self, info_hash: &[u8; 20]) -> Option<Event> {
+        let timer = self.torrents.remove(info_hash)?;
+        timer.started.then_some(Event::Stopped)
+    }
+
+    /// The event to send with the next announce of `info_hash`. `complete`
+    /// is whether the download has finished.
+    pub fn event(&self, info_hash: &[u8; 20], complete: bool) -> Option<Event> {
+        let timer = self.torrents.get(info_hash)?;
+        if !timer.started {
+            Some(Event::Started)
+        } else if complete && !timer.completed {
+            Some(Event::Completed)
+        } else {
+            None
+        }
+    }
+
+    /// The download finished, announce `completed` right away.
+    pub fn completed(&mut self, info_hash: &[u8; 20], now: Instant) {
+        if let Some(timer) = self.torrents.get_mut(info_hash) {
+            if !timer.completed {
+                timer.next_announce = now;
+            }
+        }
+    }
+
+    /// Every torrent whose announce is due at `now`.
+    pub fn due(&self, now: Instant) -> Vec<[u8; 20]> {
+        self.torrents
+            .iter()
+            .filter(|(_, timer)| timer.next_announce <= now)
+            .map(|(info_hash, _)| *info_hash)
+            .collect()
+    }
+
+    /// When the next announce of any torrent is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.torrents
+            .values()
+            .map(|timer| timer.next_announce)
+            .min()
+    }
+
+    /// Record a successful announce and schedule the next one at the
+    /// interval the tracker asked for.
+    ///
+    /// `event` is what was sent with the announce.
+    pub fn announced(
+        &mut self,
+        info_hash: &[u8; 20],
+        event: Option<Event>,
+        response: &TrackerPeerResponse,
+        now: Instant,
+    ) {
+        let Some(timer) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+
+        match event {
+            Some(Event::Started) => timer.started = true,
+            Some(Event::Completed) => timer.completed = true,
+            _ => {}
+        }
+
+        timer.interval = Duration::from_secs(response.interval() as u64).max(MIN_ANNOUNCE_INTERVAL);
+        timer.min_interval = response
+            .min_interval()
+            .map(|secs| Duration::from_secs(secs as u64));
+        timer.last_announce = Some(now);
+        timer.failures = 0;
+        timer.next_announce = now + timer.interval.max(timer.min_interval.unwrap_or_default());
+    }
+
+    /// The announce failed, retry with an exponential backoff.
+    pub fn failed(&mut self, info_hash: &[u8; 20], now: Instant) {
+        let Some(timer) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+
+        let backoff = RETRY_INTERVAL.saturating_mul(1 << timer.failures.min(16));
+        timer.failures += 1;
+        timer.next_announce = now + backoff.min(timer.interval);
+    }
+
+    /// The user asked for a re-announce. It is scheduled right away unless
+    /// the tracker's `min interval` has not passed since the last announce,
+    /// in which case it is moved up to when it is allowed and that time is
+    /// returned as the error.
+    pub fn force(&mut self, info_hash: &[u8; 20], now: Instant) -> Result<(), Instant> {
+        let ignore_min_interval = self.ignore_min_interval;
+        let Some(timer) = self.torrents.get_mut(info_hash) else {
+            return Ok(());
+        };
+
+        match timer.reannounce_allowed(ignore_min_interval) {
+            Some(allowed) if allowed > now => {
+                timer.next_announce = timer.next_announce.min(allowed);
+                Err(allowed)
+            }
+            _ => {
+                timer.next_announce = now;
+                Ok(())
+            }
+        }
+    }
+
+    /// How long until the user may force a re-announce of `info_hash`,
+    /// `None` if they may right away.
+    pub fn cooldown(&self, info_hash: &[u8; 20], now: Instant) -> Option<Duration> {
+        self.torrents
+            .get(info_hash)?
+            .reannounce_allowed(self.ignore_min_interval)
+            .map(|allowed| allowed.saturating_duration_since(now))
+            .filter(|cooldown| !cooldown.is_zero())
+    }
+}
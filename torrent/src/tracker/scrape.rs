@@ -0,0 +1,510 @@
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{encoding, url_encode_bytes, Tracker};
+use crate::dns::http_client;
+
+// https://www.bittorrent.org/beps/bep_0048.html
+
+/// Most trackers cap how many info hashes a single scrape may carry,
+/// usually because of request line limits. This keeps us comfortably below them.
+pub const MAX_HASHES_PER_SCRAPE: usize = 64;
+
+/// How long to wait between scrapes of the same tracker if it does not tell us otherwise.
+pub const DEFAULT_SCRAPE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Replies in a row with fewer files than asked for before a tracker's
+/// batch size shrinks to what it answered. A single short reply may just
+/// be a tracker that doesn't know some of the torrents.
+pub const SHORT_REPLIES_TO_SHRINK: u32 = 3;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ScrapeError {
+    /// The announce URL does not follow the `.../announce` convention so
+    /// the tracker does not support scraping.
+    NoScrapeUrl,
+    InvalidUrl,
+    RequestFailed,
+    InvalidResponse,
+    Failure(String),
+    /// UDP trackers (BEP 15) scrape with their own action on the UDP
+    /// protocol, which is not implemented yet.
+    UnsupportedProtocol,
+}
+
+/// Derive the scrape URL from an announce URL.
+///
+/// UDP trackers have no scrape URL, they are scraped through the same
+/// endpoint as announces, so their announce URL is returned as is.
+///
+/// Take the announce URL, find the last `/` in it. If the text immediately
+/// following that `/` isn't `announce` it will be taken as a sign that the
+/// tracker doesn't support the scrape convention. If it does, substitute
+/// `scrape` for `announce` to find the scrape page.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    if announce.starts_with("udp://") {
+        return Some(announce.to_owned());
+    }
+
+    let slash = announce.rfind('/')?;
+    let (base, last) = announce.split_at(slash + 1);
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{base}scrape{rest}"))
+}
+
+/// Swarm statistics for a single torrent as reported by a tracker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScrapeStats {
+    /// The number of active peers that have completed downloading (seeders).
+    pub complete: usize,
+    /// The number of peers that have ever completed downloading.
+    pub downloaded: usize,
+    /// The number of active peers that have not completed downloading (leechers).
+    pub incomplete: usize,
+}
+
+impl ScrapeStats {
+    /// Combine the counts of another tracker for the same torrent.
+    pub fn merge(&mut self, other: &ScrapeStats) {
+        self.complete = self.complete.max(other.complete);
+        self.downloaded = self.downloaded.max(other.downloaded);
+        self.incomplete = self.incomplete.max(other.incomplete);
+    }
+
+    /// Every peer in the swarm, seeders and leechers.
+    pub fn peers(&self) -> usize {
+        self.complete + self.incomplete
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScrapeResponse {
+    /// Statistics keyed by info hash.
+    pub files: HashMap<[u8; 20], ScrapeStats>,
+    /// The tracker asked us not to scrape it again before this many seconds.
+    pub min_request_interval: Option<u64>,
+}
+
+impl ScrapeResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ScrapeError> {
+        let Ok(Value::Dict(root)) = serde_bencode::from_bytes::<Value>(bytes) else {
+            return Err(ScrapeError::InvalidResponse);
+        };
+
+        if let Some(Value::Bytes(reason)) = root.get(b"failure reason".as_slice()) {
+            return Err(ScrapeError::Failure(
+                String::from_utf8_lossy(reason).into_owned(),
+            ));
+        }
+
+        let mut response = ScrapeResponse::default();
+
+        if let Some(Value::Dict(files)) = root.get(b"files".as_slice()) {
+            for (hash, stats) in files {
+                let (Ok(hash), Value::Dict(stats)) = (<[u8; 20]>::try_from(hash.as_slice()), stats)
+                else {
+                    continue;
+                };
+
+                let int = |key: &[u8]| match stats.get(key) {
+                    Some(Value::Int(n)) => (*n).max(0) as usize,
+                    _ => 0,
+                };
+
+                response.files.insert(
+                    hash,
+                    ScrapeStats {
+                        complete: int(b"complete"),
+                        downloaded: int(b"downloaded"),
+                        incomplete: int(b"incomplete"),
+                    },
+                );
+            }
+        }
+
+        if let Some(Value::Dict(flags)) = root.get(b"flags".as_slice()) {
+            if let Some(Value::Int(n)) = flags.get(b"min_request_interval".as_slice()) {
+                response.min_request_interval = Some((*n).max(0) as u64);
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+/// A set of info hashes to be scraped from one tracker in a single request.
+#[derive(Debug, Clone)]
+pub struct ScrapeBatch {
+    pub scrape_url: String,
+    pub info_hashes: Vec<[u8; 20]>,
+}
+
+impl Tracker {
+    /// Scrape the tracker behind `announce` for the given info hashes.
+    pub fn scrape(announce: &str, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, ScrapeError> {
+        let scrape_url = scrape_url(announce).ok_or(ScrapeError::NoScrapeUrl)?;
+        Self::scrape_batch(&ScrapeBatch {
+            scrape_url,
+            info_hashes: info_hashes.to_vec(),
+        })
+    }
+
+    /// Scrape `info_hashes` from every tracker in `announces` and keep the
+    /// highest counts reported for each torrent, since every tracker only
+    /// knows about the peers that announced to it.
+    ///
+    /// Trackers that fail are skipped, an error is only returned if none answered.
+    pub fn scrape_all(
+        announces: &[String],
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, ScrapeError> {
+        let mut stats: HashMap<[u8; 20], ScrapeStats> = HashMap::new();
+        let mut last_error = ScrapeError::NoScrapeUrl;
+        let mut answered = false;
+
+        for announce in announces {
+            match Self::scrape(announce, info_hashes) {
+                Ok(response) => {
+                    answered = true;
+                    for (hash, file) in response.files {
+                        stats.entry(hash).or_default().merge(&file);
+                    }
+                }
+                Err(err) => last_error = err,
+            }
+        }
+
+        if answered {
+            Ok(stats)
+        } else {
+            Err(last_error)
+        }
+    }
+
+    pub fn scrape_batch(batch: &ScrapeBatch) -> Result<ScrapeResponse, ScrapeError> {
+        if batch.scrape_url.starts_with("udp://") {
+            // TODO: use the scrape action once the UDP tracker protocol is implemented
+            return Err(ScrapeError::UnsupportedProtocol);
+        }
+
+        let Ok(mut url) = reqwest::Url::parse(&batch.scrape_url) else {
+            return Err(ScrapeError::InvalidUrl);
+        };
+
+        let hashes = batch
+            .info_hashes
+            .iter()
+            .map(|hash| format!("info_hash={}", url_encode_bytes(hash)))
+            .collect::<Vec<_>>()
+            .join("&");
+        // Some trackers already carry a query, e.g. a passkey
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{hashes}"),
+            _ => hashes,
+        };
+        url.set_query(Some(&query));
+
+        let Ok(response) = http_client()
+            .get(url)
+            .header(reqwest::header::ACCEPT_ENCODING, encoding::ACCEPT_ENCODING)
+            .send()
+        else {
+            return Err(ScrapeError::RequestFailed);
+        };
+
+        let headers = response.headers().clone();
+        let body = match encoding::read_body(response) {
+            Ok(body) => body,
+            Err(encoding::DecodeError::Read(_)) => return Err(ScrapeError::RequestFailed),
+            Err(_) => return Err(ScrapeError::InvalidResponse),
+        };
+        let Ok(body) = encoding::decode_body(&headers, &body) else {
+            return Err(ScrapeError::InvalidResponse);
+        };
+
+        ScrapeResponse::from_bytes(&body)
+    }
+}
+
+struct TrackerScrapeState {
+    pending: Vec<[u8; 20]>,
+    next_scrape: Instant,
+    /// How many hashes this tracker will answer per request, learned from
+    /// responses that keep coming back with fewer files than we asked for.
+    batch_size: usize,
+    /// Replies in a row that left hashes out.
+    short_replies: u32,
+    /// Hashes left out of the last reply, dropped if the next leaves them
+    /// out again as the tracker doesn't know them.
+    missed: Vec<[u8; 20]>,
+}
+
+/// Collects the torrents that need scraping and groups them per tracker,
+/// so a large seeding session sends a handful of multi-hash scrapes instead
+/// of one request per torrent.
+pub struct ScrapeScheduler {
+    interval: Duration,
+    batch_size: usize,
+    trackers: HashMap<String, TrackerScrapeState>,
+}
+
+impl Default for ScrapeScheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_SCRAPE_INTERVAL)
+    }
+}
+
+impl ScrapeScheduler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            batch_size: MAX_HASHES_PER_SCRAPE,
+            trackers: HashMap::new(),
+        }
+    }
+
+    /// Set the maximum number of info hashes sent in one scrape request.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Queue `info_hash` to be scraped from the tracker behind `announce`.
+    /// Returns `false` if the tracker does not support scraping.
+    pub fn queue(&mut self, announce: &str, info_hash: [u8; 20]) -> bool {
+        let Some(scrape_url) = scrape_url(announce) else {
+            return false;
+        };
+
+        let batch_size = self.batch_size;
+        let state = self
+            .trackers
+            .entry(scrape_url)
+            .or_insert_with(|| TrackerScrapeState {
+                pending: Vec::new(),
+                next_scrape: Instant::now(),
+                batch_size,
+                short_replies: 0,
+                missed: Vec::new(),
+            });
+
+        if !state.pending.contains(&info_hash) {
+            state.pending.push(info_hash);
+        }
+        true
+    }
+
+    /// When the next batch is due, `None` once nothing is queued.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.trackers
+            .values()
+            .filter(|state| !state.pending.is_empty())
+            .map(|state| state.next_scrape)
+            .min()
+    }
+
+    /// Take the next batch for every tracker that is due at `now`.
+    ///
+    /// At most one batch per tracker is handed out per call, the remaining
+    /// hashes stay queued until that tracker's batch has been completed.
+    pub fn due(&mut self, now: Instant) -> Vec<ScrapeBatch> {
+        let mut batches = Vec::new();
+        for (scrape_url, state) in &mut self.trackers {
+            if state.pending.is_empty() || state.next_scrape > now {
+                continue;
+            }
+
+            let take = state.batch_size.min(state.pending.len());
+            batches.push(ScrapeBatch {
+                scrape_url: scrape_url.clone(),
+                info_hashes: state.pending.drain(..take).collect(),
+            });
+
+            // Hold the tracker until `completed` or `failed` reschedules it.
+            state.next_scrape = now + self.interval;
+        }
+        batches
+    }
+
+    /// Record the response for `batch`. Hashes the tracker left out are
+    /// queued again once (pagination), and the tracker's batch size shrinks
+    /// to what it answered after `SHORT_REPLIES_TO_SHRINK` short replies in
+    /// a row. A full reply lets it grow back.
+    pub fn completed(&mut self, batch: &ScrapeBatch, response: &ScrapeResponse, now: Instant) {
+        let Some(state) = self.trackers.get_mut(&batch.scrape_url) else {
+            return;
+        };
+
+        let missing: Vec<[u8; 20]> = batch
+            .info_hashes
+            .iter()
+            .filter(|hash| !response.files.contains_key(*hash))
+            .copied()
+            .collect();
+
+        if missing.is_empty() {
+            state.short_replies = 0;
+            state.batch_size = (state.batch_size * 2).min(self.batch_size);
+        } else if !response.files.is_empty() {
+            state.short_replies += 1;
+            if state.short_replies >= SHORT_REPLIES_TO_SHRINK {
+                state.batch_size = response.files.len();
+                state.short_replies = 0;
+            }
+            for hash in &missing {
+                if !state.missed.contains(hash) && !state.pending.contains(hash) {
+                    state.pending.insert(0, *hash);
+                }
+            }
+        }
+        state.missed = missing;
+
+        let min_interval = response
+            .min_request_interval
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+
+        // Continue paging straight away unless the tracker asked us to slow down.
+        state.next_scrape = if state.pending.is_empty() {
+            now + self.interval.max(min_interval)
+        } else {
+            now + min_interval
+        };
+    }
+
+    /// The scrape of `batch` failed, requeue its hashes and back off for a full interval.
+    pub fn failed(&mut self, batch: &ScrapeBatch, now: Instant) {
+        if let Some(state) = self.trackers.get_mut(&batch.scrape_url) {
+            for hash in &batch.info_hashes {
+                if !state.pending.contains(hash) {
+                    state.pending.push(*hash);
+                }
+            }
+            state.next_scrape = now + self.interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANNOUNCE: &str = "http://tracker.test/announce?passkey=abc";
+
+    fn response(hashes: &[[u8; 20]]) -> ScrapeResponse {
+        ScrapeResponse {
+            files: hashes
+                .iter()
+                .map(|hash| (*hash, ScrapeStats::default()))
+                .collect(),
+            min_request_interval: None,
+        }
+    }
+
+    #[test]
+    fn scrape_urls_follow_the_announce_convention() {
+        assert_eq!(
+            scrape_url(ANNOUNCE).as_deref(),
+            Some("http://tracker.test/scrape?passkey=abc")
+        );
+        assert_eq!(
+            scrape_url("http://tracker.test/x/announce.php").as_deref(),
+            Some("http://tracker.test/x/scrape.php")
+        );
+        assert_eq!(scrape_url("http://tracker.test/a"), None);
+        assert_eq!(scrape_url("http://tracker.test/announce/x"), None);
+    }
+
+    #[test]
+    fn responses_are_keyed_by_info_hash() {
+        let body = b"d5:filesd20:aaaaaaaaaaaaaaaaaaaa\
+                     d8:completei5e10:downloadedi7e10:incompletei-3ee\
+                     3:bad\
+                     d8:completei1eee\
+                     5:flagsd20:min_request_intervali900eee";
+        let response = ScrapeResponse::from_bytes(body).unwrap();
+        assert_eq!(response.files.len(), 1);
+        assert_eq!(
+            response.files[b"aaaaaaaaaaaaaaaaaaaa"],
+            ScrapeStats {
+                complete: 5,
+                downloaded: 7,
+                incomplete: 0
+            }
+        );
+        assert_eq!(response.min_request_interval, Some(900));
+
+        assert!(matches!(
+            ScrapeResponse::from_bytes(b"d14:failure reason4:nopee"),
+            Err(ScrapeError::Failure(reason)) if reason == "nope"
+        ));
+        assert!(matches!(
+            ScrapeResponse::from_bytes(b"le"),
+            Err(ScrapeError::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn batches_page_through_what_the_tracker_leaves_out() {
+        let hashes: Vec<[u8; 20]> = (0..5).map(|n| [n; 20]).collect();
+        let mut scheduler = ScrapeScheduler::default().with_batch_size(4);
+        for hash in &hashes {
+            assert!(scheduler.queue(ANNOUNCE, *hash));
+        }
+        assert!(!scheduler.queue("http://tracker.test/a", hashes[0]));
+
+        let now = Instant::now();
+        let batches = scheduler.due(now);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].info_hashes, hashes[..4]);
+        // One batch per tracker at a time
+        assert!(scheduler.due(now).is_empty());
+
+        // It answered for three, the fourth goes first in the next batch
+        scheduler.completed(&batches[0], &response(&hashes[..3]), now);
+        let batches = scheduler.due(now);
+        assert_eq!(batches[0].info_hashes, [hashes[3], hashes[4]]);
+
+        // Left out twice in a row, the tracker doesn't know it
+        scheduler.completed(&batches[0], &response(&hashes[4..]), now);
+        assert_eq!(scheduler.next_due(), None);
+    }
+
+    #[test]
+    fn short_replies_shrink_the_batch_and_full_ones_grow_it_back() {
+        let mut scheduler = ScrapeScheduler::new(Duration::ZERO).with_batch_size(4);
+        for n in 0..64 {
+            scheduler.queue(ANNOUNCE, [n; 20]);
+        }
+        let now = Instant::now();
+        for _ in 0..SHORT_REPLIES_TO_SHRINK {
+            let batch = scheduler.due(now).remove(0);
+            assert_eq!(batch.info_hashes.len(), 4);
+            scheduler.completed(&batch, &response(&batch.info_hashes[..2]), now);
+        }
+        let batch = scheduler.due(now).remove(0);
+        assert_eq!(batch.info_hashes.len(), 2);
+
+        scheduler.completed(&batch, &response(&batch.info_hashes), now);
+        assert_eq!(scheduler.due(now).remove(0).info_hashes.len(), 4);
+    }
+
+    #[test]
+    fn failed_batches_are_queued_again_after_an_interval() {
+        let mut scheduler = ScrapeScheduler::new(Duration::from_secs(60));
+        scheduler.queue(ANNOUNCE, [1; 20]);
+        let now = Instant::now();
+        let batch = scheduler.due(now).remove(0);
+        scheduler.failed(&batch, now);
+
+        assert!(scheduler.due(now).is_empty());
+        let later = now + Duration::from_secs(60);
+        assert_eq!(scheduler.next_due(), Some(later));
+        assert_eq!(scheduler.due(later)[0].info_hashes, [[1; 20]]);
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+use crate::{rate_limit::Direction, units::ByteSize};
+
+// Private trackers count what every user uploaded and downloaded from what
+// their client announces, and ban those whose ratio drops too low. The same
+// count is kept per tracker host across torrents and restarts, so a quota
+// can warn before the tracker does.
+
+/// Everything announced to the trackers on one host, all-time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackerStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Announces the tracker answered without a failure reason.
+    pub announces: u64,
+    /// Announces that went unanswered or the tracker refused.
+    pub failures: u64,
+}
+
+impl TrackerStats {
+    /// `0.0..=1.0`, `None` before the first announce.
+    pub fn success_rate(&self) -> Option<f64> {
+        match self.announces + self.failures {
+            0 => None,
+            total => Some(self.announces as f64 / total as f64),
+        }
+    }
+
+    /// Uploaded over downloaded, `None` while nothing was downloaded.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.downloaded > 0).then(|| self.uploaded as f64 / self.downloaded as f64)
+    }
+
+    fn transferred(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Upload => self.uploaded,
+            Direction::Download => self.downloaded,
+        }
+    }
+}
+
+/// Soft limits on what is transferred with a tracker host, e.g. what a
+/// private tracker allows before the ratio has to be made up. `0` is
+/// unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrackerQuota {
+    pub upload: ByteSize,
+    pub download: ByteSize,
+}
+
+/// A quota that is nearly or entirely used up, e.g. `downloaded 92GiB of
+/// the 100GiB quota`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaWarning {
+    pub direction: Direction,
+    pub used: u64,
+    pub quota: u64,
+}
+
+impl fmt::Display for QuotaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self.direction {
+            Direction::Upload => "uploaded",
+            Direction::Download => "downloaded",
+        };
+        write!(
+            f,
+            "{verb} {} of the {} quota",
+            ByteSize(self.used),
+            ByteSize(self.quota)
+        )
+    }
+}
+
+impl TrackerQuota {
+    /// The quotas `before` was under `warn_at` (`0.0..=1.0`) or all of and
+    /// `after` is not, so each warns once as it is approached and once as
+    /// it is used up.
+    pub fn crossed(
+        &self,
+        before: &TrackerStats,
+        after: &TrackerStats,
+        warn_at: f64,
+    ) -> Vec<QuotaWarning> {
+        let mut warnings = Vec::new();
+        for (direction, quota) in [
+            (Direction::Upload, self.upload.bytes()),
+            (Direction::Download, self.download.bytes()),
+        ] {
+            if quota == 0 {
+                continue;
+            }
+            let (was, is) = (before.transferred(direction), after.transferred(direction));
+            let warning = (quota as f64 * warn_at.clamp(0.0, 1.0)) as u64;
+            let crossed = |threshold: u64| was < threshold && is >= threshold;
+            if crossed(warning) || crossed(quota) {
+                warnings.push(QuotaWarning {
+                    direction,
+                    used: is,
+                    quota,
+                });
+            }
+        }
+        warnings
+    }
+}
+
+/// What a torrent announced to each tracker host so far, so an announce
+/// credits the host with what was transferred since the last one.
+#[derive(Debug, Clone, Default)]
+pub struct AnnouncedTotals {
+    hosts: HashMap<String, (u64, u64)>,
+}
+
+impl AnnouncedTotals {
+    /// `host` was told of `uploaded` and `downloaded` in total, returns
+    /// how much of each is new to it.
+    pub fn credit(&mut self, host: &str, uploaded: u64, downloaded: u64) -> (u64, u64) {
+        let announced = self.hosts.entry(host.to_owned()).or_default();
+        let new = (
+            uploaded.saturating_sub(announced.0),
+            downloaded.saturating_sub(announced.1),
+        );
+        *announced = (uploaded.max(announced.0), downloaded.max(announced.1));
+        new
+    }
+}
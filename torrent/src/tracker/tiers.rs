@@ -0,0 +1,106 @@
+use rand::seq::SliceRandom;
+
+use super::{
+    client::TrackerError, filter::TrackerFilter, Tracker, TrackerRequest, TrackerResponse,
+};
+use crate::meta_info::MetaInfo;
+
+// https://www.bittorrent.org/beps/bep_0012.html
+
+/// The trackers of a torrent grouped in tiers as described by BEP 12.
+///
+/// Trackers are tried tier by tier, in order within a tier. Each tier is
+/// shuffled once when created and a tracker that answers is moved to the
+/// front of its tier, so later announces go to it first.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        Self { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Every tracker in the order they should be tried.
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.tiers.iter().flatten()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Drop every tracker `filter` does not allow, and any tier left empty.
+    pub fn retain(&mut self, filter: &TrackerFilter) {
+        for tier in &mut self.tiers {
+            tier.retain(|tracker| filter.allows(tracker));
+        }
+        self.tiers.retain(|tier| !tier.is_empty());
+    }
+
+    /// `url` answered, move it to the front of its tier.
+    pub fn succeeded(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(index) = tier.iter().position(|tracker| tracker == url) {
+                let tracker = tier.remove(index);
+                tier.insert(0, tracker);
+                return;
+            }
+        }
+    }
+}
+
+impl From<&MetaInfo> for TrackerTiers {
+    fn from(meta_info: &MetaInfo) -> Self {
+        Self::new(meta_info.tiers())
+    }
+}
+
+impl Tracker {
+    /// Announce to the first tracker in `tiers` that answers, returning its
+    /// url along with the response, or the last error.
+    pub fn announce_tiers(
+        request: &TrackerRequest,
+        tiers: &mut TrackerTiers,
+    ) -> Result<(String, TrackerResponse), TrackerError> {
+        Self::announce_tiers_with(request, tiers, |_, _| {})
+    }
+
+    /// `announce_tiers`, calling `attempted` with the url of every tracker
+    /// tried and whether it answered with a success, e.g. to keep count of
+    /// how reliable each tracker is.
+    pub fn announce_tiers_with(
+        request: &TrackerRequest,
+        tiers: &mut TrackerTiers,
+        mut attempted: impl FnMut(&str, bool),
+    ) -> Result<(String, TrackerResponse), TrackerError> {
+        let mut last_error = TrackerError::InvalidUrl;
+        let urls: Vec<String> = tiers.iter().cloned().collect();
+
+        for url in urls {
+            match Self::announce(request, &url) {
+                Ok(response) => {
+                    attempted(&url, matches!(response, TrackerResponse::Success(_)));
+                    tiers.succeeded(&url);
+                    return Ok((url, response));
+                }
+                Err(err) => {
+                    attempted(&url, false);
+                    last_error = err;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
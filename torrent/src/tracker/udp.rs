@@ -0,0 +1,315 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::{
+    client::TrackerError, Event, Peers, Peers6, TrackerPeer, TrackerPeerResponse, TrackerRequest,
+};
+use crate::{
+    interface::{address_family, outgoing},
+    proxy::{proxy_for, Traffic},
+};
+
+// https://www.bittorrent.org/beps/bep_0015.html
+
+// A UDP tracker is first asked for a connection id, which the announce then
+// carries. Over IPv6 the announce response has 18 byte peers instead of 6,
+// the address family of the packet decides which.
+
+/// How long to wait for each answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// Large enough for an announce response with a few hundred peers.
+const MAX_PACKET: usize = 8192;
+
+/// Announce to a `udp://` tracker over IPv4 and IPv6, whichever the
+/// tracker's host resolves to and are in use, merging the peers of both.
+pub async fn announce(
+    request: &TrackerRequest,
+    tracker_url: &str,
+    timeout: Duration,
+) -> Result<TrackerPeerResponse, TrackerError> {
+    // Announcing directly would reveal our address to the tracker
+    if proxy_for(Traffic::Trackers).is_some() {
+        return Err(TrackerError::Proxied);
+    }
+    let url = reqwest::Url::parse(tracker_url).map_err(|_| TrackerError::InvalidUrl)?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+        return Err(TrackerError::InvalidUrl);
+    };
+    let addrs: Vec<SocketAddr> = lookup_host(format!("{host}:{port}"))
+        .await
+        .map_err(|_| TrackerError::Connect)?
+        .collect();
+
+    let family = address_family();
+    let v4 = addrs
+        .iter()
+        .find(|addr| addr.is_ipv4() && family.allows_ipv4());
+    let v6 = addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() && family.allows_ipv6());
+    let mut merged: Option<TrackerPeerResponse> = None;
+    let mut last_error = TrackerError::Connect;
+    for addr in [v4, v6].into_iter().flatten() {
+        match announce_to(request, *addr, timeout).await {
+            Ok(response) => match &mut merged {
+                Some(merged) => merged.merge(response),
+                None => merged = Some(response),
+            },
+            Err(err) => last_error = err,
+        }
+    }
+    merged.ok_or(last_error)
+}
+
+async fn announce_to(
+    request: &TrackerRequest,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Result<TrackerPeerResponse, TrackerError> {
+    let socket = outgoing()
+        .udp_socket(addr)
+        .and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        })
+        .map_err(|_| TrackerError::Connect)?;
+    socket
+        .connect(addr)
+        .await
+        .map_err(|_| TrackerError::Connect)?;
+
+    let transaction_id: u32 = rand::random();
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    let response = exchange(&socket, &packet, timeout).await?;
+    let connection_id = body(&response, ACTION_CONNECT, transaction_id)?
+        .get(..8)
+        .ok_or(TrackerError::InvalidResponse)?
+        .to_vec();
+
+    let transaction_id: u32 = rand::random();
+    let event: u32 = match request.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    let mut peer_id = [0; 20];
+    let len = request.peer_id.len().min(20);
+    peer_id[..len].copy_from_slice(&request.peer_id.as_bytes()[..len]);
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id);
+    packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(request.info_hash.as_bytes());
+    packet.extend_from_slice(&peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&event.to_be_bytes());
+    // The tracker uses the address the packet came from
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&rand::random::<u32>().to_be_bytes());
+    // As many peers as the tracker likes
+    packet.extend_from_slice(&(-1i32).to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    let response = exchange(&socket, &packet, timeout).await?;
+
+    let body = body(&response, ACTION_ANNOUNCE, transaction_id)?;
+    // interval, leechers and seeders
+    let Some((header, peers)) = body.split_at_checked(12) else {
+        return Err(TrackerError::InvalidResponse);
+    };
+    let interval = u32::from_be_bytes(header[..4].try_into().expect("header is 12 bytes"));
+    let (peers, peers6) = match addr {
+        SocketAddr::V4(_) => (compact_peers(peers, false), Vec::new()),
+        SocketAddr::V6(_) => (Vec::new(), compact_peers(peers, true)),
+    };
+
+    Ok(TrackerPeerResponse {
+        interval: interval as usize,
+        min_interval: None,
+        peers: Peers(peers),
+        peers6: Peers6(peers6),
+    })
+}
+
+/// Send `packet` and wait for the answer.
+async fn exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, TrackerError> {
+    socket
+        .send(packet)
+        .await
+        .map_err(|_| TrackerError::Connect)?;
+    let mut buf = vec![0; MAX_PACKET];
+    let len = tokio::time::timeout(timeout, socket.recv(&mut buf))
+        .await
+        .map_err(|_| TrackerError::Timeout)?
+        .map_err(|_| TrackerError::Connect)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// What follows the action and transaction id of `response`, after
+/// checking they answer what we sent.
+fn body(response: &[u8], action: u32, transaction_id: u32) -> Result<&[u8], TrackerError> {
+    let Some((header, body)) = response.split_at_checked(8) else {
+        return Err(TrackerError::InvalidResponse);
+    };
+    let answered = u32::from_be_bytes(header[..4].try_into().expect("header is 8 bytes"));
+    let answered_id = u32::from_be_bytes(header[4..].try_into().expect("header is 8 bytes"));
+    if answered_id != transaction_id {
+        return Err(TrackerError::InvalidResponse);
+    }
+    match answered {
+        ACTION_ERROR => Err(TrackerError::Failure(
+            String::from_utf8_lossy(body).into_owned(),
+        )),
+        answered if answered == action => Ok(body),
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
+
+/// 4 byte IPv4 or 16 byte IPv6 address and 2 byte port per peer.
+fn compact_peers(bytes: &[u8], ipv6: bool) -> Vec<TrackerPeer> {
+    let len = if ipv6 { 18 } else { 6 };
+    bytes
+        .chunks_exact(len)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(len - 2);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(ip) => Ipv6Addr::from(ip).into(),
+                Err(_) => Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).into(),
+            };
+            TrackerPeer {
+                addr: SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])),
+                peer_id: None,
+            }
+        })
+        .filter(|peer| peer.addr.port() != 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identity::Identity, meta_info::MetaInfo};
+
+    #[test]
+    fn answers_must_match_what_was_sent() {
+        let mut response = [ACTION_ANNOUNCE.to_be_bytes(), 7u32.to_be_bytes()].concat();
+        response.extend(b"body");
+        assert_eq!(body(&response, ACTION_ANNOUNCE, 7).unwrap(), b"body");
+        assert!(matches!(
+            body(&response, ACTION_ANNOUNCE, 8),
+            Err(TrackerError::InvalidResponse)
+        ));
+        assert!(matches!(
+            body(&response, ACTION_CONNECT, 7),
+            Err(TrackerError::InvalidResponse)
+        ));
+        assert!(matches!(
+            body(&response[..7], ACTION_ANNOUNCE, 7),
+            Err(TrackerError::InvalidResponse)
+        ));
+
+        let mut error = [ACTION_ERROR.to_be_bytes(), 7u32.to_be_bytes()].concat();
+        error.extend(b"unregistered torrent");
+        assert!(matches!(
+            body(&error, ACTION_ANNOUNCE, 7),
+            Err(TrackerError::Failure(reason)) if reason == "unregistered torrent"
+        ));
+    }
+
+    #[test]
+    fn peers_without_a_port_are_dropped() {
+        let peers = [127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 0, 10, 0, 0];
+        let peers = compact_peers(&peers, false);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, "127.0.0.1:6881".parse().unwrap());
+
+        let mut peers6 = Ipv6Addr::LOCALHOST.octets().to_vec();
+        peers6.extend(51413u16.to_be_bytes());
+        assert_eq!(
+            compact_peers(&peers6, true)[0].addr,
+            "[::1]:51413".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn announces_over_a_connection_id() {
+        let tracker = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = tracker.local_addr().unwrap();
+        let torrent = MetaInfo::from_bytes(
+            b"d8:announce0:4:infod6:lengthi5e4:name1:a\
+              12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        let info_hash = torrent.info().hash();
+        let request = TrackerRequest::new_compact(&torrent, &Identity::new())
+            .with_port(6881)
+            .with_event(Some(Event::Started));
+
+        let answer = std::thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET];
+            let (len, from) = tracker.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 16);
+            assert_eq!(buf[..8], PROTOCOL_ID.to_be_bytes());
+            let mut reply = [
+                ACTION_CONNECT.to_be_bytes(),
+                buf[12..16].try_into().unwrap(),
+            ]
+            .concat();
+            reply.extend(42u64.to_be_bytes());
+            tracker.send_to(&reply, from).unwrap();
+
+            let (len, from) = tracker.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 98);
+            assert_eq!(buf[..8], 42u64.to_be_bytes());
+            assert_eq!(buf[16..36], *info_hash.as_bytes());
+            // started
+            assert_eq!(buf[80..84], 2u32.to_be_bytes());
+            assert_eq!(buf[96..98], 6881u16.to_be_bytes());
+            let mut reply = [
+                ACTION_ANNOUNCE.to_be_bytes(),
+                buf[12..16].try_into().unwrap(),
+            ]
+            .concat();
+            for field in [1800u32, 3, 5] {
+                reply.extend(field.to_be_bytes());
+            }
+            reply.extend([10, 0, 0, 2, 0x1a, 0xe1]);
+            tracker.send_to(&reply, from).unwrap();
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let response = runtime
+            .block_on(announce_to(&request, addr, Duration::from_secs(5)))
+            .unwrap();
+        answer.join().unwrap();
+
+        assert_eq!(response.interval(), 1800);
+        assert_eq!(
+            response.addrs().collect::<Vec<_>>(),
+            ["10.0.0.2:6881".parse().unwrap()]
+        );
+    }
+}
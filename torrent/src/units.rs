@@ -0,0 +1,353 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr, time::Duration};
+
+// Sizes, durations and ratios as people write them, for CLI flags and the
+// config alike: `1.5MiB`, `4M`, `48h`, `1h30m`, `2.0`. Everything prints in
+// a form that parses back to the same value.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnitError {
+    Empty,
+    InvalidNumber(String),
+    UnknownUnit(String),
+    /// A duration without a unit, which would be ambiguous.
+    MissingUnit(String),
+    OutOfRange(String),
+}
+
+impl fmt::Display for UnitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnitError::Empty => write!(f, "no value given"),
+            UnitError::InvalidNumber(s) => write!(f, "{s:?} is not a number"),
+            UnitError::UnknownUnit(unit) => write!(f, "unknown unit {unit:?}"),
+            UnitError::MissingUnit(s) => write!(f, "{s:?} needs a unit, e.g. 30m, 48h or 7d"),
+            UnitError::OutOfRange(s) => write!(f, "{s:?} is out of range"),
+        }
+    }
+}
+
+impl std::error::Error for UnitError {}
+
+/// Split `s` into its leading number and the unit after it.
+fn split_number(s: &str) -> Result<(f64, &str), UnitError> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(end);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| UnitError::InvalidNumber(s.to_owned()))?;
+    Ok((number, unit.trim_start()))
+}
+
+/// An amount of data, e.g. `1.5MiB`, `4M`, `700MB` or `512` (bytes).
+///
+/// Single letters and `KiB`, `MiB`... are powers of 1024, `KB`, `MB`... are
+/// powers of 1000. Units are not case sensitive and `/s` is ignored, so
+/// rates read the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+const BINARY_UNITS: [(&str, u64); 4] = [
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(UnitError::Empty);
+        }
+        let (number, unit) = split_number(s)?;
+        let unit = unit.strip_suffix("/s").unwrap_or(unit).trim_end();
+        let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "t" | "tib" => 1 << 40,
+            "kb" => 1_000,
+            "mb" => 1_000_000,
+            "gb" => 1_000_000_000,
+            "tb" => 1_000_000_000_000,
+            _ => return Err(UnitError::UnknownUnit(unit.to_owned())),
+        };
+
+        let bytes = (number * multiplier as f64).round();
+        if !bytes.is_finite() || bytes > u64::MAX as f64 {
+            return Err(UnitError::OutOfRange(s.to_owned()));
+        }
+        Ok(ByteSize(bytes as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    /// The largest binary unit the size is a whole number of hundredths
+    /// of, e.g. `1.5MiB`, `4MiB` or `1000B`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0 as u128;
+        for (unit, size) in BINARY_UNITS {
+            let size = size as u128;
+            if bytes >= size && (bytes * 100).is_multiple_of(size) {
+                let whole = bytes / size;
+                let hundredths = (bytes * 100 / size) % 100;
+                return match hundredths {
+                    0 => write!(f, "{whole}{unit}"),
+                    _ if hundredths.is_multiple_of(10) => {
+                        write!(f, "{whole}.{}{unit}", hundredths / 10)
+                    }
+                    _ => write!(f, "{whole}.{hundredths:02}{unit}"),
+                };
+            }
+        }
+        write!(f, "{bytes}B")
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    /// `"1.5MiB"`, or a plain number of bytes.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Raw::Text(text) => text.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// A span of time, e.g. `48h`, `1h30m`, `90s` or `7d`. A bare number is
+/// refused, as seconds and minutes are both plausible.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+const DURATION_UNITS: [(&str, u64); 4] = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+
+impl HumanDuration {
+    pub fn as_duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(UnitError::Empty);
+        }
+
+        let mut rest = s;
+        let mut secs = 0.0;
+        while !rest.is_empty() {
+            let (number, after) = split_number(rest)?;
+            let unit_len = after
+                .find(|c: char| c.is_ascii_digit() || c == '.' || c.is_whitespace())
+                .unwrap_or(after.len());
+            let (unit, after) = after.split_at(unit_len);
+            let multiplier = match unit.to_ascii_lowercase().as_str() {
+                "" => return Err(UnitError::MissingUnit(s.to_owned())),
+                "s" | "sec" | "secs" => 1,
+                "m" | "min" | "mins" => 60,
+                "h" | "hr" | "hrs" => 3600,
+                "d" | "day" | "days" => 86400,
+                "w" | "week" | "weeks" => 7 * 86400,
+                _ => return Err(UnitError::UnknownUnit(unit.to_owned())),
+            };
+            secs += number * multiplier as f64;
+            rest = after.trim_start();
+        }
+
+        if !secs.is_finite() || secs > u64::MAX as f64 {
+            return Err(UnitError::OutOfRange(s.to_owned()));
+        }
+        Ok(HumanDuration(Duration::from_secs(secs.round() as u64)))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    /// Every unit from days to seconds that isn't zero, e.g. `2d`, `1h30m`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut secs = self.0.as_secs();
+        if secs == 0 {
+            return write!(f, "0s");
+        }
+        for (unit, size) in DURATION_UNITS {
+            if secs >= size {
+                write!(f, "{}{unit}", secs / size)?;
+                secs %= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// An upload ratio, e.g. `2.0` or `0.5`.
+pub fn parse_ratio(s: &str) -> Result<f64, UnitError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(UnitError::Empty);
+    }
+    let ratio = s
+        .parse::<f64>()
+        .map_err(|_| UnitError::InvalidNumber(s.to_owned()))?;
+    if !ratio.is_finite() || ratio < 0.0 {
+        return Err(UnitError::OutOfRange(s.to_owned()));
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(s: &str) -> u64 {
+        s.parse::<ByteSize>().unwrap().bytes()
+    }
+
+    fn secs(s: &str) -> u64 {
+        s.parse::<HumanDuration>().unwrap().as_duration().as_secs()
+    }
+
+    #[test]
+    fn sizes_are_binary_unless_spelled_decimal() {
+        assert_eq!(size("512"), 512);
+        assert_eq!(size("4M"), 4 << 20);
+        assert_eq!(size("1.5MiB"), 3 << 19);
+        assert_eq!(size("1.5 mib"), 3 << 19);
+        assert_eq!(size("700MB"), 700_000_000);
+        assert_eq!(size("2k/s"), 2048);
+        assert_eq!(size(" 1G "), 1 << 30);
+
+        assert_eq!("".parse::<ByteSize>(), Err(UnitError::Empty));
+        assert_eq!(
+            "MiB".parse::<ByteSize>(),
+            Err(UnitError::InvalidNumber("MiB".to_owned()))
+        );
+        assert_eq!(
+            "4 parsecs".parse::<ByteSize>(),
+            Err(UnitError::UnknownUnit("parsecs".to_owned()))
+        );
+        assert!(matches!(
+            "99999999999T".parse::<ByteSize>(),
+            Err(UnitError::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn sizes_print_in_a_form_that_parses_back() {
+        for (bytes, text) in [
+            (0, "0B"),
+            (1000, "1000B"),
+            (1 << 10, "1KiB"),
+            (3 << 19, "1.5MiB"),
+            (5 << 28, "1.25GiB"),
+            (1 << 40, "1TiB"),
+        ] {
+            assert_eq!(ByteSize(bytes).to_string(), text);
+            assert_eq!(size(text), bytes);
+        }
+    }
+
+    #[test]
+    fn durations_need_a_unit() {
+        assert_eq!(secs("90s"), 90);
+        assert_eq!(secs("1h30m"), 5400);
+        assert_eq!(secs("1h 30m"), 5400);
+        assert_eq!(secs("1.5h"), 5400);
+        assert_eq!(secs("2 days"), 2 * 86400);
+        assert_eq!(secs("1w"), 7 * 86400);
+
+        assert_eq!(
+            "90".parse::<HumanDuration>(),
+            Err(UnitError::MissingUnit("90".to_owned()))
+        );
+        assert_eq!(
+            "1h30".parse::<HumanDuration>(),
+            Err(UnitError::MissingUnit("1h30".to_owned()))
+        );
+        assert_eq!(
+            "3 fortnights".parse::<HumanDuration>(),
+            Err(UnitError::UnknownUnit("fortnights".to_owned()))
+        );
+    }
+
+    #[test]
+    fn durations_print_every_unit_that_isnt_zero() {
+        for (secs, text) in [(0, "0s"), (90, "1m30s"), (86400 + 60, "1d1m"), (3600, "1h")] {
+            let duration = HumanDuration(Duration::from_secs(secs));
+            assert_eq!(duration.to_string(), text);
+            assert_eq!(text.parse::<HumanDuration>(), Ok(duration));
+        }
+    }
+
+    #[test]
+    fn config_values_take_numbers_or_text() {
+        let size: ByteSize = serde_json::from_str("1024").unwrap();
+        assert_eq!(size, ByteSize(1024));
+        let size: ByteSize = serde_json::from_str("\"1KiB\"").unwrap();
+        assert_eq!(size, ByteSize(1024));
+        assert!(serde_json::from_str::<ByteSize>("\"1 yottabyte\"").is_err());
+        assert_eq!(serde_json::to_string(&size).unwrap(), "\"1KiB\"");
+
+        let duration: HumanDuration = serde_json::from_str("\"48h\"").unwrap();
+        assert_eq!(serde_json::to_string(&duration).unwrap(), "\"2d\"");
+    }
+
+    #[test]
+    fn ratios_are_non_negative_numbers() {
+        assert_eq!(parse_ratio("2.0"), Ok(2.0));
+        assert_eq!(parse_ratio(" 0.5 "), Ok(0.5));
+        assert_eq!(parse_ratio(""), Err(UnitError::Empty));
+        assert_eq!(
+            parse_ratio("-1"),
+            Err(UnitError::OutOfRange("-1".to_owned()))
+        );
+        assert_eq!(
+            parse_ratio("inf"),
+            Err(UnitError::OutOfRange("inf".to_owned()))
+        );
+        assert_eq!(
+            parse_ratio("lots"),
+            Err(UnitError::InvalidNumber("lots".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt, path::PathBuf, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{
+    info_hash::InfoHash,
+    meta_info::MetaInfo,
+    source::{SourceError, SourceResolver},
+};
+
+// Torrents that grow over time, e.g. a season that gets a new episode every
+// week, can name a URL in their info dictionary where the latest version is
+// published (BEP 39). Another version is another torrent with an info hash
+// of its own, updating means adding it in place of the old one with the
+// same output directory, so the files both share are kept.
+//
+// Every version must carry the same `originator`, the key of whoever
+// publishes them. The signature BEP 39 has the new version carry is not
+// checked yet, so updates should only be applied from URLs the user trusts.
+
+/// What to do once a torrent's `update-url` has a newer version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateAction {
+    /// Don't check for updates.
+    Ignore,
+    /// Show what changed and let the user decide.
+    #[default]
+    Prompt,
+    /// Replace the torrent with the new version right away.
+    Auto,
+}
+
+impl FromStr for UpdateAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(UpdateAction::Ignore),
+            "prompt" => Ok(UpdateAction::Prompt),
+            "auto" => Ok(UpdateAction::Auto),
+            _ => Err(format!(
+                "unknown action {s}, expected ignore, prompt or auto"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for UpdateAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UpdateAction::Ignore => "ignore",
+            UpdateAction::Prompt => "prompt",
+            UpdateAction::Auto => "auto",
+        })
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UpdateError {
+    /// The torrent has no `update-url`.
+    NotUpdatable,
+    /// The `update-url` is not an http(s) URL.
+    InvalidUrl(String),
+    Source(SourceError),
+    /// What the `update-url` serves is not a version of this torrent.
+    OriginatorMismatch,
+}
+
+impl From<SourceError> for UpdateError {
+    fn from(err: SourceError) -> Self {
+        UpdateError::Source(err)
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::NotUpdatable => f.write_str("the torrent has no update-url"),
+            UpdateError::InvalidUrl(url) => write!(f, "invalid update-url {url}"),
+            UpdateError::Source(err) => write!(f, "unable to fetch the update: {err:?}"),
+            UpdateError::OriginatorMismatch => {
+                f.write_str("the update-url serves a torrent from another originator")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+/// A newer version of a torrent, with the files it adds and drops.
+#[derive(Debug)]
+pub struct TorrentUpdate {
+    pub old: InfoHash,
+    pub new: MetaInfo,
+    /// The .torrent file of the new version, as published.
+    pub bytes: Vec<u8>,
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl TorrentUpdate {
+    fn new(old: &MetaInfo, new: MetaInfo, bytes: Vec<u8>) -> Self {
+        let old_files: BTreeSet<PathBuf> = old.info().file_paths().into_iter().collect();
+        let new_files: BTreeSet<PathBuf> = new.info().file_paths().into_iter().collect();
+        Self {
+            old: old.info().hash(),
+            added: new_files.difference(&old_files).cloned().collect(),
+            removed: old_files.difference(&new_files).cloned().collect(),
+            new,
+            bytes,
+        }
+    }
+}
+
+impl fmt::Display for TorrentUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} -> {}", self.old, self.new.info().hash())?;
+        for path in &self.added {
+            writeln!(f, "+ {}", path.display())?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path.display())?;
+        }
+        if self.added.is_empty() && self.removed.is_empty() {
+            writeln!(f, "same files, changed data")?;
+        }
+        Ok(())
+    }
+}
+
+/// Fetch the torrent at `torrent`'s `update-url`, returning it if it is a
+/// newer version and `None` if it is the same torrent.
+pub fn check_update(
+    torrent: &MetaInfo,
+    resolver: &SourceResolver,
+) -> Result<Option<TorrentUpdate>, UpdateError> {
+    let url = torrent
+        .info()
+        .update_url()
+        .ok_or(UpdateError::NotUpdatable)?;
+    let url = Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| UpdateError::InvalidUrl(url.to_owned()))?;
+
+    let bytes = resolver.download(url)?;
+    let latest = MetaInfo::from_bytes(&bytes).map_err(SourceError::from)?;
+
+    if latest.info().originator() != torrent.info().originator() {
+        return Err(UpdateError::OriginatorMismatch);
+    }
+    if latest.info().hash() == torrent.info().hash() {
+        return Ok(None);
+    }
+    Ok(Some(TorrentUpdate::new(torrent, latest, bytes)))
+}
@@ -0,0 +1,457 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{dns::http_client, natpmp};
+
+// UPnP Internet Gateway Device port mapping
+// https://openconnectivity.org/developer/specifications/upnp-resources/upnp/internet-gateway-device-igd-v-2-0/
+
+/// Where gateways listen for discovery requests (SSDP).
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+/// How long to wait for a gateway to answer discovery.
+pub const DISCOVER_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a mapping is requested for. Gateways drop it afterwards, so a
+/// crashed client doesn't leave the port open forever.
+pub const LEASE: Duration = Duration::from_secs(60 * 60);
+/// Mappings are renewed well before their lease runs out, and also in case
+/// the gateway restarted and forgot them.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The services a gateway may offer port mapping through, most common first.
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The gateway only accepts mappings without a lease.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u32 = 725;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UpnpError {
+    Io(io::Error),
+    /// No gateway answered discovery.
+    NoGateway,
+    /// The gateway's description has no port mapping service.
+    NoService,
+    Http(reqwest::Error),
+    /// The gateway refused the action, e.g. `718 ConflictInMappingEntry`.
+    Refused {
+        code: u32,
+        description: String,
+    },
+}
+
+impl From<io::Error> for UpnpError {
+    fn from(err: io::Error) -> Self {
+        UpnpError::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for UpnpError {
+    fn from(err: reqwest::Error) -> Self {
+        UpnpError::Http(err)
+    }
+}
+
+impl fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpnpError::Io(err) => write!(f, "{err}"),
+            UpnpError::NoGateway => write!(f, "no UPnP gateway found"),
+            UpnpError::NoService => write!(f, "the gateway does not support port mapping"),
+            UpnpError::Http(err) => write!(f, "{err}"),
+            UpnpError::Refused { code, description } => {
+                write!(f, "the gateway refused: {code} {description}")
+            }
+        }
+    }
+}
+
+/// A router that maps ports for us.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    control_url: reqwest::Url,
+    service_type: &'static str,
+    /// Our address on the gateway's network, where mapped ports point to.
+    local_ip: IpAddr,
+}
+
+impl Gateway {
+    /// Find the gateway of the local network.
+    pub fn discover(timeout: Duration) -> Result<Self, UpnpError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(timeout))?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\r\n",
+            timeout.as_secs().max(1)
+        );
+        socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 2048];
+        while Instant::now() < deadline {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let response = String::from_utf8_lossy(&buf[..len]);
+            let location = response.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("location")
+                    .then(|| value.trim().to_owned())
+            });
+            // Other UPnP devices answer too, keep looking
+            if let Some(gateway) = location.and_then(|location| Self::from_location(&location).ok())
+            {
+                return Ok(gateway);
+            }
+        }
+        Err(UpnpError::NoGateway)
+    }
+
+    /// The gateway described at `location`, the url a gateway answers discovery with.
+    pub fn from_location(location: &str) -> Result<Self, UpnpError> {
+        let location = reqwest::Url::parse(location).map_err(|_| UpnpError::NoService)?;
+        let description = http_client().get(location.clone()).send()?.text()?;
+
+        let (service_type, control_url) = SERVICE_TYPES
+            .iter()
+            .find_map(|&service_type| {
+                // The control url follows the service type inside the same <service>
+                description.split("<service>").find_map(|service| {
+                    if tag(service, "serviceType")? != service_type {
+                        return None;
+                    }
+                    Some((service_type, tag(service, "controlURL")?))
+                })
+            })
+            .ok_or(UpnpError::NoService)?;
+
+        let base = tag(&description, "URLBase")
+            .and_then(|base| reqwest::Url::parse(base).ok())
+            .unwrap_or_else(|| location.clone());
+        let control_url = base.join(control_url).map_err(|_| UpnpError::NoService)?;
+
+        // The address we reach the gateway from is the one it can reach us at
+        let gateway_addr = control_url
+            .socket_addrs(|| Some(80))?
+            .into_iter()
+            .next()
+            .ok_or(UpnpError::NoGateway)?;
+        let local_ip = local_ip_towards(gateway_addr)?;
+
+        Ok(Self {
+            control_url,
+            service_type,
+            local_ip,
+        })
+    }
+
+    pub fn local_ip(&self) -> IpAddr {
+        self.local_ip
+    }
+
+    /// Forward `port` on the gateway to the same port on this machine.
+    pub fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        description: &str,
+    ) -> Result<(), UpnpError> {
+        let arguments = |lease: Duration| {
+            format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort><NewProtocol>{}</NewProtocol><NewInternalPort>{port}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>{description}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                protocol.as_str(),
+                self.local_ip,
+                lease.as_secs()
+            )
+        };
+
+        match self.call("AddPortMapping", &arguments(LEASE)) {
+            Err(UpnpError::Refused { code, .. }) if code == ONLY_PERMANENT_LEASES_SUPPORTED => self
+                .call("AddPortMapping", &arguments(Duration::ZERO))
+                .map(|_| ()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub fn remove_port_mapping(&self, protocol: Protocol, port: u16) -> Result<(), UpnpError> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort><NewProtocol>{}</NewProtocol>",
+            protocol.as_str()
+        );
+        self.call("DeletePortMapping", &arguments).map(|_| ())
+    }
+
+    /// The gateway's address on the internet.
+    pub fn external_ip(&self) -> Result<IpAddr, UpnpError> {
+        let response = self.call("GetExternalIPAddress", "")?;
+        tag(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or(UpnpError::Refused {
+                code: 0,
+                description: "no external address".to_owned(),
+            })
+    }
+
+    /// Run a SOAP `action` on the gateway, returning the response body.
+    fn call(&self, action: &str, arguments: &str) -> Result<String, UpnpError> {
+        let body = format!(
+            "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            self.service_type
+        );
+        let response = http_client()
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{action}\"", self.service_type))
+            .body(body)
+            .send()?;
+
+        let success = response.status().is_success();
+        let text = response.text()?;
+        if success {
+            return Ok(text);
+        }
+        Err(UpnpError::Refused {
+            code: tag(&text, "errorCode")
+                .and_then(|code| code.trim().parse().ok())
+                .unwrap_or(0),
+            description: tag(&text, "errorDescription")
+                .unwrap_or("unknown error")
+                .to_owned(),
+        })
+    }
+}
+
+/// The contents of the first `<name>` element in `xml`, ignoring namespaces.
+/// Good enough for the flat documents gateways send.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(&xml[start..end])
+}
+
+pub(crate) fn local_ip_towards(addr: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The protocol a port is mapped over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingMethod {
+    Upnp,
+    NatPmp,
+    Pcp,
+}
+
+impl fmt::Display for MappingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingMethod::Upnp => write!(f, "UPnP"),
+            MappingMethod::NatPmp => write!(f, "NAT-PMP"),
+            MappingMethod::Pcp => write!(f, "PCP"),
+        }
+    }
+}
+
+/// Whether the listen port is reachable from the internet through the gateway.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MappingStatus {
+    /// Mapping was not attempted yet, or is turned off.
+    #[default]
+    NotStarted,
+    /// The port is mapped for both TCP and UDP.
+    Mapped {
+        /// The port on the gateway, NAT-PMP and PCP gateways may pick
+        /// another one than the listen port.
+        port: u16,
+        /// `None` if the gateway would not tell.
+        external_ip: Option<IpAddr>,
+        method: MappingMethod,
+    },
+    /// No gateway, or one that doesn't support mapping.
+    Unavailable(String),
+    /// The gateway refused to map the port, e.g. because another machine
+    /// already has it.
+    Failed(String),
+}
+
+impl fmt::Display for MappingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingStatus::NotStarted => write!(f, "not started"),
+            MappingStatus::Mapped {
+                port,
+                external_ip: Some(ip),
+                method,
+            } => write!(f, "port {port} open at {ip} over {method}"),
+            MappingStatus::Mapped { port, method, .. } => {
+                write!(f, "port {port} open over {method}")
+            }
+            MappingStatus::Unavailable(reason) => write!(f, "unavailable, {reason}"),
+            MappingStatus::Failed(reason) => write!(f, "failed, {reason}"),
+        }
+    }
+}
+
+/// A gateway of either kind.
+#[derive(Debug)]
+enum Router {
+    Upnp(Gateway),
+    NatPmp(natpmp::Gateway),
+}
+
+/// Keeps the listen port mapped on the gateway for TCP (peers) and UDP
+/// (DHT, uTP) while the daemon runs. UPnP is tried first, NAT-PMP and PCP
+/// when no UPnP gateway answers.
+#[derive(Debug)]
+pub struct PortMapper {
+    port: u16,
+    router: Option<Router>,
+    status: MappingStatus,
+    refresh_at: Option<Instant>,
+}
+
+impl PortMapper {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            router: None,
+            status: MappingStatus::NotStarted,
+            refresh_at: None,
+        }
+    }
+
+    pub fn status(&self) -> &MappingStatus {
+        &self.status
+    }
+
+    /// Discover the gateway and map the port. Blocks for up to
+    /// `DISCOVER_TIMEOUT` and a few seconds more for NAT-PMP and PCP.
+    pub fn start(&mut self, now: Instant) {
+        let router = Gateway::discover(DISCOVER_TIMEOUT)
+            .map(Router::Upnp)
+            .or_else(|upnp| match natpmp::Gateway::discover() {
+                Ok(gateway) => Ok(Router::NatPmp(gateway)),
+                Err(natpmp) => Err(format!("{upnp}, {natpmp}")),
+            });
+        match router {
+            Ok(router) => {
+                self.router = Some(router);
+                self.map(now);
+            }
+            Err(reason) => self.status = MappingStatus::Unavailable(reason),
+        }
+    }
+
+    /// Renew the mapping once `REFRESH_INTERVAL` has passed, or half of
+    /// the lifetime a NAT-PMP or PCP gateway granted.
+    pub fn tick(&mut self, now: Instant) {
+        if self.refresh_at.is_some_and(|refresh_at| now >= refresh_at) {
+            self.map(now);
+        }
+    }
+
+    fn map(&mut self, now: Instant) {
+        let Some(router) = &self.router else {
+            return;
+        };
+        self.refresh_at = Some(now + REFRESH_INTERVAL);
+
+        self.status = match router {
+            Router::Upnp(gateway) => {
+                let mapped = [Protocol::Tcp, Protocol::Udp]
+                    .into_iter()
+                    .try_for_each(|protocol| {
+                        gateway.add_port_mapping(protocol, self.port, crate::CLIENT_NAME)
+                    });
+                match mapped {
+                    Ok(()) => MappingStatus::Mapped {
+                        port: self.port,
+                        external_ip: gateway.external_ip().ok(),
+                        method: MappingMethod::Upnp,
+                    },
+                    Err(err) => MappingStatus::Failed(err.to_string()),
+                }
+            }
+            Router::NatPmp(gateway) => {
+                // The gateway may pick another external port, the TCP one
+                // is what peers are told about
+                let mapped = gateway
+                    .add_port_mapping(Protocol::Tcp, self.port, LEASE)
+                    .and_then(|tcp| {
+                        gateway.add_port_mapping(Protocol::Udp, self.port, LEASE)?;
+                        Ok(tcp)
+                    });
+                match mapped {
+                    Ok(tcp) => {
+                        self.refresh_at = Some(now + (tcp.lifetime / 2).min(REFRESH_INTERVAL));
+                        MappingStatus::Mapped {
+                            port: tcp.external_port,
+                            external_ip: tcp.external_ip,
+                            method: match gateway.version() {
+                                natpmp::Version::NatPmp => MappingMethod::NatPmp,
+                                natpmp::Version::Pcp => MappingMethod::Pcp,
+                            },
+                        }
+                    }
+                    Err(err) => MappingStatus::Failed(err.to_string()),
+                }
+            }
+        };
+    }
+
+    /// Remove the mapping, e.g. on shutdown.
+    pub fn stop(&mut self) {
+        self.refresh_at = None;
+        if let (Some(router), MappingStatus::Mapped { .. }) = (&self.router, &self.status) {
+            for protocol in [Protocol::Tcp, Protocol::Udp] {
+                let removed = match router {
+                    Router::Upnp(gateway) => gateway
+                        .remove_port_mapping(protocol, self.port)
+                        .map_err(|err| err.to_string()),
+                    Router::NatPmp(gateway) => gateway
+                        .remove_port_mapping(protocol, self.port)
+                        .map_err(|err| err.to_string()),
+                };
+                if let Err(err) = removed {
+                    log::warn!("unable to remove {} port mapping: {err}", protocol.as_str());
+                }
+            }
+        }
+        self.status = MappingStatus::NotStarted;
+    }
+}
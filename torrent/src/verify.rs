@@ -0,0 +1,364 @@
+use memmap2::{Mmap, MmapOptions};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+};
+
+use crate::{
+    hash_pool::HashPool,
+    meta_info::{FileSpan, Info},
+    operation::{Cancelled, Operation},
+};
+
+// A recheck reads every byte of the torrent once, copying it from the page
+// cache into a buffer only to hash it is half the work. `MappedFiles` maps
+// the files instead and hashes the pages where they are. Only a window of
+// each file is mapped at a time, so a torrent of hundreds of GB doesn't take
+// up as much address space, and the windows of files a recheck is done with
+// are unmapped as it moves on.
+//
+// A recheck maps the pieces in order on its own thread and hands them to the
+// hash pool, whose threads fault the pages in and hash them at the same
+// time. Only a few pieces per thread are in flight, so the windows they
+// keep alive stay close together.
+
+/// How much of a file is mapped at once, more if a piece needs it.
+pub const MAP_WINDOW: u64 = 64 * 1024 * 1024;
+/// Pieces a recheck has waiting for or being hashed, per hashing thread.
+const PIECES_IN_FLIGHT: usize = 2;
+
+/// The result of checking one piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PieceCheck {
+    Good,
+    /// The data did not match the piece hash.
+    Bad,
+    /// The piece could not be checked because a neighboring file it shares
+    /// bytes with is missing or too short.
+    Unverifiable,
+}
+
+/// A range of a file covered by a piece that did not verify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileRange {
+    /// Offset within the file.
+    pub offset: u64,
+    pub len: u64,
+    pub check: PieceCheck,
+}
+
+/// The pieces covering the file at `file_index`, as an inclusive range.
+pub fn covering_pieces(info: &Info, file_index: usize) -> Option<(usize, usize)> {
+    let lengths = info.file_lengths();
+    let length = *lengths.get(file_index)? as u64;
+    if length == 0 {
+        return None;
+    }
+
+    let start: u64 = lengths[..file_index].iter().map(|&l| l as u64).sum();
+    let piece_length = info.piece_length() as u64;
+    Some((
+        (start / piece_length) as usize,
+        ((start + length - 1) / piece_length) as usize,
+    ))
+}
+
+/// Read and hash the piece at `index` from the files under `root`.
+pub fn check_piece(info: &Info, root: &Path, index: usize, target_file: usize) -> PieceCheck {
+    let paths = info.file_paths();
+    let attributes = info.file_attributes();
+    let mut piece = Vec::with_capacity(info.piece_len(index));
+
+    for span in info.piece_spans(index) {
+        // Pad files are zeros and never written to disk
+        if attributes[span.file_index].padding {
+            piece.resize(piece.len() + span.len as usize, 0);
+            continue;
+        }
+
+        let read = (|| -> io::Result<()> {
+            let mut file = File::open(root.join(&paths[span.file_index]))?;
+            file.seek(SeekFrom::Start(span.offset))?;
+            let start = piece.len();
+            piece.resize(start + span.len as usize, 0);
+            file.read_exact(&mut piece[start..])
+        })();
+
+        if read.is_err() {
+            // If the file being verified is short that is simply bad data,
+            // if a neighbor is missing we cannot say anything about this piece.
+            return if span.file_index == target_file {
+                PieceCheck::Bad
+            } else {
+                PieceCheck::Unverifiable
+            };
+        }
+    }
+
+    let digest = sha1_smol::Sha1::from(&piece).digest().bytes();
+    if info.pieces().get(index) == Some(&digest) {
+        PieceCheck::Good
+    } else {
+        PieceCheck::Bad
+    }
+}
+
+/// Verify only the file at `file_index` against the pieces covering it,
+/// which is much faster than a full recheck when one file is suspect.
+///
+/// Returns the ranges of the file that are bad or could not be verified,
+/// adjacent ranges with the same outcome are merged.
+pub fn verify_file(info: &Info, root: &Path, file_index: usize) -> Vec<FileRange> {
+    let Some((first, last)) = covering_pieces(info, file_index) else {
+        return Vec::new();
+    };
+
+    let lengths = info.file_lengths();
+    let file_start: u64 = lengths[..file_index].iter().map(|&l| l as u64).sum();
+    let file_end = file_start + lengths[file_index] as u64;
+    let piece_length = info.piece_length() as u64;
+
+    let mut ranges: Vec<FileRange> = Vec::new();
+    for index in first..=last {
+        let check = check_piece(info, root, index, file_index);
+        if check == PieceCheck::Good {
+            continue;
+        }
+
+        let piece_start = index as u64 * piece_length;
+        let start = piece_start.max(file_start);
+        let end = (piece_start + info.piece_len(index) as u64).min(file_end);
+        let range = FileRange {
+            offset: start - file_start,
+            len: end - start,
+            check,
+        };
+
+        match ranges.last_mut() {
+            Some(last) if last.check == check && last.offset + last.len == range.offset => {
+                last.len += range.len;
+            }
+            _ => ranges.push(range),
+        }
+    }
+
+    ranges
+}
+
+/// Hash every piece of the torrent from the files under `root` on `pool`,
+/// reporting each one to `operation` and stopping if it is cancelled. Run
+/// it as a `DiskScheduler` task, so checks don't run alongside each other.
+///
+/// Returns whether each piece is good, by index.
+pub fn recheck(
+    info: &Info,
+    root: &Path,
+    operation: &Operation,
+    pool: &HashPool,
+) -> Result<Vec<bool>, Cancelled> {
+    operation.set_total(info.piece_count() as u64);
+    let mut files = MappedFiles::new(info, root);
+    let mut good = vec![false; info.piece_count()];
+    let (sender, hashed) = mpsc::channel();
+    let mut in_flight = 0;
+    let receive = |good: &mut Vec<bool>| {
+        let (index, matches) = hashed.recv().expect("a hashing thread panicked");
+        good[index] = matches;
+        operation.advance(1);
+    };
+
+    for index in 0..info.piece_count() {
+        operation.checkpoint()?;
+        if in_flight == pool.threads() * PIECES_IN_FLIGHT {
+            receive(&mut good);
+            in_flight -= 1;
+        }
+        // Bad or unverifiable, the piece has to be downloaded either way
+        let Ok(piece) = files.piece(index, usize::MAX) else {
+            operation.advance(1);
+            continue;
+        };
+        let expected = info.pieces()[index];
+        let sender = sender.clone();
+        pool.execute(move || {
+            let _ = sender.send((index, piece.digest() == expected));
+        });
+        in_flight += 1;
+    }
+    for _ in 0..in_flight {
+        receive(&mut good);
+    }
+    Ok(good)
+}
+
+/// A mapped range of a file.
+#[derive(Debug)]
+struct Window {
+    file: usize,
+    offset: u64,
+    /// Shared with the pieces handed out that are still being hashed.
+    map: Arc<Mmap>,
+}
+
+impl Window {
+    fn contains(&self, span: &FileSpan) -> bool {
+        self.file == span.file_index
+            && self.offset <= span.offset
+            && span.offset + span.len <= self.offset + self.map.len() as u64
+    }
+}
+
+/// The torrent's files under a directory, hashed piece by piece through
+/// memory maps. Best used in piece order.
+#[derive(Debug)]
+pub struct MappedFiles<'a> {
+    info: &'a Info,
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+    /// At most one per file.
+    windows: Vec<Window>,
+}
+
+/// A part of a piece's data.
+#[derive(Debug)]
+enum Part {
+    Mapped {
+        map: Arc<Mmap>,
+        start: usize,
+        len: usize,
+    },
+    /// A pad file's zeros, never written to disk.
+    Zeros(u64),
+    /// Read rather than mapped, e.g. on file systems without support for it.
+    Read(Vec<u8>),
+}
+
+/// The data of a piece, mapped from its files and ready to be hashed on
+/// another thread.
+#[derive(Debug)]
+pub struct MappedPiece {
+    parts: Vec<Part>,
+}
+
+impl MappedPiece {
+    pub fn digest(&self) -> [u8; 20] {
+        let mut hasher = sha1_smol::Sha1::new();
+        for part in &self.parts {
+            match part {
+                Part::Mapped { map, start, len } => hasher.update(&map[*start..start + len]),
+                Part::Zeros(len) => hash_zeros(&mut hasher, *len),
+                Part::Read(data) => hasher.update(data),
+            }
+        }
+        hasher.digest().bytes()
+    }
+}
+
+impl<'a> MappedFiles<'a> {
+    pub fn new(info: &'a Info, root: &Path) -> Self {
+        Self {
+            info,
+            root: root.to_owned(),
+            paths: info.file_paths(),
+            windows: Vec::new(),
+        }
+    }
+
+    /// Hash the piece at `index`, like `check_piece`.
+    pub fn check_piece(&mut self, index: usize, target_file: usize) -> PieceCheck {
+        match self.piece(index, target_file) {
+            Ok(piece) if self.info.pieces().get(index) == Some(&piece.digest()) => PieceCheck::Good,
+            Ok(_) => PieceCheck::Bad,
+            Err(check) => check,
+        }
+    }
+
+    /// Map the data of the piece at `index`, or say why it can't be
+    /// checked like `check_piece` does.
+    pub fn piece(&mut self, index: usize, target_file: usize) -> Result<MappedPiece, PieceCheck> {
+        let spans = self.info.piece_spans(index);
+        let attributes = self.info.file_attributes();
+        // Windows of files before this piece are done with
+        if let Some(first) = spans.first() {
+            self.windows
+                .retain(|window| window.file >= first.file_index);
+        }
+
+        let mut parts = Vec::with_capacity(spans.len());
+        for span in &spans {
+            // Pad files are zeros and never written to disk
+            if attributes[span.file_index].padding {
+                parts.push(Part::Zeros(span.len));
+                continue;
+            }
+            match self.span(span) {
+                Ok(part) => parts.push(part),
+                // See `check_piece`
+                Err(_) if span.file_index == target_file => return Err(PieceCheck::Bad),
+                Err(_) => return Err(PieceCheck::Unverifiable),
+            }
+        }
+        Ok(MappedPiece { parts })
+    }
+
+    /// The data of `span`, mapping a window of its file from it if needed.
+    fn span(&mut self, span: &FileSpan) -> io::Result<Part> {
+        let position = match self.windows.iter().position(|window| window.contains(span)) {
+            Some(position) => position,
+            None => {
+                self.windows.retain(|window| window.file != span.file_index);
+                let mut file = File::open(self.root.join(&self.paths[span.file_index]))?;
+                let file_len = file.metadata()?.len();
+                if file_len < span.offset + span.len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                let len = (file_len - span.offset).min(MAP_WINDOW.max(span.len));
+                // Safety: the data is only hashed, what is written to the
+                // file meanwhile makes the piece bad at worst. Truncating
+                // it while mapped would fault, which flud never does to a
+                // torrent being checked.
+                let map = unsafe {
+                    MmapOptions::new()
+                        .offset(span.offset)
+                        .len(len as usize)
+                        .map(&file)
+                };
+                let Ok(map) = map else {
+                    let mut data = vec![0; span.len as usize];
+                    file.seek(SeekFrom::Start(span.offset))?;
+                    file.read_exact(&mut data)?;
+                    return Ok(Part::Read(data));
+                };
+                #[cfg(unix)]
+                let _ = map.advise(memmap2::Advice::Sequential);
+
+                self.windows.push(Window {
+                    file: span.file_index,
+                    offset: span.offset,
+                    map: Arc::new(map),
+                });
+                self.windows.len() - 1
+            }
+        };
+
+        let window = &self.windows[position];
+        Ok(Part::Mapped {
+            map: Arc::clone(&window.map),
+            start: (span.offset - window.offset) as usize,
+            len: span.len as usize,
+        })
+    }
+}
+
+fn hash_zeros(hasher: &mut sha1_smol::Sha1, mut len: u64) {
+    const ZEROS: [u8; 4096] = [0; 4096];
+    while len > 0 {
+        let chunk = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..chunk]);
+        len -= chunk as u64;
+    }
+}
@@ -0,0 +1,340 @@
+use reqwest::{header, StatusCode, Url};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dns::DnsCache,
+    info_hash::InfoHash,
+    meta_info::Info,
+    proxy::{http_client_builder, Traffic},
+    rate_limit::{Direction, RateLimiter},
+};
+
+// https://www.bittorrent.org/beps/bep_0019.html
+// https://www.bittorrent.org/beps/bep_0017.html
+
+// A web seed is a plain HTTP(S) server hosting the torrent's files. Pieces
+// are fetched with range requests of the files they span and checked against
+// the piece hashes like any piece from a peer, web seeds have every piece.
+//
+// The older http seeds are scripts that serve whole pieces, asked for by
+// info hash and piece index. They are used the same way.
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Wait after the first failure, doubled for every failure in a row after it.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(30);
+/// The longest wait before trying a web seed again, whatever it asked for.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Stop using a web seed after this many failures in a row.
+pub const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WebSeedError {
+    InvalidUrl,
+    Timeout,
+    /// The server could not be reached.
+    Connect,
+    /// The server answered with a non-success HTTP status.
+    Status(u16),
+    /// The server is busy and asked us to come back after the given time.
+    Busy(Duration),
+    /// The server sent less or, for http seeds, more than was requested.
+    ShortResponse,
+    /// The server sent the whole file rather than the range asked for.
+    RangeIgnored,
+    /// The data did not match the piece hash.
+    HashMismatch,
+}
+
+impl From<reqwest::Error> for WebSeedError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            WebSeedError::Timeout
+        } else if let Some(status) = err.status() {
+            WebSeedError::Status(status.as_u16())
+        } else {
+            WebSeedError::Connect
+        }
+    }
+}
+
+/// How an HTTP source serves a torrent's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedProtocol {
+    /// `url-list` (BEP 19, GetRight style): the files as they are.
+    WebSeed,
+    /// `httpseeds` (BEP 17, Hoffman style): a script serving pieces.
+    HttpSeed { info_hash: InfoHash },
+}
+
+impl SeedProtocol {
+    /// A short name for listing sources, `web seed` or `http seed`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SeedProtocol::WebSeed => "web seed",
+            SeedProtocol::HttpSeed { .. } => "http seed",
+        }
+    }
+}
+
+/// One web seed or http seed of a torrent.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: Url,
+    protocol: SeedProtocol,
+    http: reqwest::Client,
+    rate_limiter: RateLimiter,
+    /// Failures in a row, reset by every piece fetched.
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// Bytes of good pieces received.
+    downloaded: u64,
+}
+
+impl WebSeed {
+    /// A web seed from the torrent's `url-list`.
+    pub fn new(url: &str) -> Result<Self, WebSeedError> {
+        Self::with_protocol(url, SeedProtocol::WebSeed)
+    }
+
+    /// An http seed from the torrent's `httpseeds`.
+    pub fn http_seed(url: &str, info_hash: InfoHash) -> Result<Self, WebSeedError> {
+        Self::with_protocol(url, SeedProtocol::HttpSeed { info_hash })
+    }
+
+    /// Count the pieces fetched against the session's download cap.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    fn with_protocol(url: &str, protocol: SeedProtocol) -> Result<Self, WebSeedError> {
+        let url = Url::parse(url).map_err(|_| WebSeedError::InvalidUrl)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(WebSeedError::InvalidUrl);
+        }
+
+        let http = http_client_builder(Traffic::Peers)
+            .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+            .read_timeout(DEFAULT_READ_TIMEOUT)
+            .dns_resolver(Arc::new(DnsCache::default()))
+            .build()
+            .expect("failed to build http client");
+
+        Ok(Self {
+            url,
+            protocol,
+            http,
+            rate_limiter: RateLimiter::default(),
+            failures: 0,
+            retry_at: None,
+            downloaded: 0,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn protocol(&self) -> SeedProtocol {
+        self.protocol
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether a piece may be requested from this web seed at `now`.
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.failures < MAX_FAILURES && self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+
+    /// The URL of the file at `file_index`. For a single file torrent the
+    /// URL is the file itself unless it ends in `/`, in which case the name
+    /// is appended. For a multi-file torrent the name and the file's path
+    /// are always appended, as the torrent declares it: the server knows
+    /// nothing of files renamed so they don't collide on disk.
+    pub fn file_url(&self, info: &Info, file_index: usize) -> Option<Url> {
+        let path = info.declared_file_paths().into_iter().nth(file_index)?;
+        let mut url = self.url.clone();
+        if info.is_single_file() && !url.path().ends_with('/') {
+            return Some(url);
+        }
+
+        let parts: Vec<&str> = path
+            .components()
+            .map(|part| part.as_os_str().to_str())
+            .collect::<Option<_>>()?;
+        url.path_segments_mut().ok()?.pop_if_empty().extend(parts);
+        Some(url)
+    }
+
+    /// The URL of the piece at `index` from an http seed, the info hash and
+    /// index appended to any query the URL already has.
+    pub fn piece_url(&self, info_hash: &InfoHash, index: usize) -> Url {
+        let mut url = self.url.clone();
+        let params = format!("info_hash={}&piece={index}", info_hash.url_encoded());
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{params}"),
+            _ => params,
+        };
+        url.set_query(Some(&query));
+        url
+    }
+
+    /// Fetch the piece at `index` and check it against its hash.
+    pub async fn fetch_piece(
+        &mut self,
+        info: &Info,
+        index: usize,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        self.rate_limiter
+            .throttle_async(Direction::Download, info.piece_len(index))
+            .await;
+        let result = self.try_fetch_piece(info, index).await;
+        match &result {
+            Ok(piece) => {
+                self.failures = 0;
+                self.retry_at = None;
+                self.downloaded += piece.len() as u64;
+            }
+            Err(err) => self.failed(err, Instant::now()),
+        }
+        result
+    }
+
+    fn failed(&mut self, err: &WebSeedError, now: Instant) {
+        self.failures += 1;
+        let wait = match err {
+            WebSeedError::Busy(wait) => *wait,
+            _ => DEFAULT_BACKOFF.saturating_mul(1 << (self.failures - 1).min(16)),
+        };
+        // A server asking for a wait of years would overflow the instant
+        self.retry_at = Some(now + wait.min(MAX_BACKOFF));
+    }
+
+    async fn try_fetch_piece(&self, info: &Info, index: usize) -> Result<Vec<u8>, WebSeedError> {
+        let piece = match self.protocol {
+            SeedProtocol::WebSeed => self.fetch_files(info, index).await?,
+            SeedProtocol::HttpSeed { info_hash } => {
+                self.fetch_from_script(info_hash, index, info.piece_len(index))
+                    .await?
+            }
+        };
+
+        let digest = sha1_smol::Sha1::from(&piece).digest().bytes();
+        if info.pieces().get(index) != Some(&digest) {
+            return Err(WebSeedError::HashMismatch);
+        }
+        Ok(piece)
+    }
+
+    async fn fetch_files(&self, info: &Info, index: usize) -> Result<Vec<u8>, WebSeedError> {
+        let attributes = info.file_attributes();
+        let mut piece = Vec::with_capacity(info.piece_len(index));
+
+        for span in info.piece_spans(index) {
+            // Pad files are zeros and not hosted by the server
+            if attributes[span.file_index].padding {
+                piece.resize(piece.len() + span.len as usize, 0);
+                continue;
+            }
+            if span.len == 0 {
+                continue;
+            }
+
+            let url = self
+                .file_url(info, span.file_index)
+                .ok_or(WebSeedError::InvalidUrl)?;
+            piece.extend(self.fetch_range(url, span.offset, span.len).await?);
+        }
+        Ok(piece)
+    }
+
+    async fn fetch_from_script(
+        &self,
+        info_hash: InfoHash,
+        index: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let response = self
+            .http
+            .get(self.piece_url(&info_hash, index))
+            .send()
+            .await?;
+
+        // A busy http seed answers with the seconds to wait as the body
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response
+                .text()
+                .await
+                .ok()
+                .and_then(|body| body.trim().parse().ok())
+                .map_or(DEFAULT_BACKOFF, Duration::from_secs);
+            return Err(WebSeedError::Busy(retry_after));
+        }
+        if !status.is_success() {
+            return Err(WebSeedError::Status(status.as_u16()));
+        }
+
+        let body = response.bytes().await?;
+        if body.len() != len {
+            return Err(WebSeedError::ShortResponse);
+        }
+        Ok(body.to_vec())
+    }
+
+    async fn fetch_range(&self, url: Url, offset: u64, len: u64) -> Result<Vec<u8>, WebSeedError> {
+        let mut response = self
+            .http
+            .get(url)
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", offset, offset + len - 1),
+            )
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .map_or(DEFAULT_BACKOFF, Duration::from_secs);
+            return Err(WebSeedError::Busy(retry_after));
+        }
+        if !status.is_success() {
+            return Err(WebSeedError::Status(status.as_u16()));
+        }
+        // A server that ignores the range sends the whole file, which is
+        // only of use when the range starts it
+        if status != StatusCode::PARTIAL_CONTENT && offset > 0 {
+            return Err(WebSeedError::RangeIgnored);
+        }
+
+        // Never more than was asked for, whatever the server sends
+        let len = len as usize;
+        let mut body = Vec::with_capacity(len);
+        while body.len() < len {
+            let Some(chunk) = response.chunk().await? else {
+                break;
+            };
+            let take = chunk.len().min(len - body.len());
+            body.extend_from_slice(&chunk[..take]);
+        }
+        match body.len() == len {
+            true => Ok(body),
+            false => Err(WebSeedError::ShortResponse),
+        }
+    }
+}